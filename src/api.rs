@@ -0,0 +1,220 @@
+//! Hyprwatch server API client
+//!
+//! Talks to shadow's own endpoints under `/api/shadow/`. The osquery TLS
+//! endpoints are handled by osqueryd itself and are not routed through here.
+
+use crate::errors;
+use crate::logging::info;
+use crate::telemetry;
+use anyhow::{Context, Result};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Instant;
+use tokio::fs;
+
+/// A tag for the host, given as KEY=VALUE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Tag {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s
+            .split_once('=')
+            .filter(|(key, _)| !key.trim().is_empty())
+            .ok_or_else(|| format!("expected KEY=VALUE, got '{}'", s))?;
+        Ok(Tag {
+            key: key.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct EnrollResponse {
+    pub enroll_secret: String,
+}
+
+/// Client for the shadow API, authenticated with the enroll secret once enrolled
+#[derive(Clone)]
+pub struct ApiClient {
+    client: reqwest::Client,
+    server: String,
+    host_id: String,
+    enroll_secret: Option<String>,
+}
+
+impl ApiClient {
+    pub async fn new(server: &str, ca_cert: Option<&Path>, host_id: &str) -> Result<Self> {
+        let client = if let Some(ca_path) = ca_cert {
+            let cert_pem = fs::read(ca_path).await?;
+            let cert = reqwest::Certificate::from_pem(&cert_pem)?;
+            reqwest::Client::builder()
+                .add_root_certificate(cert)
+                .build()?
+        } else {
+            reqwest::Client::new()
+        };
+
+        Ok(Self {
+            client,
+            server: server.to_string(),
+            host_id: host_id.to_string(),
+            enroll_secret: None,
+        })
+    }
+
+    pub fn host_id(&self) -> &str {
+        &self.host_id
+    }
+
+    /// The HTTP client, trusting the server's CA
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("https://{}{}", self.server, path)
+    }
+
+    /// Enroll with the server, returning the enroll secret for osqueryd. Tags
+    /// describe where the host runs, e.g. its Kubernetes node.
+    #[tracing::instrument(name = "enroll", skip_all, fields(host_id = %self.host_id))]
+    pub async fn enroll(
+        &mut self,
+        org_token: &str,
+        tags: &BTreeMap<String, String>,
+    ) -> Result<String> {
+        let mut body = serde_json::json!({
+            "host_id": self.host_id,
+            "org_token": org_token,
+        });
+        if !tags.is_empty() {
+            body["tags"] = serde_json::json!(tags);
+        }
+
+        let started = Instant::now();
+        let response = self
+            .client
+            .post(self.url("/api/shadow/enroll"))
+            .json(&body)
+            .send()
+            .await;
+        let elapsed = started.elapsed();
+        let result = match &response {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        telemetry::record_enroll(elapsed, &result);
+        info!(
+            status = %result,
+            duration_ms = elapsed.as_millis() as u64,
+            "Enrollment request finished in {} ms ({})",
+            elapsed.as_millis(),
+            result
+        );
+        let response = response
+            .context("Failed to connect to server")
+            .context(errors::SERVER_UNREACHABLE)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let code = match status {
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => errors::ENROLL_UNAUTHORIZED,
+                status if status.is_server_error() => errors::ENROLL_SERVER_ERROR,
+                _ => errors::ENROLL_REJECTED,
+            };
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("Enrollment failed ({}): {}", status, body).context(code));
+        }
+
+        let res: EnrollResponse = response
+            .json()
+            .await
+            .context("Failed to parse enrollment response")
+            .context(errors::ENROLL_BAD_RESPONSE)?;
+
+        self.enroll_secret = Some(res.enroll_secret.clone());
+        Ok(res.enroll_secret)
+    }
+
+    /// POST a JSON payload to an authenticated shadow endpoint
+    pub async fn post<T: Serialize + ?Sized>(&self, path: &str, payload: &T) -> Result<()> {
+        self.send(path, |request| request.json(payload)).await.map(|_| ())
+    }
+
+    /// POST a JSON payload and parse the server's JSON reply, or the default
+    /// if it replied with nothing
+    pub async fn post_for<T: Serialize + ?Sized, R: DeserializeOwned + Default>(
+        &self,
+        path: &str,
+        payload: &T,
+    ) -> Result<R> {
+        let body = self
+            .send(path, |request| request.json(payload))
+            .await?
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read the reply to {}", path))?;
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(R::default());
+        }
+        serde_json::from_slice(&body).with_context(|| format!("Failed to parse the reply to {}", path))
+    }
+
+    /// POST a file to an authenticated shadow endpoint, naming the host in the
+    /// query string
+    pub async fn upload(&self, path: &str, content_type: &str, data: Vec<u8>) -> Result<()> {
+        self.send(path, |request| {
+            request
+                .query(&[("host_id", &self.host_id)])
+                .header(reqwest::header::CONTENT_TYPE, content_type)
+                .body(data)
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Use the enroll secret from an earlier enrollment, while the server
+    /// can't be reached
+    pub fn reuse_enrollment(&mut self, enroll_secret: String) {
+        self.enroll_secret = Some(enroll_secret);
+    }
+
+    /// The enroll secret, once enrolled
+    pub fn enroll_secret(&self) -> Option<&str> {
+        self.enroll_secret.as_deref()
+    }
+
+    async fn send(
+        &self,
+        path: &str,
+        build: impl FnOnce(reqwest::RequestBuilder) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response> {
+        let secret = self
+            .enroll_secret
+            .as_deref()
+            .context("Not enrolled with server")?;
+
+        let request = self.client.post(self.url(path)).bearer_auth(secret);
+        let response = build(request)
+            .send()
+            .await
+            .with_context(|| format!("Failed to POST {}", path))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("POST {} failed ({}): {}", path, status, body);
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,318 @@
+//! Encryption at rest
+//!
+//! With `--encrypt-at-rest`, what the agent keeps on disk for when the server
+//! is unreachable (the spooled logs and live query results, the last config,
+//! and the last enrollment with its enroll secret) is encrypted with
+//! AES-256-GCM, so a stolen laptop's data directory doesn't give up weeks of
+//! collected results in plaintext. The key is made on first use and held by
+//! the OS: as a systemd credential on Linux (sealed with the TPM if there is
+//! one, and the host's credential secret), in the System keychain on macOS,
+//! and with DPAPI on Windows, tied to the account the agent runs as. Files
+//! written before it was turned on are still read, and replaced as they're
+//! rewritten. If the key is lost, what it encrypted is dropped as unreadable.
+//!
+//! Only root can use the host's credential secret, so for a Linux service
+//! running as its own account, `shadow service install` makes the key and the
+//! unit has systemd decrypt it into the service's credentials directory.
+
+use anyhow::{Context, Result};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// Start of an encrypted file, followed by the nonce and the ciphertext
+const MAGIC: &[u8] = b"SHADOWENC1";
+
+/// The key, once `--encrypt-at-rest` has loaded it
+static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+
+/// Load the key from the OS keystore, making it on first use, so that
+/// [`seal`] encrypts from now on
+pub fn init(data_dir: &Path) -> Result<()> {
+    let key = match platform::load(data_dir).context("Failed to read the at-rest key from the OS keystore")? {
+        Some(key) => key,
+        None => {
+            let key = new_key()?;
+            platform::store(data_dir, &key).context("Failed to save the at-rest key in the OS keystore")?;
+            key
+        }
+    };
+    let key = UnboundKey::new(&AES_256_GCM, &key).map_err(|_| anyhow::anyhow!("The at-rest key is malformed"))?;
+    let _ = KEY.set(LessSafeKey::new(key));
+    Ok(())
+}
+
+/// Make the key for the service ahead of time, as root, unless it's made
+/// already, returning the credential systemd is to load it from
+#[cfg(target_os = "linux")]
+pub fn provision(data_dir: &Path) -> Result<std::path::PathBuf> {
+    let path = data_dir.join(platform::KEY_FILE);
+    if !path.exists() {
+        platform::store(data_dir, &new_key()?).context("Failed to save the at-rest key as a systemd credential")?;
+    }
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+pub use platform::CREDENTIAL_NAME;
+
+fn new_key() -> Result<Vec<u8>> {
+    let mut key = vec![0u8; AES_256_GCM.key_len()];
+    SystemRandom::new()
+        .fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("Failed to generate the at-rest key"))?;
+    Ok(key)
+}
+
+/// `data` encrypted, if encryption at rest is on
+pub fn seal(data: &[u8]) -> Result<Vec<u8>> {
+    let Some(key) = KEY.get() else {
+        return Ok(data.to_vec());
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("Failed to generate a nonce"))?;
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + data.len() + AES_256_GCM.tag_len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    let mut ciphertext = data.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(MAGIC), &mut ciphertext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// The plaintext of what [`seal`] wrote, or of a file written unencrypted
+pub fn open(data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(sealed) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+    let key = KEY
+        .get()
+        .context("It's encrypted, and --encrypt-at-rest is off, so the key isn't loaded")?;
+    if sealed.len() < NONCE_LEN {
+        anyhow::bail!("It's truncated");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow::anyhow!("It's truncated"))?;
+    let mut plaintext = ciphertext.to_vec();
+    let len = key
+        .open_in_place(nonce, Aad::from(MAGIC), &mut plaintext)
+        .map_err(|_| anyhow::anyhow!("It can't be decrypted with the at-rest key; it's corrupt or from another key"))?
+        .len();
+    plaintext.truncate(len);
+    Ok(plaintext)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{Context, Result};
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+
+    /// The key, encrypted by `systemd-creds`
+    pub const KEY_FILE: &str = "at_rest_key.cred";
+
+    /// Name bound into the credential, so it can't be passed off as another,
+    /// and the name systemd gives it in the credentials directory
+    pub const CREDENTIAL_NAME: &str = "shadow-at-rest-key";
+
+    /// Before systemd 256, `systemd-creds` needs root to read the host's
+    /// credential secret, so any other account has to be given the key
+    fn check_root() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        if std::fs::metadata("/proc/self").is_ok_and(|meta| meta.uid() == 0) {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "Only root can use systemd-creds; install the service with --encrypt-at-rest so systemd gives \
+             the agent the key, or run it as root"
+        )
+    }
+
+    pub fn load(data_dir: &Path) -> Result<Option<Vec<u8>>> {
+        // The service's unit has systemd decrypt the key for us
+        if let Some(dir) = std::env::var_os("CREDENTIALS_DIRECTORY") {
+            let path = Path::new(&dir).join(CREDENTIAL_NAME);
+            if path.exists() {
+                let key = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+                return Ok(Some(key));
+            }
+        }
+        let path = data_dir.join(KEY_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        check_root()?;
+        let output = Command::new("systemd-creds")
+            .arg("decrypt")
+            .arg(format!("--name={}", CREDENTIAL_NAME))
+            .arg(&path)
+            .arg("-")
+            .stdin(Stdio::null())
+            .output()
+            .context("Failed to run systemd-creds")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "systemd-creds couldn't decrypt {}: {}",
+                path.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(Some(output.stdout))
+    }
+
+    pub fn store(data_dir: &Path, key: &[u8]) -> Result<()> {
+        check_root()?;
+        let path = data_dir.join(KEY_FILE);
+        let temp = path.with_extension("tmp");
+        let mut child = Command::new("systemd-creds")
+            .arg("encrypt")
+            .arg(format!("--name={}", CREDENTIAL_NAME))
+            .arg("-")
+            .arg(&temp)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to run systemd-creds, which encryption at rest needs on Linux")?;
+        // The key goes through a pipe, never the command line
+        child.stdin.take().context("No stdin for systemd-creds")?.write_all(key)?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "systemd-creds couldn't encrypt the key: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        std::fs::rename(&temp, &path).with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::Result;
+    use security_framework::passwords;
+    use std::path::Path;
+
+    const SERVICE: &str = "com.hyprwatch.shadow.at-rest-key";
+
+    /// errSecItemNotFound
+    const NOT_FOUND: i32 = -25300;
+
+    /// Each data directory has its own key
+    fn account(data_dir: &Path) -> String {
+        data_dir.display().to_string()
+    }
+
+    pub fn load(data_dir: &Path) -> Result<Option<Vec<u8>>> {
+        match passwords::get_generic_password(SERVICE, &account(data_dir)) {
+            Ok(key) => Ok(Some(key)),
+            Err(e) if e.code() == NOT_FOUND => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn store(data_dir: &Path, key: &[u8]) -> Result<()> {
+        passwords::set_generic_password(SERVICE, &account(data_dir), key)?;
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use anyhow::{Context, Result};
+    use std::path::Path;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Cryptography::{
+        CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN, CRYPT_INTEGER_BLOB,
+    };
+
+    /// The key, protected by DPAPI for the agent's account
+    const KEY_FILE: &str = "at_rest_key.dpapi";
+
+    /// Run `f`, a DPAPI call, on `data`, returning its output
+    fn dpapi(
+        data: &[u8],
+        f: impl FnOnce(*const CRYPT_INTEGER_BLOB, *mut CRYPT_INTEGER_BLOB) -> windows_sys::core::BOOL,
+    ) -> Result<Vec<u8>> {
+        let input = CRYPT_INTEGER_BLOB {
+            cbData: data.len() as u32,
+            pbData: data.as_ptr() as *mut u8,
+        };
+        let mut output = CRYPT_INTEGER_BLOB {
+            cbData: 0,
+            pbData: std::ptr::null_mut(),
+        };
+        if f(&input, &mut output) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // SAFETY: on success DPAPI returns `cbData` bytes at `pbData`,
+        // allocated with LocalAlloc for the caller to free
+        let result = unsafe { std::slice::from_raw_parts(output.pbData, output.cbData as usize) }.to_vec();
+        unsafe { LocalFree(output.pbData.cast()) };
+        Ok(result)
+    }
+
+    pub fn load(data_dir: &Path) -> Result<Option<Vec<u8>>> {
+        let path = data_dir.join(KEY_FILE);
+        let protected = match std::fs::read(&path) {
+            Ok(protected) => protected,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        // SAFETY: the blobs are valid for the call, and the optional
+        // arguments are null
+        let key = dpapi(&protected, |input, output| unsafe {
+            CryptUnprotectData(
+                input,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })
+        .with_context(|| format!("DPAPI couldn't decrypt {}", path.display()))?;
+        Ok(Some(key))
+    }
+
+    pub fn store(data_dir: &Path, key: &[u8]) -> Result<()> {
+        // SAFETY: as in `load`
+        let protected = dpapi(key, |input, output| unsafe {
+            CryptProtectData(
+                input,
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                output,
+            )
+        })
+        .context("DPAPI couldn't encrypt the key")?;
+        let path = data_dir.join(KEY_FILE);
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, protected)
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use anyhow::Result;
+    use std::path::Path;
+
+    pub fn load(_data_dir: &Path) -> Result<Option<Vec<u8>>> {
+        anyhow::bail!("There's no supported OS keystore on this platform")
+    }
+
+    pub fn store(_data_dir: &Path, _key: &[u8]) -> Result<()> {
+        anyhow::bail!("There's no supported OS keystore on this platform")
+    }
+}
@@ -0,0 +1,260 @@
+//! Local audit log
+//!
+//! Append-only JSON lines in the data directory recording security-relevant
+//! actions taken on the agent, and by whom: enrollment, the settings it was
+//! started with and what changed since the previous start, osquery binaries it
+//! installed (with their hashes), osqueryd restarts, pauses, database purges,
+//! and service management.
+//!
+//! The log is hash-chained: each entry carries a sequence number and the
+//! SHA-256 of the line before it, so editing or removing an entry breaks the
+//! chain from that point, which `shadow audit verify` reports. Rewriting the
+//! whole chain is caught by the server, which receives the latest entry's hash
+//! (the head) in every heartbeat. Writers hold an exclusive lock on the file
+//! while appending, so entries from `shadow pause` and the running agent chain
+//! correctly.
+
+use crate::logging::error;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Audit log filename within the data directory
+pub const AUDIT_LOG_FILE: &str = "audit.log";
+
+/// Actor for actions the agent takes on its own
+pub const AGENT: &str = "shadow";
+
+/// `prev` of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Serialize)]
+struct AuditEntry<'a> {
+    seq: u64,
+    prev: String,
+    time: DateTime<Utc>,
+    actor: &'a str,
+    action: &'a str,
+    detail: serde_json::Value,
+}
+
+/// The chain fields of an entry, as read back
+#[derive(Debug, Deserialize)]
+struct Chain {
+    seq: Option<u64>,
+    prev: Option<String>,
+    action: Option<String>,
+    #[serde(default)]
+    detail: serde_json::Value,
+}
+
+/// The latest entry, reported to the server so a rewritten log is noticed
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditHead {
+    pub seq: u64,
+    pub hash: String,
+}
+
+/// The user responsible for the current process, preferring the sudo caller
+pub fn current_user() -> String {
+    ["SUDO_USER", "USER", "USERNAME"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// The last line of the log, without its newline
+fn last_line(file: &mut File) -> std::io::Result<Option<Vec<u8>>> {
+    let len = file.metadata()?.len();
+    // Entries are small, so the last one is almost always in the final chunk
+    let mut start = len.saturating_sub(64 * 1024);
+    loop {
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        while tail.last() == Some(&b'\n') {
+            tail.pop();
+        }
+        match tail.iter().rposition(|&b| b == b'\n') {
+            Some(newline) => return Ok(Some(tail[newline + 1..].to_vec())),
+            None if start == 0 => return Ok((!tail.is_empty()).then_some(tail)),
+            None => start = 0,
+        }
+    }
+}
+
+/// Sequence number and hash of the last entry, if there is one
+fn head_of(file: &mut File) -> std::io::Result<Option<AuditHead>> {
+    let Some(line) = last_line(file)? else {
+        return Ok(None);
+    };
+    // A last line without one is reported by `shadow audit verify`
+    let seq = serde_json::from_slice::<Chain>(&line)
+        .ok()
+        .and_then(|chain| chain.seq)
+        .unwrap_or(0);
+    Ok(Some(AuditHead {
+        seq,
+        hash: sha256(&line),
+    }))
+}
+
+fn append(path: &Path, actor: &str, action: &str, detail: serde_json::Value) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .context("Failed to open audit log")?;
+    file.lock().context("Failed to lock audit log")?;
+
+    let head = head_of(&mut file)?;
+    let entry = AuditEntry {
+        seq: head.as_ref().map_or(1, |head| head.seq + 1),
+        prev: head.map_or_else(|| GENESIS.to_string(), |head| head.hash),
+        time: Utc::now(),
+        actor,
+        action,
+        detail,
+    };
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+    file.write_all(&line).context("Failed to write audit log")?;
+    Ok(())
+}
+
+/// Append an entry to the audit log for `actor`
+pub async fn record_by(data_dir: &Path, actor: &str, action: &str, detail: serde_json::Value) -> Result<()> {
+    let path = data_dir.join(AUDIT_LOG_FILE);
+    let (actor, action) = (actor.to_string(), action.to_string());
+    tokio::task::spawn_blocking(move || append(&path, &actor, &action, detail)).await?
+}
+
+/// Append an entry to the audit log for the current user
+pub async fn record(data_dir: &Path, action: &str, detail: serde_json::Value) -> Result<()> {
+    record_by(data_dir, &current_user(), action, detail).await
+}
+
+/// Record an action the agent took on its own, logging rather than failing
+/// if the log can't be written
+pub async fn record_agent(data_dir: &Path, action: &str, detail: serde_json::Value) {
+    if let Err(e) = record_by(data_dir, AGENT, action, detail).await {
+        error!("Failed to write audit log: {:#}", e);
+    }
+}
+
+/// The latest entry, if the log exists
+pub fn head(data_dir: &Path) -> Option<AuditHead> {
+    let mut file = File::open(data_dir.join(AUDIT_LOG_FILE)).ok()?;
+    head_of(&mut file).ok().flatten()
+}
+
+/// Stand-in for a secret setting: enough to see that it changed, without
+/// recording it
+pub fn redact(value: &str) -> String {
+    format!("sha256:{}", &sha256(value.as_bytes())[..12])
+}
+
+/// The settings recorded by the most recent start
+fn previous_settings(path: &Path) -> Option<BTreeMap<String, String>> {
+    let data = std::fs::read(path).ok()?;
+    data.split(|&b| b == b'\n')
+        .rev()
+        .filter_map(|line| serde_json::from_slice::<Chain>(line).ok())
+        .find(|chain| chain.action.as_deref() == Some("start"))
+        .and_then(|chain| serde_json::from_value(chain.detail["settings"].clone()).ok())
+}
+
+/// Record the agent starting with `settings`, and which of them changed since
+/// the previous start
+pub async fn record_start(data_dir: &Path, settings: BTreeMap<String, String>) {
+    let path = data_dir.join(AUDIT_LOG_FILE);
+    let previous = tokio::task::spawn_blocking(move || previous_settings(&path))
+        .await
+        .ok()
+        .flatten();
+    let changed: BTreeMap<&String, serde_json::Value> = match &previous {
+        Some(previous) => previous
+            .keys()
+            .chain(settings.keys())
+            .filter(|key| previous.get(*key) != settings.get(*key))
+            .map(|key| {
+                let change = serde_json::json!({ "from": previous.get(key), "to": settings.get(key) });
+                (key, change)
+            })
+            .collect(),
+        None => BTreeMap::new(),
+    };
+    let detail = serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "settings": settings,
+        "changed": changed,
+    });
+    if let Err(e) = record(data_dir, "start", detail).await {
+        error!("Failed to write audit log: {:#}", e);
+    }
+}
+
+/// Result of checking the hash chain
+#[derive(Debug)]
+pub struct Verified {
+    /// The latest entry, if there are any
+    pub head: Option<AuditHead>,
+}
+
+/// Check the hash chain, failing at the first altered entry
+pub async fn check(data_dir: &Path) -> Result<Verified> {
+    let path = data_dir.join(AUDIT_LOG_FILE);
+    let data = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut previous: Option<(u64, String)> = None;
+    for (index, line) in data.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        let chain: Chain = serde_json::from_slice(line)
+            .with_context(|| format!("Line {} isn't a valid entry, the log has been altered", number))?;
+        let hash = sha256(line);
+        let (Some(seq), Some(prev)) = (chain.seq, chain.prev) else {
+            anyhow::bail!("Line {} is missing its chain fields, the log has been altered", number);
+        };
+        let (expected_seq, expected_prev) = match &previous {
+            Some((previous_seq, previous_hash)) => (previous_seq + 1, previous_hash.as_str()),
+            None => (1, GENESIS),
+        };
+        if seq != expected_seq || prev != expected_prev {
+            anyhow::bail!(
+                "Chain broken at line {} (entry {}): the log has been altered before this point",
+                number,
+                seq
+            );
+        }
+        previous = Some((seq, hash));
+    }
+
+    Ok(Verified {
+        head: previous.map(|(seq, hash)| AuditHead { seq, hash }),
+    })
+}
+
+/// `shadow audit verify`
+pub async fn verify(data_dir: &Path) -> Result<()> {
+    let verified = check(data_dir).await?;
+    match verified.head {
+        Some(head) => println!("Audit log intact: {} entries, head {}", head.seq, head.hash),
+        None => println!("Audit log is empty"),
+    }
+    Ok(())
+}
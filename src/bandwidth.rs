@@ -0,0 +1,60 @@
+//! Upload bandwidth limits
+//!
+//! Hosts on metered or satellite links may have a contractual budget for how
+//! much they send. With `--result-upload-limit` and `--carve-upload-limit` and
+//! the TLS relay, osqueryd's uploads are held back so each kind stays under
+//! its own ceiling, measured as sent (after compression). Each ceiling is a
+//! token bucket: a short burst is allowed, then requests wait their turn, in
+//! the order they came, until the bucket has refilled enough for them.
+
+use crate::telemetry;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Seconds' worth of bytes that can go at once after a quiet spell
+const BURST_SECONDS: f64 = 2.0;
+
+/// A ceiling on bytes per second
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    burst: f64,
+    /// Tokens left, negative while requests are waiting for ones not yet
+    /// refilled, and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// A bucket for `kb_per_sec` kilobytes per second
+    pub fn new(kb_per_sec: u64) -> Self {
+        let rate = (kb_per_sec.max(1) * 1024) as f64;
+        let burst = rate * BURST_SECONDS;
+        TokenBucket {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Wait until `bytes` can be sent. A request bigger than the burst still
+    /// goes, once the ones before it have, and holds up those after it for as
+    /// long as it takes the bucket to refill.
+    pub async fn acquire(&self, category: &str, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+            *last = now;
+            *tokens -= bytes as f64;
+            match *tokens < 0.0 {
+                true => Duration::from_secs_f64(-*tokens / self.rate),
+                false => Duration::ZERO,
+            }
+        };
+        if !wait.is_zero() {
+            telemetry::record_upload_throttled(category, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
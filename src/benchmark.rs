@@ -0,0 +1,550 @@
+//! Compliance benchmarks
+//!
+//! `shadow benchmark cis` checks the host against the CIS benchmark
+//! recommendations for its OS that osquery can see, e.g. kernel parameters,
+//! file permissions, firewall and disk encryption settings, by running each
+//! check's query in osqueryd's shell. Each check passes or fails by whether
+//! its query returns rows, and the score is the share of checks that passed,
+//! leaving out any that couldn't run. The report goes to the terminal, to a
+//! JSON or HTML file for auditors, and with `--upload` to the server through
+//! the running agent. The checks are a subset of each benchmark, not a
+//! certified assessment.
+
+use crate::api::ApiClient;
+use crate::audit;
+use crate::ipc;
+use crate::osquery;
+use crate::output::{self, OutputFormat};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Where uploaded reports go
+const UPLOAD_PATH: &str = "/api/shadow/benchmarks";
+
+/// A benchmark `shadow benchmark` can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// CIS benchmark for the host's OS
+    Cis,
+}
+
+/// What a check's query has to return to pass
+#[derive(Clone, Copy)]
+enum Expect {
+    Rows,
+    NoRows,
+}
+
+struct Rule {
+    id: &'static str,
+    section: &'static str,
+    title: &'static str,
+    platform: &'static str,
+    sql: &'static str,
+    expect: Expect,
+}
+
+const CIS: &[Rule] = &[
+    // Linux
+    Rule {
+        id: "linux-cramfs",
+        section: "Filesystem",
+        title: "The cramfs module isn't loaded",
+        platform: "linux",
+        sql: "SELECT name FROM kernel_modules WHERE name = 'cramfs'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-usb-storage",
+        section: "Filesystem",
+        title: "The usb-storage module isn't loaded",
+        platform: "linux",
+        sql: "SELECT name FROM kernel_modules WHERE name = 'usb_storage'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-tmp-partition",
+        section: "Filesystem",
+        title: "/tmp is a separate partition",
+        platform: "linux",
+        sql: "SELECT path FROM mounts WHERE path = '/tmp'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-tmp-options",
+        section: "Filesystem",
+        title: "/tmp is mounted nodev, nosuid, and noexec",
+        platform: "linux",
+        sql: "SELECT path FROM mounts WHERE path = '/tmp' AND flags LIKE '%nodev%' AND flags LIKE '%nosuid%' \
+              AND flags LIKE '%noexec%'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-bootloader-permissions",
+        section: "Secure boot",
+        title: "The bootloader config is owned by root and not readable by others",
+        platform: "linux",
+        sql: "SELECT path FROM file WHERE path IN ('/boot/grub/grub.cfg', '/boot/grub2/grub.cfg') \
+              AND (uid != 0 OR gid != 0 OR mode NOT IN ('0400', '0600'))",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-aslr",
+        section: "Process hardening",
+        title: "Address space layout randomization is enabled",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'kernel.randomize_va_space' AND current_value = '2'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-suid-dumpable",
+        section: "Process hardening",
+        title: "setuid programs can't dump core",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'fs.suid_dumpable' AND current_value = '0'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-ip-forward",
+        section: "Network",
+        title: "IP forwarding is disabled",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'net.ipv4.ip_forward' AND current_value != '0'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-send-redirects",
+        section: "Network",
+        title: "ICMP redirects aren't sent",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'net.ipv4.conf.all.send_redirects' AND current_value != '0'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-accept-redirects",
+        section: "Network",
+        title: "ICMP redirects aren't accepted",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'net.ipv4.conf.all.accept_redirects' \
+              AND current_value != '0'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-source-route",
+        section: "Network",
+        title: "Source-routed packets aren't accepted",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'net.ipv4.conf.all.accept_source_route' \
+              AND current_value != '0'",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "linux-syncookies",
+        section: "Network",
+        title: "TCP SYN cookies are enabled",
+        platform: "linux",
+        sql: "SELECT name FROM system_controls WHERE name = 'net.ipv4.tcp_syncookies' AND current_value = '1'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-auditd",
+        section: "Logging and auditing",
+        title: "auditd is running",
+        platform: "linux",
+        sql: "SELECT pid FROM processes WHERE name = 'auditd'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-passwd-permissions",
+        section: "System file permissions",
+        title: "/etc/passwd is owned by root and not writable by others",
+        platform: "linux",
+        sql: "SELECT path FROM file WHERE path = '/etc/passwd' AND uid = 0 AND gid = 0 AND mode IN ('0644', '0640', '0600')",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-shadow-permissions",
+        section: "System file permissions",
+        title: "The password hash file isn't readable by others",
+        platform: "linux",
+        sql: "SELECT path FROM file WHERE path = '/etc/shadow' AND uid = 0 AND mode IN ('0000', '0400', '0600', '0640')",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "linux-uid-0",
+        section: "User accounts",
+        title: "root is the only account with UID 0",
+        platform: "linux",
+        sql: "SELECT username FROM users WHERE uid = 0 AND username != 'root'",
+        expect: Expect::NoRows,
+    },
+    // macOS
+    Rule {
+        id: "macos-auto-update",
+        section: "Software updates",
+        title: "Automatic update checks are enabled",
+        platform: "darwin",
+        sql: "SELECT key FROM plist WHERE path = '/Library/Preferences/com.apple.SoftwareUpdate.plist' \
+              AND key = 'AutomaticCheckEnabled' AND value = '1'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-firewall",
+        section: "Network",
+        title: "The application firewall is enabled",
+        platform: "darwin",
+        sql: "SELECT global_state FROM alf WHERE global_state >= 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-firewall-stealth",
+        section: "Network",
+        title: "The firewall's stealth mode is enabled",
+        platform: "darwin",
+        sql: "SELECT stealth_enabled FROM alf WHERE stealth_enabled = 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-remote-login",
+        section: "Sharing",
+        title: "Remote login (SSH) is disabled",
+        platform: "darwin",
+        sql: "SELECT remote_login FROM sharing_preferences WHERE remote_login = 1",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "macos-screen-sharing",
+        section: "Sharing",
+        title: "Screen sharing is disabled",
+        platform: "darwin",
+        sql: "SELECT screen_sharing FROM sharing_preferences WHERE screen_sharing = 1",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "macos-file-sharing",
+        section: "Sharing",
+        title: "File sharing is disabled",
+        platform: "darwin",
+        sql: "SELECT file_sharing FROM sharing_preferences WHERE file_sharing = 1",
+        expect: Expect::NoRows,
+    },
+    Rule {
+        id: "macos-filevault",
+        section: "Storage",
+        title: "FileVault encrypts the boot volume",
+        platform: "darwin",
+        sql: "SELECT de.name FROM mounts m JOIN disk_encryption de ON m.device_alias = de.name \
+              WHERE m.path = '/' AND de.encrypted = 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-gatekeeper",
+        section: "System integrity",
+        title: "Gatekeeper is enabled",
+        platform: "darwin",
+        sql: "SELECT assessments_enabled FROM gatekeeper WHERE assessments_enabled = 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-sip",
+        section: "System integrity",
+        title: "System Integrity Protection is enabled",
+        platform: "darwin",
+        sql: "SELECT enabled FROM sip_config WHERE config_flag = 'sip' AND enabled = 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "macos-guest",
+        section: "User accounts",
+        title: "The guest account is disabled",
+        platform: "darwin",
+        sql: "SELECT key FROM plist WHERE path = '/Library/Preferences/com.apple.loginwindow.plist' \
+              AND key = 'GuestEnabled' AND value = '1'",
+        expect: Expect::NoRows,
+    },
+    // Windows
+    Rule {
+        id: "windows-firewall",
+        section: "Network",
+        title: "Windows Firewall is on",
+        platform: "windows",
+        sql: "SELECT firewall FROM windows_security_center WHERE firewall = 'Good'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-rdp",
+        section: "Network",
+        title: "Remote Desktop connections are denied",
+        platform: "windows",
+        sql: "SELECT data FROM registry WHERE path = \
+              'HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Control\\Terminal Server\\fDenyTSConnections' \
+              AND data = '1'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-smb1",
+        section: "Network",
+        title: "The SMBv1 server is disabled",
+        platform: "windows",
+        sql: "SELECT data FROM registry WHERE path = \
+              'HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Services\\LanmanServer\\Parameters\\SMB1' \
+              AND data = '0'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-auto-update",
+        section: "Software updates",
+        title: "Automatic updates are on",
+        platform: "windows",
+        sql: "SELECT autoupdate FROM windows_security_center WHERE autoupdate = 'Good'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-antivirus",
+        section: "Malware defenses",
+        title: "Antivirus is on and up to date",
+        platform: "windows",
+        sql: "SELECT antivirus FROM windows_security_center WHERE antivirus = 'Good'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-uac",
+        section: "User accounts",
+        title: "User Account Control is on",
+        platform: "windows",
+        sql: "SELECT user_account_control FROM windows_security_center WHERE user_account_control = 'Good'",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-bitlocker",
+        section: "Storage",
+        title: "BitLocker protects the system drive",
+        platform: "windows",
+        sql: "SELECT drive_letter FROM bitlocker_info WHERE drive_letter = 'C:' AND protection_status = 1",
+        expect: Expect::Rows,
+    },
+    Rule {
+        id: "windows-lsa-protection",
+        section: "Credential protection",
+        title: "LSA runs as a protected process",
+        platform: "windows",
+        sql: "SELECT data FROM registry WHERE path = \
+              'HKEY_LOCAL_MACHINE\\SYSTEM\\CurrentControlSet\\Control\\Lsa\\RunAsPPL' AND data IN ('1', '2')",
+        expect: Expect::Rows,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Pass,
+    Fail,
+    /// The query couldn't run, e.g. a table missing on this OS version
+    Error,
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outcome {
+    pub id: String,
+    pub section: String,
+    pub title: String,
+    pub status: Status,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A benchmark's results on this host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub benchmark: Kind,
+    pub platform: String,
+    pub hostname: Option<String>,
+    pub os: Option<String>,
+    pub generated_at: DateTime<Utc>,
+    /// Percent of the checks that ran that passed
+    pub score: f64,
+    pub passed: usize,
+    pub failed: usize,
+    pub errors: usize,
+    pub checks: Vec<Outcome>,
+}
+
+/// Run a benchmark's checks for this platform with `osqueryd_path`
+async fn run(osqueryd_path: &Path, benchmark: Kind) -> Report {
+    let rules = match benchmark {
+        Kind::Cis => CIS,
+    };
+    let mut checks = Vec::new();
+    for rule in rules.iter().filter(|rule| rule.platform == osquery::PLATFORM) {
+        let (status, error) = match osquery::shell_query(osqueryd_path, rule.sql).await {
+            Ok(rows) => match (rule.expect, rows.is_empty()) {
+                (Expect::Rows, false) | (Expect::NoRows, true) => (Status::Pass, None),
+                _ => (Status::Fail, None),
+            },
+            Err(e) => (Status::Error, Some(format!("{:#}", e))),
+        };
+        checks.push(Outcome {
+            id: rule.id.to_string(),
+            section: rule.section.to_string(),
+            title: rule.title.to_string(),
+            status,
+            error,
+        });
+    }
+    let count = |status| checks.iter().filter(|check| check.status == status).count();
+    let (passed, failed, errors) = (count(Status::Pass), count(Status::Fail), count(Status::Error));
+    let host = osquery::shell_query(osqueryd_path, "SELECT s.hostname, o.name, o.version FROM system_info s, os_version o")
+        .await
+        .ok()
+        .and_then(|rows| rows.into_iter().next())
+        .unwrap_or_default();
+    Report {
+        benchmark,
+        platform: osquery::PLATFORM.to_string(),
+        hostname: host.get("hostname").cloned(),
+        os: host.get("name").map(|name| format!("{} {}", name, host.get("version").map_or("", |v| v))),
+        generated_at: Utc::now(),
+        score: match passed + failed {
+            0 => 0.0,
+            ran => (passed * 1000 / ran) as f64 / 10.0,
+        },
+        passed,
+        failed,
+        errors,
+        checks,
+    }
+}
+
+/// `shadow benchmark`: run a benchmark, print its report, write it to
+/// `report_file` and with `upload` send it to the server through the running
+/// agent, failing if any check did
+pub async fn command(
+    data_dir: &Path,
+    osqueryd_path: &Path,
+    benchmark: Kind,
+    format: OutputFormat,
+    report_file: Option<PathBuf>,
+    upload: bool,
+) -> Result<()> {
+    let report = run(osqueryd_path, benchmark).await;
+    output::one(format, &report, |report| {
+        for check in &report.checks {
+            let mark = match check.status {
+                Status::Pass => "ok   ",
+                Status::Fail => "FAIL ",
+                Status::Error => "ERROR",
+            };
+            println!("{}  {:<30} {}", mark, check.id, check.title);
+            if let Some(error) = &check.error {
+                println!("       {}", error);
+            }
+        }
+        println!();
+        println!(
+            "Score: {}% ({} passed, {} failed, {} couldn't run)",
+            report.score, report.passed, report.failed, report.errors
+        );
+    })?;
+    if let Some(path) = report_file {
+        write(&report, &path)?;
+        eprintln!("Wrote {}", path.display());
+    }
+    if upload {
+        eprintln!("{}", ipc::upload_benchmark(data_dir, &report).await?);
+    }
+    if report.failed > 0 {
+        anyhow::bail!("{} of {} checks failed", report.failed, report.passed + report.failed);
+    }
+    Ok(())
+}
+
+/// Write a report to `path`, as HTML if it ends in `.html`, otherwise JSON
+fn write(report: &Report, path: &Path) -> Result<()> {
+    let html = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+    let data = match html {
+        true => to_html(report).into_bytes(),
+        false => serde_json::to_vec_pretty(report)?,
+    };
+    std::fs::write(path, data).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn to_html(report: &Report) -> String {
+    let title = format!(
+        "CIS benchmark: {}",
+        escape(report.hostname.as_deref().unwrap_or(&report.platform))
+    );
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>\n\
+         body {{ font-family: sans-serif; margin: 2em; }}\n\
+         table {{ border-collapse: collapse; }}\n\
+         td, th {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}\n\
+         .pass {{ color: #1a7f37; }} .fail {{ color: #cf222e; }} .error {{ color: #9a6700; }}\n\
+         </style>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{os}, {generated}</p>\n\
+         <p><strong>Score: {score}%</strong> ({passed} passed, {failed} failed, {errors} couldn't run)</p>\n\
+         <table>\n<tr><th>Result</th><th>Check</th><th>Section</th><th>Description</th></tr>\n",
+        os = escape(report.os.as_deref().unwrap_or(&report.platform)),
+        generated = report.generated_at.to_rfc3339(),
+        score = report.score,
+        passed = report.passed,
+        failed = report.failed,
+        errors = report.errors,
+    );
+    for check in &report.checks {
+        let (class, result) = match check.status {
+            Status::Pass => ("pass", "Pass"),
+            Status::Fail => ("fail", "Fail"),
+            Status::Error => ("error", "Error"),
+        };
+        let detail = match &check.error {
+            Some(error) => format!("{}<br><small>{}</small>", escape(&check.title), escape(error)),
+            None => escape(&check.title),
+        };
+        html.push_str(&format!(
+            "<tr><td class=\"{}\">{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            class,
+            result,
+            escape(&check.id),
+            escape(&check.section),
+            detail
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Send a report to the server from the running agent, on behalf of `user`
+pub async fn upload(data_dir: &Path, api: &ApiClient, report: &Report, user: &str) -> Result<String> {
+    let mut body = serde_json::to_value(report)?;
+    body["host_id"] = serde_json::json!(api.host_id());
+    api.post(UPLOAD_PATH, &body)
+        .await
+        .context("Failed to upload the benchmark report")?;
+    audit::record_by(
+        data_dir,
+        user,
+        "benchmark_upload",
+        serde_json::json!({
+            "benchmark": report.benchmark,
+            "score": report.score,
+            "passed": report.passed,
+            "failed": report.failed,
+        }),
+    )
+    .await?;
+    Ok(format!("Uploaded the benchmark report (score {}%) to the server", report.score))
+}
@@ -0,0 +1,672 @@
+//! File carving
+//!
+//! osquery can send files from a host to the server ("carving") for incident
+//! responders, but the server's config alone decides whether it may and what
+//! it may take. With `--carve` and the TLS relay, the agent turns osqueryd's
+//! carver on and holds it to local limits: live queries that carve are only
+//! passed on if every path they name is under a `--carve-path` prefix, carves
+//! bigger than `--carve-max-mb` are refused when they start, and each carve is
+//! recorded in the audit log. Without `--carve`, the relay keeps the carver off
+//! and refuses carve uploads, whatever the config says. Either way the
+//! server's config can't change the carver's options, and the `carve()` SQL
+//! function, whose paths can't be checked ahead, stays off.
+//!
+//! Only paths given as plain literals (`path = '...'` or `path LIKE '...'`) can
+//! be checked, so a carving query that computes a path in any other way,
+//! compares it in a row value, or renames it is refused. A carve is only let
+//! start if it's for a live query that was checked this way, so carves from
+//! scheduled queries in the config are refused too.
+//!
+//! With `--carve-encryption-key`, each carve is also sealed before it leaves
+//! the host, so the server and anything in between only see ciphertext and
+//! only responders with the org's private key can read it. Every carve gets an
+//! ephemeral X25519 key, whose public half goes in the request that starts it;
+//! the AES-256-GCM key for its blocks is derived from that and the org's key
+//! with HKDF-SHA256, and each block is sealed with its block ID as the nonce.
+
+use crate::audit;
+use crate::denylist::Blocked;
+use crate::logging::info;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::rand::SystemRandom;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Carving queries waiting for their carve to start, and carves being sealed,
+/// at most
+const MAX_PENDING: usize = 100;
+
+/// How carves are sealed, for whoever decrypts them
+const ALGORITHM: &str = "X25519-HKDF-SHA256-AES-256-GCM";
+
+/// HKDF info for carve keys
+const KDF_INFO: &[u8] = b"hyprwatch carve";
+
+/// The org's X25519 public key for sealing carves, given as 32 bytes in base64
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CarveKey(Vec<u8>);
+
+impl FromStr for CarveKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = BASE64
+            .decode(s.trim())
+            .map_err(|e| format!("expected a base64 X25519 public key: {}", e))?;
+        if key.len() != 32 {
+            return Err(format!("expected a 32-byte X25519 public key, got {} bytes", key.len()));
+        }
+        Ok(CarveKey(key))
+    }
+}
+
+/// The key a carve's blocks are sealed with, once the server has started it
+pub struct Sealing {
+    key: LessSafeKey,
+}
+
+/// Carves being sealed, by session ID
+struct Sealer {
+    key: CarveKey,
+    /// First 8 bytes of the key's SHA-256, so responders can tell which key
+    /// a carve needs
+    key_id: String,
+    sessions: Mutex<BTreeMap<String, Sealing>>,
+}
+
+/// What osqueryd sends to start a carve
+#[derive(Debug, Deserialize)]
+struct Begin {
+    #[serde(default)]
+    carve_id: String,
+    /// The live query that asked for it
+    #[serde(default)]
+    request_id: String,
+    carve_size: u64,
+    #[serde(default)]
+    block_count: u64,
+}
+
+/// Whether and what osqueryd may carve
+#[derive(Default)]
+pub struct Carver {
+    enabled: bool,
+    /// Path prefixes carves may read from, any if empty
+    paths: Vec<String>,
+    max_bytes: u64,
+    data_dir: PathBuf,
+    /// Paths each allowed carving query names, by live query ID
+    pending: Mutex<BTreeMap<String, Vec<String>>>,
+    sealer: Option<Sealer>,
+}
+
+impl Carver {
+    /// A carver allowed to read under `paths`, in carves of at most
+    /// `max_bytes`, sealed for the holder of `key` if given
+    pub fn new(data_dir: PathBuf, paths: &[String], max_bytes: u64, key: Option<CarveKey>) -> Self {
+        Carver {
+            enabled: true,
+            paths: paths.iter().map(|path| path.trim().to_string()).collect(),
+            max_bytes,
+            data_dir,
+            pending: Mutex::new(BTreeMap::new()),
+            sealer: key.map(|key| Sealer {
+                key_id: Sha256::digest(&key.0)[..8].iter().map(|b| format!("{:02x}", b)).collect(),
+                key,
+                sessions: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// A carver that's off
+    pub fn disabled() -> Self {
+        Carver::default()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Whether carves are sealed before they're sent
+    pub fn seals(&self) -> bool {
+        self.sealer.is_some()
+    }
+
+    /// Add a new ephemeral key to a request to start a carve, returning the
+    /// request and the carve's sealing key for [`Carver::started`]
+    pub fn seal_begin(&self, body: &[u8]) -> Result<(Vec<u8>, Sealing)> {
+        let sealer = self.sealer.as_ref().context("Carves aren't sealed")?;
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        let rng = SystemRandom::new();
+        let private = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| anyhow::anyhow!("Failed to generate a key for the carve"))?;
+        let public = private
+            .compute_public_key()
+            .map_err(|_| anyhow::anyhow!("Failed to generate a key for the carve"))?;
+        let salt = [public.as_ref(), &sealer.key.0].concat();
+        let unbound = agreement::agree_ephemeral(private, &UnparsedPublicKey::new(&X25519, &sealer.key.0), |secret| {
+            let prk = Salt::new(HKDF_SHA256, &salt).extract(secret);
+            prk.expand(&[KDF_INFO], &AES_256_GCM).map(UnboundKey::from)
+        })
+        .map_err(|_| anyhow::anyhow!("--carve-encryption-key isn't a usable X25519 key"))?
+        .map_err(|_| anyhow::anyhow!("Failed to derive the carve's key"))?;
+        request["encryption"] = serde_json::json!({
+            "algorithm": ALGORITHM,
+            "key_id": sealer.key_id,
+            "ephemeral_public_key": BASE64.encode(public.as_ref()),
+        });
+        let sealing = Sealing {
+            key: LessSafeKey::new(unbound),
+        };
+        Ok((serde_json::to_vec(&request)?, sealing))
+    }
+
+    /// Keep a carve's sealing key for its blocks, once the server's reply has
+    /// given it a session
+    pub fn started(&self, sealing: Sealing, reply: &[u8]) -> Result<()> {
+        let sealer = self.sealer.as_ref().context("Carves aren't sealed")?;
+        let reply: Value = serde_json::from_slice(reply).context("Reply isn't JSON")?;
+        let session = reply
+            .get("session_id")
+            .and_then(Value::as_str)
+            .context("Reply has no session ID")?;
+        let mut sessions = sealer.sessions.lock().unwrap();
+        if sessions.len() >= MAX_PENDING {
+            sessions.pop_first();
+        }
+        sessions.insert(session.to_string(), sealing);
+        Ok(())
+    }
+
+    /// Seal the data in a request with a carve's block. A block whose key was
+    /// lost, e.g. to a restart, can't be sent at all.
+    pub fn seal_block(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let sealer = self.sealer.as_ref().context("Carves aren't sealed")?;
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        let session = request
+            .get("session_id")
+            .and_then(Value::as_str)
+            .context("Request has no session ID")?
+            .to_string();
+        let block = match request.get("block_id") {
+            Some(Value::String(id)) => id.parse().ok(),
+            Some(id) => id.as_u64(),
+            None => None,
+        }
+        .context("Request has no block ID")?;
+        let mut data = BASE64
+            .decode(request.get("data").and_then(Value::as_str).unwrap_or_default())
+            .context("Block isn't base64")?;
+
+        // Kept for the carve's life, as osqueryd may send a block again
+        let sessions = sealer.sessions.lock().unwrap();
+        let sealing = sessions
+            .get(&session)
+            .with_context(|| format!("No key for carve session {}, so its blocks can't be sealed", session))?;
+        let mut nonce = [0; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&block.to_be_bytes());
+        sealing
+            .key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| anyhow::anyhow!("Failed to seal a carve block"))?;
+        request["data"] = Value::String(BASE64.encode(&data));
+        Ok(serde_json::to_vec(&request)?)
+    }
+
+    /// osqueryd's carver flags
+    pub fn osqueryd_flags(&self) -> [(&'static str, &'static str); 2] {
+        [
+            ("--disable_carver", if self.enabled { "false" } else { "true" }),
+            ("--carver_disable_function", "true"),
+        ]
+    }
+
+    /// Remove the carver's options from a config from the server, so the
+    /// agent's flags stand
+    pub fn filter_config(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        if let Some(options) = config.get_mut("options").and_then(Value::as_object_mut) {
+            options.retain(|name, _| !name.starts_with("carver_") && name != "disable_carver");
+        }
+        Ok(serde_json::to_vec(&config)?)
+    }
+
+    /// Remove live queries that carve paths they may not from a distributed
+    /// read reply
+    pub fn filter_live(&self, body: &[u8]) -> Result<(Vec<u8>, Vec<Blocked>)> {
+        let mut reply: Value = serde_json::from_slice(body).context("Live queries aren't JSON")?;
+        let mut blocked = Vec::new();
+        if let Some(queries) = reply.get_mut("queries").and_then(Value::as_object_mut) {
+            let mut pending = self.pending.lock().unwrap();
+            queries.retain(|id, sql| {
+                let sql = sql.as_str().unwrap_or_default();
+                if !carves(sql) {
+                    return true;
+                }
+                match self.check(sql) {
+                    Ok(paths) => {
+                        if pending.len() >= MAX_PENDING {
+                            pending.pop_first();
+                        }
+                        pending.insert(id.clone(), paths);
+                        true
+                    }
+                    Err(reason) => {
+                        blocked.push(Blocked {
+                            name: id.clone(),
+                            live: true,
+                            reason,
+                        });
+                        false
+                    }
+                }
+            });
+        }
+        Ok((serde_json::to_vec(&reply)?, blocked))
+    }
+
+    /// Remove the live queries that carve from a live query channel reply:
+    /// their carves couldn't be told apart from unchecked ones, so they're
+    /// left to osqueryd's distributed reads
+    pub fn filter_channel(&self, body: &[u8]) -> Result<(Vec<u8>, Vec<Blocked>)> {
+        let mut reply: Value = serde_json::from_slice(body).context("Live queries aren't JSON")?;
+        let mut blocked = Vec::new();
+        if let Some(queries) = reply.get_mut("queries").and_then(Value::as_object_mut) {
+            queries.retain(|id, sql| {
+                if !carves(sql.as_str().unwrap_or_default()) {
+                    return true;
+                }
+                blocked.push(Blocked {
+                    name: id.clone(),
+                    live: true,
+                    reason: "carves, which only osqueryd's distributed reads may".to_string(),
+                });
+                false
+            });
+        }
+        Ok((serde_json::to_vec(&reply)?, blocked))
+    }
+
+    /// The paths a carving query names, or why it may not run
+    fn check(&self, sql: &str) -> Result<Vec<String>, String> {
+        if !self.enabled {
+            return Err("carving is off (--carve)".to_string());
+        }
+        let paths = paths(sql)?;
+        if paths.is_empty() {
+            return Err("carves without naming a path".to_string());
+        }
+        if self.paths.is_empty() {
+            return Ok(paths);
+        }
+        for path in &paths {
+            // What a pattern can match is under the directory before its first
+            // wildcard (osquery globs with `%` and `%%`), and prefixes match
+            // whole components, so /var/log doesn't let /var/logstash through
+            let fixed = match path.find('%') {
+                Some(wildcard) => path[..wildcard].rfind(std::path::is_separator).map_or("", |slash| &path[..=slash]),
+                None => path.as_str(),
+            };
+            if path.contains("..") || !self.paths.iter().any(|prefix| Path::new(fixed).starts_with(prefix)) {
+                return Err(format!("carves '{}', outside --carve-path", path));
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Check a request to start a carve and record it in the audit log, or
+    /// say why it's refused
+    pub async fn begin(&self, body: &[u8]) -> Result<(), String> {
+        if !self.enabled {
+            return Err("carving is off (--carve)".to_string());
+        }
+        let begin: Begin = serde_json::from_slice(body).map_err(|e| format!("invalid carve request: {}", e))?;
+        let paths = self.pending.lock().unwrap().remove(&begin.request_id);
+        let allowed = paths.is_some() && begin.carve_size <= self.max_bytes;
+        audit::record_agent(
+            &self.data_dir,
+            "carve",
+            serde_json::json!({
+                "carve_id": begin.carve_id,
+                "request_id": begin.request_id,
+                "paths": paths,
+                "carve_size": begin.carve_size,
+                "block_count": begin.block_count,
+                "allowed": allowed,
+            }),
+        )
+        .await;
+        let Some(paths) = paths else {
+            return Err(format!(
+                "carve {} isn't for a live query the agent checked (request {})",
+                begin.carve_id, begin.request_id
+            ));
+        };
+        if !allowed {
+            return Err(format!(
+                "carve {} is {} MB, over --carve-max-mb ({} MB)",
+                begin.carve_id,
+                begin.carve_size.div_ceil(1024 * 1024),
+                self.max_bytes / (1024 * 1024)
+            ));
+        }
+        info!(
+            "Carving {} ({} KB) for live query {}",
+            paths.join(", "),
+            begin.carve_size.div_ceil(1024),
+            begin.request_id
+        );
+        Ok(())
+    }
+}
+
+/// Whether a query carves files, rather than just reading the carves table
+fn carves(sql: &str) -> bool {
+    let sql = sql.to_ascii_lowercase();
+    let words: Vec<&str> = sql.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).collect();
+    words.contains(&"carves") && words.contains(&"carve")
+}
+
+/// A piece of a query, as far as checking carve paths goes
+#[derive(Debug, PartialEq)]
+enum Token {
+    /// A keyword or name, lowercase
+    Word(String),
+    /// A quoted name, lowercase, which is never a keyword
+    Quoted(String),
+    /// A string literal
+    Literal(String),
+    Symbol(char),
+}
+
+/// The paths a carving query names, or why they can't be checked: each
+/// mention of `path` has to be a column in the select list, or `path = '...'`
+/// or `path LIKE '...'` after it, so no path is computed
+fn paths(sql: &str) -> Result<Vec<String>, String> {
+    const COMPUTED: &str = "computes a path to carve, only path = '...' or path LIKE '...' can be checked";
+    let tokens = tokens(sql)?;
+    let word = |i: Option<usize>, words: &[&str]| {
+        matches!(i.and_then(|i| tokens.get(i)), Some(Token::Word(w)) if words.contains(&w.as_str()))
+    };
+    let symbol = |i: Option<usize>, c: char| i.and_then(|i| tokens.get(i)) == Some(&Token::Symbol(c));
+    let mut paths = Vec::new();
+    // Between SELECT and FROM, where columns are selected rather than compared
+    let mut selecting = false;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Word(w) if w == "select" => selecting = true,
+            Token::Word(w) if w == "from" => selecting = false,
+            _ => {}
+        }
+        if !matches!(token, Token::Word(w) | Token::Quoted(w) if w == "path") {
+            continue;
+        }
+        // `carves.path`, or an alias's
+        let start = match symbol(i.checked_sub(1), '.') {
+            true => i.checked_sub(2),
+            false => Some(i),
+        };
+        let before = start.and_then(|start| start.checked_sub(1));
+        if selecting {
+            if (word(before, &["select", "distinct", "all"]) || symbol(before, ','))
+                && (symbol(Some(i + 1), ',') || word(Some(i + 1), &["from"]))
+            {
+                continue;
+            }
+            // A condition on `path AS p` is one on p, which isn't checked
+            let renamed =
+                matches!(tokens.get(i + 1), Some(Token::Word(_) | Token::Quoted(_))) || word(before, &["as"]);
+            if renamed {
+                return Err("renames path, so its conditions can't be checked".to_string());
+            }
+            return Err(COMPUTED.to_string());
+        }
+        // SQLite splits `(path, carve) = ('...', 1)` into a condition on path
+        if symbol(Some(i + 1), ',') {
+            return Err("compares path in a row value, which can't be checked".to_string());
+        }
+        if !(word(before, &["where", "and", "or"]) || symbol(before, '(')) {
+            return Err(COMPUTED.to_string());
+        }
+        let mut next = i + 1;
+        if symbol(Some(next), '=') {
+            next += 1;
+            // `==` is the same as `=`
+            if symbol(Some(next), '=') {
+                next += 1;
+            }
+        } else if word(Some(next), &["like"]) {
+            next += 1;
+        } else {
+            return Err(COMPUTED.to_string());
+        }
+        let Some(Token::Literal(literal)) = tokens.get(next) else {
+            return Err(COMPUTED.to_string());
+        };
+        if !(next + 1 == tokens.len()
+            || symbol(Some(next + 1), ')')
+            || symbol(Some(next + 1), ';')
+            || word(Some(next + 1), &["and", "or"]))
+        {
+            return Err(COMPUTED.to_string());
+        }
+        paths.push(literal.clone());
+    }
+    Ok(paths)
+}
+
+/// Split a query into words, literals, and symbols, refusing comments, which
+/// could hide part of it from the check
+fn tokens(sql: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '\'' | '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        // A doubled quote is one quote
+                        Some(q) if q == close && close != ']' && chars.peek() == Some(&close) => {
+                            chars.next();
+                            text.push(q);
+                        }
+                        Some(q) if q == close => break,
+                        Some(c) => text.push(c),
+                        None => return Err("has an unterminated quote".to_string()),
+                    }
+                }
+                tokens.push(match c {
+                    '\'' => Token::Literal(text),
+                    _ => Token::Quoted(text.to_lowercase()),
+                });
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = c.to_lowercase().to_string();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_' || **c == '$') {
+                    word.extend(c.to_lowercase());
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+            '-' if chars.peek() == Some(&'-') => return Err("has a comment".to_string()),
+            '/' if chars.peek() == Some(&'*') => return Err("has a comment".to_string()),
+            c => tokens.push(Token::Symbol(c)),
+        }
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn carver(paths: &[&str]) -> Carver {
+        let paths: Vec<String> = paths.iter().map(|path| path.to_string()).collect();
+        Carver::new(PathBuf::new(), &paths, 1 << 20, None)
+    }
+
+    #[test]
+    fn tokens_split_literals_and_quoted_names() {
+        assert_eq!(
+            tokens("SELECT \"Path\" FROM carves WHERE path = 'it''s'").unwrap(),
+            vec![
+                Token::Word("select".to_string()),
+                Token::Quoted("path".to_string()),
+                Token::Word("from".to_string()),
+                Token::Word("carves".to_string()),
+                Token::Word("where".to_string()),
+                Token::Word("path".to_string()),
+                Token::Symbol('='),
+                Token::Literal("it's".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokens_refuse_comments_and_unterminated_quotes() {
+        assert!(tokens("SELECT * FROM carves WHERE carve = 1 -- AND path = '/x'").is_err());
+        assert!(tokens("SELECT * FROM carves WHERE carve = 1 /* */").is_err());
+        assert!(tokens("SELECT * FROM carves WHERE path = '/x").is_err());
+        // Inside a literal they're just text
+        assert!(tokens("SELECT * FROM carves WHERE path = '/x--/*'").is_ok());
+    }
+
+    #[test]
+    fn carves_only_when_carving() {
+        assert!(carves("SELECT * FROM carves WHERE carve = 1 AND path = '/x'"));
+        assert!(!carves("SELECT * FROM carves"));
+        assert!(!carves("SELECT * FROM processes"));
+    }
+
+    #[test]
+    fn accepts_literal_paths() {
+        let carver = carver(&["/var/log"]);
+        for (sql, paths) in [
+            ("SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/syslog'", vec!["/var/log/syslog"]),
+            ("SELECT path, carve FROM carves WHERE path LIKE '/var/log/%' AND carve = 1", vec!["/var/log/%"]),
+            ("select c.path from carves c where c.carve = 1 and (c.path == '/var/log/a');", vec!["/var/log/a"]),
+            (
+                "SELECT * FROM carves WHERE carve = 1 AND (path = '/var/log/a' OR path = '/var/log/b')",
+                vec!["/var/log/a", "/var/log/b"],
+            ),
+            ("SELECT * FROM carves WHERE carve = 1 AND path = '/var/log'", vec!["/var/log"]),
+        ] {
+            assert_eq!(carver.check(sql), Ok(paths.iter().map(|p| p.to_string()).collect()), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn any_path_without_prefixes() {
+        let carver = carver(&[]);
+        assert!(carver.check("SELECT * FROM carves WHERE carve = 1 AND path = '/etc/shadow'").is_ok());
+    }
+
+    #[test]
+    fn refuses_when_disabled_or_without_a_path() {
+        assert!(Carver::disabled().check("SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/a'").is_err());
+        assert!(carver(&["/var/log"]).check("SELECT * FROM carves WHERE carve = 1").is_err());
+    }
+
+    #[test]
+    fn refuses_paths_outside_prefixes() {
+        let carver = carver(&["/var/log"]);
+        for sql in [
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/etc/shadow'",
+            // Sibling of the prefix, not under it
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/logstash/x'",
+            "SELECT * FROM carves WHERE carve = 1 AND path LIKE '/var/log%'",
+            "SELECT * FROM carves WHERE carve = 1 AND path LIKE '%/var/log/x'",
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/../../etc/shadow'",
+            "SELECT * FROM carves WHERE carve = 1 AND (path = '/var/log/a' OR path = '/etc/shadow')",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn refuses_computed_paths() {
+        let carver = carver(&["/var/log"]);
+        for sql in [
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/' || '../../etc/shadow'",
+            "SELECT * FROM carves WHERE carve = 1 AND path = substr('/var/log/x/etc/shadow', 11)",
+            "SELECT * FROM carves WHERE carve = 1 AND path IN ('/etc/shadow')",
+            "SELECT * FROM carves WHERE carve = 1 AND path = (SELECT '/etc/shadow')",
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/a' AND NOT path = '/etc/shadow'",
+            "SELECT * FROM carves WHERE carve = 1 AND '/etc/shadow' = path",
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/a' OR trim(path) = '/etc/shadow'",
+            "SELECT upper(path) FROM carves WHERE carve = 1 AND path = '/var/log/a'",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn refuses_row_values() {
+        let carver = carver(&["/var/log"]);
+        for sql in [
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/x' AND (path, carve) = ('/etc/shadow', 1)",
+            "SELECT * FROM carves WHERE path = '/var/log/x' AND (carve, path) = (1, '/etc/shadow')",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn refuses_aliases() {
+        let carver = carver(&["/var/log"]);
+        for sql in [
+            "SELECT path AS p FROM carves WHERE carve = 1 AND path = '/var/log/x' AND p = '/etc/shadow'",
+            "SELECT path p FROM carves WHERE carve = 1 AND path = '/var/log/x' AND p = '/etc/shadow'",
+            "SELECT carve, path \"p\" FROM carves WHERE carve = 1 AND path = '/var/log/x'",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn quoted_names_are_not_keywords() {
+        let carver = carver(&["/var/log"]);
+        // A quoted "from" mustn't end the select list early, or a quoted
+        // "select" start one in the WHERE clause
+        for sql in [
+            "SELECT \"from\", path AS p FROM carves WHERE carve = 1 AND path = '/var/log/x' AND p = '/etc/shadow'",
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/x' AND (\"select\", path) = (1, '/etc/shadow')",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn refuses_comment_and_string_tricks() {
+        let carver = carver(&["/var/log"]);
+        for sql in [
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/x' --' OR path = '/etc/shadow'",
+            "SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/x' /* AND path = '/etc/shadow' */",
+        ] {
+            assert!(carver.check(sql).is_err(), "{}", sql);
+        }
+        // Doubled quotes keep the rest in the literal, which is then one path
+        assert_eq!(
+            carver.check("SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/x'' OR path = ''/etc/shadow'"),
+            Ok(vec!["/var/log/x' OR path = '/etc/shadow".to_string()])
+        );
+        // A comment marker inside a literal is part of the path
+        assert_eq!(
+            carver.check("SELECT * FROM carves WHERE carve = 1 AND path = '/var/log/--x'"),
+            Ok(vec!["/var/log/--x".to_string()])
+        );
+    }
+}
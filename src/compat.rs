@@ -0,0 +1,125 @@
+//! osqueryd compatibility with the host
+//!
+//! The osqueryd we download is built for glibc-based systems. On musl-based
+//! distributions like Alpine, on systems with a glibc older than it was built
+//! against, or with a binary for another architecture, the kernel or loader
+//! refuses to run it and all we get is `Exec format error` or a bare "No such
+//! file or directory" for a file that plainly exists. Before using osqueryd we
+//! make sure it runs, and if it doesn't, work out why and say what to do.
+
+use crate::osquery::osqueryd_command;
+use anyhow::Result;
+use std::path::Path;
+
+/// What to do about an osqueryd that can't run here
+const REMEDY: &str = "Run shadow on a glibc-based system (Debian, Ubuntu, RHEL, or a *-slim container image), \
+     or point --osqueryd-path at an osqueryd built for this system, e.g. from source";
+
+/// Make sure osqueryd can run on this host, explaining what's wrong if not
+pub async fn check(osqueryd_path: &Path) -> Result<()> {
+    let output = match osqueryd_command(osqueryd_path).arg("--version").output().await {
+        Ok(output) => output,
+        Err(e) => {
+            let problem = diagnose(osqueryd_path)
+                .unwrap_or_else(|| format!("it couldn't be started ({})", e));
+            anyhow::bail!("{} can't run on this host: {}.\n{}.", osqueryd_path.display(), problem, REMEDY);
+        }
+    };
+    if output.status.success() {
+        return Ok(());
+    }
+    // The loader reports missing symbol versions itself
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if let Some(required) = missing_glibc(&stderr) {
+        let installed = installed_glibc().unwrap_or_else(|| "an older version".to_string());
+        anyhow::bail!(
+            "{} can't run on this host: it needs glibc {} or newer, and this system has {}.\n{}.",
+            osqueryd_path.display(),
+            required,
+            installed,
+            REMEDY
+        );
+    }
+    Ok(())
+}
+
+/// `version `GLIBC_2.28' not found` from the dynamic loader
+fn missing_glibc(stderr: &str) -> Option<&str> {
+    let (_, rest) = stderr.split_once("`GLIBC_")?;
+    let (version, _) = rest.split_once('\'')?;
+    Some(version)
+}
+
+fn installed_glibc() -> Option<String> {
+    let output = std::process::Command::new("getconf")
+        .arg("GNU_LIBC_VERSION")
+        .output()
+        .ok()?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!version.is_empty()).then_some(version)
+}
+
+/// Why the kernel wouldn't start osqueryd, going by its ELF header
+#[cfg(target_os = "linux")]
+fn diagnose(osqueryd_path: &Path) -> Option<String> {
+    let data = std::fs::read(osqueryd_path).ok()?;
+    if data.get(..4) != Some(b"\x7fELF") {
+        return Some("it isn't a Linux executable".to_string());
+    }
+    // Only 64-bit little-endian builds are downloaded
+    if data.get(4..6) != Some(&[2, 1]) {
+        return Some("it isn't a 64-bit little-endian executable".to_string());
+    }
+    let u16_at = |offset: usize| Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().ok()?));
+    let u64_at = |offset: usize| Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().ok()?));
+
+    let machine = match u16_at(18)? {
+        0x3e => "x86_64",
+        0xb7 => "aarch64",
+        _ => "another architecture",
+    };
+    if machine != std::env::consts::ARCH {
+        return Some(format!(
+            "it's built for {}, but this host is {}",
+            machine,
+            std::env::consts::ARCH
+        ));
+    }
+
+    // PT_INTERP names the dynamic loader, which must exist for exec to work
+    let table = u64_at(32)? as usize;
+    let entry_size = u16_at(54)? as usize;
+    for index in 0..u16_at(56)? as usize {
+        let header = table + index * entry_size;
+        if u32::from_le_bytes(data.get(header..header + 4)?.try_into().ok()?) != 3 {
+            continue;
+        }
+        let offset = u64_at(header + 8)? as usize;
+        let size = u64_at(header + 32)? as usize;
+        let interpreter = String::from_utf8_lossy(data.get(offset..offset + size)?);
+        let interpreter = interpreter.trim_end_matches('\0');
+        if Path::new(interpreter).exists() {
+            return None;
+        }
+        let musl = std::fs::read_dir("/lib").is_ok_and(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("ld-musl-"))
+        });
+        return Some(if musl {
+            format!(
+                "it needs glibc's loader {}, and this is a musl-based system (e.g. Alpine); \
+                 `apk add gcompat` may be enough",
+                interpreter
+            )
+        } else {
+            format!("it needs the loader {}, which this system doesn't have", interpreter)
+        });
+    }
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn diagnose(_osqueryd_path: &Path) -> Option<String> {
+    None
+}
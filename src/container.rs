@@ -0,0 +1,206 @@
+//! Container runtimes
+//!
+//! Containers started from the same image on the same host, or on cloned VMs,
+//! report the same hardware UUID, so hosts identified by it overwrite each
+//! other on the server. When we find we're in a container we default to
+//! osquery's instance ID instead.
+//!
+//! osquery's docker_* and lxd_* tables query the runtime's API socket, which
+//! is often mounted somewhere else when shadow itself runs in a container. We
+//! check the socket answers before handing it to osqueryd.
+
+use crate::logging::warning;
+use std::fmt;
+use std::path::Path;
+
+/// Container runtime shadow is running under
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    Docker,
+    Podman,
+    Containerd,
+    Kubernetes,
+    Lxc,
+    /// Windows Subsystem for Linux, which shares the Windows host's hardware
+    /// UUID
+    Wsl,
+}
+
+impl Runtime {
+    /// Identifier in the style of systemd-detect-virt
+    pub fn id(self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+            Runtime::Containerd => "containerd",
+            Runtime::Kubernetes => "kubernetes",
+            Runtime::Lxc => "lxc",
+            Runtime::Wsl => "wsl",
+        }
+    }
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Runtime::Docker => write!(f, "Docker"),
+            Runtime::Podman => write!(f, "Podman"),
+            Runtime::Containerd => write!(f, "containerd"),
+            Runtime::Kubernetes => write!(f, "Kubernetes"),
+            Runtime::Lxc => write!(f, "LXC"),
+            Runtime::Wsl => write!(f, "WSL"),
+        }
+    }
+}
+
+/// The container runtime we're running under, if any
+#[cfg(target_os = "linux")]
+pub fn detect() -> Option<Runtime> {
+    use std::path::Path;
+
+    if Path::new("/.dockerenv").exists() {
+        return Some(Runtime::Docker);
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some(Runtime::Podman);
+    }
+    // Set by LXC and systemd-nspawn for the container's init
+    let environ = std::fs::read("/proc/1/environ").unwrap_or_default();
+    if environ
+        .split(|&b| b == 0)
+        .any(|var| var == b"container=lxc" || var == b"container=lxc-libvirt")
+    {
+        return Some(Runtime::Lxc);
+    }
+    // WSL kernels are named e.g. 5.15.153.1-microsoft-standard-WSL2
+    let osrelease = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    if osrelease.to_lowercase().contains("microsoft") {
+        return Some(Runtime::Wsl);
+    }
+    // cgroup v1 paths name the runtime; under cgroup v2 the path is usually
+    // just "/", so the mountinfo of our root is checked as well
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup").unwrap_or_default();
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    let root = mountinfo
+        .lines()
+        .find(|line| line.split_whitespace().nth(4) == Some("/"))
+        .unwrap_or_default();
+    for text in [cgroup.as_str(), root] {
+        if text.contains("kubepods") {
+            return Some(Runtime::Kubernetes);
+        }
+        if text.contains("/docker/") || text.contains("docker-") {
+            return Some(Runtime::Docker);
+        }
+        if text.contains("libpod") {
+            return Some(Runtime::Podman);
+        }
+        if text.contains("containerd") {
+            return Some(Runtime::Containerd);
+        }
+        if text.contains("/lxc/") || text.contains("lxc.payload") {
+            return Some(Runtime::Lxc);
+        }
+    }
+    None
+}
+
+/// Containers are only detected on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn detect() -> Option<Runtime> {
+    None
+}
+
+/// An API socket for osquery's container tables
+#[derive(Debug, Clone, Copy)]
+pub enum Socket {
+    /// The Docker Engine API, also served by Podman (docker_* tables)
+    Docker,
+    /// The LXD API (lxd_* tables)
+    Lxd,
+}
+
+impl Socket {
+    /// osqueryd flag setting the socket path
+    pub fn flag(self) -> &'static str {
+        match self {
+            Socket::Docker => "--docker_socket",
+            Socket::Lxd => "--lxd_socket",
+        }
+    }
+
+    /// A cheap request the API answers with 200 OK
+    #[cfg(unix)]
+    fn probe(self) -> &'static str {
+        match self {
+            Socket::Docker => "/_ping",
+            Socket::Lxd => "/1.0",
+        }
+    }
+
+    fn tables(self) -> &'static str {
+        match self {
+            Socket::Docker => "docker_*",
+            Socket::Lxd => "lxd_*",
+        }
+    }
+}
+
+/// The runtime's default socket, if it exists. Under Kubernetes the node's
+/// sockets are found under the host filesystem mount.
+pub fn default_socket(kind: Socket, hostfs: Option<&Path>) -> Option<std::path::PathBuf> {
+    let candidates: &[&str] = match kind {
+        Socket::Docker => &["var/run/docker.sock", "run/podman/podman.sock"],
+        Socket::Lxd => &["var/snap/lxd/common/lxd/unix.socket", "var/lib/lxd/unix.socket"],
+    };
+    let root = hostfs.unwrap_or(Path::new("/"));
+    candidates
+        .iter()
+        .map(|path| root.join(path))
+        .find(|path| path.exists())
+}
+
+/// Check osqueryd will be able to use the socket, warning with what to fix if
+/// not. The socket is passed to osqueryd either way, in case it appears later.
+pub async fn check_socket(kind: Socket, path: &Path) {
+    if let Err(e) = probe_socket(kind, path).await {
+        warning!(
+            "Can't use {} ({:#}), so the {} tables will be empty. \
+             Mount the socket into the container, and run shadow as root or in the group owning it.",
+            path.display(),
+            e,
+            kind.tables()
+        );
+    }
+}
+
+#[cfg(unix)]
+async fn probe_socket(kind: Socket, path: &Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", kind.probe());
+    let exchange = async {
+        let mut stream = tokio::net::UnixStream::connect(path).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = vec![0; 64];
+        let n = stream.read(&mut response).await?;
+        response.truncate(n);
+        anyhow::Ok(response)
+    };
+    let response = tokio::time::timeout(std::time::Duration::from_secs(5), exchange)
+        .await
+        .context("no response")??;
+    let status_line = String::from_utf8_lossy(&response);
+    let status = status_line.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        anyhow::bail!("unexpected response: {}", status);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn probe_socket(_kind: Socket, _path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("osquery's container tables need a Unix socket")
+}
@@ -0,0 +1,113 @@
+//! Local collection controls
+//!
+//! `shadow pause` stops osqueryd and suppresses restarts until `shadow resume`,
+//! e.g. while troubleshooting performance on a host. A pause marker in the data
+//! directory, written by the running agent when asked through the control
+//! socket (see [`crate::ipc`]) or by the commands themselves when it isn't
+//! running, is polled by the supervisor and survives restarts. Every change is
+//! recorded in the audit log.
+
+use crate::audit;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+/// Pause marker filename within the data directory
+const PAUSE_FILE: &str = "paused.json";
+
+/// How often the agent checks for pause/resume
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseState {
+    pub paused_at: DateTime<Utc>,
+    pub paused_by: String,
+    pub reason: Option<String>,
+    /// Resume automatically at this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl PauseState {
+    fn is_expired(&self) -> bool {
+        self.until.is_some_and(|until| until <= Utc::now())
+    }
+}
+
+/// Pause collection on behalf of `user`
+pub async fn pause(
+    data_dir: &Path,
+    user: &str,
+    reason: Option<String>,
+    duration: Option<Duration>,
+) -> Result<PauseState> {
+    let now = Utc::now();
+    let until = duration
+        .map(|d| chrono::Duration::from_std(d).map(|d| now + d))
+        .transpose()
+        .context("Pause duration out of range")?;
+
+    let state = PauseState {
+        paused_at: now,
+        paused_by: user.to_string(),
+        reason,
+        until,
+    };
+
+    fs::write(data_dir.join(PAUSE_FILE), serde_json::to_vec_pretty(&state)?)
+        .await
+        .context("Failed to write pause marker")?;
+    audit::record_by(data_dir, user, "pause", serde_json::to_value(&state)?).await?;
+    Ok(state)
+}
+
+/// What a pause means, for whoever asked for it
+pub fn describe(state: &PauseState) -> String {
+    match state.until {
+        Some(until) => format!("Collection paused until {}", until.to_rfc3339()),
+        None => "Collection paused until `shadow resume`".to_string(),
+    }
+}
+
+/// Resume collection on behalf of `user`, returning whether it was paused
+pub async fn resume(data_dir: &Path, user: &str) -> Result<bool> {
+    match fs::remove_file(data_dir.join(PAUSE_FILE)).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context("Failed to remove pause marker"),
+    }
+    audit::record_by(data_dir, user, "resume", serde_json::Value::Null).await?;
+    Ok(true)
+}
+
+/// Current pause state, or None if collection is running
+pub async fn pause_state(data_dir: &Path) -> Option<PauseState> {
+    let data = fs::read(data_dir.join(PAUSE_FILE)).await.ok()?;
+    let state: PauseState = serde_json::from_slice(&data).ok()?;
+    if state.is_expired() {
+        let _ = fs::remove_file(data_dir.join(PAUSE_FILE)).await;
+        let detail = serde_json::json!({ "expired": state.until });
+        audit::record_agent(data_dir, "resume", detail).await;
+        return None;
+    }
+    Some(state)
+}
+
+/// Wait until collection is paused
+pub async fn wait_for_pause(data_dir: &Path) -> PauseState {
+    loop {
+        if let Some(state) = pause_state(data_dir).await {
+            return state;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Wait until collection is resumed
+pub async fn wait_for_resume(data_dir: &Path) {
+    while pause_state(data_dir).await.is_some() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
@@ -0,0 +1,211 @@
+//! osqueryd crash artifacts
+//!
+//! When osqueryd exits abnormally, look for the evidence the OS left behind
+//! (core dumps on Linux, crash reports on macOS, WER minidumps on Windows) and
+//! copy it into `crashes/` in the data directory, keeping only the most recent
+//! few. The collected files are listed in the agent status and heartbeat so the
+//! server knows there is something to retrieve.
+
+use crate::logging::{error, info};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+
+/// Artifact directory within the data directory
+const CRASH_DIR: &str = "crashes";
+
+/// Number of artifacts to keep
+const MAX_ARTIFACTS: usize = 5;
+
+/// Artifacts larger than this (typically full core dumps) are not copied
+const MAX_ARTIFACT_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Crash handlers may still be writing when the process has already exited
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+
+/// Names of the artifacts collected so far, oldest first
+pub fn list(data_dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(data_dir.join(CRASH_DIR)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    // Names start with the collection time, so this sorts oldest first
+    names.sort();
+    names
+}
+
+/// Collect artifacts for an osqueryd (`pid`) that crashed after `since`,
+/// returning how many were found
+pub async fn collect(data_dir: &Path, pid: Option<u32>, since: SystemTime) -> usize {
+    tokio::time::sleep(SETTLE_DELAY).await;
+
+    let dir = data_dir.join(CRASH_DIR);
+    if let Err(e) = fs::create_dir_all(&dir).await {
+        error!("Failed to create crash artifact directory: {}", e);
+        return 0;
+    }
+
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut collected = 0;
+    for source in find_artifacts(pid, since).await {
+        let size = fs::metadata(&source).await.map(|m| m.len()).unwrap_or(0);
+        if size > MAX_ARTIFACT_SIZE {
+            info!(
+                "Not collecting {} ({} MiB), too large",
+                source.display(),
+                size / 1024 / 1024
+            );
+            continue;
+        }
+
+        let name = artifact_name(&source);
+        let dest = dir.join(format!("{}-{}", stamp, name));
+        match fs::copy(&source, &dest).await {
+            Ok(_) => {
+                info!("Collected crash artifact {}", source.display());
+                collected += 1;
+            }
+            Err(e) => error!("Failed to collect {}: {}", source.display(), e),
+        }
+    }
+
+    rotate(data_dir).await;
+    collected
+}
+
+/// Delete all but the newest artifacts
+async fn rotate(data_dir: &Path) {
+    let names = list(data_dir);
+    let excess = names.len().saturating_sub(MAX_ARTIFACTS);
+    for name in &names[..excess] {
+        let _ = fs::remove_file(data_dir.join(CRASH_DIR).join(name)).await;
+    }
+}
+
+/// Name to store an artifact under. WER reports are all called `Report.wer`,
+/// so those are named after their report directory instead.
+fn artifact_name(source: &Path) -> String {
+    let file = source.file_name().unwrap_or_default().to_string_lossy();
+    match source.parent().and_then(|p| p.file_name()) {
+        Some(parent) if file == "Report.wer" => format!("{}.wer", parent.to_string_lossy()),
+        _ => file.into_owned(),
+    }
+}
+
+/// Whether a file in a crash directory belongs to this osqueryd
+fn is_osqueryd_artifact(name: &str, pid: Option<u32>) -> bool {
+    if name.contains("osqueryd") || name == "core" {
+        return true;
+    }
+    // e.g. core.1234
+    pid.is_some_and(|pid| {
+        let pid = pid.to_string();
+        name.split(|c: char| !c.is_ascii_digit()).any(|part| part == pid)
+    })
+}
+
+/// Files written after `since` in the platform's crash directories
+async fn find_artifacts(pid: Option<u32>, since: SystemTime) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for dir in crash_dirs().await {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !is_osqueryd_artifact(&name, pid) {
+                continue;
+            }
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+            let path = if file_type.is_dir() {
+                // WER keeps each report in its own directory
+                entry.path().join("Report.wer")
+            } else {
+                entry.path()
+            };
+            let modified = fs::metadata(&path).await.and_then(|m| m.modified());
+            if modified.is_ok_and(|modified| modified >= since) {
+                found.push(path);
+            }
+        }
+    }
+    found
+}
+
+/// Where the kernel and systemd-coredump/apport put core dumps, based on
+/// `kernel.core_pattern`
+#[cfg(target_os = "linux")]
+async fn crash_dirs() -> Vec<PathBuf> {
+    let pattern = fs::read_to_string("/proc/sys/kernel/core_pattern")
+        .await
+        .unwrap_or_default();
+    let pattern = pattern.trim();
+
+    if let Some(handler) = pattern.strip_prefix('|') {
+        if handler.contains("systemd-coredump") {
+            return vec![PathBuf::from("/var/lib/systemd/coredump")];
+        }
+        if handler.contains("apport") {
+            return vec![PathBuf::from("/var/crash")];
+        }
+        info!("Core dumps are piped to {}, not collecting them", handler);
+        return Vec::new();
+    }
+
+    // A plain pattern is relative to the crashing process's working directory,
+    // which osqueryd inherits from us
+    let path = Path::new(pattern);
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::new(),
+    };
+    if dir.to_string_lossy().contains('%') {
+        // Per-process directories can't be searched reliably
+        return Vec::new();
+    }
+    if dir.is_absolute() {
+        vec![dir]
+    } else {
+        std::env::current_dir()
+            .map(|cwd| vec![cwd.join(dir)])
+            .unwrap_or_default()
+    }
+}
+
+/// Crash reports from ReportCrash, and core dumps if enabled
+#[cfg(target_os = "macos")]
+async fn crash_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![
+        PathBuf::from("/Library/Logs/DiagnosticReports"),
+        PathBuf::from("/cores"),
+    ];
+    if let Some(home) = std::env::var_os("HOME") {
+        dirs.push(Path::new(&home).join("Library/Logs/DiagnosticReports"));
+    }
+    dirs
+}
+
+/// WER LocalDumps minidumps and report archives
+#[cfg(windows)]
+async fn crash_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(local) = std::env::var_os("LOCALAPPDATA") {
+        dirs.push(Path::new(&local).join("CrashDumps"));
+    }
+    if let Some(program_data) = std::env::var_os("ProgramData") {
+        let wer = Path::new(&program_data).join(r"Microsoft\Windows\WER");
+        dirs.push(wer.join("ReportArchive"));
+        dirs.push(wer.join("ReportQueue"));
+    }
+    dirs
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+async fn crash_dirs() -> Vec<PathBuf> {
+    Vec::new()
+}
@@ -0,0 +1,327 @@
+//! `shadow status --watch`
+//!
+//! A live view of the agent for someone investigating the host: the
+//! supervisor's state, the resources shadow and osqueryd are using, the latest
+//! lines of `shadow.log`, and the distributed queries osqueryd has started
+//! (which it logs with `--distributed_loginfo`), redrawn in place until `q` is
+//! pressed. The status comes from the running agent when it answers, and from
+//! the status file otherwise.
+
+use crate::errors;
+use crate::ipc::{self, Request, Response};
+use crate::logfile;
+use crate::resources::{ResourceSampler, ResourceUsage};
+use crate::status::{self, secs_since, AgentState, AgentStatus};
+use anyhow::{Context, Result};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, Wrap};
+use ratatui::Frame;
+use std::io::{IsTerminal, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+/// How much of the end of the log file is read
+const LOG_TAIL: u64 = 256 * 1024;
+
+/// What osqueryd logs as it starts a distributed query
+const DISTRIBUTED_MARKER: &str = "distributed query";
+
+/// Everything shown on one refresh
+struct Snapshot {
+    status: Result<AgentStatus, String>,
+    agent_running: bool,
+    agent: Option<ResourceUsage>,
+    osqueryd: Option<ResourceUsage>,
+    log: Vec<String>,
+    queries: Vec<String>,
+}
+
+/// `shadow status --watch`
+pub async fn run(data_dir: &Path, interval: Duration) -> Result<()> {
+    if !std::io::stdout().is_terminal() {
+        anyhow::bail!("--watch needs a terminal; use --json for scripts");
+    }
+    let mut sampler = ResourceSampler::new();
+    let mut terminal = ratatui::init();
+    let result = async {
+        loop {
+            let snapshot = snapshot(data_dir, &mut sampler).await;
+            terminal
+                .draw(|frame| draw(frame, data_dir, interval, &snapshot))
+                .context("Failed to draw")?;
+            if quit_requested(interval).await? {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    ratatui::restore();
+    result
+}
+
+/// Wait up to `interval` for a key, returning whether it asks to quit
+async fn quit_requested(interval: Duration) -> Result<bool> {
+    tokio::task::spawn_blocking(move || {
+        if !event::poll(interval)? {
+            return Ok(false);
+        }
+        Ok(match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => {
+                matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL))
+            }
+            _ => false,
+        })
+    })
+    .await?
+}
+
+async fn snapshot(data_dir: &Path, sampler: &mut ResourceSampler) -> Snapshot {
+    let (status, agent_running) = match ipc::call(data_dir, &Request::Status).await {
+        Ok(Some(Response::Status { status })) => (Ok(*status), true),
+        _ => match status::read(data_dir).await {
+            Ok(status) => {
+                let alive = status::agent_alive(&status);
+                (Ok(status), alive)
+            }
+            Err(e) => (Err(format!("{:#}", e)), false),
+        },
+    };
+    let agent = match &status {
+        Ok(status) if agent_running => sampler.sample(status.agent_pid),
+        _ => None,
+    };
+    let osqueryd = match &status {
+        Ok(status) => status.child_pid.and_then(|pid| sampler.sample_tree(pid)),
+        Err(_) => None,
+    };
+
+    let path = data_dir.join(logfile::FILE_NAME);
+    let lines = tokio::task::spawn_blocking(move || tail(&path))
+        .await
+        .unwrap_or_default();
+    let queries = lines
+        .iter()
+        .filter(|line| line.to_ascii_lowercase().contains(DISTRIBUTED_MARKER))
+        .cloned()
+        .collect();
+
+    Snapshot {
+        status,
+        agent_running,
+        agent,
+        osqueryd,
+        log: lines,
+        queries,
+    }
+}
+
+/// The last lines of the log file
+fn tail(path: &Path) -> Vec<String> {
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return Vec::new();
+    };
+    let len = file.metadata().map_or(0, |metadata| metadata.len());
+    let start = len.saturating_sub(LOG_TAIL);
+    if file.seek(SeekFrom::Start(start)).is_err() {
+        return Vec::new();
+    }
+    let mut data = Vec::new();
+    if file.read_to_end(&mut data).is_err() {
+        return Vec::new();
+    }
+    let text = String::from_utf8_lossy(&data);
+    let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+    // The first line is likely cut off
+    if start > 0 && !lines.is_empty() {
+        lines.remove(0);
+    }
+    lines
+}
+
+fn state_color(state: AgentState) -> Color {
+    match state {
+        AgentState::Running => Color::Green,
+        AgentState::Provisioning | AgentState::Enrolling | AgentState::Paused => Color::Yellow,
+        AgentState::Backoff | AgentState::CrashLoop | AgentState::Stopped => Color::Red,
+    }
+}
+
+/// A duration for people, e.g. `3h 12m`
+pub fn duration(secs: u64) -> String {
+    match secs {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m {}s", s / 60, s % 60),
+        s if s < 86400 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+fn draw(frame: &mut Frame, data_dir: &Path, interval: Duration, snapshot: &Snapshot) {
+    let [header, resources, logs, footer] = Layout::vertical([
+        Constraint::Length(9),
+        Constraint::Length(5),
+        Constraint::Min(6),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let title = format!(" shadow - {} ", data_dir.display());
+    let summary = match &snapshot.status {
+        Ok(status) => Paragraph::new(status_lines(status, snapshot.agent_running)),
+        Err(e) => Paragraph::new(e.as_str()).red(),
+    };
+    frame.render_widget(
+        summary
+            .wrap(Wrap { trim: true })
+            .block(Block::bordered().title(title)),
+        header,
+    );
+
+    frame.render_widget(resource_table(snapshot), resources);
+
+    let [log, queries] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(logs);
+    frame.render_widget(tail_widget(&snapshot.log, " Log ", log.height), log);
+    frame.render_widget(
+        tail_widget(&snapshot.queries, " Distributed queries ", queries.height),
+        queries,
+    );
+
+    frame.render_widget(
+        Line::from(format!(
+            " q to quit, refreshing every {}s",
+            interval.as_secs()
+        ))
+        .dim(),
+        footer,
+    );
+}
+
+fn status_lines(status: &AgentStatus, running: bool) -> Vec<Line<'static>> {
+    let label = |text: &'static str| {
+        Span::styled(
+            format!("{:<13}", text),
+            Style::new().add_modifier(Modifier::BOLD),
+        )
+    };
+    let agent = match running {
+        true => format!(
+            "running (pid {}, v{})",
+            status.agent_pid, status.agent_version
+        ),
+        false => format!("not running (last pid {})", status.agent_pid),
+    };
+    let mut lines = vec![
+        Line::from(vec![label("Agent"), Span::raw(agent)]),
+        Line::from(vec![
+            label("State"),
+            Span::styled(
+                status.state.to_string(),
+                Style::new().fg(state_color(status.state)),
+            ),
+            Span::raw(format!(" for {}", duration(secs_since(status.state_since)))),
+        ]),
+    ];
+    let osqueryd = match (status.child_pid, status.child_started_at) {
+        (Some(pid), Some(started)) => format!("pid {}, up {}", pid, duration(secs_since(started))),
+        (Some(pid), None) => format!("pid {}", pid),
+        _ => "not running".to_string(),
+    };
+    let version = status
+        .osquery_version
+        .as_deref()
+        .unwrap_or("unknown version");
+    lines.push(Line::from(vec![
+        label("osqueryd"),
+        Span::raw(format!("{} ({})", osqueryd, version)),
+    ]));
+    let restarts = match &status.last_restart_reason {
+        Some(reason) => format!("{} (last: {})", status.restarts, reason),
+        None => status.restarts.to_string(),
+    };
+    lines.push(Line::from(vec![label("Restarts"), Span::raw(restarts)]));
+    if let Some(degraded) = &status.degraded {
+        lines.push(Line::from(vec![
+            label("Degraded"),
+            Span::raw(degraded.clone()).yellow(),
+        ]));
+    }
+    if status.log_upload_failures > 0 {
+        let failures = format!("{} failed log uploads", status.log_upload_failures);
+        lines.push(Line::from(vec![
+            label("Logging"),
+            Span::raw(failures).yellow(),
+        ]));
+    }
+    if let Some(error) = &status.last_error {
+        lines.push(Line::from(vec![
+            label("Last error"),
+            Span::raw(error.clone()).red(),
+        ]));
+    }
+    if let Some(code) = status.last_error_code.as_deref().and_then(errors::lookup) {
+        lines.push(Line::from(vec![label("Hint"), Span::raw(code.hint)]));
+    }
+    lines
+}
+
+fn resource_table(snapshot: &Snapshot) -> Table<'static> {
+    let row = |name: &'static str, usage: &Option<ResourceUsage>| match usage {
+        Some(usage) => Row::new(vec![
+            name.to_string(),
+            format!("{:.1}%", usage.cpu_percent),
+            format!("{} MB", usage.rss_bytes / (1024 * 1024)),
+            duration(usage.cpu_time_ms / 1000),
+            usage
+                .open_fds
+                .map_or("-".to_string(), |fds| fds.to_string()),
+            usage.processes.to_string(),
+        ]),
+        None => Row::new(vec![name.to_string(), "-".to_string()]),
+    };
+    let database = snapshot
+        .status
+        .as_ref()
+        .ok()
+        .and_then(|status| status.osquery_db_bytes)
+        .map_or(String::new(), |bytes| {
+            format!(" database {} MB ", bytes / (1024 * 1024))
+        });
+    Table::new(
+        vec![
+            row("shadow", &snapshot.agent),
+            row("osqueryd", &snapshot.osqueryd),
+        ],
+        [
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(6),
+            Constraint::Length(10),
+        ],
+    )
+    .header(Row::new(["", "CPU", "Memory", "CPU time", "FDs", "Processes"]).bold())
+    .block(
+        Block::bordered()
+            .title(" Resources ")
+            .title_bottom(database),
+    )
+}
+
+/// The last lines that fit in a box `height` rows tall
+fn tail_widget<'a>(lines: &'a [String], title: &'a str, height: u16) -> Paragraph<'a> {
+    let shown = lines
+        .len()
+        .saturating_sub(height.saturating_sub(2) as usize);
+    let text: Vec<Line> = lines[shown..]
+        .iter()
+        .map(|line| Line::raw(line.as_str()))
+        .collect();
+    Paragraph::new(text).block(Block::bordered().title(title))
+}
@@ -0,0 +1,171 @@
+//! osquery database size
+//!
+//! osqueryd keeps its state in a RocksDB database, `osquery.db` in the data
+//! directory. Event tables buffer events there until they expire, and results
+//! queue there while the server is unreachable, so on busy hosts or after long
+//! outages it can grow to gigabytes. The agent measures it periodically,
+//! reporting the size in heartbeats and `status.json` and warning once it
+//! passes a threshold. `shadow purge-db`, or the database passing the purge
+//! threshold, leaves a request in the data directory; the supervisor then stops
+//! osqueryd, deletes the database, and starts osqueryd with a fresh one. That
+//! loses buffered events and results and resets differential queries, so it's
+//! a last resort after lowering `--osquery-events-expiry`. With
+//! `--host-identifier instance` the database also holds the host's identity, so
+//! it's never purged.
+
+use crate::audit;
+use crate::logging::{info, warning};
+use crate::resources::dir_size;
+use crate::status::SharedStatus;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::fs;
+
+/// Database directory within the data directory
+pub const DIR_NAME: &str = "osquery.db";
+
+/// Purge request filename within the data directory
+const PURGE_FILE: &str = "purge-db.json";
+
+/// How often the database is measured
+const MEASURE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the supervisor checks for a purge request
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Size thresholds for the database
+#[derive(Debug, Clone, Copy)]
+pub struct DbLimits {
+    /// Warn past this size, if set
+    pub warn_bytes: Option<u64>,
+    /// Purge the database past this size, if set
+    pub purge_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PurgeRequest {
+    pub requested_at: DateTime<Utc>,
+    pub requested_by: String,
+    pub reason: Option<String>,
+}
+
+fn megabytes(bytes: u64) -> u64 {
+    bytes / (1024 * 1024)
+}
+
+async fn write_request(data_dir: &Path, request: &PurgeRequest) -> Result<()> {
+    fs::write(data_dir.join(PURGE_FILE), serde_json::to_vec_pretty(request)?)
+        .await
+        .context("Failed to write purge request")?;
+    let detail = serde_json::to_value(request)?;
+    audit::record_by(data_dir, &request.requested_by, "purge_db_requested", detail).await
+}
+
+/// `shadow purge-db`
+pub async fn request_purge(data_dir: &Path, reason: Option<String>) -> Result<()> {
+    let request = PurgeRequest {
+        requested_at: Utc::now(),
+        requested_by: audit::current_user(),
+        reason,
+    };
+    write_request(data_dir, &request).await?;
+    println!("osquery's database will be purged by the running agent, or when it next starts");
+    Ok(())
+}
+
+/// Pending purge request, if any
+pub async fn purge_request(data_dir: &Path) -> Option<PurgeRequest> {
+    let data = fs::read(data_dir.join(PURGE_FILE)).await.ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Wait until a purge is requested
+pub async fn wait_for_purge(data_dir: &Path) -> PurgeRequest {
+    loop {
+        if let Some(request) = purge_request(data_dir).await {
+            return request;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Drop a purge request without acting on it
+pub async fn discard_request(data_dir: &Path) {
+    let _ = fs::remove_file(data_dir.join(PURGE_FILE)).await;
+}
+
+/// Delete the database and the request. osqueryd must not be running.
+pub async fn purge(data_dir: &Path, request: &PurgeRequest) -> Result<()> {
+    // Removed first, so a database that can't be deleted isn't retried forever
+    discard_request(data_dir).await;
+    let path = data_dir.join(DIR_NAME);
+    let size = dir_size(&path).unwrap_or(0);
+    match fs::remove_dir_all(&path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to delete {}", path.display())),
+    }
+    info!(
+        "Purged osquery's database ({} MB), requested by {}{}",
+        megabytes(size),
+        request.requested_by,
+        request.reason.as_ref().map(|r| format!(": {}", r)).unwrap_or_default()
+    );
+    audit::record_agent(data_dir, "purge_db", serde_json::json!({ "bytes": size })).await;
+    Ok(())
+}
+
+/// Measure the database until the agent exits
+pub fn spawn(data_dir: PathBuf, status: SharedStatus, limits: DbLimits) {
+    tokio::spawn(async move {
+        let path = data_dir.join(DIR_NAME);
+        let mut ticker = tokio::time::interval(MEASURE_INTERVAL);
+        let mut warned = false;
+        loop {
+            ticker.tick().await;
+            let measured = path.clone();
+            let Ok(size) = tokio::task::spawn_blocking(move || dir_size(&measured)).await else {
+                continue;
+            };
+            status.update(|status| status.osquery_db_bytes = size);
+            let size = size.unwrap_or(0);
+
+            match limits.warn_bytes {
+                Some(warn_bytes) if size > warn_bytes && !warned => {
+                    warning!(
+                        "osquery's database is {} MB, over the {} MB threshold. \
+                         Events may be arriving faster than they expire. Lower --osquery-events-expiry, or run `shadow purge-db`.",
+                        megabytes(size),
+                        megabytes(warn_bytes)
+                    );
+                    warned = true;
+                }
+                Some(warn_bytes) if size <= warn_bytes && warned => {
+                    info!("osquery's database is back under {} MB", megabytes(warn_bytes));
+                    warned = false;
+                }
+                _ => {}
+            }
+
+            if let Some(purge_bytes) = limits.purge_bytes {
+                if size > purge_bytes && purge_request(&data_dir).await.is_none() {
+                    let request = PurgeRequest {
+                        requested_at: Utc::now(),
+                        requested_by: audit::AGENT.to_string(),
+                        reason: Some(format!(
+                            "database reached {} MB, over the {} MB limit",
+                            megabytes(size),
+                            megabytes(purge_bytes)
+                        )),
+                    };
+                    if let Err(e) = write_request(&data_dir, &request).await {
+                        warning!("Failed to request a database purge: {:#}", e);
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,199 @@
+//! D-Bus status interface
+//!
+//! On Linux, the agent publishes its status on the system bus as
+//! `cloud.hyprwatch.Shadow` at `/cloud/hyprwatch/Shadow`, so desktop
+//! management tools and shell extensions can show whether the agent is
+//! healthy without reading files only root can. The `cloud.hyprwatch.Shadow1`
+//! interface has read-only properties (with `PropertiesChanged` sent as they
+//! change) and a `StateChanged` signal. Owning the name takes the bus policy
+//! `shadow service install` writes; without it, nothing is published.
+
+use crate::status::SharedStatus;
+
+/// Well-known name on the system bus
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub const BUS_NAME: &str = "cloud.hyprwatch.Shadow";
+
+/// Object the interface is served at
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub const OBJECT_PATH: &str = "/cloud/hyprwatch/Shadow";
+
+/// Interface name, versioned so it can change without breaking clients
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub const INTERFACE: &str = "cloud.hyprwatch.Shadow1";
+
+/// Publish the agent's status on the system bus until the agent exits
+#[cfg(target_os = "linux")]
+pub fn spawn(status: SharedStatus) {
+    tokio::spawn(async move {
+        if let Err(e) = platform::run(status).await {
+            crate::logging::info!("Not publishing status on D-Bus: {}", e);
+        }
+    });
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn spawn(_status: SharedStatus) {}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::{BUS_NAME, OBJECT_PATH};
+    use crate::health;
+    use crate::status::{AgentState, AgentStatus, SharedStatus};
+    use chrono::{DateTime, Utc};
+    use std::time::Duration;
+    use zbus::object_server::SignalEmitter;
+
+    /// How often to check for changes
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    struct Shadow {
+        status: SharedStatus,
+    }
+
+    #[zbus::interface(name = "cloud.hyprwatch.Shadow1")]
+    impl Shadow {
+        /// provisioning, enrolling, running, backoff, crash-loop, paused, or stopped
+        #[zbus(property)]
+        fn state(&self) -> String {
+            self.status.snapshot().state.to_string()
+        }
+
+        /// When the agent entered its state, in seconds since the epoch
+        #[zbus(property)]
+        fn state_since(&self) -> i64 {
+            self.status.snapshot().state_since.timestamp()
+        }
+
+        /// Whether osqueryd is running under supervision, or collection is
+        /// deliberately paused
+        #[zbus(property)]
+        fn healthy(&self) -> bool {
+            health::problem(&self.status.snapshot()).is_none()
+        }
+
+        /// Why the agent isn't healthy, empty when it is
+        #[zbus(property)]
+        fn reason(&self) -> String {
+            health::problem(&self.status.snapshot()).unwrap_or_default()
+        }
+
+        #[zbus(property)]
+        fn last_error(&self) -> String {
+            self.status.snapshot().last_error.unwrap_or_default()
+        }
+
+        #[zbus(property)]
+        fn last_error_code(&self) -> String {
+            self.status.snapshot().last_error_code.unwrap_or_default()
+        }
+
+        #[zbus(property)]
+        fn agent_version(&self) -> String {
+            self.status.snapshot().agent_version
+        }
+
+        #[zbus(property)]
+        fn osquery_version(&self) -> String {
+            self.status.snapshot().osquery_version.unwrap_or_default()
+        }
+
+        /// 0 while osqueryd isn't running
+        #[zbus(property)]
+        fn osqueryd_pid(&self) -> u32 {
+            self.status.snapshot().child_pid.unwrap_or(0)
+        }
+
+        #[zbus(signal, name = "StateChanged")]
+        async fn emit_state_changed(
+            emitter: &SignalEmitter<'_>,
+            state: &str,
+            healthy: bool,
+            reason: &str,
+        ) -> zbus::Result<()>;
+    }
+
+    /// What the properties are derived from, to tell when they change
+    #[derive(PartialEq)]
+    struct Published {
+        state: AgentState,
+        state_since: DateTime<Utc>,
+        problem: Option<String>,
+        last_error: Option<String>,
+        last_error_code: Option<String>,
+        osquery_version: Option<String>,
+        child_pid: Option<u32>,
+    }
+
+    impl Published {
+        fn new(status: AgentStatus) -> Self {
+            Published {
+                problem: health::problem(&status),
+                state: status.state,
+                state_since: status.state_since,
+                last_error: status.last_error,
+                last_error_code: status.last_error_code,
+                osquery_version: status.osquery_version,
+                child_pid: status.child_pid,
+            }
+        }
+    }
+
+    pub async fn run(status: SharedStatus) -> zbus::Result<()> {
+        let connection = zbus::connection::Builder::system()?
+            .name(BUS_NAME)?
+            .serve_at(
+                OBJECT_PATH,
+                Shadow {
+                    status: status.clone(),
+                },
+            )?
+            .build()
+            .await?;
+        let iface = connection
+            .object_server()
+            .interface::<_, Shadow>(OBJECT_PATH)
+            .await?;
+        let mut last = Published::new(status.snapshot());
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            let now = Published::new(status.snapshot());
+            if now == last {
+                continue;
+            }
+            let emitter = iface.signal_emitter();
+            let shadow = iface.get().await;
+            let (old, new) = (&last, &now);
+            if old.state != new.state
+                || old.state_since != new.state_since
+                || old.problem != new.problem
+            {
+                Shadow::emit_state_changed(
+                    emitter,
+                    &new.state.to_string(),
+                    new.problem.is_none(),
+                    new.problem.as_deref().unwrap_or_default(),
+                )
+                .await?;
+                shadow.state_changed(emitter).await?;
+                shadow.state_since_changed(emitter).await?;
+                shadow.healthy_changed(emitter).await?;
+                shadow.reason_changed(emitter).await?;
+            }
+            if old.last_error != new.last_error || old.last_error_code != new.last_error_code {
+                shadow.last_error_changed(emitter).await?;
+                shadow.last_error_code_changed(emitter).await?;
+            }
+            if old.osquery_version != new.osquery_version {
+                shadow.osquery_version_changed(emitter).await?;
+            }
+            if old.child_pid != new.child_pid {
+                shadow.osqueryd_pid_changed(emitter).await?;
+            }
+            drop(shadow);
+            last = now;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Remote debugging
+//!
+//! The server can turn up logging on one host while someone investigates it,
+//! by answering a heartbeat with a debug request, e.g.
+//! `{"debug": {"log_filter": "debug", "osqueryd_verbose": true, "ttl_secs": 3600}}`.
+//! The agent's log filter is replaced (RUST_LOG syntax, `debug` if not given),
+//! osqueryd is restarted with `--verbose` if asked, and both go back to the
+//! agent's own settings when the TTL runs out, at most a day later, or when a
+//! request with a TTL of 0 arrives. A new request replaces the current one, so
+//! the server can repeat a request in each reply to keep it going; osqueryd is
+//! only restarted when its verbosity changes. The session is shown in
+//! `shadow status` and heartbeats, recorded in the audit log, and ends with
+//! the agent, so a restart always goes back to normal.
+
+use crate::audit;
+use crate::logging::{self, error, info};
+use crate::status::SharedStatus;
+use crate::supervisor::{self, Action};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Longest a session can last
+const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Log filter when the server doesn't give one
+const DEFAULT_FILTER: &str = "debug";
+
+/// A debug request from the server
+#[derive(Debug, Clone, Deserialize)]
+pub struct DebugRequest {
+    /// Log filter for the agent, in RUST_LOG syntax
+    pub log_filter: Option<String>,
+    /// Run osqueryd with `--verbose`
+    #[serde(default)]
+    pub osqueryd_verbose: bool,
+    /// How long to keep it up, 0 to end the current session
+    pub ttl_secs: u64,
+}
+
+/// Debugging in effect
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DebugSession {
+    pub log_filter: String,
+    pub osqueryd_verbose: bool,
+    /// When logging goes back to normal
+    pub until: DateTime<Utc>,
+}
+
+/// Carry out debug requests until the agent exits, returning where to send
+/// them. osqueryd is restarted through the supervisor's `requests`.
+pub fn spawn(
+    status: SharedStatus,
+    data_dir: PathBuf,
+    requests: mpsc::Sender<supervisor::Request>,
+) -> mpsc::Sender<DebugRequest> {
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(run(status, data_dir, requests, rx));
+    tx
+}
+
+async fn run(
+    status: SharedStatus,
+    data_dir: PathBuf,
+    requests: mpsc::Sender<supervisor::Request>,
+    mut rx: mpsc::Receiver<DebugRequest>,
+) {
+    loop {
+        let current = status.snapshot().debug;
+        let expired = async {
+            match &current {
+                Some(session) => {
+                    let left = (session.until - Utc::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(left).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+        let next = tokio::select! {
+            request = rx.recv() => match request {
+                Some(request) => match session(request) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        error!("Ignoring debug request from the server: {}", e);
+                        continue;
+                    }
+                },
+                None => return,
+            },
+            _ = expired => None,
+        };
+        apply(&status, &data_dir, &requests, current, next).await;
+    }
+}
+
+/// The session a request asks for, or None to end it
+fn session(request: DebugRequest) -> Result<Option<DebugSession>, String> {
+    if request.ttl_secs == 0 {
+        return Ok(None);
+    }
+    let log_filter = request
+        .log_filter
+        .unwrap_or_else(|| DEFAULT_FILTER.to_string());
+    logging::check_filter(&log_filter).map_err(|e| format!("{:#}", e))?;
+    let ttl = Duration::from_secs(request.ttl_secs).min(MAX_TTL);
+    Ok(Some(DebugSession {
+        log_filter,
+        osqueryd_verbose: request.osqueryd_verbose,
+        until: Utc::now() + chrono::Duration::from_std(ttl).unwrap_or_default(),
+    }))
+}
+
+async fn apply(
+    status: &SharedStatus,
+    data_dir: &Path,
+    requests: &mpsc::Sender<supervisor::Request>,
+    current: Option<DebugSession>,
+    next: Option<DebugSession>,
+) {
+    let filter = |session: &Option<DebugSession>| session.as_ref().map(|s| s.log_filter.clone());
+    let verbose =
+        |session: &Option<DebugSession>| session.as_ref().is_some_and(|s| s.osqueryd_verbose);
+    let changed = filter(&current) != filter(&next);
+    let restart = verbose(&current) != verbose(&next);
+    // Nothing to do, or the same session for longer
+    if !changed && !restart {
+        if current.is_some() {
+            status.update(|status| status.debug = next);
+        }
+        return;
+    }
+    if changed {
+        if let Err(e) = logging::set_filter(filter(&next).as_deref()) {
+            error!("{:#}", e);
+        }
+    }
+    match &next {
+        Some(session) => info!(
+            "Debugging until {} for the server (log filter `{}`{})",
+            session.until.to_rfc3339(),
+            session.log_filter,
+            if session.osqueryd_verbose {
+                ", osqueryd verbose"
+            } else {
+                ""
+            }
+        ),
+        None => info!("Debugging ended, logging is back to normal"),
+    }
+    status.update(|status| status.debug = next.clone());
+    let action = if next.is_some() { "debug" } else { "debug_end" };
+    audit::record_agent(data_dir, action, serde_json::json!(next)).await;
+
+    // A paused osqueryd picks up the change when it's started again
+    if restart && !status.snapshot().paused() {
+        let (reply, _) = oneshot::channel();
+        let request = supervisor::Request {
+            action: Action::Reload,
+            requested_by: "the server, for debugging".to_string(),
+            reply,
+        };
+        let _ = requests.send(request).await;
+    }
+}
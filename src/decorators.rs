@@ -0,0 +1,37 @@
+//! Local decorators
+//!
+//! SIEM pipelines often route on a site code or similar key that has to be on
+//! every result, but osqueryd only adds the decorators the server's config
+//! asks for. With `--decorator` and the TLS relay, the agent adds its own
+//! key/values to the decorations of every result osqueryd sends, replacing
+//! any of the same name from the server, so they're there whatever the
+//! config says.
+
+use crate::api::Tag;
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+
+/// Add `decorators` to every result in a logger request
+pub fn apply(decorators: &[Tag], body: &[u8]) -> Result<Vec<u8>> {
+    let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+    if request.get("log_type").and_then(Value::as_str) != Some("result") {
+        return Ok(body.to_vec());
+    }
+    let Some(items) = request.get_mut("data").and_then(Value::as_array_mut) else {
+        return Ok(body.to_vec());
+    };
+    for item in items.iter_mut().filter_map(Value::as_object_mut) {
+        let decorations = item
+            .entry("decorations")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !decorations.is_object() {
+            *decorations = Value::Object(Map::new());
+        }
+        if let Some(decorations) = decorations.as_object_mut() {
+            for decorator in decorators {
+                decorations.insert(decorator.key.clone(), Value::String(decorator.value.clone()));
+            }
+        }
+    }
+    Ok(serde_json::to_vec(&request)?)
+}
@@ -0,0 +1,250 @@
+//! Result deduplication
+//!
+//! When osqueryd restarts, e.g. after an upgrade or with a fresh database, its
+//! differential queries can report every current row as added again, flooding
+//! the server with rows it already has. With `--dedup-results` and the TLS
+//! relay, the agent remembers a hash of each added row the server accepted and
+//! drops it if it's reported added again before being reported removed. Only
+//! differential results are checked: osquery reports every row of an event
+//! table as added and none as removed, so a scheduled query that reads one
+//! (learned from the configs osqueryd fetches) can repeat a row for real, and
+//! is left alone. The cache holds the most recent rows up to a limit and is
+//! saved in the data directory, so it outlasts restarts of both osqueryd and
+//! the agent.
+
+use crate::telemetry;
+use anyhow::{Context, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// File in the data directory
+const FILE_NAME: &str = "result_hashes";
+
+/// Rows a logger request adds and removes, to remember once the server has
+/// them
+#[derive(Default)]
+pub struct Pending {
+    added: Vec<u64>,
+    removed: Vec<u64>,
+}
+
+/// Hashes of added rows the server has, oldest first
+pub struct Dedup {
+    path: PathBuf,
+    capacity: usize,
+    cache: Mutex<Cache>,
+    /// Scheduled queries that read an event table, from the last config
+    evented: Mutex<HashSet<String>>,
+}
+
+#[derive(Default)]
+struct Cache {
+    /// Each hash with the sequence number it was last added under
+    hashes: HashMap<u64, u64>,
+    order: VecDeque<(u64, u64)>,
+    next: u64,
+    dirty: bool,
+}
+
+impl Cache {
+    fn insert(&mut self, hash: u64, capacity: usize) {
+        let seq = self.next;
+        self.next += 1;
+        self.hashes.insert(hash, seq);
+        self.order.push_back((hash, seq));
+        while self.hashes.len() > capacity {
+            let Some((hash, seq)) = self.order.pop_front() else {
+                break;
+            };
+            // An older entry for a row added again since is stale
+            if self.hashes.get(&hash) == Some(&seq) {
+                self.hashes.remove(&hash);
+            }
+        }
+        // Stale entries from removed rows would otherwise pile up
+        if self.order.len() > capacity * 2 {
+            let hashes = &self.hashes;
+            self.order.retain(|(hash, seq)| hashes.get(hash) == Some(seq));
+        }
+        self.dirty = true;
+    }
+}
+
+impl Dedup {
+    /// Open the cache in `data_dir`, keeping up to `capacity` rows
+    pub fn open(data_dir: &Path, capacity: usize) -> Result<Self> {
+        let path = data_dir.join(FILE_NAME);
+        let mut cache = Cache::default();
+        match std::fs::read(&path) {
+            Ok(data) => {
+                for chunk in data.chunks_exact(8) {
+                    cache.insert(u64::from_le_bytes(chunk.try_into()?), capacity);
+                }
+                cache.dirty = false;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+        Ok(Dedup {
+            path,
+            capacity,
+            cache: Mutex::new(cache),
+            evented: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().hashes.len()
+    }
+
+    /// Learn which scheduled queries read an event table from a config
+    pub fn learn_config(&self, body: &[u8]) -> Result<()> {
+        let config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        let mut evented = HashSet::new();
+        let mut learn = |prefix: &str, queries: &serde_json::Map<String, Value>| {
+            for (name, query) in queries {
+                let sql = query.get("query").and_then(Value::as_str).unwrap_or_default();
+                if reads_events(sql) {
+                    evented.insert(format!("{}{}", prefix, name));
+                }
+            }
+        };
+        if let Some(schedule) = config.get("schedule").and_then(Value::as_object) {
+            learn("", schedule);
+        }
+        if let Some(packs) = config.get("packs").and_then(Value::as_object) {
+            for (pack, contents) in packs {
+                if let Some(queries) = contents.get("queries").and_then(Value::as_object) {
+                    learn(&format!("pack_{}_", pack), queries);
+                }
+            }
+        }
+        *self.evented.lock().unwrap() = evented;
+        Ok(())
+    }
+
+    /// Drop rows the server already has from a logger request, returning it
+    /// with the rows to remember if the server takes it, and how many were
+    /// dropped
+    pub fn apply(&self, body: &[u8]) -> Result<(Vec<u8>, Pending, usize)> {
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        let mut pending = Pending::default();
+        if request.get("log_type").and_then(Value::as_str) != Some("result") {
+            return Ok((body.to_vec(), pending, 0));
+        }
+        let Some(items) = request.get_mut("data").and_then(Value::as_array_mut) else {
+            return Ok((body.to_vec(), pending, 0));
+        };
+        let cache = self.cache.lock().unwrap();
+        let evented = self.evented.lock().unwrap();
+        // Rows added earlier in this request
+        let mut seen = HashSet::new();
+        let mut dropped = 0;
+        items.retain_mut(|item| {
+            let name = item.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+            if evented.contains(&name) {
+                return true;
+            }
+            // Event format has `action` and `columns`, batch format
+            // `diffResults`; snapshots are meant to repeat
+            match item.get("action").and_then(Value::as_str) {
+                Some("added") => {
+                    let hash = hash(&name, item.get("columns"));
+                    if cache.hashes.contains_key(&hash) || !seen.insert(hash) {
+                        telemetry::record_dropped_results(&name, "duplicate", 1);
+                        dropped += 1;
+                        return false;
+                    }
+                    pending.added.push(hash);
+                    return true;
+                }
+                Some("removed") => {
+                    pending.removed.push(hash(&name, item.get("columns")));
+                    return true;
+                }
+                _ => {}
+            }
+            let Some(diff) = item.get_mut("diffResults").and_then(Value::as_object_mut) else {
+                return true;
+            };
+            if let Some(rows) = diff.get_mut("added").and_then(Value::as_array_mut) {
+                rows.retain(|row| {
+                    let hash = hash(&name, Some(row));
+                    if cache.hashes.contains_key(&hash) || !seen.insert(hash) {
+                        telemetry::record_dropped_results(&name, "duplicate", 1);
+                        dropped += 1;
+                        return false;
+                    }
+                    pending.added.push(hash);
+                    true
+                });
+            }
+            if let Some(rows) = diff.get("removed").and_then(Value::as_array) {
+                pending.removed.extend(rows.iter().map(|row| hash(&name, Some(row))));
+            }
+            let empty = |key: &str| diff.get(key).and_then(Value::as_array).is_none_or(Vec::is_empty);
+            !(empty("added") && empty("removed"))
+        });
+        drop(evented);
+        drop(cache);
+        if dropped == 0 {
+            return Ok((body.to_vec(), pending, 0));
+        }
+        Ok((serde_json::to_vec(&request)?, pending, dropped))
+    }
+
+    /// Remember the rows of a request the server took
+    pub fn commit(&self, pending: Pending) {
+        let mut cache = self.cache.lock().unwrap();
+        for hash in pending.removed {
+            cache.dirty |= cache.hashes.remove(&hash).is_some();
+        }
+        for hash in pending.added {
+            cache.insert(hash, self.capacity);
+        }
+    }
+
+    /// Write the cache to the data directory if it's changed
+    pub fn save(&self) -> Result<()> {
+        let data = {
+            let mut cache = self.cache.lock().unwrap();
+            if !cache.dirty {
+                return Ok(());
+            }
+            cache.dirty = false;
+            let hashes = &cache.hashes;
+            cache
+                .order
+                .iter()
+                .filter(|(hash, seq)| hashes.get(hash) == Some(seq))
+                .flat_map(|(hash, _)| hash.to_le_bytes())
+                .collect::<Vec<u8>>()
+        };
+        let temp = self.path.with_extension("tmp");
+        std::fs::write(&temp, data)
+            .and_then(|_| std::fs::rename(&temp, &self.path))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Whether a query's SQL reads an event table, which osquery names `*_events`
+fn reads_events(sql: &str) -> bool {
+    sql.to_ascii_lowercase()
+        .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .any(|word| word.ends_with("_events"))
+}
+
+/// A row's hash, by its query and columns but not when it was seen
+fn hash(name: &str, columns: Option<&Value>) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0]);
+    if let Some(columns) = columns {
+        hasher.update(columns.to_string().as_bytes());
+    }
+    let digest = hasher.finalize();
+    u64::from_le_bytes(digest[..8].try_into().expect("SHA-256 is 32 bytes"))
+}
@@ -0,0 +1,151 @@
+//! Local query denylist
+//!
+//! Some hosts must never run certain queries, whatever the server's config
+//! says, e.g. browser history on executives' laptops. With `--deny-table` and
+//! `--deny-query` and the TLS relay, the agent removes scheduled queries that
+//! read a denied table or whose name matches a denied pattern from each config
+//! osqueryd fetches, does the same for live queries, and drops any results for
+//! denied queries osqueryd still sends. Tables are found by name anywhere in a
+//! query's SQL, erring on the side of blocking. What was blocked is reported
+//! to the server, so it isn't left wondering why results never arrive.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Where blocked queries are reported
+pub const REPORT_PATH: &str = "/api/shadow/blocked-queries";
+
+/// A query kept from osqueryd
+#[derive(Debug, Clone, Serialize)]
+pub struct Blocked {
+    /// Scheduled query name, as it appears in results, or live query ID
+    pub name: String,
+    pub live: bool,
+    /// The denied table or pattern
+    pub reason: String,
+}
+
+/// Tables and query names this host won't run
+pub struct Denylist {
+    /// Lowercase
+    tables: Vec<String>,
+    queries: Vec<Regex>,
+}
+
+impl Denylist {
+    pub fn new(tables: &[String], queries: &[String]) -> Result<Self> {
+        let queries = queries
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Invalid --deny-query pattern '{}'", pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Denylist {
+            tables: tables.iter().map(|table| table.trim().to_ascii_lowercase()).collect(),
+            queries,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty() && self.queries.is_empty()
+    }
+
+    /// Why a query is denied, by its name, if it is
+    fn name_denied(&self, name: &str) -> Option<String> {
+        self.queries
+            .iter()
+            .find(|pattern| pattern.is_match(name))
+            .map(|pattern| format!("name matches '{}'", pattern))
+    }
+
+    /// Why a query is denied, by its name or SQL, if it is
+    fn denied(&self, name: &str, sql: &str) -> Option<String> {
+        if let Some(reason) = self.name_denied(name) {
+            return Some(reason);
+        }
+        let sql = sql.to_ascii_lowercase();
+        sql.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .find_map(|word| self.tables.iter().find(|table| *table == word))
+            .map(|table| format!("reads table '{}'", table))
+    }
+
+    /// Remove denied queries from a config, top-level schedule and inline
+    /// packs alike
+    pub fn filter_config(&self, body: &[u8]) -> Result<(Vec<u8>, Vec<Blocked>)> {
+        let mut config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        let mut blocked = Vec::new();
+        if let Some(schedule) = config.get_mut("schedule").and_then(Value::as_object_mut) {
+            self.filter_queries(schedule, "", &mut blocked);
+        }
+        if let Some(packs) = config.get_mut("packs").and_then(Value::as_object_mut) {
+            for (pack, contents) in packs.iter_mut() {
+                if let Some(queries) = contents.get_mut("queries").and_then(Value::as_object_mut) {
+                    self.filter_queries(queries, &format!("pack_{}_", pack), &mut blocked);
+                }
+            }
+        }
+        Ok((serde_json::to_vec(&config)?, blocked))
+    }
+
+    fn filter_queries(
+        &self,
+        queries: &mut serde_json::Map<String, Value>,
+        prefix: &str,
+        blocked: &mut Vec<Blocked>,
+    ) {
+        queries.retain(|name, query| {
+            let name = format!("{}{}", prefix, name);
+            let sql = query.get("query").and_then(Value::as_str).unwrap_or_default();
+            match self.denied(&name, sql) {
+                Some(reason) => {
+                    blocked.push(Blocked {
+                        name,
+                        live: false,
+                        reason,
+                    });
+                    false
+                }
+                None => true,
+            }
+        });
+    }
+
+    /// Remove denied queries from a distributed read reply
+    pub fn filter_live(&self, body: &[u8]) -> Result<(Vec<u8>, Vec<Blocked>)> {
+        let mut reply: Value = serde_json::from_slice(body).context("Live queries aren't JSON")?;
+        let mut blocked = Vec::new();
+        if let Some(queries) = reply.get_mut("queries").and_then(Value::as_object_mut) {
+            queries.retain(|id, sql| match self.denied(id, sql.as_str().unwrap_or_default()) {
+                Some(reason) => {
+                    blocked.push(Blocked {
+                        name: id.clone(),
+                        live: true,
+                        reason,
+                    });
+                    false
+                }
+                None => true,
+            });
+        }
+        Ok((serde_json::to_vec(&reply)?, blocked))
+    }
+
+    /// Drop results of denied queries from a logger request, by name or by
+    /// having been `blocked` from the config, in case osqueryd runs one from
+    /// a config it fetched before
+    pub fn filter_logs(&self, body: &[u8], blocked: &BTreeSet<String>) -> Result<Vec<u8>> {
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        if request.get("log_type").and_then(Value::as_str) == Some("result") {
+            if let Some(items) = request.get_mut("data").and_then(Value::as_array_mut) {
+                items.retain(|item| {
+                    let name = item.get("name").and_then(Value::as_str).unwrap_or_default();
+                    !blocked.contains(name) && self.name_denied(name).is_none()
+                });
+            }
+        }
+        Ok(serde_json::to_vec(&request)?)
+    }
+}
@@ -0,0 +1,193 @@
+//! Distributed query timings
+//!
+//! Live (distributed) queries reach osqueryd in a distributed read, run, and
+//! go back to the server in a distributed write. With the TLS relay in the
+//! path, the agent times each query by its ID: the read (the server's answer
+//! to the request that carried it), execution (from handing the read to
+//! osqueryd until osqueryd writes the query's results), the write, and the
+//! total from the start of the read to the end of the write. Queries from the
+//! live channel are timed too, without a read. The timings of the most recent
+//! queries are kept for each stage, and their percentiles are sent as metrics
+//! and in heartbeats, so hosts where live queries are slow stand out.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Most recent queries whose timings the percentiles are taken over
+const WINDOW: usize = 1000;
+
+/// How long a query that was read waits for its results before it's
+/// forgotten, e.g. when the denylist took it out or osqueryd was restarted
+const PENDING_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// A stage of a distributed query
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Read,
+    Execute,
+    Write,
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [Stage::Read, Stage::Execute, Stage::Write, Stage::Total];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Read => "read",
+            Stage::Execute => "execute",
+            Stage::Write => "write",
+            Stage::Total => "total",
+        }
+    }
+}
+
+/// A query osqueryd has read and not yet written the results of
+struct Pending {
+    read_started: Instant,
+    read: Duration,
+    delivered: Instant,
+}
+
+#[derive(Default)]
+struct Timings {
+    pending: HashMap<String, Pending>,
+    /// Recent durations of each stage, oldest first, indexed by [`Stage`]
+    recent: [VecDeque<Duration>; 4],
+    /// Queries timed since the agent started
+    queries: u64,
+}
+
+impl Timings {
+    fn record(&mut self, stage: Stage, duration: Duration) {
+        let recent = &mut self.recent[stage as usize];
+        if recent.len() == WINDOW {
+            recent.pop_front();
+        }
+        recent.push_back(duration);
+    }
+}
+
+fn timings() -> &'static Mutex<Timings> {
+    static TIMINGS: OnceLock<Mutex<Timings>> = OnceLock::new();
+    TIMINGS.get_or_init(Mutex::default)
+}
+
+/// Percentiles of one stage over the recent queries, in milliseconds
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Percentiles {
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+}
+
+/// How long recent distributed queries took, for heartbeats
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// Queries timed since the agent started
+    queries: u64,
+    /// Only for queries osqueryd read itself, not those from the live channel
+    read: Option<Percentiles>,
+    execute: Option<Percentiles>,
+    write: Option<Percentiles>,
+    total: Option<Percentiles>,
+}
+
+/// Note the queries in a distributed read the server answered with `body`,
+/// after `took`, as handed to osqueryd
+pub fn read(body: &[u8], took: Duration) {
+    let ids = query_ids(body, "queries");
+    if ids.is_empty() {
+        return;
+    }
+    let now = Instant::now();
+    let mut timings = timings().lock().unwrap();
+    timings
+        .pending
+        .retain(|_, pending| now.duration_since(pending.delivered) < PENDING_TTL);
+    for id in ids {
+        timings.pending.insert(
+            id,
+            Pending {
+                read_started: now - took,
+                read: took,
+                delivered: now,
+            },
+        );
+    }
+}
+
+/// Time the queries whose results osqueryd wrote in `body`, which the server
+/// took after `took`
+pub fn written(body: &[u8], took: Duration) {
+    let now = Instant::now();
+    let write_started = now - took;
+    let mut timings = timings().lock().unwrap();
+    for id in query_ids(body, "queries").into_iter().chain(query_ids(body, "statuses")) {
+        let Some(pending) = timings.pending.remove(&id) else {
+            continue;
+        };
+        timings.record(Stage::Read, pending.read);
+        timings.record(Stage::Execute, write_started.saturating_duration_since(pending.delivered));
+        timings.record(Stage::Write, took);
+        timings.record(Stage::Total, now.duration_since(pending.read_started));
+        timings.queries += 1;
+    }
+}
+
+/// Time a query from the live channel, which ran for `execute` and whose
+/// results took `write` to send
+pub fn live(execute: Duration, write: Duration) {
+    let mut timings = timings().lock().unwrap();
+    timings.record(Stage::Execute, execute);
+    timings.record(Stage::Write, write);
+    timings.record(Stage::Total, execute + write);
+    timings.queries += 1;
+}
+
+/// Percentiles of a stage, if any query has been timed through it
+pub fn percentiles(stage: Stage) -> Option<Percentiles> {
+    let mut durations: Vec<Duration> = timings().lock().unwrap().recent[stage as usize].iter().copied().collect();
+    if durations.is_empty() {
+        return None;
+    }
+    durations.sort_unstable();
+    // Nearest rank
+    let at = |quantile: f64| {
+        let rank = (quantile * durations.len() as f64).ceil() as usize;
+        durations[rank.clamp(1, durations.len()) - 1].as_millis() as u64
+    };
+    Some(Percentiles {
+        p50_ms: at(0.50),
+        p95_ms: at(0.95),
+        p99_ms: at(0.99),
+    })
+}
+
+/// Queries timed since the agent started
+pub fn queries() -> u64 {
+    timings().lock().unwrap().queries
+}
+
+/// The timings for heartbeats, once a query has been timed
+pub fn summary() -> Option<Summary> {
+    let queries = queries();
+    (queries > 0).then(|| Summary {
+        queries,
+        read: percentiles(Stage::Read),
+        execute: percentiles(Stage::Execute),
+        write: percentiles(Stage::Write),
+        total: percentiles(Stage::Total),
+    })
+}
+
+/// The query IDs keying the `key` object of a distributed read or write
+fn query_ids(body: &[u8], key: &str) -> Vec<String> {
+    serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|body| body.get(key)?.as_object().map(|queries| queries.keys().cloned().collect()))
+        .unwrap_or_default()
+}
@@ -0,0 +1,108 @@
+//! Agent self-diagnosis
+//!
+//! `shadow doctor` checks the things that most often stop a host from
+//! reporting: the agent and osqueryd, the data directory, the connection to the
+//! server, and the audit log. The running agent runs the checks itself when
+//! asked through the control socket (see [`crate::ipc`]), so they see its live
+//! state and run with its privileges; when it isn't running, the command runs
+//! the checks that don't need it.
+
+use crate::audit;
+use crate::health;
+use crate::network;
+use crate::notify::check_health;
+use crate::output::{self, OutputFormat};
+use crate::status::SharedStatus;
+use crate::storage::{self, Storage};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+
+/// How long the server gets to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Outcome of one check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<String, String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Check {
+            name: name.to_string(),
+            ok,
+            detail,
+        }
+    }
+}
+
+async fn check_agent(status: Option<&SharedStatus>) -> Result<String, String> {
+    let Some(status) = status else {
+        return Err("not running".to_string());
+    };
+    let Some(snapshot) = check_health(status).await else {
+        return Err("not responding, or lost track of osqueryd".to_string());
+    };
+    if let Some(problem) = health::problem(&snapshot) {
+        return Err(problem);
+    }
+    Ok(match snapshot.child_pid {
+        Some(pid) => format!("{}, osqueryd pid {}", snapshot.state, pid),
+        None => snapshot.state.to_string(),
+    })
+}
+
+async fn check_data_dir(data_dir: &Path) -> Result<String, String> {
+    match storage::check(data_dir).await {
+        Storage::Writable => Ok(format!("{} is writable", data_dir.display())),
+        storage => Err(storage.to_string()),
+    }
+}
+
+async fn check_server(server: &str) -> Result<String, String> {
+    let elapsed = network::connect_time(server, CONNECT_TIMEOUT)
+        .await
+        .map_err(|e| format!("can't connect to {}: {}", server, e))?;
+    Ok(format!("connected to {} in {} ms", server, elapsed.as_millis()))
+}
+
+async fn check_audit_log(data_dir: &Path) -> Result<String, String> {
+    let verified = audit::check(data_dir).await.map_err(|e| format!("{:#}", e))?;
+    Ok(match verified.head {
+        Some(head) => format!("intact, {} entries", head.seq),
+        None => "empty".to_string(),
+    })
+}
+
+/// Run the checks. `status` is the running agent's, if this is the agent.
+pub async fn run(data_dir: &Path, status: Option<&SharedStatus>, server: &str) -> Vec<Check> {
+    vec![
+        Check::new("agent", check_agent(status).await),
+        Check::new("data directory", check_data_dir(data_dir).await),
+        Check::new("server", check_server(server).await),
+        Check::new("audit log", check_audit_log(data_dir).await),
+    ]
+}
+
+/// Print the checks, failing if any did
+pub fn print(checks: &[Check], format: OutputFormat) -> Result<()> {
+    output::list(format, checks, |checks| {
+        for check in checks {
+            let mark = if check.ok { "ok  " } else { "FAIL" };
+            println!("{}  {:<15} {}", mark, check.name, check.detail);
+        }
+    })?;
+    let failed = checks.iter().filter(|check| !check.ok).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! Error codes
+//!
+//! Failures someone on the host can act on carry a stable code from this
+//! catalog, with a short hint on what to do, so helpdesk staff can act on an
+//! error without escalating it. A code is attached as context, so the message
+//! reads "E1021: Enrollment failed (401 Unauthorized): ..." and the code is
+//! found wherever it sits in the error's chain. The hint is printed when shadow
+//! exits with an error (as JSON with `--log-format json`), shown in
+//! `shadow status` with the agent's last error, and `shadow explain` lists the
+//! whole catalog. Codes are never reused for a different failure.
+
+use crate::logging::{self, LogFormat};
+use crate::output::{self, OutputFormat};
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub summary: &'static str,
+    pub hint: &'static str,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code)
+    }
+}
+
+// E1xxx: the server and enrollment
+
+pub const SERVER_UNREACHABLE: ErrorCode = ErrorCode {
+    code: "E1001",
+    summary: "Can't connect to the server",
+    hint: "Check that the host can resolve and reach the server on port 443 through any proxy or firewall. \
+           `shadow doctor` tests the connection.",
+};
+
+pub const ORG_TOKEN_MISSING: ErrorCode = ErrorCode {
+    code: "E1011",
+    summary: "No org token",
+    hint: "Pass the org token from the dashboard with --org-token or SHADOW_ORG_TOKEN.",
+};
+
+pub const ENROLL_UNAUTHORIZED: ErrorCode = ErrorCode {
+    code: "E1021",
+    summary: "The server rejected the org token",
+    hint: "The token has expired or been revoked. Rotate it in the dashboard and reinstall the service with the new one.",
+};
+
+pub const ENROLL_REJECTED: ErrorCode = ErrorCode {
+    code: "E1022",
+    summary: "The server refused to enroll the host",
+    hint: "Check that --server is the Hyprwatch server. The response body usually says why.",
+};
+
+pub const ENROLL_SERVER_ERROR: ErrorCode = ErrorCode {
+    code: "E1023",
+    summary: "The server failed to enroll the host",
+    hint: "Usually temporary; the service restarts and tries again. If it persists, contact support with the host ID.",
+};
+
+pub const ENROLL_BAD_RESPONSE: ErrorCode = ErrorCode {
+    code: "E1024",
+    summary: "The server's enrollment response wasn't understood",
+    hint:
+        "Check that --server is the Hyprwatch server and that no proxy is rewriting its responses.",
+};
+
+// E2xxx: provisioning osquery
+
+pub const OSQUERY_DOWNLOAD: ErrorCode = ErrorCode {
+    code: "E2001",
+    summary: "Couldn't download osquery",
+    hint: "Allow the host to reach github.com, or install osquery and pass its osqueryd with --osqueryd-path.",
+};
+
+pub const OSQUERY_CHECKSUM: ErrorCode = ErrorCode {
+    code: "E2002",
+    summary: "The downloaded osquery failed verification",
+    hint: "The download was corrupted or altered, often by a proxy. Try again, or install osquery from a trusted \
+           package and pass --osqueryd-path.",
+};
+
+pub const OSQUERY_NOT_FOUND: ErrorCode = ErrorCode {
+    code: "E2003",
+    summary: "osqueryd isn't at --osqueryd-path",
+    hint: "Fix the path, or leave --osqueryd-path out to have shadow download osquery.",
+};
+
+pub const OSQUERY_INCOMPATIBLE: ErrorCode = ErrorCode {
+    code: "E2004",
+    summary: "osqueryd can't run on this host",
+    hint: "The message says why. See \"osqueryd can't run on this host\" under Troubleshooting in the README.",
+};
+
+pub const HOST_ID: ErrorCode = ErrorCode {
+    code: "E2011",
+    summary: "Couldn't read the host identifier from osquery",
+    hint: "In containers and cloned VMs pass --host-identifier instance. Otherwise `shadow doctor` checks osqueryd.",
+};
+
+// E3xxx: running osqueryd and the agent
+
+pub const CRASH_LOOP: ErrorCode = ErrorCode {
+    code: "E3001",
+    summary: "osqueryd keeps crashing",
+    hint: "`shadow status` lists crash artifacts, and shadow.log has osqueryd's last output. `shadow doctor` checks \
+           the usual causes.",
+};
+
+pub const AGENT_LIMIT: ErrorCode = ErrorCode {
+    code: "E3011",
+    summary: "The agent exceeded its resource limits",
+    hint: "The service restarts it. If it keeps happening, raise --agent-memory-limit-mb or --agent-cpu-limit-percent \
+           and report it.",
+};
+
+// E4xxx: the data directory and local control
+
+pub const DATA_DIR: ErrorCode = ErrorCode {
+    code: "E4001",
+    summary: "Can't create the data directory",
+    hint: "Check that the disk isn't full or read-only, and run shadow as root or as the owner of --data-dir.",
+};
+
+pub const AGENT_PERMISSION: ErrorCode = ErrorCode {
+    code: "E4011",
+    summary: "Not allowed to control the agent",
+    hint: "Run the command as root (elevated on Windows) or as the user the agent runs as.",
+};
+
+pub const AGENT_NOT_RUNNING: ErrorCode = ErrorCode {
+    code: "E4012",
+    summary: "The agent isn't running",
+    hint: "Start it with `shadow service start`, then try again.",
+};
+
+/// Every code, in order
+pub const CATALOG: &[ErrorCode] = &[
+    SERVER_UNREACHABLE,
+    ORG_TOKEN_MISSING,
+    ENROLL_UNAUTHORIZED,
+    ENROLL_REJECTED,
+    ENROLL_SERVER_ERROR,
+    ENROLL_BAD_RESPONSE,
+    OSQUERY_DOWNLOAD,
+    OSQUERY_CHECKSUM,
+    OSQUERY_NOT_FOUND,
+    OSQUERY_INCOMPATIBLE,
+    HOST_ID,
+    CRASH_LOOP,
+    AGENT_LIMIT,
+    DATA_DIR,
+    AGENT_PERMISSION,
+    AGENT_NOT_RUNNING,
+];
+
+/// The code attached to an error, if any
+pub fn code_of(error: &anyhow::Error) -> Option<ErrorCode> {
+    error.downcast_ref::<ErrorCode>().copied()
+}
+
+/// Look up a code, e.g. from `shadow status`
+pub fn lookup(code: &str) -> Option<ErrorCode> {
+    CATALOG
+        .iter()
+        .find(|entry| entry.code.eq_ignore_ascii_case(code))
+        .copied()
+}
+
+/// An error as printed on exit
+#[derive(Debug, Serialize)]
+struct Report<'a> {
+    code: Option<&'a str>,
+    message: String,
+    hint: Option<&'a str>,
+}
+
+/// Print the error shadow is exiting with, in the console's log format
+pub fn print(error: &anyhow::Error) {
+    let code = code_of(error);
+    match logging::format() {
+        LogFormat::Pretty => {
+            eprintln!("Error: {:#}", error);
+            if let Some(code) = code {
+                eprintln!("\nHint ({}): {}", code, code.hint);
+            }
+        }
+        LogFormat::Json => {
+            let report = Report {
+                code: code.map(|code| code.code),
+                message: format!("{:#}", error),
+                hint: code.map(|code| code.hint),
+            };
+            let line = serde_json::json!({ "error": report });
+            eprintln!("{}", line);
+        }
+    }
+}
+
+/// `shadow explain`
+pub fn explain(code: Option<&str>, format: OutputFormat) -> Result<()> {
+    let entries = match code {
+        Some(code) => {
+            vec![lookup(code).ok_or_else(|| anyhow::anyhow!("Unknown error code {}", code))?]
+        }
+        None => CATALOG.to_vec(),
+    };
+    output::list(format, &entries, |entries| {
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            println!("{}  {}", entry.code, entry.summary);
+            println!("       {}", entry.hint);
+        }
+    })
+}
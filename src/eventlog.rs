@@ -0,0 +1,164 @@
+//! Windows Event Log
+//!
+//! On Windows, agent health events (start, enrollment, osquery installs,
+//! osqueryd crashes) and errors are written to the Application log under the
+//! "Hyprwatch Shadow" source, so SOC teams watching the Event Log see them
+//! without shipping shadow's own logs. `shadow service install` registers the
+//! source. Does nothing on other platforms.
+
+/// Event source name, as shown in Event Viewer
+#[cfg_attr(not(windows), allow(dead_code))]
+pub const SOURCE: &str = "Hyprwatch Shadow";
+
+/// Agent events, each with a stable event ID to filter and alert on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The agent started
+    Started,
+    /// Enrolled with the server
+    Enrolled,
+    /// Downloaded and installed osquery, on first start or after an agent
+    /// upgrade moved to a new osquery version
+    OsqueryInstalled,
+    /// osqueryd crashed or was killed for exceeding resource limits
+    OsqueryCrashed,
+    /// osqueryd keeps crashing shortly after being started
+    CrashLoop,
+    /// Anything else the agent logged as an error
+    Error,
+}
+
+#[cfg_attr(not(windows), allow(dead_code))]
+impl Event {
+    fn id(self) -> u32 {
+        match self {
+            Event::Started => 100,
+            Event::Enrolled => 101,
+            Event::OsqueryInstalled => 102,
+            Event::OsqueryCrashed => 200,
+            Event::CrashLoop => 201,
+            Event::Error => 300,
+        }
+    }
+}
+
+/// Write an event to the Event Log
+#[cfg(windows)]
+pub fn report(event: Event, message: &str) {
+    platform::report(event, message);
+}
+
+#[cfg(not(windows))]
+pub fn report(_event: Event, _message: &str) {}
+
+#[cfg(windows)]
+pub use platform::{register, unregister};
+
+#[cfg(windows)]
+mod platform {
+    use super::{Event, SOURCE};
+    use anyhow::Result;
+    use std::sync::OnceLock;
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    use windows_sys::Win32::System::EventLog::{
+        RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE,
+    };
+    use windows_sys::Win32::System::Registry::{
+        RegDeleteTreeW, RegSetKeyValueW, HKEY_LOCAL_MACHINE, REG_DWORD, REG_EXPAND_SZ,
+    };
+
+    /// Message file whose messages are just "%1", so events show our text as
+    /// is without shipping a message DLL of our own
+    const MESSAGE_FILE: &str = r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+    /// Error, warning, and information
+    const TYPES_SUPPORTED: u32 = 7;
+
+    /// The event source handle, stored as an integer so it can live in a static
+    static SOURCE_HANDLE: OnceLock<usize> = OnceLock::new();
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    fn key() -> Vec<u16> {
+        wide(&format!(
+            r"SYSTEM\CurrentControlSet\Services\EventLog\Application\{}",
+            SOURCE
+        ))
+    }
+
+    pub fn report(event: Event, message: &str) {
+        let handle = *SOURCE_HANDLE.get_or_init(|| {
+            let source = wide(SOURCE);
+            // SAFETY: `source` is a valid NUL-terminated string
+            unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) as usize }
+        });
+        if handle == 0 {
+            return;
+        }
+
+        let kind = match event {
+            Event::Started | Event::Enrolled | Event::OsqueryInstalled => EVENTLOG_INFORMATION_TYPE,
+            Event::OsqueryCrashed => EVENTLOG_WARNING_TYPE,
+            Event::CrashLoop | Event::Error => EVENTLOG_ERROR_TYPE,
+        };
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle came from RegisterEventSourceW and is never
+        // closed; `strings` holds one valid NUL-terminated string
+        unsafe {
+            ReportEventW(
+                handle as _,
+                kind,
+                0,
+                event.id(),
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null(),
+            );
+        }
+    }
+
+    fn set_value(name: &str, kind: u32, data: &[u8]) -> Result<()> {
+        let key = key();
+        let name = wide(name);
+        // SAFETY: all pointers are valid for the lengths given
+        let result = unsafe {
+            RegSetKeyValueW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                name.as_ptr(),
+                kind,
+                data.as_ptr().cast(),
+                data.len() as u32,
+            )
+        };
+        if result != ERROR_SUCCESS {
+            anyhow::bail!("Failed to register the {} event source (error {})", SOURCE, result);
+        }
+        Ok(())
+    }
+
+    /// Register the event source under the Application log
+    pub fn register() -> Result<()> {
+        let message_file: Vec<u8> = wide(MESSAGE_FILE)
+            .iter()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        set_value("EventMessageFile", REG_EXPAND_SZ, &message_file)?;
+        set_value("TypesSupported", REG_DWORD, &TYPES_SUPPORTED.to_le_bytes())
+    }
+
+    /// Remove the event source; past events stay in the log
+    pub fn unregister() {
+        let key = key();
+        // SAFETY: `key` is a valid NUL-terminated string
+        unsafe {
+            RegDeleteTreeW(HKEY_LOCAL_MACHINE, key.as_ptr());
+        }
+    }
+}
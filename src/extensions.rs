@@ -0,0 +1,510 @@
+//! Queries through osqueryd's extension socket
+//!
+//! Some tables, like `osquery_schedule`, only describe the osqueryd that is
+//! queried, so running a separate osqueryd in shell mode won't do. osqueryd
+//! serves its extension manager API on the extensions socket (a Unix socket,
+//! or a named pipe on Windows), and its `query` call runs SQL inside the
+//! daemon. This is a client for just that call, speaking Thrift's binary
+//! protocol over the unframed transport osquery uses.
+//!
+//! The socket is also where extensions attach to osqueryd and add tables of
+//! their own, so the agent puts it in a directory of the data directory that
+//! only the agent's user can enter, and keeps a list of the extensions
+//! attached, with the binary serving each. With `--allow-extension`, an
+//! extension whose binary isn't on the list is detached again. That is
+//! detection after the fact, once per check: until then the extension serves
+//! its tables, and it can attach again straight away. Who can attach at all is
+//! decided by the socket's permissions.
+
+use crate::audit;
+use crate::logging::{debug, warning};
+use crate::status::SharedStatus;
+use anyhow::{Context, Result};
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Take};
+
+/// How long a query may take, including connecting
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest response we read
+const MAX_RESPONSE: u64 = 64 * 1024 * 1024;
+
+/// Directory in the data directory holding the socket, and the sockets of the
+/// extensions attached to it
+#[cfg(unix)]
+const SOCKET_DIR: &str = "extensions";
+
+/// How often the attached extensions are checked
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The extensions attached to osqueryd, with the socket each serves on
+const EXTENSIONS_QUERY: &str = "SELECT uuid, name, version, path FROM osquery_extensions WHERE type = 'extension';";
+
+/// Thrift message types
+const CALL: u32 = 1;
+const REPLY: u32 = 2;
+const EXCEPTION: u32 = 3;
+
+/// Strict binary protocol version, in the top half of a message's type
+const VERSION_1: u32 = 0x8001_0000;
+
+/// Thrift field types
+const STOP: u8 = 0;
+const BOOL: u8 = 2;
+const BYTE: u8 = 3;
+const DOUBLE: u8 = 4;
+const I16: u8 = 6;
+const I32: u8 = 8;
+const I64: u8 = 10;
+const STRING: u8 = 11;
+const STRUCT: u8 = 12;
+const MAP: u8 = 13;
+const SET: u8 = 14;
+const LIST: u8 = 15;
+
+/// A result row, by column name
+pub type Row = BTreeMap<String, String>;
+
+/// Where osqueryd should put its extensions socket. The default on Unix is
+/// under /var/osquery, which may not exist and which --harden-osqueryd doesn't
+/// let osqueryd write to.
+#[cfg(unix)]
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(SOCKET_DIR).join("osquery.em")
+}
+
+/// Where osqueryd should put its extensions socket: a pipe of its own, so a
+/// profile's osqueryd doesn't take the agent's
+#[cfg(not(unix))]
+pub fn socket_path(data_dir: &Path) -> PathBuf {
+    match crate::profiles::name_of(data_dir) {
+        Some(profile) => PathBuf::from(format!(r"\\.\pipe\hyprwatch-shadow-{}.em", profile)),
+        None => PathBuf::from(r"\\.\pipe\hyprwatch-shadow.em"),
+    }
+}
+
+/// Make the directory for the extensions socket, which only the agent's user
+/// (and root) may enter, so nobody else can attach an extension or query
+/// osqueryd through it. osqueryd makes the pipe itself on Windows, with the
+/// default DACL for named pipes.
+pub fn prepare(data_dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let dir = data_dir.join(SOCKET_DIR);
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .and_then(|_| std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)))
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = data_dir;
+    Ok(())
+}
+
+/// An extension allowed to attach: its binary, and optionally the binary's
+/// SHA-256, as `PATH` or `PATH=SHA256`
+#[derive(Debug, Clone)]
+pub struct AllowedExtension {
+    path: PathBuf,
+    sha256: Option<String>,
+}
+
+impl FromStr for AllowedExtension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Paths may contain '=', hashes can't
+        let (path, sha256) = match s.rsplit_once('=') {
+            Some((path, hash)) if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                (path, Some(hash.to_ascii_lowercase()))
+            }
+            _ => (s, None),
+        };
+        let path = PathBuf::from(path);
+        if !path.is_absolute() {
+            return Err(format!("expected an absolute path to the extension's binary, got '{}'", path.display()));
+        }
+        Ok(Self { path, sha256 })
+    }
+}
+
+impl AllowedExtension {
+    fn allows(&self, binary: &Binary) -> bool {
+        let path = std::fs::canonicalize(&self.path).unwrap_or_else(|_| self.path.clone());
+        path == binary.path && self.sha256.as_ref().is_none_or(|sha256| *sha256 == binary.sha256)
+    }
+}
+
+/// An extension attached to osqueryd, as `shadow status` shows it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Extension {
+    pub name: String,
+    pub version: String,
+    /// The binary serving it, if it could be found
+    pub binary: Option<PathBuf>,
+    pub sha256: Option<String>,
+}
+
+/// The executable of a process, with its hash
+#[derive(Debug, Clone)]
+struct Binary {
+    path: PathBuf,
+    sha256: String,
+}
+
+/// Keep the status's list of attached extensions current, detaching those
+/// not in `allowed`, unless it's empty
+pub async fn watch(socket: PathBuf, status: SharedStatus, data_dir: PathBuf, allowed: Vec<AllowedExtension>) {
+    // Identified once per registration; osqueryd gives each a new UUID
+    let mut binaries: HashMap<String, Option<Binary>> = HashMap::new();
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        if status.snapshot().child_pid.is_none() {
+            continue;
+        }
+        let rows = match query(&socket, EXTENSIONS_QUERY).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                debug!("Failed to list osqueryd's extensions: {:#}", e);
+                continue;
+            }
+        };
+        binaries.retain(|uuid, _| rows.iter().any(|row| row.get("uuid") == Some(uuid)));
+
+        let mut attached = Vec::new();
+        for row in rows {
+            let field = |name: &str| row.get(name).cloned().unwrap_or_default();
+            let uuid = field("uuid");
+            if !binaries.contains_key(&uuid) {
+                let binary = identify(Path::new(&field("path"))).await;
+                binaries.insert(uuid.clone(), binary);
+            }
+            let binary = binaries.get(&uuid).cloned().flatten();
+            let extension = Extension {
+                name: field("name"),
+                version: field("version"),
+                binary: binary.as_ref().map(|binary| binary.path.clone()),
+                sha256: binary.as_ref().map(|binary| binary.sha256.clone()),
+            };
+            let permitted = allowed.is_empty()
+                || binary
+                    .as_ref()
+                    .is_some_and(|binary| allowed.iter().any(|allowed| allowed.allows(binary)));
+            if permitted {
+                attached.push(extension);
+                continue;
+            }
+
+            warning!(
+                "Detaching extension {} ({}), which --allow-extension doesn't allow",
+                extension.name,
+                extension
+                    .binary
+                    .as_ref()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "binary unknown".to_string())
+            );
+            if let Err(e) = detach(&socket, &uuid).await {
+                warning!("Failed to detach extension {}: {:#}", extension.name, e);
+            }
+            audit::record_agent(&data_dir, "extension_rejected", serde_json::json!(extension)).await;
+        }
+        status.update(|status| status.extensions = attached);
+    }
+}
+
+/// The binary serving the extension socket at `path`, from the process on the
+/// other end of a connection to it
+async fn identify(path: &Path) -> Option<Binary> {
+    let stream = connect(path).await.ok()?;
+    let pid = peer_pid(&stream)?;
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::Some(&[pid]),
+        true,
+        ProcessRefreshKind::nothing().with_exe(UpdateKind::Always),
+    );
+    let path = system.process(pid)?.exe()?.to_path_buf();
+    let data = tokio::fs::read(&path).await.ok()?;
+    Some(Binary {
+        sha256: format!("{:x}", Sha256::digest(&data)),
+        path,
+    })
+}
+
+#[cfg(unix)]
+fn peer_pid(stream: &tokio::net::UnixStream) -> Option<u32> {
+    let pid = stream.peer_cred().ok()?.pid()?;
+    u32::try_from(pid).ok()
+}
+
+#[cfg(windows)]
+fn peer_pid(stream: &tokio::net::windows::named_pipe::NamedPipeClient) -> Option<u32> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::System::Pipes::GetNamedPipeServerProcessId;
+
+    let mut pid = 0;
+    // SAFETY: the handle is the open pipe's, and `pid` outlives the call
+    let found = unsafe { GetNamedPipeServerProcessId(stream.as_raw_handle(), &mut pid) };
+    (found != 0).then_some(pid)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn peer_pid(_stream: &tokio::io::DuplexStream) -> Option<u32> {
+    None
+}
+
+/// Detach the extension registered as `uuid` from the osqueryd listening on
+/// `socket`, removing its tables
+async fn detach(socket: &Path, uuid: &str) -> Result<()> {
+    let uuid: i64 = uuid.parse().context("Invalid extension UUID")?;
+    tokio::time::timeout(QUERY_TIMEOUT, async {
+        let stream = connect(socket)
+            .await
+            .with_context(|| format!("Failed to connect to osqueryd at {}", socket.display()))?;
+        // ExtensionManager_deregisterExtension_args { 1: i64 uuid }
+        let mut args = vec![I64];
+        args.extend(1i16.to_be_bytes());
+        args.extend(uuid.to_be_bytes());
+        let mut reader = call(stream, "deregisterExtension", &args).await?;
+
+        // ExtensionManager_deregisterExtension_result { 0: ExtensionStatus success }
+        let mut status = None;
+        while let Some((kind, id)) = reader.field().await? {
+            match (kind, id) {
+                (STRUCT, 0) => status = Some(reader.status().await?),
+                _ => reader.skip(kind).await?,
+            }
+        }
+        match status.context("osqueryd returned no result")? {
+            (0, _) => Ok(()),
+            (code, message) => Err(anyhow::anyhow!("osqueryd refused ({}): {}", code, message)),
+        }
+    })
+    .await
+    .context("osqueryd didn't answer in time")?
+}
+
+/// Run `sql` in the osqueryd listening on `socket`
+pub async fn query(socket: &Path, sql: &str) -> Result<Vec<Row>> {
+    tokio::time::timeout(QUERY_TIMEOUT, async {
+        let stream = connect(socket)
+            .await
+            .with_context(|| format!("Failed to connect to osqueryd at {}", socket.display()))?;
+        exchange(stream, sql).await
+    })
+    .await
+    .context("osqueryd didn't answer in time")?
+}
+
+#[cfg(unix)]
+async fn connect(socket: &Path) -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(socket).await
+}
+
+#[cfg(windows)]
+async fn connect(
+    socket: &Path,
+) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    tokio::net::windows::named_pipe::ClientOptions::new().open(socket)
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn connect(_socket: &Path) -> std::io::Result<tokio::io::DuplexStream> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Send + Unpin>(
+    stream: S,
+    sql: &str,
+) -> Result<Vec<Row>> {
+    // ExtensionManager_query_args { 1: string sql }
+    let mut args = vec![STRING];
+    args.extend(1i16.to_be_bytes());
+    put_string(&mut args, sql);
+    let mut reader = call(stream, "query", &args).await?;
+
+    // ExtensionManager_query_result { 0: ExtensionResponse success }
+    let mut rows = None;
+    while let Some((kind, id)) = reader.field().await? {
+        match (kind, id) {
+            (STRUCT, 0) => rows = Some(reader.response().await?),
+            _ => reader.skip(kind).await?,
+        }
+    }
+    rows.context("osqueryd returned no result")?
+}
+
+/// Call `method` with the fields of its arguments struct, `args`, returning a
+/// reader at the start of its result struct
+async fn call<S: AsyncRead + AsyncWrite + Send + Unpin>(
+    mut stream: S,
+    method: &str,
+    args: &[u8],
+) -> Result<Reader<Take<S>>> {
+    let mut request = Vec::new();
+    request.extend((VERSION_1 | CALL).to_be_bytes());
+    put_string(&mut request, method);
+    request.extend(1i32.to_be_bytes());
+    request.extend(args);
+    request.push(STOP);
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut reader = Reader(stream.take(MAX_RESPONSE));
+    let header = reader.0.read_u32().await?;
+    if header & 0xffff_0000 != VERSION_1 {
+        anyhow::bail!("Unexpected response from osqueryd");
+    }
+    reader.string().await?;
+    reader.0.read_i32().await?;
+
+    match header & 0xff {
+        REPLY => Ok(reader),
+        EXCEPTION => anyhow::bail!("osqueryd rejected the {} call: {}", method, reader.exception().await?),
+        _ => anyhow::bail!("Unexpected response from osqueryd"),
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, value: &str) {
+    buf.extend((value.len() as i32).to_be_bytes());
+    buf.extend(value.as_bytes());
+}
+
+struct Reader<R>(R);
+
+impl<R: AsyncRead + Send + Unpin> Reader<R> {
+    async fn len(&mut self) -> Result<usize> {
+        let len = self.0.read_i32().await?;
+        usize::try_from(len).context("Invalid response from osqueryd")
+    }
+
+    async fn string(&mut self) -> Result<String> {
+        let mut data = vec![0; self.len().await?.min(MAX_RESPONSE as usize)];
+        self.0.read_exact(&mut data).await?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// The next field's type and ID, or None at the end of a struct
+    async fn field(&mut self) -> Result<Option<(u8, i16)>> {
+        match self.0.read_u8().await? {
+            STOP => Ok(None),
+            kind => Ok(Some((kind, self.0.read_i16().await?))),
+        }
+    }
+
+    /// ExtensionResponse { 1: ExtensionStatus status, 2: list<map<string, string>> response }
+    async fn response(&mut self) -> Result<Result<Vec<Row>>> {
+        let mut status = None;
+        let mut rows = Vec::new();
+        while let Some((kind, id)) = self.field().await? {
+            match (kind, id) {
+                (STRUCT, 1) => status = Some(self.status().await?),
+                (LIST, 2) => rows = self.rows().await?,
+                _ => self.skip(kind).await?,
+            }
+        }
+        Ok(match status {
+            Some((0, _)) | None => Ok(rows),
+            Some((code, message)) => Err(anyhow::anyhow!(
+                "osquery query failed ({}): {}",
+                code,
+                message
+            )),
+        })
+    }
+
+    /// ExtensionStatus { 1: i32 code, 2: string message, 3: i64 uuid }
+    async fn status(&mut self) -> Result<(i32, String)> {
+        let (mut code, mut message) = (0, String::new());
+        while let Some((kind, id)) = self.field().await? {
+            match (kind, id) {
+                (I32, 1) => code = self.0.read_i32().await?,
+                (STRING, 2) => message = self.string().await?,
+                _ => self.skip(kind).await?,
+            }
+        }
+        Ok((code, message))
+    }
+
+    async fn rows(&mut self) -> Result<Vec<Row>> {
+        let kind = self.0.read_u8().await?;
+        let count = self.len().await?;
+        let mut rows = Vec::new();
+        for _ in 0..count {
+            if kind != MAP {
+                self.skip(kind).await?;
+                continue;
+            }
+            let (key, value) = (self.0.read_u8().await?, self.0.read_u8().await?);
+            let mut row = Row::new();
+            for _ in 0..self.len().await? {
+                if key == STRING && value == STRING {
+                    row.insert(self.string().await?, self.string().await?);
+                } else {
+                    self.skip(key).await?;
+                    self.skip(value).await?;
+                }
+            }
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    /// TApplicationException { 1: string message, 2: i32 type }
+    async fn exception(&mut self) -> Result<String> {
+        let mut message = String::new();
+        while let Some((kind, id)) = self.field().await? {
+            match (kind, id) {
+                (STRING, 1) => message = self.string().await?,
+                _ => self.skip(kind).await?,
+            }
+        }
+        Ok(message)
+    }
+
+    /// Read past a value of a type we don't use
+    fn skip(&mut self, kind: u8) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            match kind {
+                BOOL | BYTE => drop(self.0.read_u8().await?),
+                I16 => drop(self.0.read_i16().await?),
+                I32 => drop(self.0.read_i32().await?),
+                DOUBLE | I64 => drop(self.0.read_i64().await?),
+                STRING => drop(self.string().await?),
+                STRUCT => {
+                    while let Some((kind, _)) = self.field().await? {
+                        self.skip(kind).await?;
+                    }
+                }
+                MAP => {
+                    let (key, value) = (self.0.read_u8().await?, self.0.read_u8().await?);
+                    for _ in 0..self.len().await? {
+                        self.skip(key).await?;
+                        self.skip(value).await?;
+                    }
+                }
+                SET | LIST => {
+                    let kind = self.0.read_u8().await?;
+                    for _ in 0..self.len().await? {
+                        self.skip(kind).await?;
+                    }
+                }
+                _ => anyhow::bail!("Invalid response from osqueryd"),
+            }
+            Ok(())
+        })
+    }
+}
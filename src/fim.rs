@@ -0,0 +1,94 @@
+//! Local file integrity monitoring paths
+//!
+//! osquery's `file_events` watch the `file_paths` categories in the config,
+//! which comes from the server's packs and can't know about a directory only
+//! one host has. With `--fim-paths` and the TLS relay, the agent reads extra
+//! `file_paths` and `exclude_paths` from a local YAML file and adds them to
+//! every config osqueryd fetches, next to the server's: paths for a category
+//! the server also watches are added to its list rather than replacing it, so
+//! the server's monitoring carries on whatever the file says.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The paths file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Overlay {
+    /// Paths to watch, by category
+    #[serde(default)]
+    file_paths: BTreeMap<String, Vec<String>>,
+    /// Paths not to watch, by category
+    #[serde(default)]
+    exclude_paths: BTreeMap<String, Vec<String>>,
+}
+
+/// Load paths from a YAML file like
+/// `{file_paths: {app: ["/opt/app/etc/%%"]}, exclude_paths: {app: ["/opt/app/etc/cache/%%"]}}`
+pub fn load(path: &Path) -> Result<Overlay> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let overlay: Overlay = serde_norway::from_str(&text)
+        .with_context(|| format!("Invalid FIM paths in {}", path.display()))?;
+    for (category, paths) in overlay.file_paths.iter().chain(&overlay.exclude_paths) {
+        if category.is_empty() {
+            anyhow::bail!("Empty category name in {}", path.display());
+        }
+        if paths.iter().any(|path| path.trim().is_empty()) {
+            anyhow::bail!("Empty path in category {} in {}", category, path.display());
+        }
+    }
+    Ok(overlay)
+}
+
+impl Overlay {
+    /// Paths to watch, across categories
+    pub fn len(&self) -> usize {
+        self.file_paths.values().map(Vec::len).sum()
+    }
+
+    /// Add the paths to a config from the server
+    pub fn apply(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        let Some(config_map) = config.as_object_mut() else {
+            anyhow::bail!("Config isn't a JSON object");
+        };
+        merge(config_map, "file_paths", &self.file_paths);
+        merge(config_map, "exclude_paths", &self.exclude_paths);
+        Ok(serde_json::to_vec(&config)?)
+    }
+}
+
+/// Add `local` to the categories under `key`, keeping the server's paths
+fn merge(config: &mut Map<String, Value>, key: &str, local: &BTreeMap<String, Vec<String>>) {
+    if local.is_empty() {
+        return;
+    }
+    let categories = config
+        .entry(key)
+        .or_insert_with(|| Value::Object(Map::new()));
+    if !categories.is_object() {
+        *categories = Value::Object(Map::new());
+    }
+    let Some(categories) = categories.as_object_mut() else {
+        return;
+    };
+    for (category, paths) in local {
+        let existing = categories
+            .entry(category.clone())
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if !existing.is_array() {
+            *existing = Value::Array(Vec::new());
+        }
+        if let Some(existing) = existing.as_array_mut() {
+            for path in paths {
+                if !existing.iter().any(|p| p.as_str() == Some(path.as_str())) {
+                    existing.push(Value::String(path.clone()));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+//! Gatekeeper on macOS
+//!
+//! A quarantined osqueryd (copied from a browser download, AirDrop, or a
+//! quarantining MDM agent) makes Gatekeeper step in on its first launch. On a
+//! headless Mac there's nobody to click through the dialog, so osqueryd never
+//! starts and all we see is a process that hangs or dies. Before the first
+//! launch we look for the quarantine attribute and ask Gatekeeper what it
+//! thinks of the bundle. A quarantined osquery we downloaded from the official
+//! release ourselves has the attribute removed; one the user supplied is left
+//! alone, with the command or MDM setting that fixes it.
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Check Gatekeeper won't stop osqueryd's first launch. `provisioned` is set
+/// when shadow downloaded osqueryd itself.
+#[cfg(target_os = "macos")]
+pub async fn check(osqueryd_path: &Path, provisioned: bool) -> Result<()> {
+    use crate::logging::{info, warning};
+    use anyhow::Context;
+
+    // Gatekeeper assesses the whole bundle, and quarantine on any file in it
+    // counts
+    let bundle = osqueryd_path
+        .ancestors()
+        .find(|path| path.extension().is_some_and(|ext| ext == "app"))
+        .unwrap_or(osqueryd_path);
+    if !is_quarantined(bundle).await {
+        return Ok(());
+    }
+
+    if provisioned {
+        let output = tokio::process::Command::new("xattr")
+            .args(["-dr", QUARANTINE])
+            .arg(bundle)
+            .output()
+            .await
+            .context("Failed to run xattr")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Can't remove the quarantine attribute from {}: {}",
+                bundle.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        info!("  osquery:   cleared quarantine attribute");
+        return Ok(());
+    }
+
+    match assess(bundle).await {
+        Assessment::Accepted(source) => warning!(
+            "{} is quarantined ({}), so Gatekeeper may prompt before its first launch. \
+             Remove the attribute with `sudo xattr -dr {} {}`, or install osquery with its signed .pkg.",
+            bundle.display(),
+            source,
+            QUARANTINE,
+            bundle.display()
+        ),
+        Assessment::Rejected(source) => anyhow::bail!(
+            "{} is quarantined and Gatekeeper rejects it ({}), so macOS won't start it without someone \
+             approving it on screen.\nUse the signed, notarized osquery .pkg, or omit --osqueryd-path to let \
+             shadow provision it. To run this build anyway, remove the attribute with \
+             `sudo xattr -dr {} {}`, or allow its Team ID with a System Policy profile from your MDM.",
+            bundle.display(),
+            source,
+            QUARANTINE,
+            bundle.display()
+        ),
+        Assessment::Unknown => warning!(
+            "{} is quarantined, and Gatekeeper couldn't assess it. \
+             Remove the attribute with `sudo xattr -dr {} {}` if osqueryd doesn't start.",
+            bundle.display(),
+            QUARANTINE,
+            bundle.display()
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+pub async fn check(_osqueryd_path: &Path, _provisioned: bool) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "macos")]
+const QUARANTINE: &str = "com.apple.quarantine";
+
+/// Whether the file, or anything in the bundle, carries the quarantine
+/// attribute
+#[cfg(target_os = "macos")]
+async fn is_quarantined(path: &Path) -> bool {
+    // `xattr -r` lists "path: attribute" for every file in the bundle
+    tokio::process::Command::new("xattr")
+        .arg("-r")
+        .arg(path)
+        .output()
+        .await
+        .is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.ends_with(&format!(": {}", QUARANTINE)))
+        })
+}
+
+#[cfg(target_os = "macos")]
+enum Assessment {
+    /// Gatekeeper allows it, with the source, e.g. "Notarized Developer ID"
+    Accepted(String),
+    Rejected(String),
+    Unknown,
+}
+
+#[cfg(target_os = "macos")]
+async fn assess(path: &Path) -> Assessment {
+    let Ok(output) = tokio::process::Command::new("spctl")
+        .args(["--assess", "--type", "execute", "-v"])
+        .arg(path)
+        .output()
+        .await
+    else {
+        return Assessment::Unknown;
+    };
+    // "path: accepted\nsource=Notarized Developer ID", on stderr
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let source = stderr
+        .lines()
+        .find_map(|line| line.strip_prefix("source="))
+        .unwrap_or("no source given")
+        .to_string();
+    match output.status.code() {
+        Some(0) => Assessment::Accepted(source),
+        // spctl exits with 3 when the assessment fails
+        Some(3) => Assessment::Rejected(source),
+        _ => Assessment::Unknown,
+    }
+}
@@ -0,0 +1,230 @@
+//! Optional hardening of the osqueryd child
+//!
+//! osqueryd parses a great deal of untrusted data (files, package databases,
+//! browser profiles) with root privileges. With `--harden-osqueryd`, the
+//! riskiest things it never needs to do are taken away, as defense in depth.
+//! This is a denylist, not a sandbox: everything not named is still allowed.
+//! On Linux osqueryd gets `no_new_privs` and a seccomp filter refusing a list
+//! of syscalls (mounting, loading kernel modules, tracing other processes,
+//! changing the clock, rebooting); syscalls not on it, including ones added to
+//! the kernel later, are allowed. On macOS it runs under a `sandbox-exec`
+//! profile that allows everything except writing outside its data directory
+//! and temporary files, and listening on the network. The restrictions are
+//! inherited by osquery's worker process.
+
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+/// Restrictions for osqueryd
+#[derive(Debug, Clone)]
+pub struct Hardening {
+    /// osqueryd's data directory, which it may write to
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub data_dir: PathBuf,
+    /// Allow eBPF and perf events, for osquery's bpf_* tables
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub allow_bpf: bool,
+}
+
+impl Hardening {
+    /// Whether osqueryd can be hardened on this platform
+    pub fn is_supported() -> bool {
+        cfg!(any(target_os = "linux", target_os = "macos"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Hardening;
+    use std::path::Path;
+    use tokio::process::Command;
+
+    /// `AUDIT_ARCH_*` for the architecture we were built for, which the filter
+    /// checks so syscalls can't be smuggled in through another ABI
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// x32 syscalls on x86_64 have this bit set
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Syscalls osqueryd never needs, refused with EPERM
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_acct,
+        libc::SYS_add_key,
+        libc::SYS_adjtimex,
+        libc::SYS_clock_adjtime,
+        libc::SYS_clock_settime,
+        libc::SYS_chroot,
+        libc::SYS_delete_module,
+        libc::SYS_finit_module,
+        libc::SYS_fsconfig,
+        libc::SYS_fsmount,
+        libc::SYS_fsopen,
+        libc::SYS_init_module,
+        libc::SYS_kexec_file_load,
+        libc::SYS_kexec_load,
+        libc::SYS_keyctl,
+        libc::SYS_mount,
+        libc::SYS_move_mount,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_pivot_root,
+        libc::SYS_process_vm_writev,
+        libc::SYS_ptrace,
+        libc::SYS_quotactl,
+        libc::SYS_reboot,
+        libc::SYS_request_key,
+        libc::SYS_setdomainname,
+        libc::SYS_sethostname,
+        libc::SYS_setns,
+        libc::SYS_settimeofday,
+        libc::SYS_swapoff,
+        libc::SYS_swapon,
+        libc::SYS_umount2,
+        libc::SYS_unshare,
+        libc::SYS_userfaultfd,
+        libc::SYS_vhangup,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+    ];
+
+    /// Needed for osquery's bpf_* tables
+    const BPF: &[libc::c_long] = &[libc::SYS_bpf, libc::SYS_perf_event_open];
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// The seccomp filter program
+    fn filter(hardening: &Hardening) -> Vec<libc::sock_filter> {
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+        // Offsets into struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+
+        let mut program = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, ARCH),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1),
+            statement(BPF_RET | BPF_K, deny),
+        ]);
+        let denied = DENIED
+            .iter()
+            .chain(if hardening.allow_bpf { &[][..] } else { BPF });
+        for &nr in denied {
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+            program.push(statement(BPF_RET | BPF_K, deny));
+        }
+        program.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+        program
+    }
+
+    pub fn command(hardening: &Hardening, osqueryd_path: &Path) -> Command {
+        use std::os::unix::process::CommandExt;
+
+        let mut cmd = crate::osquery::osqueryd_command(osqueryd_path);
+        let program = filter(hardening);
+        // SAFETY: the closure only makes prctl calls, which are async-signal
+        // safe, on a filter built before forking and moved into the closure
+        unsafe {
+            cmd.as_std_mut().pre_exec(move || {
+                let fprog = libc::sock_fprog {
+                    len: program.len() as u16,
+                    filter: program.as_ptr() as *mut libc::sock_filter,
+                };
+                if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                    || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &fprog) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        cmd
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::Hardening;
+    use std::path::Path;
+    use tokio::process::Command;
+
+    const SANDBOX_EXEC: &str = "/usr/bin/sandbox-exec";
+
+    /// Quote a string for the sandbox profile language
+    fn quote(value: &str) -> String {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    fn profile(hardening: &Hardening) -> String {
+        // Profiles match real paths, and /var is a symlink to /private/var
+        let data_dir = std::fs::canonicalize(&hardening.data_dir).unwrap_or_else(|_| hardening.data_dir.clone());
+        format!(
+            r#"(version 1)
+(allow default)
+(deny file-write*)
+(allow file-write*
+    (subpath {data_dir})
+    (subpath "/private/tmp")
+    (subpath "/private/var/folders")
+    (literal "/dev/null")
+    (literal "/dev/dtracehelper"))
+(deny network-bind (local ip))
+(deny network-inbound (local ip))
+"#,
+            data_dir = quote(&data_dir.to_string_lossy()),
+        )
+    }
+
+    pub fn command(hardening: &Hardening, osqueryd_path: &Path) -> Command {
+        // sandbox-exec applies the profile, then execs osqueryd in place, so
+        // the pid we supervise is still osqueryd's
+        let mut cmd = crate::osquery::child_command(Path::new(SANDBOX_EXEC));
+        cmd.arg("-p").arg(profile(hardening)).arg(osqueryd_path);
+        cmd
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use super::Hardening;
+    use std::path::Path;
+    use tokio::process::Command;
+
+    pub fn command(_hardening: &Hardening, osqueryd_path: &Path) -> Command {
+        crate::osquery::osqueryd_command(osqueryd_path)
+    }
+}
+
+impl Hardening {
+    /// A command running osqueryd with the restrictions applied
+    pub fn command(&self, osqueryd_path: &Path) -> Command {
+        platform::command(self, osqueryd_path)
+    }
+}
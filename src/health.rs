@@ -0,0 +1,157 @@
+//! Local health endpoint
+//!
+//! With `--health-listen`, the agent answers `GET /healthz` with 200 while
+//! osqueryd is running under supervision (or collection is deliberately
+//! paused), and 503 otherwise, with a JSON body giving the reason. That's what
+//! Kubernetes liveness probes and generic uptime checks expect. It uses the
+//! same checks as the systemd watchdog, so an agent whose supervisor has
+//! wedged is reported as unhealthy too.
+
+use crate::logging::warning;
+use crate::notify::check_health;
+use crate::status::{secs_since, AgentState, AgentStatus, SharedStatus};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request head we read
+const MAX_REQUEST: usize = 8 * 1024;
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    state: Option<AgentState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    osqueryd_pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    osqueryd_uptime_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    degraded: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// Why the agent isn't healthy, if it isn't
+pub fn problem(status: &AgentStatus) -> Option<String> {
+    match status.state {
+        AgentState::Running if status.child_pid.is_some() => None,
+        AgentState::Running => Some("osqueryd isn't running".to_string()),
+        AgentState::Paused => None,
+        AgentState::Provisioning => Some("provisioning osquery".to_string()),
+        AgentState::Enrolling => Some("enrolling with the server".to_string()),
+        AgentState::Backoff => Some(match &status.last_restart_reason {
+            Some(reason) => format!("osqueryd exited ({}), waiting to restart it", reason),
+            None => "osqueryd exited, waiting to restart it".to_string(),
+        }),
+        AgentState::CrashLoop => Some("osqueryd keeps exiting shortly after starting".to_string()),
+        AgentState::Stopped => Some("osqueryd has stopped".to_string()),
+    }
+}
+
+async fn health(status: &SharedStatus) -> (bool, Health) {
+    let Some(snapshot) = check_health(status).await else {
+        let health = Health {
+            status: "unhealthy",
+            state: None,
+            reason: Some("the agent isn't responding, or lost track of osqueryd".to_string()),
+            osqueryd_pid: None,
+            osqueryd_uptime_secs: None,
+            degraded: None,
+            last_error: None,
+        };
+        return (false, health);
+    };
+    let reason = problem(&snapshot);
+    let healthy = reason.is_none();
+    let health = Health {
+        status: if healthy { "ok" } else { "unhealthy" },
+        state: Some(snapshot.state),
+        reason,
+        osqueryd_pid: snapshot.child_pid,
+        osqueryd_uptime_secs: snapshot.child_started_at.map(secs_since),
+        degraded: snapshot.degraded,
+        last_error: snapshot.last_error,
+    };
+    (healthy, health)
+}
+
+/// Serve the health endpoint until the agent exits
+pub async fn spawn(addr: SocketAddr, status: SharedStatus) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            warning!("Can't serve the health endpoint on {} ({}), so it's disabled", addr, e);
+            return;
+        }
+    };
+    if !addr.ip().is_loopback() {
+        warning!(
+            "The health endpoint on {} is reachable from other hosts. \
+             It only reports the agent's state, but bind it to 127.0.0.1 unless a probe needs it.",
+            addr
+        );
+    }
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let status = status.clone();
+            tokio::spawn(async move {
+                let _ = serve(stream, &status).await;
+            });
+        }
+    });
+}
+
+async fn serve(mut stream: TcpStream, status: &SharedStatus) -> std::io::Result<()> {
+    // Only the request line matters, so read until the end of the head
+    let mut request = Vec::new();
+    let read = async {
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        std::io::Result::Ok(())
+    };
+    if tokio::time::timeout(REQUEST_TIMEOUT, read).await.is_err() {
+        return Ok(());
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or_default().split_whitespace();
+    let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let path = path.split('?').next().unwrap_or_default();
+
+    let (code, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => {
+            let (healthy, health) = health(status).await;
+            let code = if healthy { "200 OK" } else { "503 Service Unavailable" };
+            (code, serde_json::to_string(&health).unwrap_or_default())
+        }
+        (_, "/healthz") => ("405 Method Not Allowed", r#"{"error":"method not allowed"}"#.to_string()),
+        _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        code,
+        body.len() + 1
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+        response.push('\n');
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,148 @@
+//! Agent heartbeat
+//!
+//! Posts a small health report to the server on an interval, independent of
+//! osqueryd's own traffic, so the server can tell "osquery broken" apart from
+//! "host offline". The payload carries a schema version, bumped whenever a
+//! field is removed or changes meaning, so the server can keep reading older
+//! agents. Each interval is jittered, so hosts restarted together (after an
+//! outage or a rollout) don't keep heartbeating in lockstep.
+
+use crate::api::ApiClient;
+use crate::audit::{self, AuditHead};
+use crate::debug::{DebugRequest, DebugSession};
+use crate::distributed;
+use crate::logging::{self, error, ErrorSummary};
+use crate::remote::{self, SignedCommand};
+use crate::resources::{ResourceSampler, ResourceUsage};
+use crate::status::{secs_since, AgentState, SharedStatus};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Version of the heartbeat payload
+const SCHEMA_VERSION: u32 = 2;
+
+/// Intervals vary by up to this fraction either way
+const JITTER: f64 = 0.1;
+
+#[derive(Debug, Serialize)]
+struct Heartbeat {
+    schema_version: u32,
+    host_id: String,
+    sent_at: DateTime<Utc>,
+    /// When to expect the next heartbeat, give or take the jitter
+    interval_secs: u64,
+    agent_version: &'static str,
+    osquery_version: Option<String>,
+    os_version: Option<String>,
+    /// SHA-256 of the agent's settings, with secrets hashed, so hosts running
+    /// with different settings stand out
+    config_hash: Option<String>,
+    agent_uptime_secs: u64,
+    state: AgentState,
+    state_since: DateTime<Utc>,
+    last_error: Option<String>,
+    last_error_code: Option<String>,
+    errors: ErrorSummary,
+    osqueryd_running: bool,
+    paused: bool,
+    osqueryd_uptime_secs: Option<u64>,
+    restarts: u32,
+    last_restart_reason: Option<String>,
+    crash_artifacts: Vec<String>,
+    degraded: Option<String>,
+    agent_resources: Option<ResourceUsage>,
+    osqueryd_resources: Option<ResourceUsage>,
+    osquery_db_bytes: Option<u64>,
+    log_upload_failures: u64,
+    last_log_upload_failure_at: Option<DateTime<Utc>>,
+    audit_head: Option<AuditHead>,
+    debug: Option<DebugSession>,
+    /// How long recent live queries took, once one has been timed
+    distributed: Option<distributed::Summary>,
+}
+
+/// What the server can ask for in reply
+#[derive(Debug, Default, Deserialize)]
+struct Reply {
+    debug: Option<DebugRequest>,
+    #[serde(default)]
+    commands: Vec<SignedCommand>,
+}
+
+/// Hash of the agent's settings for heartbeats, with secrets already redacted
+pub fn config_hash(settings: &BTreeMap<String, String>) -> String {
+    let data = serde_json::to_vec(settings).unwrap_or_default();
+    format!("{:x}", Sha256::digest(&data))
+}
+
+/// `interval`, give or take the jitter
+fn jittered(interval: Duration) -> Duration {
+    interval.mul_f64(1.0 + rand::random_range(-JITTER..=JITTER))
+}
+
+/// Send heartbeats forever, passing on debug requests and commands in the
+/// server's replies
+pub async fn run(
+    api: ApiClient,
+    status: SharedStatus,
+    data_dir: PathBuf,
+    interval: Duration,
+    config_hash: Option<String>,
+    debug_requests: mpsc::Sender<DebugRequest>,
+    commands: Option<mpsc::Sender<SignedCommand>>,
+) {
+    let mut sampler = ResourceSampler::new();
+    let os_version = sysinfo::System::long_os_version();
+
+    loop {
+        let status = status.snapshot();
+        let heartbeat = Heartbeat {
+            schema_version: SCHEMA_VERSION,
+            host_id: api.host_id().to_string(),
+            sent_at: Utc::now(),
+            interval_secs: interval.as_secs(),
+            agent_version: env!("CARGO_PKG_VERSION"),
+            osquery_version: status.osquery_version.clone(),
+            os_version: os_version.clone(),
+            config_hash: config_hash.clone(),
+            agent_uptime_secs: secs_since(status.agent_started_at),
+            state: status.state,
+            state_since: status.state_since,
+            last_error: status.last_error.clone(),
+            last_error_code: status.last_error_code.clone(),
+            errors: logging::error_summary(),
+            osqueryd_running: status.child_pid.is_some(),
+            paused: status.paused(),
+            osqueryd_uptime_secs: status.child_started_at.map(secs_since),
+            restarts: status.restarts,
+            last_restart_reason: status.last_restart_reason,
+            crash_artifacts: status.crash_artifacts,
+            degraded: status.degraded,
+            agent_resources: sampler.sample(std::process::id()),
+            osqueryd_resources: status.child_pid.and_then(|pid| sampler.sample_tree(pid)),
+            osquery_db_bytes: status.osquery_db_bytes,
+            log_upload_failures: status.log_upload_failures,
+            last_log_upload_failure_at: status.last_log_upload_failure_at,
+            audit_head: audit::head(&data_dir),
+            debug: status.debug,
+            distributed: distributed::summary(),
+        };
+
+        match api.post_for::<_, Reply>("/api/shadow/heartbeat", &heartbeat).await {
+            Ok(reply) => {
+                if let Some(request) = reply.debug {
+                    let _ = debug_requests.send(request).await;
+                }
+                remote::pass_on(commands.as_ref(), reply.commands).await;
+            }
+            Err(e) => error!("Failed to send heartbeat: {:#}", e),
+        }
+
+        tokio::time::sleep(jittered(interval)).await;
+    }
+}
@@ -0,0 +1,215 @@
+//! Event hooks
+//!
+//! Runs an executable or posts to a webhook when something happens that an
+//! org may want to act on before the server does: the agent enrolled, osqueryd
+//! crashed, an osquery upgrade was applied, or osqueryd started crash-looping.
+//! The event is passed as JSON, on stdin to an executable (with the event name
+//! in `SHADOW_EVENT`) and as the body of a POST to a webhook. Hooks run in the
+//! background so a slow one can't hold up supervision; failures are logged.
+
+use crate::logging::error;
+use crate::osquery::child_command;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// How long a hook may run, or a webhook take to answer
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configured hooks and the host they report for
+static HOOKS: OnceLock<(Vec<Hook>, String)> = OnceLock::new();
+
+/// Events a hook can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// Enrolled with the server
+    Enrolled,
+    /// osqueryd crashed or was killed for exceeding resource limits
+    OsquerydCrashed,
+    /// osquery was upgraded to the version this agent ships with
+    UpgradeApplied,
+    /// osqueryd keeps crashing shortly after being started
+    CrashLoop,
+}
+
+impl HookEvent {
+    const ALL: [HookEvent; 4] = [
+        HookEvent::Enrolled,
+        HookEvent::OsquerydCrashed,
+        HookEvent::UpgradeApplied,
+        HookEvent::CrashLoop,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            HookEvent::Enrolled => "enrolled",
+            HookEvent::OsquerydCrashed => "osqueryd_crashed",
+            HookEvent::UpgradeApplied => "upgrade_applied",
+            HookEvent::CrashLoop => "crash_loop",
+        }
+    }
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a hook runs
+#[derive(Debug, Clone)]
+pub enum Target {
+    Command(PathBuf),
+    Webhook(String),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Command(path) => write!(f, "{}", path.display()),
+            // Webhook URLs often carry a token, so only show where they go
+            Target::Webhook(url) => match reqwest::Url::parse(url) {
+                Ok(url) => write!(f, "webhook to {}", url.host_str().unwrap_or_default()),
+                Err(_) => f.write_str("webhook"),
+            },
+        }
+    }
+}
+
+/// A hook from `--hook EVENT=TARGET`
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub event: HookEvent,
+    pub target: Target,
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EVENT=COMMAND or EVENT=URL, got '{}'", s))?;
+        let event = HookEvent::ALL
+            .into_iter()
+            .find(|e| e.name() == event.trim())
+            .ok_or_else(|| {
+                let names: Vec<&str> = HookEvent::ALL.iter().map(|e| e.name()).collect();
+                format!("unknown event '{}' ({})", event.trim(), names.join(", "))
+            })?;
+        let target = target.trim();
+        let target = if target.starts_with("https://") || target.starts_with("http://") {
+            reqwest::Url::parse(target).map_err(|e| format!("invalid webhook URL: {}", e))?;
+            Target::Webhook(target.to_string())
+        } else {
+            let path = PathBuf::from(target);
+            if !path.is_absolute() {
+                return Err(format!("hook command '{}' must be an absolute path", target));
+            }
+            Target::Command(path)
+        };
+        Ok(Hook { event, target })
+    }
+}
+
+/// The JSON a hook is given
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    event: HookEvent,
+    time: DateTime<Utc>,
+    host_id: &'a str,
+    agent_version: &'static str,
+    detail: serde_json::Value,
+}
+
+/// Set up hooks for the host, once its identifier is known
+pub fn init(hooks: Vec<Hook>, host_id: &str) {
+    let _ = HOOKS.set((hooks, host_id.to_string()));
+}
+
+/// Run the hooks for an event in the background
+pub fn fire(event: HookEvent, detail: serde_json::Value) {
+    let Some((hooks, host_id)) = HOOKS.get() else {
+        return;
+    };
+    let targets: Vec<Target> = hooks
+        .iter()
+        .filter(|hook| hook.event == event)
+        .map(|hook| hook.target.clone())
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+    let payload = Payload {
+        event,
+        time: Utc::now(),
+        host_id,
+        agent_version: env!("CARGO_PKG_VERSION"),
+        detail,
+    };
+    let body = match serde_json::to_vec(&payload) {
+        Ok(body) => body,
+        Err(e) => return error!("Failed to encode {} event for hooks: {}", event, e),
+    };
+    for target in targets {
+        let body = body.clone();
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(HOOK_TIMEOUT, run(&target, event, body))
+                .await
+                .context("timed out")
+                .and_then(|result| result);
+            if let Err(e) = result {
+                error!("{} hook {} failed: {:#}", event, target, e);
+            }
+        });
+    }
+}
+
+async fn run(target: &Target, event: HookEvent, body: Vec<u8>) -> Result<()> {
+    match target {
+        Target::Command(path) => {
+            let mut child = child_command(path)
+                .env("SHADOW_EVENT", event.name())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .context("Failed to start")?;
+            if let Some(mut stdin) = child.stdin.take() {
+                // A hook that doesn't read the event closes stdin early
+                let _ = stdin.write_all(&body).await;
+            }
+            let output = child.wait_with_output().await?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                anyhow::bail!("exited with {}: {}", output.status, stderr.trim());
+            }
+        }
+        Target::Webhook(url) => {
+            // reqwest's errors show the URL, and with it any token in it
+            client()
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(reqwest::Error::without_url)?;
+        }
+    }
+    Ok(())
+}
+
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
+}
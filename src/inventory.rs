@@ -0,0 +1,577 @@
+//! Software inventory
+//!
+//! Vulnerability matchers such as Grype or Dependency-Track want a host's
+//! packages named the way their advisories are: as package URLs (purl) for
+//! distribution and language packages, or as CPEs for applications. `shadow
+//! inventory` lists what's installed through osquery's package tables (Debian
+//! and RPM packages, Python packages, Homebrew, macOS applications, and
+//! Windows programs), names each package both ways where it can, and writes
+//! the list as an SBOM for the host: a CycloneDX 1.5 JSON document, or with
+//! `--format spdx` an SPDX 2.3 one, in which the host's OS contains every
+//! package. `--output` lists the packages themselves instead, like other
+//! commands' records. CPEs for applications are built from the
+//! publisher and name osquery reports, so they're a best guess that matchers
+//! may have to match loosely. With `--inventory-interval` the running agent
+//! uploads the inventory to the server on a schedule. Each listing also saves
+//! a snapshot for `shadow inventory diff`.
+
+use crate::api::ApiClient;
+use crate::logging::{error, info, warning};
+use crate::osquery;
+use crate::output::{self, OutputFormat};
+use crate::service;
+use crate::snapshots;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where inventories are uploaded
+const UPLOAD_PATH: &str = "/api/shadow/inventory";
+
+type Row = HashMap<String, String>;
+
+/// SBOM formats `shadow inventory` writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Format {
+    /// CycloneDX 1.5 JSON
+    #[default]
+    Cyclonedx,
+    /// SPDX 2.3 JSON
+    Spdx,
+}
+
+/// How a table's packages are named
+#[derive(Clone, Copy)]
+enum Ecosystem {
+    Deb,
+    Rpm,
+    Pypi,
+    Homebrew,
+    MacApp,
+    WindowsProgram,
+}
+
+struct Source {
+    table: &'static str,
+    /// osquery's platform name, or "all"
+    platform: &'static str,
+    sql: &'static str,
+    ecosystem: Ecosystem,
+}
+
+const SOURCES: &[Source] = &[
+    Source {
+        table: "deb_packages",
+        platform: "linux",
+        sql: "SELECT name, version, arch FROM deb_packages WHERE status LIKE '% installed'",
+        ecosystem: Ecosystem::Deb,
+    },
+    Source {
+        table: "rpm_packages",
+        platform: "linux",
+        sql: "SELECT name, version, release, epoch, arch, vendor FROM rpm_packages",
+        ecosystem: Ecosystem::Rpm,
+    },
+    Source {
+        table: "homebrew_packages",
+        platform: "darwin",
+        sql: "SELECT name, version FROM homebrew_packages",
+        ecosystem: Ecosystem::Homebrew,
+    },
+    Source {
+        table: "apps",
+        platform: "darwin",
+        sql: "SELECT name, bundle_short_version AS version, bundle_identifier FROM apps",
+        ecosystem: Ecosystem::MacApp,
+    },
+    Source {
+        table: "programs",
+        platform: "windows",
+        sql: "SELECT name, version, publisher FROM programs",
+        ecosystem: Ecosystem::WindowsProgram,
+    },
+    Source {
+        table: "python_packages",
+        platform: "all",
+        sql: "SELECT name, version FROM python_packages",
+        ecosystem: Ecosystem::Pypi,
+    },
+];
+
+/// The host's OS, for naming distribution packages
+#[derive(Debug, Default)]
+struct Os {
+    name: String,
+    version: String,
+    /// e.g. "ubuntu" or "rhel"
+    platform: String,
+    major: String,
+    codename: String,
+    hostname: String,
+}
+
+/// A CycloneDX component
+#[derive(Debug, Serialize)]
+struct Component {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// Names the package across versions, for snapshots
+    #[serde(skip)]
+    key: String,
+    #[serde(rename = "bom-ref")]
+    bom_ref: String,
+    name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    supplier: Option<Supplier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purl: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpe: Option<String>,
+    properties: Vec<Property>,
+}
+
+/// A package as `shadow inventory --output` lists it
+#[derive(Debug, Serialize)]
+struct Package<'a> {
+    name: &'a str,
+    version: &'a str,
+    /// The osquery table it was found in
+    source: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purl: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cpe: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Supplier {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Property {
+    name: &'static str,
+    value: String,
+}
+
+/// The inventory, as a CycloneDX document
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Inventory {
+    bom_format: &'static str,
+    spec_version: &'static str,
+    serial_number: String,
+    version: u32,
+    metadata: Value,
+    components: Vec<Component>,
+    /// The host's OS depends on every package
+    dependencies: Value,
+    #[serde(skip)]
+    os: Os,
+    #[serde(skip)]
+    created: DateTime<Utc>,
+}
+
+impl Inventory {
+    /// Packages listed
+    pub fn len(&self) -> usize {
+        self.components.len()
+    }
+
+    /// Versions of each package, e.g. `deb_packages:libc6:amd64` =>
+    /// `2.39-0ubuntu8.3`
+    pub fn versions(&self) -> BTreeMap<String, String> {
+        let mut versions = BTreeMap::<String, String>::new();
+        for component in &self.components {
+            // The same Python package can be in several environments at different versions
+            versions
+                .entry(component.key.clone())
+                .and_modify(|versions| {
+                    versions.push_str(", ");
+                    versions.push_str(&component.version);
+                })
+                .or_insert_with(|| component.version.clone());
+        }
+        versions
+    }
+
+    fn packages(&self) -> Vec<Package<'_>> {
+        self.components
+            .iter()
+            .map(|component| Package {
+                name: &component.name,
+                version: &component.version,
+                source: component.key.split(':').next().unwrap_or_default(),
+                purl: component.purl.as_deref(),
+                cpe: component.cpe.as_deref(),
+            })
+            .collect()
+    }
+
+    pub fn hostname(&self) -> &str {
+        &self.os.hostname
+    }
+
+    /// The inventory as an SPDX document
+    fn spdx(&self) -> Value {
+        let host = serde_json::json!({
+            "SPDXID": "SPDXRef-Host",
+            "name": self.os.name,
+            "versionInfo": self.os.version,
+            "downloadLocation": "NOASSERTION",
+            "filesAnalyzed": false,
+            "primaryPackagePurpose": "OPERATING-SYSTEM",
+        });
+        let mut packages = vec![host];
+        let mut relationships = vec![serde_json::json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": "SPDXRef-Host",
+        })];
+        for (i, component) in self.components.iter().enumerate() {
+            let id = format!("SPDXRef-Package-{}", i + 1);
+            let mut external_refs = Vec::new();
+            if let Some(purl) = &component.purl {
+                external_refs.push(serde_json::json!({
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": purl,
+                }));
+            }
+            if let Some(cpe) = &component.cpe {
+                external_refs.push(serde_json::json!({
+                    "referenceCategory": "SECURITY",
+                    "referenceType": "cpe23Type",
+                    "referenceLocator": cpe,
+                }));
+            }
+            let supplier = match &component.supplier {
+                Some(supplier) => format!("Organization: {}", supplier.name),
+                None => "NOASSERTION".to_string(),
+            };
+            let mut package = serde_json::json!({
+                "SPDXID": id,
+                "name": component.name,
+                "supplier": supplier,
+                "downloadLocation": "NOASSERTION",
+                "filesAnalyzed": false,
+                "primaryPackagePurpose": component.kind.to_uppercase(),
+                "externalRefs": external_refs,
+            });
+            if !component.version.is_empty() {
+                package["versionInfo"] = Value::from(component.version.as_str());
+            }
+            packages.push(package);
+            relationships.push(serde_json::json!({
+                "spdxElementId": "SPDXRef-Host",
+                "relationshipType": "CONTAINS",
+                "relatedSpdxElement": id,
+            }));
+        }
+        serde_json::json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": format!("{} software inventory", self.os.hostname),
+            "documentNamespace": self.serial_number,
+            "creationInfo": {
+                "created": self.created.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                "creators": [format!("Tool: shadow-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": packages,
+            "relationships": relationships,
+        })
+    }
+}
+
+/// List this host's packages with `osqueryd_path`, saving a snapshot
+pub async fn collect(data_dir: &Path, osqueryd_path: &Path) -> Result<Inventory> {
+    let os = osquery::shell_query(
+        osqueryd_path,
+        "SELECT o.name, o.version, o.platform, o.major, o.codename, s.hostname FROM os_version o, system_info s",
+    )
+    .await
+    .context("Failed to query the OS version")?
+    .into_iter()
+    .next()
+    .map(|mut row| Os {
+        name: row.remove("name").unwrap_or_default(),
+        version: row.remove("version").unwrap_or_default(),
+        platform: row.remove("platform").unwrap_or_default(),
+        major: row.remove("major").unwrap_or_default(),
+        codename: row.remove("codename").unwrap_or_default(),
+        hostname: row.remove("hostname").unwrap_or_default(),
+    })
+    .unwrap_or_default();
+
+    let mut components = Vec::new();
+    let mut seen = BTreeSet::new();
+    for source in SOURCES.iter().filter(|s| s.platform == "all" || s.platform == osquery::PLATFORM) {
+        let rows = match osquery::shell_query(osqueryd_path, source.sql).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                warning!("Couldn't list {}: {:#}", source.table, e);
+                continue;
+            }
+        };
+        for row in rows {
+            let Some(component) = component(source, &os, row) else {
+                continue;
+            };
+            // The same Python package can be in several environments
+            if seen.insert(component.bom_ref.clone()) {
+                components.push(component);
+            }
+        }
+    }
+
+    let created = Utc::now();
+    let metadata = serde_json::json!({
+        "timestamp": created.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        "authors": [{ "name": "shadow" }],
+        "tools": {
+            "components": [{ "type": "application", "name": "shadow", "version": env!("CARGO_PKG_VERSION") }],
+        },
+        "component": {
+            "type": "operating-system",
+            "bom-ref": "host",
+            "name": os.name,
+            "version": os.version,
+        },
+        "properties": [{ "name": "hyprwatch:hostname", "value": os.hostname }],
+    });
+    let dependencies = serde_json::json!([{
+        "ref": "host",
+        "dependsOn": components.iter().map(|c| c.bom_ref.as_str()).collect::<Vec<_>>(),
+    }]);
+    let inventory = Inventory {
+        bom_format: "CycloneDX",
+        spec_version: "1.5",
+        serial_number: format!("urn:uuid:{}", service::uuid().to_lowercase()),
+        version: 1,
+        metadata,
+        components,
+        dependencies,
+        os,
+        created,
+    };
+    snapshots::record(data_dir, osqueryd_path, &inventory).await;
+    Ok(inventory)
+}
+
+/// Name one row of a package table
+fn component(source: &Source, os: &Os, mut row: Row) -> Option<Component> {
+    let name = row.remove("name").filter(|name| !name.is_empty())?;
+    let mut version = row.remove("version").unwrap_or_default();
+    let arch = row.remove("arch").unwrap_or_default();
+    let mut publisher = None;
+    let (kind, purl, cpe) = match source.ecosystem {
+        Ecosystem::Deb => {
+            let namespace = match os.platform.as_str() {
+                "ubuntu" => "ubuntu",
+                _ => "debian",
+            };
+            let distro = match os.codename.as_str() {
+                "" => format!("{}-{}", os.platform, os.major),
+                codename => codename.to_string(),
+            };
+            let qualifiers = [("arch", arch.clone()), ("distro", distro)];
+            ("library", Some(purl("deb", Some(namespace), &name, &version, &qualifiers)), None)
+        }
+        Ecosystem::Rpm => {
+            let release = row.remove("release").unwrap_or_default();
+            if !release.is_empty() {
+                version = format!("{}-{}", version, release);
+            }
+            let epoch = row.remove("epoch").filter(|epoch| epoch != "0").unwrap_or_default();
+            let qualifiers = [
+                ("arch", arch.clone()),
+                ("distro", format!("{}-{}", os.platform, os.major)),
+                ("epoch", epoch),
+            ];
+            publisher = row.remove("vendor").filter(|vendor| !vendor.is_empty());
+            ("library", Some(purl("rpm", Some(&os.platform), &name, &version, &qualifiers)), None)
+        }
+        Ecosystem::Pypi => {
+            let name = name.to_lowercase().replace(['_', '.'], "-");
+            ("library", Some(purl("pypi", None, &name, &version, &[])), None)
+        }
+        Ecosystem::Homebrew => ("application", None, Some(cpe(&name, &name, &version))),
+        Ecosystem::MacApp => {
+            // com.google.Chrome is Google's
+            let vendor = row
+                .remove("bundle_identifier")
+                .and_then(|id| id.split('.').nth(1).map(str::to_string))
+                .unwrap_or_else(|| name.clone());
+            let product = name.strip_suffix(".app").unwrap_or(&name).to_string();
+            ("application", None, Some(cpe(&vendor, &product, &version)))
+        }
+        Ecosystem::WindowsProgram => {
+            publisher = row.remove("publisher").filter(|publisher| !publisher.is_empty());
+            // "Microsoft Corporation" is microsoft
+            let vendor = publisher
+                .as_deref()
+                .and_then(|publisher| publisher.split_whitespace().next())
+                .unwrap_or(&name)
+                .trim_end_matches([',', '.'])
+                .to_string();
+            ("application", None, Some(cpe(&vendor, &name, &version)))
+        }
+    };
+    let key = match arch.as_str() {
+        "" => format!("{}:{}", source.table, name),
+        arch => format!("{}:{}:{}", source.table, name, arch),
+    };
+    let bom_ref = purl
+        .clone()
+        .or_else(|| cpe.clone())
+        .unwrap_or_else(|| format!("{}@{}", name, version));
+    Some(Component {
+        kind,
+        key,
+        bom_ref,
+        name,
+        version,
+        supplier: publisher.map(|name| Supplier { name }),
+        purl,
+        cpe,
+        properties: vec![Property {
+            name: "hyprwatch:source",
+            value: source.table.to_string(),
+        }],
+    })
+}
+
+/// A package URL, leaving out empty qualifiers
+fn purl(kind: &str, namespace: Option<&str>, name: &str, version: &str, qualifiers: &[(&str, String)]) -> String {
+    let mut purl = format!("pkg:{}/", kind);
+    if let Some(namespace) = namespace.filter(|namespace| !namespace.is_empty()) {
+        purl.push_str(&percent_encode(namespace));
+        purl.push('/');
+    }
+    purl.push_str(&percent_encode(name));
+    if !version.is_empty() {
+        purl.push('@');
+        purl.push_str(&percent_encode(version));
+    }
+    let qualifiers: Vec<String> = qualifiers
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{}={}", key, percent_encode(value)))
+        .collect();
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    purl
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// A CPE 2.3 name for an application
+fn cpe(vendor: &str, product: &str, version: &str) -> String {
+    let version = match version {
+        "" => "*".to_string(),
+        version => cpe_escape(version),
+    };
+    format!(
+        "cpe:2.3:a:{}:{}:{}:*:*:*:*:*:*:*",
+        cpe_escape(vendor),
+        cpe_escape(product),
+        version
+    )
+}
+
+/// Lowercase, with spaces as underscores and other punctuation quoted
+fn cpe_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.trim().to_lowercase().chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '.' | '-' | '_' => escaped.push(c),
+            c if c.is_whitespace() => escaped.push('_'),
+            c if c.is_ascii() => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            _ => {}
+        }
+    }
+    escaped
+}
+
+/// `shadow inventory`: list the packages in `output`, or write the inventory
+/// as a `format` document to `file` or print it
+pub async fn command(
+    data_dir: &Path,
+    osqueryd_path: &Path,
+    format: Option<Format>,
+    output: Option<OutputFormat>,
+    file: Option<PathBuf>,
+) -> Result<()> {
+    let inventory = collect(data_dir, osqueryd_path).await?;
+    if let Some(output) = output {
+        return output::list(output, &inventory.packages(), |packages| {
+            println!("{:<18} {:<32} {:<24} ID", "SOURCE", "NAME", "VERSION");
+            for p in packages {
+                println!(
+                    "{:<18} {:<32} {:<24} {}",
+                    p.source,
+                    p.name,
+                    p.version,
+                    p.purl.or(p.cpe).unwrap_or("-")
+                );
+            }
+        });
+    }
+    let json = match format.unwrap_or_default() {
+        Format::Cyclonedx => serde_json::to_string_pretty(&inventory)?,
+        Format::Spdx => serde_json::to_string_pretty(&inventory.spdx())?,
+    };
+    match file {
+        Some(file) => {
+            std::fs::write(&file, json).with_context(|| format!("Failed to write {}", file.display()))?;
+            println!("Wrote {} ({} packages)", file.display(), inventory.len());
+        }
+        None => println!("{}", json),
+    }
+    Ok(())
+}
+
+/// Collect the inventory and send it to the server, returning how many
+/// packages it lists
+pub async fn upload(api: &ApiClient, data_dir: &Path, osqueryd_path: &Path) -> Result<usize> {
+    let inventory = collect(data_dir, osqueryd_path).await?;
+    let body = serde_json::json!({ "host_id": api.host_id(), "inventory": inventory });
+    api.post(UPLOAD_PATH, &body)
+        .await
+        .context("Failed to upload the software inventory")?;
+    Ok(inventory.len())
+}
+
+/// Upload the inventory every `interval` until the agent exits
+pub async fn run(api: ApiClient, data_dir: PathBuf, osqueryd_path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match upload(&api, &data_dir, &osqueryd_path).await {
+            Ok(count) => info!("Uploaded the software inventory ({} packages)", count),
+            Err(e) => error!("{:#}", e),
+        }
+    }
+}
@@ -0,0 +1,870 @@
+//! Local control API
+//!
+//! The running agent listens for requests from `shadow` commands on the same
+//! host (`shadowctl` is the same binary under another name): status, pause,
+//! resume, reload, upgrading osquery, doctor, replaying the spools, and
+//! uploading support bundles, benchmark reports, and the software inventory.
+//! Each connection carries one JSON request line and gets one JSON response
+//! line back. On Unix the agent listens on `shadow.sock` in the data directory;
+//! only the user the agent runs as can open the socket, and connections from
+//! anyone but that user and root are refused. On Windows it listens on the
+//! named pipe `\\.\pipe\hyprwatch-shadow`, whose DACL only admits SYSTEM,
+//! Administrators, and the pipe's owner, and which refuses remote clients. When
+//! the agent isn't running, the commands fall back to the files in the data
+//! directory where they can.
+//!
+//! Requests name the user who ran the command, but that's only what the
+//! client claims. The audit log records who the OS says is connected (the
+//! peer's uid, or the pipe client's SID), with the claimed user alongside.
+
+use crate::api::ApiClient;
+use crate::audit;
+use crate::benchmark;
+use crate::control;
+use crate::doctor::{self, Check};
+use crate::errors;
+use crate::inventory;
+use crate::logging::warning;
+use crate::output::{self, OutputFormat};
+use crate::relay::Replayer;
+use crate::spool::{self, Replayed};
+use crate::status::{self, AgentStatus, SharedStatus};
+use crate::supervisor::{self, Action};
+use crate::support;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+/// Socket filename within the data directory
+#[cfg(unix)]
+const SOCKET_FILE: &str = "shadow.sock";
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request or response we read
+const MAX_MESSAGE: u64 = 1024 * 1024;
+
+/// How long a reload or upgrade may take, including downloading osquery
+const ACTION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long other requests may take to answer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pipe name on Windows. It's fixed rather than derived from the data
+/// directory, so commands reach the service whichever directory it uses.
+#[cfg(windows)]
+const PIPE_NAME: &str = r"\\.\pipe\hyprwatch-shadow";
+
+/// The pipe for the agent using `data_dir`: [`PIPE_NAME`], or a profile's own
+#[cfg(windows)]
+fn pipe_name(data_dir: &Path) -> String {
+    match crate::profiles::name_of(data_dir) {
+        Some(profile) => format!("{}-{}", PIPE_NAME, profile),
+        None => PIPE_NAME.to_string(),
+    }
+}
+
+/// DACL for the pipe: full access for SYSTEM, Administrators, and the owner
+/// (the user the agent runs as), and nobody else
+#[cfg(windows)]
+const PIPE_SDDL: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)";
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Pause {
+        user: String,
+        reason: Option<String>,
+        minutes: Option<u64>,
+    },
+    Resume {
+        user: String,
+    },
+    Reload {
+        user: String,
+    },
+    UpgradeOsquery {
+        user: String,
+        /// The version this agent ships with if not given
+        #[serde(default)]
+        version: Option<String>,
+    },
+    Doctor,
+    UploadSupportBundle {
+        user: String,
+    },
+    UploadBenchmark {
+        user: String,
+        report: Box<benchmark::Report>,
+    },
+    UploadInventory {
+        user: String,
+    },
+    Replay {
+        user: String,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Status { status: Box<AgentStatus> },
+    Done { message: String },
+    Doctor { checks: Vec<Check> },
+    Replayed { spools: Vec<Replayed> },
+    Error { message: String },
+}
+
+impl Request {
+    /// The user the request says it's from, if it's from one
+    fn user_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Request::Status | Request::Doctor => None,
+            Request::Pause { user, .. }
+            | Request::Resume { user }
+            | Request::Reload { user }
+            | Request::UpgradeOsquery { user, .. }
+            | Request::UploadSupportBundle { user }
+            | Request::UploadBenchmark { user, .. }
+            | Request::UploadInventory { user }
+            | Request::Replay { user } => Some(user),
+        }
+    }
+}
+
+impl Response {
+    /// The message of a completed request, or why it failed
+    pub fn message(self) -> Result<String> {
+        match self {
+            Response::Done { message } => Ok(message),
+            Response::Error { message } => Err(anyhow::anyhow!(message)),
+            _ => anyhow::bail!("Unexpected response from the agent"),
+        }
+    }
+}
+
+/// What the agent's end of the socket works with
+#[derive(Clone)]
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+pub struct Agent {
+    pub data_dir: PathBuf,
+    pub status: SharedStatus,
+    /// Server the doctor checks the connection to
+    pub server: String,
+    /// Reloads and upgrades for the supervisor
+    pub requests: mpsc::Sender<supervisor::Request>,
+    /// The server's API, once enrolled, for uploading support bundles,
+    /// benchmark reports, and the inventory
+    pub api: Arc<OnceLock<ApiClient>>,
+    /// osqueryd, once found, for listing the inventory
+    pub osqueryd_path: Arc<OnceLock<PathBuf>>,
+    /// The TLS relay's spools, once it's started
+    pub replayer: Arc<OnceLock<Replayer>>,
+    /// Kept out of support bundles
+    pub org_token: Option<String>,
+}
+
+/// Carry out a request, whether it came over the socket or from the server
+pub async fn handle(agent: &Agent, request: Request) -> Response {
+    let result = match request {
+        Request::Status => {
+            return Response::Status {
+                status: Box::new(agent.status.snapshot()),
+            }
+        }
+        Request::Doctor => {
+            let checks = doctor::run(&agent.data_dir, Some(&agent.status), &agent.server).await;
+            return Response::Doctor { checks };
+        }
+        Request::Pause {
+            user,
+            reason,
+            minutes,
+        } => {
+            let duration = minutes.map(|m| Duration::from_secs(m * 60));
+            control::pause(&agent.data_dir, &user, reason, duration)
+                .await
+                .map(|state| control::describe(&state))
+                .map_err(|e| format!("{:#}", e))
+        }
+        Request::Resume { user } => match control::resume(&agent.data_dir, &user).await {
+            Ok(true) => Ok("Collection resumed".to_string()),
+            Ok(false) => Ok("Collection is not paused".to_string()),
+            Err(e) => Err(format!("{:#}", e)),
+        },
+        Request::Reload { user } => act(agent, Action::Reload, user).await,
+        Request::UpgradeOsquery { user, version } => act(agent, Action::Upgrade(version), user).await,
+        Request::UploadSupportBundle { user } => upload_support_bundle(agent, user).await,
+        Request::UploadBenchmark { user, report } => match agent.api.get() {
+            Some(api) => benchmark::upload(&agent.data_dir, api, &report, &user)
+                .await
+                .map_err(|e| format!("{:#}", e)),
+            None => Err("The agent hasn't enrolled yet, so it can't upload to the server".to_string()),
+        },
+        Request::UploadInventory { user } => upload_inventory(agent, user).await.map_err(|e| format!("{:#}", e)),
+        Request::Replay { user } => {
+            return match replay_spools(agent, user).await {
+                Ok(spools) => Response::Replayed { spools },
+                Err(e) => Response::Error {
+                    message: format!("{:#}", e),
+                },
+            }
+        }
+    };
+    match result {
+        Ok(message) => Response::Done { message },
+        Err(message) => Response::Error { message },
+    }
+}
+
+/// Build a support bundle and send it to the server. It runs as a task of its
+/// own, as it needs much more stack than the other requests.
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn upload_support_bundle(agent: &Agent, user: String) -> Result<String, String> {
+    let agent = agent.clone();
+    let upload = tokio::spawn(async move {
+        let api = agent
+            .api
+            .get()
+            .context("The agent hasn't enrolled yet, so it can't upload to the server")?;
+        let org_token = agent.org_token.as_deref();
+        support::upload(&agent.data_dir, &agent.status, &agent.server, api, org_token, &user).await
+    });
+    match upload.await {
+        Ok(result) => result.map_err(|e| format!("{:#}", e)),
+        Err(e) => Err(format!("Failed to build the support bundle: {}", e)),
+    }
+}
+
+/// List the inventory with the agent's osqueryd and send it to the server
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn upload_inventory(agent: &Agent, user: String) -> Result<String> {
+    let api = agent
+        .api
+        .get()
+        .context("The agent hasn't enrolled yet, so it can't upload to the server")?;
+    let osqueryd_path = agent
+        .osqueryd_path
+        .get()
+        .context("The agent hasn't found osqueryd yet")?;
+    let count = inventory::upload(api, &agent.data_dir, osqueryd_path).await?;
+    audit::record_by(&agent.data_dir, &user, "inventory_upload", serde_json::json!({ "packages": count })).await?;
+    Ok(format!("Uploaded the software inventory ({} packages) to the server", count))
+}
+
+/// Send what's spooled to the server now, rather than at the next retry
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn replay_spools(agent: &Agent, user: String) -> Result<Vec<Replayed>> {
+    let replayer = agent
+        .replayer
+        .get()
+        .context("The agent isn't running the TLS relay (--tls-relay), so nothing is spooled")?;
+    let spools = replayer.replay(std::time::Instant::now() + ACTION_TIMEOUT).await;
+    if spools.is_empty() {
+        anyhow::bail!("Spooling is off (--relay-spool-max-mb and --relay-live-spool-max-mb are 0)");
+    }
+    let detail: serde_json::Map<_, _> = spools
+        .iter()
+        .map(|replayed| {
+            let counts = serde_json::json!({
+                "sent": replayed.sent,
+                "rejected": replayed.rejected,
+                "left": replayed.left,
+            });
+            (replayed.spool.clone(), counts)
+        })
+        .collect();
+    audit::record_by(&agent.data_dir, &user, "spool_replay", detail.into()).await?;
+    Ok(spools)
+}
+
+/// Have the supervisor carry out an action, waiting for its answer
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn act(agent: &Agent, action: Action, user: String) -> Result<String, String> {
+    if agent.status.snapshot().paused() {
+        return Err("Collection is paused, so osqueryd isn't running. Run `shadow resume` first.".to_string());
+    }
+    let (reply, replied) = oneshot::channel();
+    let request = supervisor::Request {
+        action,
+        requested_by: user,
+        reply,
+    };
+    agent
+        .requests
+        .send(request)
+        .await
+        .map_err(|_| "The agent is no longer supervising osqueryd".to_string())?;
+    match tokio::time::timeout(ACTION_TIMEOUT, replied).await {
+        Ok(Ok(result)) => result,
+        Ok(Err(_)) => Err("The agent stopped before carrying out the request".to_string()),
+        Err(_) => Err(format!(
+            "osqueryd wasn't restarted within {}s; it will be once it's running again",
+            ACTION_TIMEOUT.as_secs()
+        )),
+    }
+}
+
+/// Answer one request from `peer`, who the OS says is connected
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn serve<S: AsyncRead + AsyncWrite>(stream: S, agent: &Agent, allowed: bool, peer: String) -> std::io::Result<()> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut reader = BufReader::new(read.take(MAX_MESSAGE));
+    let mut line = String::new();
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut line)).await {
+        Ok(Ok(n)) if n > 0 => {}
+        _ => return Ok(()),
+    }
+
+    let response = match serde_json::from_str::<Request>(&line) {
+        Ok(_) if !allowed => Response::Error {
+            message: "Only root and the user the agent runs as can control it".to_string(),
+        },
+        Ok(mut request) => {
+            if let Some(user) = request.user_mut() {
+                *user = match user.as_str() {
+                    "" => peer,
+                    claimed => format!("{} (claimed {})", peer, claimed),
+                };
+            }
+            handle(agent, request).await
+        }
+        Err(e) => Response::Error {
+            message: format!("Invalid request: {}", e),
+        },
+    };
+    let mut data = serde_json::to_vec(&response)?;
+    data.push(b'\n');
+    write.write_all(&data).await?;
+    write.shutdown().await
+}
+
+/// Answer requests until the agent exits
+#[cfg(unix)]
+pub fn spawn(agent: Agent) {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    let path = agent.data_dir.join(SOCKET_FILE);
+    // Left behind by an agent that didn't exit cleanly
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).and_then(|listener| {
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        Ok((listener, std::fs::metadata(&path)?.uid()))
+    });
+    let (listener, owner) = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            warning!(
+                "Can't listen on {} ({}). \
+                 `shadow status`, `pause`, and `resume` will only use the data directory.",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+            let allowed = uid.is_some_and(|uid| uid == 0 || uid == owner);
+            let peer = match uid {
+                Some(uid) => format!("uid {}", uid),
+                None => "unknown peer".to_string(),
+            };
+            let agent = agent.clone();
+            tokio::spawn(async move {
+                let _ = serve(stream, &agent, allowed, peer).await;
+            });
+        }
+    });
+}
+
+/// A new instance of the agent's pipe. The first is created exclusively, so
+/// the agent won't serve a pipe someone else created to impersonate it.
+#[cfg(windows)]
+fn create_pipe(name: &str, first: bool) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use windows_sys::Win32::Foundation::LocalFree;
+    use windows_sys::Win32::Security::Authorization::{
+        ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+    };
+    use windows_sys::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+
+    let sddl: Vec<u16> = PIPE_SDDL.encode_utf16().chain(std::iter::once(0)).collect();
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    let converted = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        )
+    };
+    if converted == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor,
+        bInheritHandle: 0,
+    };
+    let pipe = unsafe {
+        ServerOptions::new()
+            .first_pipe_instance(first)
+            .reject_remote_clients(true)
+            .create_with_security_attributes_raw(name, &mut attributes as *mut _ as *mut _)
+    };
+    unsafe { LocalFree(descriptor) };
+    pipe
+}
+
+/// Answer requests until the agent exits
+#[cfg(windows)]
+pub fn spawn(agent: Agent) {
+    let name = pipe_name(&agent.data_dir);
+    let mut pipe = match create_pipe(&name, true) {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            warning!(
+                "Can't listen on {} ({}). \
+                 Another agent may be running. `shadow status`, `pause`, and `resume` will only use the data directory.",
+                name,
+                e
+            );
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let connected = pipe.connect().await;
+            // The next client connects to a new instance while this one is served
+            let next = match create_pipe(&name, false) {
+                Ok(next) => next,
+                Err(e) => {
+                    warning!("Stopped listening on {} ({})", name, e);
+                    return;
+                }
+            };
+            let client = std::mem::replace(&mut pipe, next);
+            if connected.is_err() {
+                continue;
+            }
+            let peer = client_sid(&client).unwrap_or_else(|| "unknown pipe client".to_string());
+            let agent = agent.clone();
+            tokio::spawn(async move {
+                // The DACL already limits who can connect
+                let _ = serve(client, &agent, true, peer).await;
+            });
+        }
+    });
+}
+
+/// The SID of the account the pipe's client process runs as
+#[cfg(windows)]
+fn client_sid(pipe: &tokio::net::windows::named_pipe::NamedPipeServer) -> Option<String> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Foundation::{CloseHandle, LocalFree};
+    use windows_sys::Win32::Security::Authorization::ConvertSidToStringSidW;
+    use windows_sys::Win32::Security::{GetTokenInformation, TokenUser, TOKEN_QUERY, TOKEN_USER};
+    use windows_sys::Win32::System::Pipes::GetNamedPipeClientProcessId;
+    use windows_sys::Win32::System::Threading::{OpenProcess, OpenProcessToken, PROCESS_QUERY_LIMITED_INFORMATION};
+
+    let mut pid = 0;
+    // SAFETY: the handle is the connected pipe's, and `pid` outlives the call
+    if unsafe { GetNamedPipeClientProcessId(pipe.as_raw_handle(), &mut pid) } == 0 {
+        return None;
+    }
+    // SAFETY: OpenProcess takes any PID and returns null if it can't be opened
+    let process = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if process.is_null() {
+        return None;
+    }
+    let mut token = std::ptr::null_mut();
+    // SAFETY: `process` was opened for querying above
+    let opened = unsafe { OpenProcessToken(process, TOKEN_QUERY, &mut token) };
+    unsafe { CloseHandle(process) };
+    if opened == 0 {
+        return None;
+    }
+    // TOKEN_USER is followed by the SID it points to, so ask for the size first
+    let mut size = 0u32;
+    // SAFETY: a null buffer of length 0 only asks for the size
+    unsafe { GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut size) };
+    // u64s, so the buffer is aligned for TOKEN_USER
+    let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+    // SAFETY: `token` was opened for querying, and the buffer holds `size` bytes
+    let ok = unsafe { GetTokenInformation(token, TokenUser, buffer.as_mut_ptr().cast(), size, &mut size) };
+    unsafe { CloseHandle(token) };
+    if ok == 0 {
+        return None;
+    }
+    // SAFETY: GetTokenInformation filled the buffer with a TOKEN_USER
+    let sid = unsafe { (*buffer.as_ptr().cast::<TOKEN_USER>()).User.Sid };
+    let mut string = std::ptr::null_mut();
+    // SAFETY: `sid` points into `buffer`, which is still alive
+    if unsafe { ConvertSidToStringSidW(sid, &mut string) } == 0 {
+        return None;
+    }
+    // SAFETY: on success `string` is a NUL-terminated string allocated with
+    // LocalAlloc for the caller to free
+    let sid = unsafe {
+        let len = (0..).take_while(|&i| *string.add(i) != 0).count();
+        String::from_utf16_lossy(std::slice::from_raw_parts(string, len))
+    };
+    unsafe { LocalFree(string.cast()) };
+    Some(format!("user {}", sid))
+}
+
+/// Answer requests until the agent exits
+#[cfg(not(any(unix, windows)))]
+pub fn spawn(_agent: Agent) {}
+
+/// Send a request and read the response
+#[cfg_attr(not(any(unix, windows)), allow(dead_code))]
+async fn exchange<S: AsyncRead + AsyncWrite>(stream: S, request: &Request) -> Result<Response> {
+    let (read, mut write) = tokio::io::split(stream);
+    let mut data = serde_json::to_vec(request)?;
+    data.push(b'\n');
+    write.write_all(&data).await?;
+
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_MESSAGE)).read_line(&mut line).await?;
+    serde_json::from_str(&line).context("Invalid response from the agent")
+}
+
+/// Connect to the running agent, or None if it isn't listening
+#[cfg(unix)]
+async fn connect(data_dir: &Path) -> Result<Option<tokio::net::UnixStream>> {
+    use std::io::ErrorKind;
+
+    let path = data_dir.join(SOCKET_FILE);
+    match tokio::net::UnixStream::connect(&path).await {
+        Ok(stream) => Ok(Some(stream)),
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => Ok(None),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Err(anyhow::anyhow!(
+            "Permission denied connecting to the agent at {}. Run this as root (e.g. with sudo) or as the agent's user.",
+            path.display()
+        )
+        .context(errors::AGENT_PERMISSION)),
+        Err(e) => Err(e).with_context(|| format!("Failed to connect to the agent at {}", path.display())),
+    }
+}
+
+/// Connect to the running agent, or None if it isn't listening
+#[cfg(windows)]
+async fn connect(data_dir: &Path) -> Result<Option<tokio::net::windows::named_pipe::NamedPipeClient>> {
+    use std::io::ErrorKind;
+    use tokio::net::windows::named_pipe::ClientOptions;
+    use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+
+    let name = pipe_name(data_dir);
+    let deadline = tokio::time::Instant::now() + REQUEST_TIMEOUT;
+    loop {
+        match ClientOptions::new().open(&name) {
+            Ok(pipe) => return Ok(Some(pipe)),
+            // Briefly, while the agent creates the next instance
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) && tokio::time::Instant::now() < deadline => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                return Err(anyhow::anyhow!(
+                    "Permission denied connecting to the agent at {}. Run this from an elevated prompt or as the agent's user.",
+                    name
+                )
+                .context(errors::AGENT_PERMISSION))
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to connect to the agent at {}", name)),
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Send a request to the running agent, or None if it isn't listening
+#[cfg(any(unix, windows))]
+pub async fn call(data_dir: &Path, request: &Request) -> Result<Option<Response>> {
+    let Some(stream) = connect(data_dir).await? else {
+        return Ok(None);
+    };
+    let timeout = match request {
+        Request::Reload { .. }
+        | Request::UpgradeOsquery { .. }
+        | Request::UploadSupportBundle { .. }
+        | Request::UploadInventory { .. }
+        | Request::Replay { .. } => {
+            ACTION_TIMEOUT + ANSWER_TIMEOUT
+        }
+        _ => ANSWER_TIMEOUT,
+    };
+    tokio::time::timeout(timeout, exchange(stream, request))
+        .await
+        .context("The agent didn't answer in time")?
+        .map(Some)
+}
+
+/// Send a request to the running agent, or None if it isn't listening
+#[cfg(not(any(unix, windows)))]
+pub async fn call(_data_dir: &Path, _request: &Request) -> Result<Option<Response>> {
+    Ok(None)
+}
+
+/// `shadow status`
+pub async fn status(data_dir: &Path, format: OutputFormat) -> Result<()> {
+    match call(data_dir, &Request::Status).await? {
+        Some(Response::Status { status }) => status::show(&status, true, format),
+        Some(response) => response.message().map(drop),
+        None => status::print(data_dir, format).await,
+    }
+}
+
+/// `shadow pause`
+pub async fn pause(data_dir: &Path, reason: Option<String>, minutes: Option<u64>) -> Result<()> {
+    let user = audit::current_user();
+    let request = Request::Pause {
+        user: user.clone(),
+        reason: reason.clone(),
+        minutes,
+    };
+    let message = match call(data_dir, &request).await? {
+        Some(response) => response.message()?,
+        None => {
+            let duration = minutes.map(|m| Duration::from_secs(m * 60));
+            control::describe(&control::pause(data_dir, &user, reason, duration).await?)
+        }
+    };
+    println!("{}", message);
+    Ok(())
+}
+
+/// `shadow resume`
+pub async fn resume(data_dir: &Path) -> Result<()> {
+    let user = audit::current_user();
+    let request = Request::Resume { user: user.clone() };
+    let message = match call(data_dir, &request).await? {
+        Some(response) => response.message()?,
+        None if control::resume(data_dir, &user).await? => "Collection resumed".to_string(),
+        None => "Collection is not paused".to_string(),
+    };
+    println!("{}", message);
+    Ok(())
+}
+
+/// `shadow reload` and `shadow upgrade-osquery`, which need the running agent
+async fn request_action(data_dir: &Path, request: Request, what: &str) -> Result<()> {
+    match call(data_dir, &request).await? {
+        Some(response) => println!("{}", response.message()?),
+        None => {
+            let error = anyhow::anyhow!("The agent isn't running, so there's no osqueryd to {}", what);
+            return Err(error.context(errors::AGENT_NOT_RUNNING));
+        }
+    }
+    Ok(())
+}
+
+/// `shadow reload`
+pub async fn reload(data_dir: &Path) -> Result<()> {
+    let user = audit::current_user();
+    request_action(data_dir, Request::Reload { user }, "reload").await
+}
+
+/// `shadow upgrade-osquery`
+pub async fn upgrade_osquery(data_dir: &Path) -> Result<()> {
+    let user = audit::current_user();
+    request_action(data_dir, Request::UpgradeOsquery { user, version: None }, "upgrade").await
+}
+
+/// `shadow doctor`
+pub async fn doctor(data_dir: &Path, server: &str, format: OutputFormat) -> Result<()> {
+    let checks = match call(data_dir, &Request::Doctor).await? {
+        Some(Response::Doctor { checks }) => checks,
+        Some(response) => return response.message().map(drop),
+        None => doctor::run(data_dir, None, server).await,
+    };
+    doctor::print(&checks, format)
+}
+
+/// `shadow support-bundle`, written to a file, or with `upload` sent to the
+/// server by the running agent
+pub async fn support_bundle(
+    data_dir: &Path,
+    server: &str,
+    org_token: Option<&str>,
+    file: Option<PathBuf>,
+    upload: bool,
+) -> Result<()> {
+    if upload {
+        let request = Request::UploadSupportBundle {
+            user: audit::current_user(),
+        };
+        let Some(response) = call(data_dir, &request).await? else {
+            let error = anyhow::anyhow!(
+                "The agent isn't running, so it can't upload with its credentials. \
+                 Run `shadow support-bundle` without --upload and send the file instead."
+            );
+            return Err(error.context(errors::AGENT_NOT_RUNNING));
+        };
+        println!("{}", response.message()?);
+        return Ok(());
+    }
+
+    let status = match call(data_dir, &Request::Status).await? {
+        Some(Response::Status { status }) => Some(*status),
+        _ => status::read(data_dir).await.ok(),
+    };
+    let checks = match call(data_dir, &Request::Doctor).await? {
+        Some(Response::Doctor { checks }) => checks,
+        _ => doctor::run(data_dir, None, server).await,
+    };
+    let bundle = support::collect(data_dir, status.as_ref(), &checks, &Vec::from_iter(org_token))?;
+    let file = file.unwrap_or_else(|| PathBuf::from(support::file_name()));
+    support::write(&file, &bundle).await?;
+    println!("Wrote {} ({} KB)", file.display(), bundle.len().div_ceil(1024));
+    Ok(())
+}
+
+/// `shadow benchmark --upload`: have the running agent send a report to the
+/// server
+pub async fn upload_benchmark(data_dir: &Path, report: &benchmark::Report) -> Result<String> {
+    let request = Request::UploadBenchmark {
+        user: audit::current_user(),
+        report: Box::new(report.clone()),
+    };
+    let Some(response) = call(data_dir, &request).await? else {
+        let error = anyhow::anyhow!(
+            "The agent isn't running, so it can't upload with its credentials. \
+             Run `shadow benchmark` with --report and send the file instead."
+        );
+        return Err(error.context(errors::AGENT_NOT_RUNNING));
+    };
+    response.message()
+}
+
+/// `shadow inventory --upload`: have the running agent list the inventory and
+/// send it to the server
+pub async fn inventory(data_dir: &Path) -> Result<()> {
+    let request = Request::UploadInventory {
+        user: audit::current_user(),
+    };
+    let Some(response) = call(data_dir, &request).await? else {
+        let error = anyhow::anyhow!(
+            "The agent isn't running, so it can't upload with its credentials. \
+             Run `shadow inventory` without --upload and send the file instead."
+        );
+        return Err(error.context(errors::AGENT_NOT_RUNNING));
+    };
+    println!("{}", response.message()?);
+    Ok(())
+}
+
+/// How often `shadow replay` shows its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// `shadow replay`: have the running agent send what's spooled now, showing
+/// how far it's got meanwhile
+pub async fn replay(data_dir: &Path, format: OutputFormat) -> Result<()> {
+    let table = format == OutputFormat::Table;
+    if table {
+        match spool::spooled(data_dir) {
+            Ok(spooled) if spooled.is_empty() => {
+                println!("Nothing is spooled");
+                return Ok(());
+            }
+            Ok(spooled) => spool::summarize(&spooled).iter().for_each(|line| println!("{}", line)),
+            Err(e) => warning!("Couldn't list the spools: {:#}", e),
+        }
+    }
+
+    let request = Request::Replay {
+        user: audit::current_user(),
+    };
+    let progress = table.then(|| tokio::spawn(show_progress(data_dir.to_path_buf())));
+    let response = call(data_dir, &request).await;
+    if let Some(progress) = progress {
+        progress.abort();
+    }
+    let spools = match response? {
+        Some(Response::Replayed { spools }) => spools,
+        Some(response) => return response.message().map(drop),
+        None => {
+            let error = anyhow::anyhow!(
+                "The agent isn't running, so it can't send the spools; it sends them once it's started again"
+            );
+            return Err(error.context(errors::AGENT_NOT_RUNNING));
+        }
+    };
+    output::list(format, &spools, |spools| {
+        let busy: Vec<&Replayed> = spools
+            .iter()
+            .filter(|replayed| replayed.sent + replayed.rejected + replayed.left > 0)
+            .collect();
+        if busy.is_empty() {
+            println!("Nothing was spooled");
+        }
+        for replayed in busy {
+            let left_mb = replayed.left_bytes.div_ceil(1024 * 1024);
+            let mut line = match replayed.sent + replayed.rejected {
+                0 => format!("No spooled {} batches were sent", replayed.spool),
+                _ => format!("Sent {} spooled {} batches", replayed.sent, replayed.spool),
+            };
+            if replayed.rejected > 0 {
+                line += &format!(", and dropped {} the server rejected", replayed.rejected);
+            }
+            match (replayed.left, replayed.reachable) {
+                (0, _) => line += &format!("; the {} spool is empty", replayed.spool),
+                (left, false) => line += &format!(
+                    "; the server can't take them yet, so {} ({} MB) are left for the agent to retry",
+                    left, left_mb
+                ),
+                (left, true) => line += &format!(
+                    "; stopped after {} minutes with {} ({} MB) left, which the agent keeps sending",
+                    ACTION_TIMEOUT.as_secs() / 60,
+                    left,
+                    left_mb
+                ),
+            }
+            println!("{}", line);
+        }
+    })?;
+    if spools.iter().any(|replayed| !replayed.reachable) {
+        return Err(anyhow::anyhow!("The server couldn't take the spooled batches").context(errors::SERVER_UNREACHABLE));
+    }
+    Ok(())
+}
+
+/// Print how many batches are left in the spools whenever it changes, from
+/// the agent's status
+async fn show_progress(data_dir: PathBuf) {
+    let mut last = None;
+    loop {
+        tokio::time::sleep(PROGRESS_INTERVAL).await;
+        let Ok(Some(Response::Status { status })) = call(&data_dir, &Request::Status).await else {
+            continue;
+        };
+        let left = (
+            status.spooled_log_batches,
+            status.spooled_log_bytes,
+            status.spooled_live_batches,
+            status.spooled_live_bytes,
+        );
+        if last.replace(left) != Some(left) {
+            println!(
+                "  {} log batches ({} MB) and {} live query result batches ({} MB) left",
+                left.0,
+                left.1.div_ceil(1024 * 1024),
+                left.2,
+                left.3.div_ceil(1024 * 1024)
+            );
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! osquery log retention
+//!
+//! osqueryd's status logs (glog's INFO, WARNING, and ERROR files) go to
+//! `osquery_logs/` in the data directory, with new files on every start and
+//! nothing ever removing the old ones, so long-lived hosts slowly fill their
+//! disks. A janitor task periodically removes files past a maximum age, then
+//! the oldest files until the directory is under its size cap. The newest file
+//! of each kind, which osqueryd is writing to, is left alone.
+
+use crate::logging::{info, warning};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often the janitor runs
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Limits on the osquery log directory
+#[derive(Debug, Clone, Copy)]
+pub struct LogRetention {
+    /// Total size to stay under, if limited
+    pub max_bytes: Option<u64>,
+    /// Age past which files are removed, if limited
+    pub max_age: Option<Duration>,
+}
+
+impl LogRetention {
+    fn is_set(&self) -> bool {
+        self.max_bytes.is_some() || self.max_age.is_some()
+    }
+}
+
+/// What a log file holds, e.g. `osqueryd.INFO` for
+/// `osqueryd.INFO.20261016-151500.1234` or `osqueryd.results` for
+/// `osqueryd.results.log`
+fn kind(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.splitn(3, '.').take(2).collect::<Vec<_>>().join(".")
+}
+
+struct LogFile {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// Enforce the limits on `dir` until the agent exits
+pub fn spawn(dir: PathBuf, retention: LogRetention) {
+    if !retention.is_set() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(INTERVAL);
+        loop {
+            ticker.tick().await;
+            let dir = dir.clone();
+            let _ = tokio::task::spawn_blocking(move || clean(&dir, retention)).await;
+        }
+    });
+}
+
+fn clean(dir: &Path, retention: LogRetention) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<LogFile> = entries
+        .flatten()
+        .filter_map(|entry| {
+            // symlink_metadata, so glog's symlinks aren't counted twice
+            let metadata = std::fs::symlink_metadata(entry.path()).ok()?;
+            metadata.is_file().then(|| LogFile {
+                path: entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            })
+        })
+        .collect();
+    // Everything counts towards the cap, including files in use
+    let mut total: u64 = files.iter().map(|file| file.size).sum();
+
+    // osqueryd writes to the newest file of each kind
+    files.sort_by_key(|file| std::cmp::Reverse(file.modified));
+    let mut kinds = HashSet::new();
+    files.retain(|file| !kinds.insert(kind(&file.path)));
+    files.reverse();
+
+    let (mut removed, mut freed) = (0, 0);
+    let now = SystemTime::now();
+    for file in &files {
+        let expired = retention
+            .max_age
+            .is_some_and(|max_age| now.duration_since(file.modified).unwrap_or_default() > max_age);
+        let over = retention.max_bytes.is_some_and(|max_bytes| total > max_bytes);
+        if !expired && !over {
+            continue;
+        }
+        match std::fs::remove_file(&file.path) {
+            Ok(()) => {
+                total -= file.size;
+                removed += 1;
+                freed += file.size;
+            }
+            Err(e) => warning!("Failed to remove old osquery log {}: {}", file.path.display(), e),
+        }
+    }
+    if removed > 0 {
+        info!(
+            "Removed {} old osquery log files ({} MB)",
+            removed,
+            freed / (1024 * 1024)
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Kubernetes DaemonSet mode
+//!
+//! Run as a DaemonSet, shadow sits in a container on each node: the host's
+//! filesystem is mounted under a prefix, the container's root filesystem is
+//! often read-only, and the hardware UUID says nothing about which node it is.
+//! In this mode we check the pod was given the host access osquery needs, keep
+//! writable state somewhere writable, and tag the enrollment with the node name
+//! and pod labels from the Downward API.
+
+use crate::logging::warning;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where writable state goes when the data directory is read-only. An emptyDir
+/// is usually mounted here alongside a read-only root filesystem.
+const FALLBACK_STATE_DIR: &str = "/tmp/shadow";
+
+/// Processes that run as pid 1 in a pod without `hostPID`
+const CONTAINER_INITS: &[&str] = &["shadow", "pause", "tini", "dumb-init", "sh"];
+
+/// How the pod exposes the node to us
+#[derive(Debug, Clone)]
+pub struct PodConfig {
+    /// Where the host's root filesystem is mounted
+    pub hostfs: PathBuf,
+    /// Node name, from the Downward API (`spec.nodeName`)
+    pub node_name: Option<String>,
+    /// Downward API volume with a `labels` file (`metadata.labels`)
+    pub podinfo_dir: PathBuf,
+}
+
+/// The data directory, or a writable fallback if the root filesystem is
+/// read-only
+pub async fn state_dir(data_dir: PathBuf) -> PathBuf {
+    if is_writable(&data_dir).await {
+        return data_dir;
+    }
+    let fallback = PathBuf::from(FALLBACK_STATE_DIR);
+    warning!(
+        "{} isn't writable, keeping state in {} instead. \
+         Mount a hostPath volume at the data directory so the host ID and osquery \
+         database survive pod restarts.",
+        data_dir.display(),
+        fallback.display()
+    );
+    fallback
+}
+
+async fn is_writable(dir: &Path) -> bool {
+    if tokio::fs::create_dir_all(dir).await.is_err() {
+        return false;
+    }
+    let probe = dir.join(".write-test");
+    let writable = tokio::fs::write(&probe, b"").await.is_ok();
+    let _ = tokio::fs::remove_file(&probe).await;
+    writable
+}
+
+/// Warn about missing host access, which leaves osquery looking at the
+/// container instead of the node
+pub fn check_pod(config: &PodConfig) {
+    let init = std::fs::read_to_string("/proc/1/comm").unwrap_or_default();
+    if CONTAINER_INITS.contains(&init.trim()) {
+        warning!(
+            "pid 1 is {}, so the pod doesn't share the host's process namespace. \
+             Set `hostPID: true` in the DaemonSet, or process tables only show the container.",
+            init.trim()
+        );
+    }
+    if !config.hostfs.join("etc").is_dir() {
+        warning!(
+            "The host's filesystem isn't mounted at {}. \
+             Mount it read-only with a hostPath volume of `/`, or file tables only show the container.",
+            config.hostfs.display()
+        );
+    }
+    if config.node_name.is_none() {
+        warning!(
+            "The node name isn't set. \
+             Set SHADOW_K8S_NODE_NAME from the Downward API field `spec.nodeName`."
+        );
+    }
+}
+
+/// Enrollment tags describing the node and pod
+pub async fn enroll_tags(config: &PodConfig) -> BTreeMap<String, String> {
+    let mut tags = BTreeMap::new();
+    if let Some(node) = &config.node_name {
+        tags.insert("k8s.node".to_string(), node.clone());
+    }
+    tags.insert("k8s.hostfs".to_string(), config.hostfs.display().to_string());
+    let labels = tokio::fs::read_to_string(config.podinfo_dir.join("labels"))
+        .await
+        .unwrap_or_default();
+    for (key, value) in labels.lines().filter_map(parse_label) {
+        tags.insert(format!("k8s.label.{}", key), value);
+    }
+    tags
+}
+
+/// One line of a Downward API labels file: `key="value"`, with the value
+/// quoted and escaped like a JSON string
+fn parse_label(line: &str) -> Option<(String, String)> {
+    let (key, value) = line.split_once('=')?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| value.trim_matches('"').to_string());
+    Some((key.trim().to_string(), value))
+}
@@ -0,0 +1,123 @@
+//! Last-known-good config
+//!
+//! osqueryd has no schedule until the server answers its first config
+//! request, so a host that starts while the server is down collects nothing.
+//! The agent keeps the last enrollment and, through the TLS relay, the last
+//! config the server sent in the data directory. If the server can't be
+//! reached when the agent starts, it starts with the saved enrollment instead
+//! of exiting, and while the server can't answer a config request the relay
+//! answers with the saved config, set to refresh so osqueryd picks up the
+//! server's own as soon as it's back, or with the built-in packs if no config
+//! was saved. Without the relay, osqueryd's `--config_enable_backup` does the
+//! same from its database.
+
+use crate::atrest;
+use crate::logging::warning;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// The server's config, as it sent it
+const CONFIG_FILE: &str = "last_config.json";
+
+/// The enrollment the config came with
+const ENROLLMENT_FILE: &str = "last_enrollment.json";
+
+/// How often osqueryd asks again while it has a saved config, if the config
+/// doesn't say
+const REFRESH_SECS: u64 = 300;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Enrollment {
+    server: String,
+    host_id: String,
+    enroll_secret: String,
+    enrolled_at: DateTime<Utc>,
+}
+
+/// Remember an enrollment, to start with if the server is down next time
+pub fn save_enrollment(data_dir: &Path, server: &str, host_id: &str, enroll_secret: &str) {
+    let enrollment = Enrollment {
+        server: server.to_string(),
+        host_id: host_id.to_string(),
+        enroll_secret: enroll_secret.to_string(),
+        enrolled_at: Utc::now(),
+    };
+    let path = data_dir.join(ENROLLMENT_FILE);
+    if let Err(e) = serde_json::to_vec(&enrollment)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| write_private(&path, &data))
+    {
+        warning!("Couldn't save the enrollment, so the agent won't start while the server is down: {:#}", e);
+    }
+}
+
+/// The enroll secret and enrollment time from the last enrollment with
+/// `server` as `host_id`, if there was one
+pub fn load_enrollment(data_dir: &Path, server: &str, host_id: &str) -> Option<(String, DateTime<Utc>)> {
+    let data = atrest::open(std::fs::read(data_dir.join(ENROLLMENT_FILE)).ok()?).ok()?;
+    let enrollment: Enrollment = serde_json::from_slice(&data).ok()?;
+    (enrollment.server == server && enrollment.host_id == host_id)
+        .then_some((enrollment.enroll_secret, enrollment.enrolled_at))
+}
+
+/// The server's last config in the data directory
+pub struct LastConfig {
+    path: PathBuf,
+}
+
+impl LastConfig {
+    pub fn new(data_dir: &Path) -> Self {
+        LastConfig {
+            path: data_dir.join(CONFIG_FILE),
+        }
+    }
+
+    /// Keep a config from the server, if it's one osqueryd could use
+    pub fn save(&self, body: &[u8]) -> Result<()> {
+        let config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        if !config.is_object() {
+            anyhow::bail!("Config isn't a JSON object");
+        }
+        write_private(&self.path, body)
+    }
+
+    /// The saved config, set to refresh, and when it was saved
+    pub fn load(&self) -> Option<(Vec<u8>, DateTime<Utc>)> {
+        let data = atrest::open(std::fs::read(&self.path).ok()?).ok()?;
+        let saved_at = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok()?;
+        let config: Value = serde_json::from_slice(&data).ok()?;
+        Some((refreshing(config)?, saved_at.into()))
+    }
+}
+
+/// A config for osqueryd while the server can't give one, set to ask again
+/// soon
+pub fn refreshing(mut config: Value) -> Option<Vec<u8>> {
+    let options = config
+        .as_object_mut()?
+        .entry("options")
+        .or_insert_with(|| serde_json::json!({}));
+    if let Some(options) = options.as_object_mut() {
+        let refresh = options.get("config_refresh").and_then(Value::as_u64).unwrap_or(0);
+        if refresh == 0 || refresh > REFRESH_SECS {
+            options.insert("config_refresh".to_string(), serde_json::json!(REFRESH_SECS));
+        }
+    }
+    serde_json::to_vec(&config).ok()
+}
+
+/// Replace `path` in one rename, readable only by the agent's user, and
+/// encrypted with `--encrypt-at-rest`
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, atrest::seal(data)?).with_context(|| format!("Failed to write {}", temp.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::fs::rename(&temp, path).with_context(|| format!("Failed to write {}", path.display()))
+}
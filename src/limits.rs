@@ -0,0 +1,366 @@
+//! Resource caps for the osqueryd child and the agent itself
+//!
+//! On Windows there are no cgroups, and osquery's watchdog only reacts after a
+//! limit has already been breached, so osqueryd is placed in a Job Object with
+//! hard memory and CPU rate limits. Child processes osqueryd creates (its
+//! worker) inherit the job. Other platforms rely on osquery's watchdog.
+//!
+//! The agent can be capped too. A watchdog samples its memory and CPU, and
+//! once either stays over its limit the agent stops osqueryd and exits with an
+//! error, so the service manager restarts it. Twice the memory limit is also
+//! enforced as a hard cap, so a runaway allocation fails between samples:
+//! `RLIMIT_DATA` on Linux (restored for the processes the agent starts), and a
+//! Job Object on Windows, which also hard-caps CPU. Processes the agent starts
+//! break away from that job, so osqueryd only gets its own limits.
+
+use crate::audit;
+use crate::errors;
+use crate::logging::error;
+use crate::resources::ResourceSampler;
+use crate::status::SharedStatus;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::process::Child;
+
+/// How often the agent samples itself
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Consecutive samples over the memory limit before restarting
+const MEMORY_SAMPLES: usize = 3;
+
+/// Samples CPU usage is averaged over
+const CPU_SAMPLES: usize = 30;
+
+/// How long the agent waits for osqueryd to stop before exiting anyway
+const STOP_GRACE: Duration = Duration::from_secs(60);
+
+/// Why the agent exceeded its limits, once it has
+static EXCEEDED: OnceLock<String> = OnceLock::new();
+
+/// Caps applied to osqueryd and its worker
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChildLimits {
+    /// Total memory for osqueryd and its worker, in megabytes
+    pub memory_mb: Option<u64>,
+    /// Share of total machine CPU, in percent
+    pub cpu_percent: Option<u32>,
+}
+
+impl ChildLimits {
+    pub fn is_set(&self) -> bool {
+        self.memory_mb.is_some() || self.cpu_percent.is_some()
+    }
+}
+
+/// Caps on the agent process itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AgentLimits {
+    /// Resident memory, in megabytes
+    pub memory_mb: Option<u64>,
+    /// Share of total machine CPU, in percent
+    pub cpu_percent: Option<u32>,
+}
+
+impl AgentLimits {
+    pub fn is_set(&self) -> bool {
+        self.memory_mb.is_some() || self.cpu_percent.is_some()
+    }
+
+    /// The hard memory cap, in bytes
+    #[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+    fn hard_memory_cap(&self) -> Option<u64> {
+        self.memory_mb.map(|mb| mb * 2 * 1024 * 1024)
+    }
+
+    /// Restart the agent once it stays over its limits
+    pub fn watch(self, data_dir: PathBuf, status: SharedStatus) {
+        if !self.is_set() {
+            return;
+        }
+        tokio::spawn(async move {
+            let Some(reason) = self.wait_until_exceeded().await else {
+                return;
+            };
+            error!("{}, restarting the agent", reason);
+            status.update(|status| {
+                status.last_error = Some(reason.clone());
+                status.last_error_code = Some(errors::AGENT_LIMIT.code.to_string());
+            });
+            audit::record_agent(
+                &data_dir,
+                "agent_limit_exceeded",
+                serde_json::json!({ "reason": reason }),
+            )
+            .await;
+            let _ = EXCEEDED.set(reason);
+
+            // The supervisor stops osqueryd and the agent exits; this is a
+            // backstop for when it isn't supervising yet, or is paused
+            crate::power::request_shutdown();
+            tokio::time::sleep(STOP_GRACE).await;
+            error!(
+                "The agent didn't stop within {}s, exiting",
+                STOP_GRACE.as_secs()
+            );
+            std::process::exit(1);
+        });
+    }
+
+    async fn wait_until_exceeded(&self) -> Option<String> {
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as u64;
+        let mut sampler = ResourceSampler::new();
+        let mut ticker = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut over_memory = 0;
+        let mut cpu_times: VecDeque<u64> = VecDeque::new();
+        loop {
+            ticker.tick().await;
+            let usage = sampler.sample(std::process::id())?;
+
+            if let Some(memory_mb) = self.memory_mb {
+                let rss_mb = usage.rss_bytes / (1024 * 1024);
+                over_memory = if rss_mb > memory_mb {
+                    over_memory + 1
+                } else {
+                    0
+                };
+                if over_memory == MEMORY_SAMPLES {
+                    return Some(format!(
+                        "Agent memory at {} MB, over its {} MB limit",
+                        rss_mb, memory_mb
+                    ));
+                }
+            }
+
+            if let Some(cpu_percent) = self.cpu_percent {
+                cpu_times.push_back(usage.cpu_time_ms);
+                if cpu_times.len() > CPU_SAMPLES {
+                    cpu_times.pop_front();
+                    let used = cpu_times.back()? - cpu_times.front()?;
+                    let available = SAMPLE_INTERVAL.as_millis() as u64 * CPU_SAMPLES as u64 * cpus;
+                    let percent = used * 100 / available;
+                    if percent > cpu_percent as u64 {
+                        return Some(format!(
+                            "Agent CPU at {}% over the last {}s, over its {}% limit",
+                            percent,
+                            SAMPLE_INTERVAL.as_secs() * CPU_SAMPLES as u64,
+                            cpu_percent
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fail if the agent exceeded its limits, so the service manager restarts it
+pub fn check_agent() -> Result<()> {
+    match EXCEEDED.get() {
+        Some(reason) => Err(anyhow::anyhow!("{}", reason).context(errors::AGENT_LIMIT)),
+        None => Ok(()),
+    }
+}
+
+/// The data size limit the agent started with, before its own cap
+#[cfg(target_os = "linux")]
+static INHERITED_DATA_LIMIT: OnceLock<libc::rlimit> = OnceLock::new();
+
+/// The data size limit for processes the agent starts, if the agent lowered
+/// its own
+#[cfg(target_os = "linux")]
+pub fn inherited_data_limit() -> Option<libc::rlimit> {
+    INHERITED_DATA_LIMIT.get().copied()
+}
+
+#[cfg(target_os = "linux")]
+impl AgentLimits {
+    /// Lower the agent's data size limit to the hard memory cap
+    pub fn apply(&self) -> Result<()> {
+        use anyhow::Context;
+
+        let Some(cap) = self.hard_memory_cap() else {
+            return Ok(());
+        };
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        unsafe {
+            if libc::getrlimit(libc::RLIMIT_DATA, &mut limit) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to read the data size limit");
+            }
+            let lowered = libc::rlimit {
+                rlim_cur: cap.min(limit.rlim_max),
+                rlim_max: limit.rlim_max,
+            };
+            if libc::setrlimit(libc::RLIMIT_DATA, &lowered) != 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to set the data size limit");
+            }
+        }
+        let _ = INHERITED_DATA_LIMIT.set(limit);
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl AgentLimits {
+    /// No hard caps on this platform; the watchdog still applies
+    pub fn apply(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Holds the Job Object open for as long as osqueryd runs
+#[cfg(windows)]
+pub struct JobObject(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(not(windows))]
+pub enum JobObject {}
+
+#[cfg(windows)]
+unsafe impl Send for JobObject {}
+
+#[cfg(windows)]
+impl Drop for JobObject {
+    fn drop(&mut self) {
+        unsafe {
+            windows_sys::Win32::Foundation::CloseHandle(self.0);
+        }
+    }
+}
+
+#[cfg(windows)]
+impl JobObject {
+    fn new() -> Result<Self> {
+        use anyhow::Context;
+
+        let handle = unsafe {
+            windows_sys::Win32::System::JobObjects::CreateJobObjectW(
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        if handle.is_null() {
+            return Err(std::io::Error::last_os_error()).context("Failed to create job object");
+        }
+        Ok(JobObject(handle))
+    }
+
+    unsafe fn set_info<T>(
+        &self,
+        class: windows_sys::Win32::System::JobObjects::JOBOBJECTINFOCLASS,
+        info: &T,
+    ) -> Result<()> {
+        use anyhow::Context;
+
+        let ok = windows_sys::Win32::System::JobObjects::SetInformationJobObject(
+            self.0,
+            class,
+            info as *const T as *const std::ffi::c_void,
+            std::mem::size_of::<T>() as u32,
+        );
+        if ok == 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to set job object limits");
+        }
+        Ok(())
+    }
+
+    fn set_cpu_rate(&self, cpu_percent: u32) -> Result<()> {
+        use windows_sys::Win32::System::JobObjects::*;
+
+        unsafe {
+            let mut info: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION = std::mem::zeroed();
+            info.ControlFlags =
+                JOB_OBJECT_CPU_RATE_CONTROL_ENABLE | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP;
+            // CpuRate is in hundredths of a percent
+            info.Anonymous.CpuRate = cpu_percent.clamp(1, 100) * 100;
+            self.set_info(JobObjectCpuRateControlInformation, &info)
+        }
+    }
+}
+
+#[cfg(windows)]
+impl ChildLimits {
+    /// Place a freshly spawned child in a Job Object with these limits
+    pub fn apply(&self, child: &Child) -> Result<Option<JobObject>> {
+        use anyhow::Context;
+        use windows_sys::Win32::System::JobObjects::*;
+
+        if !self.is_set() {
+            return Ok(None);
+        }
+
+        let process = child.raw_handle().context("osqueryd has already exited")?;
+        let job = JobObject::new()?;
+
+        if let Some(memory_mb) = self.memory_mb {
+            unsafe {
+                let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_JOB_MEMORY;
+                info.JobMemoryLimit = (memory_mb * 1024 * 1024) as usize;
+                job.set_info(JobObjectExtendedLimitInformation, &info)?;
+            }
+        }
+
+        if let Some(cpu_percent) = self.cpu_percent {
+            job.set_cpu_rate(cpu_percent)?;
+        }
+
+        if unsafe { AssignProcessToJobObject(job.0, process as _) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to assign osqueryd to job object");
+        }
+
+        Ok(Some(job))
+    }
+}
+
+#[cfg(windows)]
+impl AgentLimits {
+    /// Place the agent in a Job Object with the hard caps, leaving the
+    /// processes it starts out of it
+    pub fn apply(&self) -> Result<()> {
+        use anyhow::Context;
+        use windows_sys::Win32::System::JobObjects::*;
+        use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+        if !self.is_set() {
+            return Ok(());
+        }
+        let job = JobObject::new()?;
+
+        unsafe {
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK;
+            if let Some(cap) = self.hard_memory_cap() {
+                info.BasicLimitInformation.LimitFlags |= JOB_OBJECT_LIMIT_PROCESS_MEMORY;
+                info.ProcessMemoryLimit = cap as usize;
+            }
+            job.set_info(JobObjectExtendedLimitInformation, &info)?;
+        }
+
+        if let Some(cpu_percent) = self.cpu_percent {
+            job.set_cpu_rate(cpu_percent)?;
+        }
+
+        if unsafe { AssignProcessToJobObject(job.0, GetCurrentProcess()) } == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Failed to assign the agent to a job object");
+        }
+        // The job lasts as long as the agent
+        std::mem::forget(job);
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+impl ChildLimits {
+    /// Job Objects are Windows-only; other platforms rely on osquery's watchdog
+    pub fn apply(&self, _child: &Child) -> Result<Option<JobObject>> {
+        Ok(None)
+    }
+}
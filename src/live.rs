@@ -0,0 +1,186 @@
+//! Live query channel
+//!
+//! osqueryd asks for live queries only every `--distributed_interval`
+//! seconds, too slow for interactive incident response. With `--live-channel`
+//! the agent keeps a long-poll request open to the server, which answers as
+//! soon as it has live queries for the host; the agent runs each one inside
+//! osqueryd through its extensions socket straight away and posts its results
+//! back as soon as they're ready, each query on its own. osqueryd's own
+//! polling carries on regardless, so while the server doesn't offer the
+//! channel or can't be reached, live queries still arrive that way. The local
+//! denylist and redaction rules apply to queries on either path, and carving
+//! queries are left to osqueryd's polling. Replies can
+//! also carry signed commands, which arrive sooner this way than with the
+//! next heartbeat.
+
+use crate::api::ApiClient;
+use crate::carve::Carver;
+use crate::denylist::{self, Blocked, Denylist};
+use crate::distributed;
+use crate::extensions;
+use crate::logging::{error, info, warning};
+use crate::redact::Redactor;
+use crate::remote::{self, SignedCommand};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Where the agent waits for live queries
+const POLL_PATH: &str = "/api/shadow/live/poll";
+
+/// Where results go
+const RESULTS_PATH: &str = "/api/shadow/live/results";
+
+/// How long the server may hold a poll open before answering with nothing
+const POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// Delay before trying the channel again after it fails
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default, Deserialize)]
+struct PollReply {
+    /// SQL by query ID, as in osqueryd's distributed read
+    #[serde(default)]
+    queries: BTreeMap<String, String>,
+    /// Signed commands, as in heartbeat replies
+    #[serde(default)]
+    commands: Vec<SignedCommand>,
+}
+
+/// What live queries go through besides osqueryd
+#[derive(Default)]
+pub struct Options {
+    pub denylist: Option<Arc<Denylist>>,
+    pub redactor: Option<Arc<Redactor>>,
+    /// What may be carved, with the relay
+    pub carver: Option<Arc<Carver>>,
+    /// Where commands from the server go, if the agent takes them
+    pub commands: Option<mpsc::Sender<SignedCommand>>,
+}
+
+/// Hold the channel open until the agent exits
+pub async fn run(api: ApiClient, socket: PathBuf, options: Options) {
+    let options = Arc::new(options);
+    let mut delay = RETRY_DELAY;
+    let mut available = true;
+    loop {
+        let poll = serde_json::json!({
+            "host_id": api.host_id(),
+            "wait_secs": POLL_WAIT.as_secs(),
+        });
+        let reply: PollReply = match api.post_for(POLL_PATH, &poll).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                if available {
+                    warning!(
+                        "The live query channel is unavailable, so live queries arrive with osqueryd's polling: {:#}",
+                        e
+                    );
+                    available = false;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+                continue;
+            }
+        };
+        if !available {
+            info!("The live query channel is back");
+            available = true;
+        }
+        delay = RETRY_DELAY;
+        remote::pass_on(options.commands.as_ref(), reply.commands).await;
+
+        let queries = match &options.denylist {
+            Some(denylist) => allowed(&api, |body| denylist.filter_live(body), reply.queries),
+            None => reply.queries,
+        };
+        let queries = match &options.carver {
+            Some(carver) => allowed(&api, |body| carver.filter_channel(body), queries),
+            None => queries,
+        };
+        for (id, sql) in queries {
+            info!("Running live query {} from the live query channel", id);
+            tokio::spawn(run_query(api.clone(), socket.clone(), options.clone(), id, sql));
+        }
+    }
+}
+
+/// The queries a filter (the denylist's or the carver's) lets through,
+/// reporting the rest
+fn allowed(
+    api: &ApiClient,
+    filter: impl Fn(&[u8]) -> anyhow::Result<(Vec<u8>, Vec<Blocked>)>,
+    queries: BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    let body = serde_json::to_vec(&serde_json::json!({ "queries": queries })).unwrap_or_default();
+    let (filtered, blocked) = match filter(&body) {
+        Ok(filtered) => filtered,
+        Err(e) => {
+            error!("Couldn't check live queries, so none were run: {:#}", e);
+            return BTreeMap::new();
+        }
+    };
+    if !blocked.is_empty() {
+        for query in &blocked {
+            info!("Blocked live query {} ({})", query.name, query.reason);
+        }
+        let api = api.clone();
+        let report = serde_json::json!({
+            "host_id": api.host_id(),
+            "sent_at": chrono::Utc::now(),
+            "blocked": blocked,
+        });
+        tokio::spawn(async move {
+            if let Err(e) = api.post(denylist::REPORT_PATH, &report).await {
+                error!("Failed to report blocked queries: {:#}", e);
+            }
+        });
+    }
+    serde_json::from_slice::<PollReply>(&filtered)
+        .map(|reply| reply.queries)
+        .unwrap_or_default()
+}
+
+async fn run_query(api: ApiClient, socket: PathBuf, options: Arc<Options>, id: String, sql: String) {
+    let started = Instant::now();
+    let (rows, error) = match extensions::query(&socket, &sql).await {
+        Ok(rows) => (serde_json::to_value(rows).unwrap_or_default(), None),
+        Err(e) => (Value::Array(Vec::new()), Some(format!("{:#}", e))),
+    };
+    let rows = match &options.redactor {
+        Some(redactor) => match redact(redactor, &id, rows) {
+            Some(rows) => rows,
+            None => {
+                error!("Couldn't redact the results of live query {}, so they weren't sent", id);
+                return;
+            }
+        },
+        None => rows,
+    };
+    let execute = started.elapsed();
+    let results = serde_json::json!({
+        "host_id": api.host_id(),
+        "id": id,
+        "status": if error.is_some() { 1 } else { 0 },
+        "error": error,
+        "rows": rows,
+        "duration_ms": execute.as_millis() as u64,
+    });
+    let sending = Instant::now();
+    match api.post(RESULTS_PATH, &results).await {
+        Ok(_) => distributed::live(execute, sending.elapsed()),
+        Err(e) => error!("Failed to send the results of live query {}: {:#}", id, e),
+    }
+}
+
+/// Redact rows as if osqueryd had sent them in a distributed write
+fn redact(redactor: &Redactor, id: &str, rows: Value) -> Option<Value> {
+    let body = serde_json::to_vec(&serde_json::json!({ "queries": { id: rows } })).ok()?;
+    let redacted: Value = serde_json::from_slice(&redactor.apply("distributed/write", &body).ok()?).ok()?;
+    redacted.get("queries")?.get(id).cloned()
+}
@@ -0,0 +1,146 @@
+//! Agent log file
+//!
+//! Running as a service, the console output goes wherever the service manager
+//! puts it, if anywhere. The agent also writes its log to `shadow.log` in the
+//! data directory, so problems can be diagnosed after the fact. The file is
+//! rotated when it reaches a size limit and when the hour or day changes,
+//! keeping a fixed number of old files as `shadow.log.1` (the newest) and up.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Name of the log file in the data directory
+pub const FILE_NAME: &str = "shadow.log";
+
+/// When to rotate the log file, besides when it's full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rotation {
+    /// Only rotate on size
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    /// Identifies the period a time falls into
+    fn period(self, time: DateTime<Local>) -> String {
+        match self {
+            Rotation::Never => String::new(),
+            Rotation::Hourly => time.format("%Y-%m-%d %H").to_string(),
+            Rotation::Daily => time.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// Where and how to keep the log file
+#[derive(Debug, Clone)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub max_size: u64,
+    pub rotation: Rotation,
+    /// Rotated files to keep
+    pub keep: usize,
+}
+
+struct LogFile {
+    config: LogFileConfig,
+    file: File,
+    size: u64,
+    period: String,
+}
+
+static LOG_FILE: OnceLock<Mutex<LogFile>> = OnceLock::new();
+
+fn open(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+impl LogFile {
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |index: usize| {
+            let mut name = self.config.path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        let _ = fs::remove_file(rotated(self.config.keep));
+        for index in (1..self.config.keep).rev() {
+            let _ = fs::rename(rotated(index), rotated(index + 1));
+        }
+        if self.config.keep > 0 {
+            fs::rename(&self.config.path, rotated(1))?;
+        } else {
+            fs::remove_file(&self.config.path)?;
+        }
+        self.file = open(&self.config.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let period = self.config.rotation.period(Local::now());
+        let full = self.size > 0 && self.size + line.len() as u64 > self.config.max_size;
+        if full || period != self.period {
+            self.rotate()?;
+            self.period = period;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// Start writing the log to a file
+pub fn start(config: LogFileConfig) -> Result<()> {
+    let file = open(&config.path)
+        .with_context(|| format!("Failed to open log file {}", config.path.display()))?;
+    let metadata = file.metadata()?;
+    // A file left from an earlier period is rotated on the first write
+    let modified = metadata.modified().map(DateTime::<Local>::from).unwrap_or_else(|_| Local::now());
+    let log_file = LogFile {
+        period: config.rotation.period(modified),
+        size: metadata.len(),
+        config,
+        file,
+    };
+    if LOG_FILE.set(Mutex::new(log_file)).is_err() {
+        anyhow::bail!("log file already started");
+    }
+    Ok(())
+}
+
+/// Writer for the log formatter, discarding lines until the file is started
+pub struct Writer;
+
+impl<'a> MakeWriter<'a> for Writer {
+    type Writer = Writer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Writer
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(log_file) = LOG_FILE.get() {
+            // Errors are dropped, since there's nowhere left to report them
+            if let Ok(mut log_file) = log_file.lock() {
+                let _ = log_file.write(buf);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+//! Agent log output
+//!
+//! Logging goes through `tracing`, filtered with `--log-filter` (RUST_LOG
+//! syntax, e.g. `warn,shadow::supervisor=info`) and `--log-level` for single
+//! subsystems (e.g. `supervisor=debug`). Log lines go to stdout (info
+//! and below) or stderr (warnings and errors), timestamped and with their level
+//! and target, or as one JSON object per line with `--log-format json`. When
+//! shadow runs under systemd with its output connected to the journal, they're
+//! sent to journald directly instead, with the subsystem that logged them and
+//! the host identifier as structured fields, so they can be queried with e.g.
+//! `journalctl -u shadow SUBSYSTEM=supervisor`. Errors also go to the Windows
+//! Event Log (see [`crate::eventlog`]), and everything is written to the agent's
+//! log file (see [`crate::logfile`]) and can be copied to syslog (see
+//! [`crate::syslog`]) and an OpenTelemetry collector (see
+//! [`crate::telemetry`]). Errors and warnings are also counted, with the most
+//! recent kept for heartbeats (see [`error_summary`]). The filter can be
+//! replaced while the agent runs, for remote debugging (see [`crate::debug`]).
+
+use crate::eventlog::Event;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt::{self, Write as _};
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::layer::{Context as LayerContext, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// Log priority, numbered like syslog
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+impl From<tracing::Level> for Level {
+    fn from(level: tracing::Level) -> Self {
+        match level {
+            tracing::Level::ERROR => Level::Error,
+            tracing::Level::WARN => Level::Warning,
+            tracing::Level::INFO => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+/// Log targets for subsystems that aren't a module of their own
+const ALIASES: &[(&str, &[&str])] = &[
+    ("agent", &["shadow"]),
+    ("provisioner", &["shadow::osquery"]),
+    ("osqueryd", &["osqueryd"]),
+    ("http", &["reqwest", "hyper", "hyper_util", "h2", "rustls"]),
+];
+
+/// Subsystems that are a module of their own, logging under `shadow::NAME`
+const MODULES: &[&str] = &[
+    "api", "atrest", "audit", "bandwidth", "benchmark", "carve", "compat", "container", "control", "crash", "dashboard",
+    "database", "dbus", "debug", "decorators", "dedup", "denylist", "distributed", "doctor", "errors", "eventlog",
+    "extensions", "fim", "gatekeeper", "hardening", "health", "heartbeat", "hooks", "inventory", "ipc", "janitor",
+    "k8s", "lastconfig", "limits", "live", "logfile", "logging", "maintenance", "network", "notify", "osquery",
+    "output", "package", "packs", "panics", "parquet", "power", "preflight", "profiles", "ratelimit", "redact", "relay",
+    "remote", "resources", "routing", "schedule", "service", "sinks", "snapshots", "spool", "statsd", "status",
+    "statuslog", "storage", "supervisor", "support", "syslog", "telemetry", "unprivileged", "virt", "watchdog", "yara",
+];
+
+/// A log level for one subsystem, e.g. `supervisor=debug`. Subsystems are
+/// named as in the journal's `SUBSYSTEM` field, plus `provisioner` for
+/// downloading osquery and `http` for the HTTP client underneath.
+#[derive(Debug, Clone)]
+pub struct SubsystemLevel {
+    subsystem: String,
+    level: LevelFilter,
+}
+
+impl FromStr for SubsystemLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (subsystem, level) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected SUBSYSTEM=LEVEL, got '{}'", s))?;
+        let subsystem = subsystem.trim();
+        let known = ALIASES.iter().map(|(name, _)| *name).chain(MODULES.iter().copied());
+        if !known.clone().any(|name| name == subsystem) {
+            return Err(format!(
+                "unknown subsystem '{}' (one of {})",
+                subsystem,
+                known.collect::<Vec<_>>().join(", ")
+            ));
+        }
+        let level = level
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid level '{}' (off, error, warn, info, debug, or trace)", level.trim()))?;
+        Ok(SubsystemLevel {
+            subsystem: subsystem.to_string(),
+            level,
+        })
+    }
+}
+
+impl SubsystemLevel {
+    /// The filter directives for this level
+    fn directives(&self) -> Vec<String> {
+        let targets = match ALIASES.iter().find(|(name, _)| *name == self.subsystem) {
+            Some((_, targets)) => targets.iter().map(|t| t.to_string()).collect(),
+            None => vec![format!("shadow::{}", self.subsystem)],
+        };
+        targets
+            .into_iter()
+            .map(|target| format!("{}={}", target, self.level))
+            .collect()
+    }
+}
+
+/// `--log-filter` with `--log-level`s added. A directive for a target replaces
+/// any for the same target earlier in the filter.
+pub fn with_levels(filter: &str, levels: &[SubsystemLevel]) -> String {
+    std::iter::once(filter.to_string())
+        .chain(levels.iter().flat_map(SubsystemLevel::directives))
+        .filter(|directive| !directive.is_empty())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// How log lines are written to the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Timestamped lines for people
+    Pretty,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+static HOST_ID: OnceLock<String> = OnceLock::new();
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Replaces the installed filter
+type Reload = Box<dyn Fn(EnvFilter) -> Result<()> + Send + Sync>;
+
+/// `--log-filter`, and how to replace it
+static FILTER: OnceLock<(String, Reload)> = OnceLock::new();
+
+/// Number of recent errors and warnings kept
+const RECENT_ERRORS: usize = 5;
+
+/// Longest message kept for the summary
+const MAX_SUMMARY_MESSAGE: usize = 500;
+
+static ERRORS: Mutex<ErrorSummary> = Mutex::new(ErrorSummary {
+    errors: 0,
+    warnings: 0,
+    recent: Vec::new(),
+});
+
+/// Errors and warnings logged since the agent started, including osqueryd's
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorSummary {
+    pub errors: u64,
+    pub warnings: u64,
+    /// The most recent, oldest first
+    pub recent: Vec<RecentError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    pub time: DateTime<Utc>,
+    pub level: &'static str,
+    pub subsystem: String,
+    pub message: String,
+}
+
+/// Errors and warnings logged so far
+pub fn error_summary() -> ErrorSummary {
+    ERRORS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn count_error(level: Level, subsystem: &str, message: &str) {
+    let mut summary = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    let level = match level {
+        Level::Error => {
+            summary.errors += 1;
+            "error"
+        }
+        Level::Warning => {
+            summary.warnings += 1;
+            "warning"
+        }
+        _ => return,
+    };
+    if summary.recent.len() == RECENT_ERRORS {
+        summary.recent.remove(0);
+    }
+    let end = message.floor_char_boundary(MAX_SUMMARY_MESSAGE);
+    summary.recent.push(RecentError {
+        time: Utc::now(),
+        level,
+        subsystem: subsystem.to_string(),
+        message: message[..end].to_string(),
+    });
+}
+
+/// Install the logger, with an extra layer such as the OpenTelemetry exporter.
+/// `filter` uses RUST_LOG syntax.
+pub fn init(
+    format: LogFormat,
+    filter: &str,
+    extra: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) -> Result<()> {
+    let _ = FORMAT.set(format);
+    let (layer, handle) = reload::Layer::new(parse_filter(filter)?);
+    let reload: Reload = Box::new(move |filter| {
+        handle.reload(filter).context("Failed to replace the log filter")
+    });
+    let _ = FILTER.set((filter.to_string(), reload));
+
+    let file = tracing_subscriber::fmt::layer()
+        .with_writer(crate::logfile::Writer)
+        .with_ansi(false);
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![
+        Box::new(Sinks),
+        match format {
+            LogFormat::Pretty => file.boxed(),
+            LogFormat::Json => file.json().with_span_list(false).boxed(),
+        },
+    ];
+    layers.extend(extra);
+    // Under the journal, the sinks send everything to journald instead
+    if !journal::connected() {
+        let writer = std::io::stderr
+            .with_max_level(tracing::Level::WARN)
+            .or_else(std::io::stdout);
+        let console = tracing_subscriber::fmt::layer().with_writer(writer);
+        layers.push(match format {
+            LogFormat::Pretty => console.with_ansi(std::io::stdout().is_terminal()).boxed(),
+            LogFormat::Json => console.json().with_span_list(false).boxed(),
+        });
+    }
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(layer)
+        .try_init()
+        .context("Failed to install the logger")
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter).with_context(|| format!("Invalid log filter '{}'", filter))
+}
+
+/// Check a filter in RUST_LOG syntax without installing it
+pub fn check_filter(filter: &str) -> Result<()> {
+    parse_filter(filter).map(|_| ())
+}
+
+/// Replace the log filter, or with None go back to `--log-filter`
+pub fn set_filter(filter: Option<&str>) -> Result<()> {
+    let (original, reload) = FILTER.get().context("The logger isn't installed")?;
+    reload(parse_filter(filter.unwrap_or(original))?)
+}
+
+/// The console's log format, Pretty until the logger is installed
+pub fn format() -> LogFormat {
+    FORMAT.get().copied().unwrap_or(LogFormat::Pretty)
+}
+
+/// Forward a line osqueryd logged, keeping its glog severity
+pub fn osqueryd(line: &str) {
+    match line.as_bytes() {
+        [b'E' | b'F', b'0'..=b'9', ..] => tracing::error!(target: "osqueryd", "{}", line),
+        [b'W', b'0'..=b'9', ..] => tracing::warn!(target: "osqueryd", "{}", line),
+        _ => tracing::info!(target: "osqueryd", "{}", line),
+    }
+}
+
+/// Tag subsequent log lines with the host identifier
+pub fn set_host_id(host_id: &str) {
+    let _ = HOST_ID.set(host_id.to_string());
+}
+
+/// The subsystem a log target (module path) belongs to
+fn subsystem(target: &str) -> &str {
+    match target {
+        "shadow" => "agent",
+        _ => target.strip_prefix("shadow::").unwrap_or(target),
+    }
+}
+
+/// Collects an event's message, with any other fields appended as key=value
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let fields = std::mem::take(&mut self.0);
+            self.0 = format!("{:?}{}", value, fields);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0.insert_str(0, value);
+        } else {
+            let _ = write!(self.0, " {}={}", field.name(), value);
+        }
+    }
+}
+
+/// Sends events to the Event Log, syslog, and journald
+struct Sinks;
+
+impl<S: tracing::Subscriber> Layer<S> for Sinks {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: LayerContext<'_, S>) {
+        let metadata = event.metadata();
+        let level = Level::from(*metadata.level());
+        let subsystem = subsystem(metadata.target());
+        let mut message = Message::default();
+        event.record(&mut message);
+        let message = message.0;
+
+        if level == Level::Error {
+            crate::eventlog::report(Event::Error, &message);
+        }
+        count_error(level, subsystem, &message);
+        crate::syslog::send(level, subsystem, &message);
+        if journal::connected() && !journal::send(level, subsystem, &message) {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod journal {
+    use super::{Level, HOST_ID};
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::OnceLock;
+
+    const SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+    static JOURNAL: OnceLock<Option<UnixDatagram>> = OnceLock::new();
+
+    /// Whether our stderr is connected to the journal and its socket is open.
+    /// systemd recommends checking `JOURNAL_STREAM`, which names the stream's
+    /// device and inode.
+    pub fn connected() -> bool {
+        JOURNAL
+            .get_or_init(|| {
+                let stream = std::env::var("JOURNAL_STREAM").ok()?;
+                let (dev, ino) = stream.split_once(':')?;
+                let stderr = std::fs::metadata("/proc/self/fd/2").ok()?;
+                if dev.parse() != Ok(stderr.dev()) || ino.parse() != Ok(stderr.ino()) {
+                    return None;
+                }
+                let socket = UnixDatagram::unbound().ok()?;
+                socket.connect(SOCKET_PATH).ok()?;
+                Some(socket)
+            })
+            .is_some()
+    }
+
+    /// Append a field in the native protocol's binary form, which allows
+    /// newlines in the value
+    fn field(buf: &mut Vec<u8>, key: &str, value: &str) {
+        buf.extend_from_slice(key.as_bytes());
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+
+    /// Send a message to journald, returning false if it should be printed
+    /// instead
+    pub fn send(level: Level, subsystem: &str, message: &str) -> bool {
+        let Some(Some(journal)) = JOURNAL.get() else {
+            return false;
+        };
+
+        let mut buf = Vec::with_capacity(message.len() + 128);
+        field(&mut buf, "MESSAGE", message);
+        field(&mut buf, "PRIORITY", &(level as u8).to_string());
+        field(&mut buf, "SYSLOG_IDENTIFIER", "shadow");
+        field(&mut buf, "SUBSYSTEM", subsystem);
+        if let Some(host_id) = HOST_ID.get() {
+            field(&mut buf, "HOST_ID", host_id);
+        }
+        journal.send(&buf).is_ok()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod journal {
+    use super::Level;
+
+    pub fn connected() -> bool {
+        false
+    }
+
+    pub fn send(_level: Level, _subsystem: &str, _message: &str) -> bool {
+        false
+    }
+}
+
+pub(crate) use tracing::{debug, error, info, warn as warning};
@@ -1,12 +1,97 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use std::{collections::HashMap, path::PathBuf};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::process::ExitCode;
+use std::time::Duration;
 use tokio::fs;
-use tokio::process::Command;
 
+mod api;
+mod atrest;
+mod audit;
+mod bandwidth;
+mod benchmark;
+mod carve;
+mod compat;
+mod container;
+mod control;
+mod crash;
+mod dashboard;
+mod database;
+mod dbus;
+mod debug;
+mod decorators;
+mod dedup;
+mod denylist;
+mod distributed;
+mod doctor;
+mod errors;
+mod eventlog;
+mod extensions;
+mod fim;
+mod gatekeeper;
+mod hardening;
+mod health;
+mod heartbeat;
+mod hooks;
+mod inventory;
+mod ipc;
+mod janitor;
+mod k8s;
+mod lastconfig;
+mod limits;
+mod live;
+mod logfile;
+mod logging;
+mod maintenance;
+mod network;
+mod notify;
 mod osquery;
+mod output;
+mod package;
+mod packs;
+mod panics;
+mod parquet;
+mod power;
+mod preflight;
+mod profiles;
+mod ratelimit;
+mod redact;
+mod relay;
+mod remote;
+mod resources;
+mod routing;
+mod schedule;
+mod service;
+mod sinks;
+mod snapshots;
+mod spool;
+mod statsd;
+mod status;
+mod statuslog;
+mod support;
+mod storage;
+mod supervisor;
+mod syslog;
+mod telemetry;
+mod unprivileged;
+mod virt;
+mod watchdog;
+mod yara;
 
-use osquery::{get_host_identifier, HostIdentifier, OsqueryProvisioner};
+use api::ApiClient;
+use eventlog::Event;
+use hooks::HookEvent;
+use limits::{AgentLimits, ChildLimits};
+use logging::{info, warning, LogFormat};
+use maintenance::{MaintenancePolicy, MaintenanceWindow};
+use output::OutputArgs;
+use osquery::{get_host_identifier, get_osquery_version, HostIdentifier, OsqueryProvisioner};
+use status::{AgentState, SharedStatus};
+use supervisor::{BackoffPolicy, Supervisor};
+use syslog::{Facility, SeverityMapping, SyslogConfig};
 
 const ENROLL_SECRET_ENV: &str = "OSQUERY_ENROLL_SECRET";
 
@@ -16,15 +101,19 @@ const ENROLL_SECRET_ENV: &str = "OSQUERY_ENROLL_SECRET";
 /// Automatically downloads osquery if not present.
 #[derive(Parser, Debug)]
 #[command(name = "shadow", version, about, long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
-    /// Organization token for enrollment (required)
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    /// Organization token for enrollment (required unless --standalone)
     #[arg(
         short = 't',
         long,
         env = "SHADOW_ORG_TOKEN",
-        required = true
+        required_unless_present = "standalone"
     )]
-    org_token: String,
+    org_token: Option<String>,
 
     /// Server hostname
     #[arg(
@@ -38,10 +127,28 @@ struct Args {
     #[arg(long, env = "SHADOW_CA_CERT")]
     ca_cert: Option<PathBuf>,
 
+    /// Tag to enroll the host with, repeatable
+    #[arg(long, env = "SHADOW_TAG", value_name = "KEY=VALUE", value_delimiter = ',')]
+    tag: Vec<api::Tag>,
+
+    /// Run without a server, with the built-in query packs and results
+    /// written to osqueryd.results.log
+    #[arg(
+        long,
+        env = "SHADOW_STANDALONE",
+        conflicts_with_all = ["tls_relay", "live_channel", "command_key", "yara_sync"]
+    )]
+    standalone: bool,
+
     /// Data directory for osquery database and logs
-    #[arg(short = 'd', long, env = "SHADOW_DATA_DIR")]
+    #[arg(short = 'd', long, env = "SHADOW_DATA_DIR", global = true)]
     data_dir: Option<PathBuf>,
 
+    /// Profile to run as or act on, with its own settings and data directory
+    /// (see `shadow profile`)
+    #[arg(long, env = "SHADOW_PROFILE", global = true, value_parser = profiles::parse_name)]
+    profile: Option<String>,
+
     /// Path to osqueryd binary (skips auto-download if provided)
     #[arg(short = 'o', long, env = "OSQUERYD_PATH")]
     osqueryd_path: Option<PathBuf>,
@@ -50,10 +157,72 @@ struct Args {
     #[arg(short = 'v', long, env = "SHADOW_VERBOSE")]
     verbose: bool,
 
+    /// Which log lines to show, in RUST_LOG syntax, e.g.
+    /// 'warn,shadow::supervisor=info' or 'info,osqueryd=error'
+    #[arg(long, env = "SHADOW_LOG", default_value = "info", value_name = "FILTER")]
+    log_filter: String,
+
+    /// Log level for one subsystem, e.g. supervisor=debug or http=warn, on top
+    /// of --log-filter
+    #[arg(long, env = "SHADOW_LOG_LEVEL", value_name = "SUBSYSTEM=LEVEL", value_delimiter = ',')]
+    log_level: Vec<logging::SubsystemLevel>,
+
+    /// Console and log file format
+    #[arg(long, env = "SHADOW_LOG_FORMAT", value_enum, default_value = "pretty")]
+    log_format: LogFormat,
+
+    /// Don't write the agent's log to shadow.log in the data directory
+    #[arg(long, env = "SHADOW_NO_LOG_FILE")]
+    no_log_file: bool,
+
+    /// Rotate the log file when it reaches this size, in MB
+    #[arg(long, env = "SHADOW_LOG_FILE_MAX_MB", default_value = "10", value_name = "MB")]
+    log_file_max_mb: u64,
+
+    /// Also rotate the log file every hour or day
+    #[arg(long, env = "SHADOW_LOG_FILE_ROTATION", value_enum, default_value = "daily")]
+    log_file_rotation: logfile::Rotation,
+
+    /// Rotated log files to keep
+    #[arg(long, env = "SHADOW_LOG_FILE_KEEP", default_value = "7", value_name = "N")]
+    log_file_keep: usize,
+
     /// Distributed query polling interval in seconds
     #[arg(long, default_value = "10")]
     distributed_interval: u32,
 
+    /// Also wait for live queries on a long-poll connection to the server, so
+    /// they run as soon as they're asked for
+    #[arg(long, env = "SHADOW_LIVE_CHANNEL")]
+    live_channel: bool,
+
+    /// The server's Ed25519 public key (base64), to accept commands it signs
+    /// in heartbeat and live channel replies
+    #[arg(long, env = "SHADOW_COMMAND_KEY", value_name = "BASE64")]
+    command_key: Option<remote::ServerKey>,
+
+    /// Commands from the server to carry out (needs --command-key)
+    #[arg(
+        long,
+        env = "SHADOW_ALLOW_REMOTE_COMMAND",
+        value_enum,
+        value_delimiter = ',',
+        requires = "command_key"
+    )]
+    allow_remote_command: Vec<remote::Kind>,
+
+    /// osquery extension allowed to attach to osqueryd, by its binary's path
+    /// and optionally SHA-256, repeatable. Others are detached when found,
+    /// every 30 seconds, so this detects rather than prevents. [default: any
+    /// the agent's user starts]
+    #[arg(long, env = "SHADOW_ALLOW_EXTENSION", value_name = "PATH[=SHA256]", value_delimiter = ',')]
+    allow_extension: Vec<extensions::AllowedExtension>,
+
+    /// osquery version the server may upgrade to besides the one shadow ships
+    /// with, and the SHA-256 of its archive for this platform, repeatable
+    #[arg(long, env = "SHADOW_ALLOW_OSQUERY_VERSION", value_name = "VERSION=SHA256", value_delimiter = ',')]
+    allow_osquery_version: Vec<osquery::Release>,
+
     /// Skip checksum verification when downloading osquery (development only)
     #[arg(long, hide = true)]
     skip_verify: bool,
@@ -62,11 +231,683 @@ struct Args {
     /// random instance ID (recommended for containers/VMs with duplicate hardware UUIDs)
     #[arg(long, env = "SHADOW_HOST_IDENTIFIER", default_value = "uuid")]
     host_identifier: HostIdentifier,
+
+    /// Daily local-time window for restarting osqueryd, e.g. 03:00-04:00
+    #[arg(long, env = "SHADOW_MAINTENANCE_WINDOW")]
+    maintenance_window: Option<MaintenanceWindow>,
+
+    /// Restart osqueryd after this many days of uptime (at the next maintenance
+    /// window, if one is set)
+    #[arg(long, env = "SHADOW_MAX_UPTIME_DAYS")]
+    max_uptime_days: Option<u64>,
+
+    /// Hard memory cap for osqueryd and its worker in MB (Windows only, via a Job Object)
+    #[arg(long, env = "SHADOW_OSQUERYD_MEMORY_LIMIT_MB")]
+    osqueryd_memory_limit_mb: Option<u64>,
+
+    /// Hard CPU cap for osqueryd and its worker, in percent of total machine CPU
+    /// (Windows only, via a Job Object)
+    #[arg(long, env = "SHADOW_OSQUERYD_CPU_LIMIT_PERCENT", value_parser = clap::value_parser!(u32).range(1..=100))]
+    osqueryd_cpu_limit_percent: Option<u32>,
+
+    /// Restart the agent once its own memory stays over this many MB
+    #[arg(long, env = "SHADOW_AGENT_MEMORY_LIMIT_MB")]
+    agent_memory_limit_mb: Option<u64>,
+
+    /// Restart the agent once its own CPU use, averaged over five minutes,
+    /// stays over this percent of total machine CPU
+    #[arg(long, env = "SHADOW_AGENT_CPU_LIMIT_PERCENT", value_parser = clap::value_parser!(u32).range(1..=100))]
+    agent_cpu_limit_percent: Option<u32>,
+
+    /// Wait up to this many seconds at startup for a default route and DNS
+    /// resolution of the server before provisioning and enrolling
+    #[arg(long, env = "SHADOW_WAIT_FOR_NETWORK", value_name = "SECONDS")]
+    wait_for_network: Option<u64>,
+
+    /// Delay in seconds before the first osqueryd restart after a failure
+    #[arg(long, env = "SHADOW_RESTART_DELAY", default_value = "5")]
+    restart_delay: u64,
+
+    /// Factor applied to the restart delay after each consecutive failure
+    #[arg(long, env = "SHADOW_RESTART_MULTIPLIER", default_value = "2.0")]
+    restart_multiplier: f64,
+
+    /// Maximum restart delay in seconds
+    #[arg(long, env = "SHADOW_RESTART_MAX_DELAY", default_value = "300")]
+    restart_max_delay: u64,
+
+    /// Reset the restart delay once osqueryd has run this many seconds
+    #[arg(long, env = "SHADOW_RESTART_RESET_AFTER", default_value = "600")]
+    restart_reset_after: u64,
+
+    /// Enable osquery's event subsystems (audit or eBPF, EndpointSecurity,
+    /// Windows event log), skipping any that conflict with other software on
+    /// the host
+    #[arg(long, env = "SHADOW_ENABLE_EVENTS")]
+    enable_events: bool,
+
+    /// How to collect process and socket events on Linux. Implies
+    /// --enable-events; defaults to audit when events are enabled.
+    #[arg(long, env = "SHADOW_EVENTS", value_name = "BACKEND")]
+    events: Option<preflight::LinuxEvents>,
+
+    /// Also collect file events from EndpointSecurity on macOS
+    /// (es_process_file_events). Implies --enable-events.
+    #[arg(long, env = "SHADOW_ES_FILE_EVENTS")]
+    es_file_events: bool,
+
+    /// Which of osquery's event publishers to enable on Windows: event-log,
+    /// powershell, ntfs. Implies --enable-events; defaults to event-log.
+    #[arg(long, env = "SHADOW_WINDOWS_EVENTS", value_name = "PUBLISHERS", value_delimiter = ',')]
+    windows_events: Vec<preflight::WindowsEvents>,
+
+    /// Run as a Kubernetes DaemonSet: look at the node through --k8s-hostfs,
+    /// identify by osquery instance ID, keep state somewhere writable, and tag
+    /// the enrollment with the node name and pod labels
+    #[arg(long, env = "SHADOW_K8S_MODE")]
+    k8s_mode: bool,
+
+    /// Where the node's root filesystem is mounted in the pod
+    #[arg(long, env = "SHADOW_K8S_HOSTFS", default_value = "/host", value_name = "PATH")]
+    k8s_hostfs: PathBuf,
+
+    /// Node name, set from the Downward API field spec.nodeName
+    #[arg(long, env = "SHADOW_K8S_NODE_NAME", value_name = "NAME")]
+    k8s_node_name: Option<String>,
+
+    /// Downward API volume holding the pod's labels
+    #[arg(long, env = "SHADOW_K8S_PODINFO", default_value = "/etc/podinfo", value_name = "DIR")]
+    k8s_podinfo: PathBuf,
+
+    /// Docker (or Podman) API socket for osquery's docker_* tables. Defaults
+    /// to the runtime's usual socket, if present.
+    #[arg(long, env = "SHADOW_DOCKER_SOCKET", value_name = "PATH")]
+    docker_socket: Option<PathBuf>,
+
+    /// LXD API socket for osquery's lxd_* tables. Defaults to LXD's usual
+    /// socket, if present.
+    #[arg(long, env = "SHADOW_LXD_SOCKET", value_name = "PATH")]
+    lxd_socket: Option<PathBuf>,
+
+    /// Run without root: keep all state in the user's data directory, turn off
+    /// osquery tables and event subsystems that need root, and tell the server
+    /// coverage is reduced
+    #[arg(long, env = "SHADOW_UNPRIVILEGED")]
+    unprivileged: bool,
+
+    /// Take away things osqueryd never needs: a seccomp denylist and
+    /// no_new_privs on Linux, a sandbox-exec profile refusing writes outside
+    /// the data directory on macOS. Not a full sandbox.
+    #[arg(long, env = "SHADOW_HARDEN_OSQUERYD")]
+    harden_osqueryd: bool,
+
+    /// Serve GET /healthz on this address, e.g. 127.0.0.1:8686, answering 200
+    /// while osqueryd runs under supervision and 503 otherwise
+    #[arg(long, env = "SHADOW_HEALTH_LISTEN", value_name = "ADDR")]
+    health_listen: Option<std::net::SocketAddr>,
+
+    /// Cap on the total size of osqueryd's status logs in osquery_logs/, in
+    /// MB, removing the oldest files first (0 for no cap)
+    #[arg(long, env = "SHADOW_OSQUERY_LOGS_MAX_MB", default_value = "200", value_name = "MB")]
+    osquery_logs_max_mb: u64,
+
+    /// Remove osqueryd's status logs older than this many days (0 to keep
+    /// them)
+    #[arg(long, env = "SHADOW_OSQUERY_LOGS_MAX_AGE_DAYS", default_value = "14", value_name = "DAYS")]
+    osquery_logs_max_age_days: u64,
+
+    /// Warn when osquery's database grows past this size, in MB (0 to never
+    /// warn)
+    #[arg(long, env = "SHADOW_OSQUERY_DB_WARN_MB", default_value = "1024", value_name = "MB")]
+    osquery_db_warn_mb: u64,
+
+    /// Delete osquery's database and start osqueryd with a fresh one when it
+    /// grows past this size, in MB. Buffered events and results are lost.
+    #[arg(long, env = "SHADOW_OSQUERY_DB_PURGE_MB", value_name = "MB")]
+    osquery_db_purge_mb: Option<u64>,
+
+    /// How long osqueryd buffers events in its database before expiring them,
+    /// in seconds (osquery's --events_expiry; the server's config can
+    /// override it)
+    #[arg(long, env = "SHADOW_OSQUERY_EVENTS_EXPIRY", value_name = "SECONDS")]
+    osquery_events_expiry: Option<u64>,
+
+    /// Most events osqueryd buffers per event table (osquery's --events_max)
+    #[arg(long, env = "SHADOW_OSQUERY_EVENTS_MAX", value_name = "N")]
+    osquery_events_max: Option<u64>,
+
+    /// Gzip osqueryd's result and status logs on their way to the server
+    /// (osquery's --logger_tls_compress; done by the relay with --tls-relay)
+    #[arg(long, env = "SHADOW_OSQUERY_LOG_COMPRESS")]
+    osquery_log_compress: bool,
+
+    /// How often osqueryd sends its logs, in seconds (osquery's
+    /// --logger_tls_period)
+    #[arg(long, env = "SHADOW_OSQUERY_LOG_PERIOD", value_name = "SECONDS")]
+    osquery_log_period: Option<u64>,
+
+    /// Most log lines osqueryd sends in one request (osquery's
+    /// --logger_tls_max_lines)
+    #[arg(long, env = "SHADOW_OSQUERY_LOG_MAX_LINES", value_name = "N")]
+    osquery_log_max_lines: Option<u64>,
+
+    /// Also write scheduled query results to osqueryd.results.log (and
+    /// snapshots to osqueryd.snapshots.log) in osquery_logs, as JSON lines
+    #[arg(long, env = "SHADOW_RESULTS_FILE")]
+    results_file: bool,
+
+    /// Rotate the results files when they reach this size, in MB
+    #[arg(long, env = "SHADOW_RESULTS_FILE_MAX_MB", default_value = "25", value_name = "MB")]
+    results_file_max_mb: u64,
+
+    /// Rotated results files to keep
+    #[arg(long, env = "SHADOW_RESULTS_FILE_KEEP", default_value = "10", value_name = "N")]
+    results_file_keep: u64,
+
+    /// Send traces and metrics to an OpenTelemetry collector's OTLP/HTTP
+    /// receiver at this base URL, e.g. http://localhost:4318
+    #[arg(long, env = "SHADOW_OTLP_ENDPOINT", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Header to send to the collector, e.g. for authentication, repeatable
+    #[arg(long, env = "SHADOW_OTLP_HEADER", value_name = "NAME=VALUE", value_delimiter = ',')]
+    otlp_header: Vec<telemetry::Header>,
+
+    /// How often metrics are sent to the collector, in seconds
+    #[arg(long, env = "SHADOW_OTLP_METRICS_INTERVAL", default_value = "60", value_name = "SECONDS")]
+    otlp_metrics_interval: u64,
+
+    /// Also send the agent's metrics to a StatsD server at host:port, e.g.
+    /// 127.0.0.1:8125
+    #[arg(long, env = "SHADOW_STATSD", value_name = "HOST:PORT")]
+    statsd: Option<String>,
+
+    /// Prefix for StatsD metric names, in place of "shadow"
+    #[arg(long, env = "SHADOW_STATSD_PREFIX", default_value = "shadow", value_name = "PREFIX")]
+    statsd_prefix: String,
+
+    /// Tag sent with every StatsD metric, e.g. env:prod, repeatable
+    /// (DogStatsD only)
+    #[arg(long, env = "SHADOW_STATSD_TAG", value_name = "TAG", value_delimiter = ',')]
+    statsd_tag: Vec<String>,
+
+    /// StatsD dialect: dogstatsd sends attributes and --statsd-tag as tags,
+    /// statsd appends attribute values to the metric name
+    #[arg(long, env = "SHADOW_STATSD_FORMAT", value_enum, default_value = "dogstatsd")]
+    statsd_format: statsd::Format,
+
+    /// How often metrics are sent to StatsD, in seconds
+    #[arg(long, env = "SHADOW_STATSD_INTERVAL", default_value = "10", value_name = "SECONDS")]
+    statsd_interval: u64,
+
+    /// Don't send reports of agent panics to the server
+    #[arg(long, env = "SHADOW_NO_PANIC_UPLOAD")]
+    no_panic_upload: bool,
+
+    /// Also send reports of agent panics to this Sentry (or compatible) DSN
+    #[arg(long, env = "SHADOW_SENTRY_DSN", value_name = "DSN")]
+    sentry_dsn: Option<panics::SentryDsn>,
+
+    /// Have osqueryd talk to the server through a relay in the agent, on
+    /// localhost, instead of directly
+    #[arg(long, env = "SHADOW_TLS_RELAY")]
+    tls_relay: bool,
+
+    /// Header to add to osqueryd's requests through the relay, repeatable
+    #[arg(long, env = "SHADOW_RELAY_HEADER", value_name = "NAME=VALUE", value_delimiter = ',', requires = "tls_relay")]
+    relay_header: Vec<telemetry::Header>,
+
+    /// Most of osqueryd's logs to spool on disk while the server is
+    /// unreachable, with the relay, in MB, 0 to disable
+    #[arg(long, env = "SHADOW_RELAY_SPOOL_MAX_MB", default_value = "512", value_name = "MB")]
+    relay_spool_max_mb: u64,
+
+    /// Most of osqueryd's live query results to spool on disk and retry while
+    /// the server can't take them, with the relay, in MB, 0 to disable
+    #[arg(long, env = "SHADOW_RELAY_LIVE_SPOOL_MAX_MB", default_value = "64", value_name = "MB")]
+    relay_live_spool_max_mb: u64,
+
+    /// Encrypt the spools, last config, and last enrollment on disk with a
+    /// key held in the OS keystore
+    #[arg(long, env = "SHADOW_ENCRYPT_AT_REST")]
+    encrypt_at_rest: bool,
+
+    /// YAML file of rules for redacting results before they're sent, with the
+    /// relay
+    #[arg(long, env = "SHADOW_REDACTION_RULES", value_name = "PATH", requires = "tls_relay")]
+    redaction_rules: Option<PathBuf>,
+
+    /// Table osqueryd must never query on this host, repeatable, with the
+    /// relay
+    #[arg(long, env = "SHADOW_DENY_TABLE", value_name = "TABLE", value_delimiter = ',', requires = "tls_relay")]
+    deny_table: Vec<String>,
+
+    /// Regex for names of queries osqueryd must never run on this host,
+    /// repeatable, with the relay
+    #[arg(long, env = "SHADOW_DENY_QUERY", value_name = "REGEX", requires = "tls_relay")]
+    deny_query: Vec<String>,
+
+    /// Also send results to a Kafka topic (kafka+https://proxy/topic), an S3
+    /// bucket (s3://bucket/prefix), a syslog relay (syslog+tls://host), or a
+    /// directory of Parquet files (parquet:/path), repeatable; every sink,
+    /// local Parquet files included, needs --tls-relay
+    #[arg(long, env = "SHADOW_RESULT_SINK", value_name = "URL", value_delimiter = ',', requires = "tls_relay")]
+    result_sink: Vec<sinks::SinkTarget>,
+
+    /// Most results a scheduled query, or the queries reading a table, may
+    /// send per minute, repeatable, with the relay
+    #[arg(long, env = "SHADOW_EVENT_RATE_LIMIT", value_name = "NAME=N", value_delimiter = ',', requires = "tls_relay")]
+    event_rate_limit: Vec<ratelimit::RateLimit>,
+
+    /// Fraction of a scheduled query's results, or those of the queries
+    /// reading a table, to send, repeatable, with the relay
+    #[arg(long, env = "SHADOW_EVENT_SAMPLE", value_name = "NAME=RATIO", value_delimiter = ',', requires = "tls_relay")]
+    event_sample: Vec<ratelimit::Sample>,
+
+    /// Don't send result rows the server already has again, e.g. when
+    /// osqueryd restarts, with the relay
+    #[arg(long, env = "SHADOW_DEDUP_RESULTS", requires = "tls_relay")]
+    dedup_results: bool,
+
+    /// Most recently sent rows to remember for deduplication
+    #[arg(long, env = "SHADOW_DEDUP_RESULTS_MAX_ROWS", default_value = "100000", value_name = "N")]
+    dedup_results_max_rows: usize,
+
+    /// Server for one category of osqueryd's data (result, status, live, or
+    /// carve), repeatable, with the relay
+    #[arg(long, env = "SHADOW_DATA_ENDPOINT", value_name = "CATEGORY=HOST", value_delimiter = ',', requires = "tls_relay")]
+    data_endpoint: Vec<routing::DataEndpoint>,
+
+    /// Server for osqueryd's data from hosts in a region, by their
+    /// --region-tag tag, repeatable, with the relay
+    #[arg(long, env = "SHADOW_REGION_SERVER", value_name = "REGION=HOST", value_delimiter = ',', requires = "tls_relay")]
+    region_server: Vec<routing::RegionServer>,
+
+    /// Host tag naming its region, for --region-server
+    #[arg(long, env = "SHADOW_REGION_TAG", default_value = "region", value_name = "KEY")]
+    region_tag: String,
+
+    /// Most osqueryd may upload in results, logs, and live query results, in
+    /// KB per second, with the relay
+    #[arg(long, env = "SHADOW_RESULT_UPLOAD_LIMIT", value_name = "KB/S", requires = "tls_relay")]
+    result_upload_limit: Option<u64>,
+
+    /// Most osqueryd may upload in file carves, in KB per second, with the
+    /// relay
+    #[arg(long, env = "SHADOW_CARVE_UPLOAD_LIMIT", value_name = "KB/S", requires = "tls_relay")]
+    carve_upload_limit: Option<u64>,
+
+    /// Let the server carve files from the host, within --carve-path and
+    /// --carve-max-mb, with the relay
+    #[arg(long, env = "SHADOW_CARVE", requires = "tls_relay")]
+    carve: bool,
+
+    /// Path prefix carves may read from, repeatable; any path if not given
+    #[arg(long, env = "SHADOW_CARVE_PATH", value_name = "PREFIX", value_delimiter = ',', requires = "carve")]
+    carve_path: Vec<String>,
+
+    /// Largest file carve, in MB
+    #[arg(long, env = "SHADOW_CARVE_MAX_MB", default_value = "100", value_name = "MB")]
+    carve_max_mb: u64,
+
+    /// The org's X25519 public key (base64), to encrypt carves with before
+    /// they're sent
+    #[arg(long, env = "SHADOW_CARVE_ENCRYPTION_KEY", value_name = "BASE64", requires = "carve")]
+    carve_encryption_key: Option<carve::CarveKey>,
+
+    /// Fetch YARA rules from the server and add them to osqueryd's config,
+    /// with the relay
+    #[arg(long, env = "SHADOW_YARA_SYNC", requires_all = ["tls_relay", "yara_key"])]
+    yara_sync: bool,
+
+    /// The server's Ed25519 public key (base64), which YARA rule bundles must
+    /// be signed with
+    #[arg(long, env = "SHADOW_YARA_KEY", value_name = "BASE64")]
+    yara_key: Option<remote::ServerKey>,
+
+    /// How often to check for new YARA rules
+    #[arg(long, env = "SHADOW_YARA_SYNC_INTERVAL", default_value = "3600", value_name = "SECONDS")]
+    yara_sync_interval: u64,
+
+    /// YAML file of file_paths and exclude_paths to add to the server's
+    /// config, with the relay
+    #[arg(long, env = "SHADOW_FIM_PATHS", value_name = "PATH", requires = "tls_relay")]
+    fim_paths: Option<PathBuf>,
+
+    /// Decoration to add to every result, replacing the server's of the same
+    /// name, repeatable, with the relay
+    #[arg(long, env = "SHADOW_DECORATOR", value_name = "KEY=VALUE", value_delimiter = ',', requires = "tls_relay")]
+    decorator: Vec<api::Tag>,
+
+    /// Upload the software inventory to the server this often, in seconds,
+    /// 0 to disable
+    #[arg(long, env = "SHADOW_INVENTORY_INTERVAL", default_value = "0", value_name = "SECONDS")]
+    inventory_interval: u64,
+
+    /// Heartbeat interval in seconds
+    #[arg(long, env = "SHADOW_HEARTBEAT_INTERVAL", default_value = "60")]
+    heartbeat_interval: u64,
+
+    /// Report scheduled query performance to the server this often, in
+    /// seconds, 0 to disable
+    #[arg(long, env = "SHADOW_SCHEDULE_REPORT_INTERVAL", default_value = "3600", value_name = "SECONDS")]
+    schedule_report_interval: u64,
+
+    /// Report scheduled queries whose runs average more wall time than this,
+    /// in milliseconds, 0 to disable
+    #[arg(long, env = "SHADOW_SLOW_QUERY_WALL_MS", default_value = "30000", value_name = "MS")]
+    slow_query_wall_ms: u64,
+
+    /// Report scheduled queries whose runs average more CPU time (user and
+    /// system) than this, in milliseconds, 0 to disable
+    #[arg(long, env = "SHADOW_SLOW_QUERY_CPU_MS", default_value = "10000", value_name = "MS")]
+    slow_query_cpu_ms: u64,
+
+    /// Rewrite status.json in the data directory at least this often, in
+    /// seconds, so its age shows the agent is alive
+    #[arg(long, env = "SHADOW_STATUS_INTERVAL", default_value = "30", value_name = "SECONDS")]
+    status_interval: u64,
+
+    /// Also send the agent's logs to syslog: 'local' for the local daemon, or
+    /// 'tcp://host[:port]' or 'tls://host[:port]' for a remote relay (RFC 5424)
+    #[arg(long, env = "SHADOW_SYSLOG", value_name = "TARGET")]
+    syslog: Option<syslog::Target>,
+
+    /// Syslog facility
+    #[arg(long, env = "SHADOW_SYSLOG_FACILITY", value_enum, default_value = "daemon")]
+    syslog_facility: Facility,
+
+    /// Syslog severity for an agent log level (error, warning, info, debug),
+    /// e.g. info=notice [default: error=err, warning=warning, info=info,
+    /// debug=debug]
+    #[arg(
+        long,
+        env = "SHADOW_SYSLOG_SEVERITY",
+        value_name = "LEVEL=SEVERITY",
+        value_delimiter = ','
+    )]
+    syslog_severity: Vec<SeverityMapping>,
+
+    /// CA certificate for a TLS syslog relay, trusted in addition to the
+    /// public roots, and by the result sinks as well as --ca-cert
+    #[arg(long, env = "SHADOW_SYSLOG_CA_CERT")]
+    syslog_ca_cert: Option<PathBuf>,
+
+    /// Run an executable or post to a webhook on an agent event (enrolled,
+    /// osqueryd_crashed, upgrade_applied, crash_loop), repeatable, e.g.
+    /// crash_loop=/usr/local/bin/page-oncall
+    #[arg(long, env = "SHADOW_HOOK", value_name = "EVENT=COMMAND|URL", value_delimiter = ',')]
+    hook: Vec<hooks::Hook>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Pause collection: stop osqueryd and suppress restarts until resumed
+    Pause {
+        /// Why collection is being paused (recorded in the audit log)
+        #[arg(short, long)]
+        reason: Option<String>,
+
+        /// Resume automatically after this many minutes
+        #[arg(short, long)]
+        minutes: Option<u64>,
+    },
+    /// Resume collection after `shadow pause`
+    Resume,
+    /// Restart osqueryd, so it enrolls and fetches its config again
+    Reload,
+    /// Stop osqueryd, install the osquery version this agent ships with if a
+    /// different one is installed, and start it again
+    UpgradeOsquery,
+    /// Check the agent, osqueryd, the data directory, the connection to the
+    /// server, and the audit log
+    Doctor {
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Delete osquery's database and restart osqueryd with a fresh one, e.g.
+    /// when it has grown to gigabytes. Buffered events and results are lost.
+    #[command(alias = "compact-db")]
+    PurgeDb {
+        /// Why the database is being purged (recorded in the audit log)
+        #[arg(short, long)]
+        reason: Option<String>,
+    },
+    /// Have the agent send the logs and live query results spooled while the
+    /// server was unreachable now, rather than at its next retry
+    Replay {
+        /// Only list the spooled batches, with their sizes and ages
+        #[arg(short, long)]
+        list: bool,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Show the agent's current state
+    Status {
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// Keep a live view open, refreshing in place (q to quit)
+        #[arg(short, long, conflicts_with_all = ["output", "json"])]
+        watch: bool,
+
+        /// Seconds between refreshes with --watch
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..), requires = "watch")]
+        interval: u64,
+    },
+    /// Describe an error code, or list them all
+    Explain {
+        /// Error code, e.g. E1021
+        code: Option<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Gather the agent's status, logs, and diagnostics into a file for
+    /// support, with secrets redacted
+    SupportBundle {
+        /// File to write [default: shadow-support-<time>.tar.gz]
+        #[arg(short, long, conflicts_with = "upload")]
+        file: Option<PathBuf>,
+
+        /// Have the running agent send the bundle to the server instead
+        #[arg(long)]
+        upload: bool,
+    },
+    /// Check the host against a security benchmark with osquery and report
+    /// its score
+    Benchmark {
+        /// Benchmark to run
+        #[arg(value_enum)]
+        kind: benchmark::Kind,
+
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// Also write the report to this file, as HTML if it ends in .html,
+        /// otherwise JSON
+        #[arg(long, value_name = "FILE")]
+        report: Option<PathBuf>,
+
+        /// Have the running agent send the report to the server
+        #[arg(long)]
+        upload: bool,
+    },
+    /// List installed packages with their package URLs and CPEs, as an SBOM
+    /// for vulnerability matchers and compliance evidence
+    #[command(args_conflicts_with_subcommands = true)]
+    Inventory {
+        #[command(subcommand)]
+        action: Option<InventoryAction>,
+
+        /// SBOM format [default: cyclonedx]
+        #[arg(long, value_enum, conflicts_with_all = ["upload", "output", "json"])]
+        format: Option<inventory::Format>,
+
+        /// File to write the SBOM to [default: print it]
+        #[arg(short, long, conflicts_with_all = ["upload", "output", "json"])]
+        file: Option<PathBuf>,
+
+        /// List the packages in this format instead of writing an SBOM
+        #[command(flatten)]
+        output: OutputArgs,
+
+        /// Have the running agent send the inventory to the server instead
+        #[arg(long, conflicts_with_all = ["output", "json"])]
+        upload: bool,
+    },
+    /// Check the audit log
+    Audit {
+        #[command(subcommand)]
+        action: AuditAction,
+    },
+    /// Run shadow as a system service
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+    /// Manage the profiles: other servers or tenants the agent also reports
+    /// to, each run as its own shadow
+    Profile {
+        #[command(subcommand)]
+        action: ProfileAction,
+    },
+    /// Build an installer that installs the service with the settings given
+    /// to this command
+    Package {
+        /// Installer format
+        #[arg(value_enum)]
+        format: package::Format,
+
+        /// shadow binary for the target platform [default: this binary]
+        #[arg(long)]
+        binary: Option<PathBuf>,
+
+        /// Directory to write the installer to
+        #[arg(long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum InventoryAction {
+    /// Show what changed on the host between two inventory snapshots
+    Diff {
+        /// Earlier snapshot: an ID from `shadow inventory snapshots`,
+        /// `previous`, `latest`, or a file
+        from: String,
+
+        /// Later snapshot, likewise
+        #[arg(default_value = "latest")]
+        to: String,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// List the saved inventory snapshots
+    Snapshots {
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ProfileAction {
+    /// Add a profile with the settings given to this command, or replace the
+    /// settings of one
+    Add {
+        /// Name of the profile
+        #[arg(value_parser = profiles::parse_name)]
+        name: String,
+    },
+    /// Remove a profile. Its data directory is left in place.
+    Remove {
+        /// Name of the profile
+        #[arg(value_parser = profiles::parse_name)]
+        name: String,
+    },
+    /// List the profiles, with whether each is running
+    List {
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ServiceAction {
+    /// Install and start the service, using the settings given to this command
+    Install {
+        /// Account to run the service as [default: a dedicated `shadow` user,
+        /// created if needed, on Linux; root elsewhere]
+        #[arg(long)]
+        user: Option<String>,
+
+        /// Install and enable the service without starting it
+        #[arg(long)]
+        no_start: bool,
+    },
+    /// Stop and remove the service. The data directory is left in place.
+    Uninstall,
+    /// Start the installed service
+    Start,
+    /// Stop the installed service
+    Stop,
+    /// Restart the installed service
+    Restart,
+    /// Show whether the service is installed and running
+    Status,
+    /// Write an AppArmor profile, SELinux policy module, or macOS TCC profile
+    /// for the service
+    Profile {
+        /// Kind of profile
+        #[arg(value_enum)]
+        kind: service::ProfileKind,
+
+        /// Directory to write the profile to
+        #[arg(long, default_value = ".")]
+        output_dir: PathBuf,
+    },
+    /// Run the agent under the Windows Service Control Manager
+    #[command(hide = true)]
+    Run,
+}
+
+impl ServiceAction {
+    /// How the action is recorded in the audit log, if it changes anything
+    fn audit(&self) -> Option<(&'static str, serde_json::Value)> {
+        match self {
+            ServiceAction::Install { user, no_start } => Some((
+                "service_install",
+                serde_json::json!({ "user": user, "start": !no_start }),
+            )),
+            ServiceAction::Uninstall => Some(("service_uninstall", serde_json::Value::Null)),
+            ServiceAction::Start => Some(("service_start", serde_json::Value::Null)),
+            ServiceAction::Stop => Some(("service_stop", serde_json::Value::Null)),
+            ServiceAction::Restart => Some(("service_restart", serde_json::Value::Null)),
+            ServiceAction::Status | ServiceAction::Profile { .. } | ServiceAction::Run => None,
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Debug)]
-struct EnrollResponse {
-    enroll_secret: String,
+#[derive(Subcommand, Debug)]
+enum AuditAction {
+    /// Check the audit log's hash chain, reporting the first altered entry
+    Verify,
+}
+
+/// Settings recorded in the audit log only as a hash
+const SECRET_SETTINGS: &[&str] = &["org_token", "otlp_header", "relay_header", "result_sink", "sentry_dsn", "hook"];
+
+/// Command-line flags for osqueryd
+#[derive(Default)]
+struct Flags(Vec<OsString>);
+
+impl Flags {
+    fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.0.push(arg.as_ref().to_os_string());
+        self
+    }
 }
 
 /// Get the platform-specific CA certificates path
@@ -102,150 +943,902 @@ fn get_default_data_dir() -> PathBuf {
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+async fn main() -> ExitCode {
+    match cli().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            errors::print(&e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn cli() -> Result<()> {
+    // `shadowctl` is this binary under another name, for the commands that
+    // talk to the running agent
+    let ctl = std::env::args_os()
+        .next()
+        .and_then(|arg0| PathBuf::from(arg0).file_stem().map(|stem| stem == "shadowctl"))
+        .unwrap_or(false);
+    let command = match ctl {
+        true => Args::command()
+            .name("shadowctl")
+            .bin_name("shadowctl")
+            .subcommand_required(true)
+            .arg_required_else_help(true),
+        false => Args::command(),
+    };
+    let matches = command.get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    // Only the agent itself exports telemetry, not the other commands
+    let agent = matches!(
+        args.command,
+        None | Some(Commands::Service {
+            action: ServiceAction::Run
+        })
+    );
+    let otlp = args.otlp_endpoint.as_ref().filter(|_| agent).map(|endpoint| telemetry::OtlpConfig {
+        endpoint: endpoint.clone(),
+        headers: args.otlp_header.clone(),
+        metrics_interval: Duration::from_secs(args.otlp_metrics_interval.max(1)),
+    });
+    let statsd = args.statsd.as_ref().filter(|_| agent).map(|address| statsd::StatsdConfig {
+        address: address.clone(),
+        prefix: args.statsd_prefix.clone(),
+        tags: args.statsd_tag.clone(),
+        format: args.statsd_format,
+        interval: Duration::from_secs(args.statsd_interval.max(1)),
+    });
+    let (_telemetry, otlp_layer) = match (&otlp, &statsd) {
+        (None, None) => (None, None),
+        _ => {
+            let (telemetry, layer) = telemetry::init(otlp.as_ref(), statsd.as_ref())?;
+            (Some(telemetry), layer)
+        }
+    };
+    let log_filter = logging::with_levels(&args.log_filter, &args.log_level);
+    logging::init(args.log_format, &log_filter, otlp_layer)?;
 
     // Resolve data directory
-    let data_dir = args.data_dir.unwrap_or_else(get_default_data_dir);
+    let mut data_dir = args.data_dir.take().unwrap_or_else(get_default_data_dir);
 
-    // Ensure data directory exists
-    fs::create_dir_all(&data_dir)
-        .await
-        .context("Failed to create data directory")?;
+    if args.k8s_mode {
+        data_dir = k8s::state_dir(data_dir).await;
+    }
+
+    // The profiles run under the agent's own service, each with a data
+    // directory under the agent's
+    if let Some(profile) = &args.profile {
+        if matches!(
+            args.command,
+            Some(Commands::Profile { .. } | Commands::Service { .. } | Commands::Package { .. })
+        ) {
+            anyhow::bail!(
+                "--profile isn't used with `shadow {}`; profiles are run by the agent's service",
+                matches.subcommand_name().unwrap_or_default()
+            );
+        }
+        data_dir = profiles::dir(&data_dir, profile);
+    }
+
+    // Containers, WSL (which shares the Windows host's hardware UUID), and
+    // nodes cloned from one image share a hardware UUID, so hosts identified by
+    // it would overwrite each other on the server
+    let platform = virt::detect();
+    let in_container = matches!(platform, virt::Platform::Container(_));
+    if args.command.is_none() && (args.k8s_mode || in_container) {
+        let environment = match platform {
+            virt::Platform::Container(container::Runtime::Wsl) => platform.to_string(),
+            virt::Platform::Container(_) => format!("a {}", platform),
+            _ => "Kubernetes".to_string(),
+        };
+        match matches.value_source("host_identifier") {
+            Some(ValueSource::DefaultValue) => {
+                args.host_identifier = HostIdentifier::Instance;
+                warning!(
+                    "Running in {}, identifying this host by osquery's instance ID instead of its hardware UUID. \
+                     Pass --host-identifier uuid to override.",
+                    environment
+                );
+            }
+            _ if matches!(args.host_identifier, HostIdentifier::Uuid) => warning!(
+                "Running in {}, where hardware UUIDs may be shared between hosts. \
+                 --host-identifier instance is recommended.",
+                environment
+            ),
+            _ => {}
+        }
+    }
+
+    // Ensure data directory exists. `explain` doesn't need one, so anyone can
+    // run it.
+    if !matches!(args.command, Some(Commands::Explain { .. })) {
+        fs::create_dir_all(&data_dir)
+            .await
+            .context("Failed to create data directory")
+            .context(errors::DATA_DIR)?;
+    }
+
+    let mut config_hash = None;
+    if agent {
+        let settings = service::settings(&Args::command(), &matches, &["help", "version"])
+            .into_iter()
+            .map(|setting| {
+                let value = setting.values.join(",");
+                match SECRET_SETTINGS.contains(&setting.id.as_str()) {
+                    true => (setting.id, audit::redact(&value)),
+                    false => (setting.id, value),
+                }
+            })
+            .collect();
+        config_hash = Some(heartbeat::config_hash(&settings));
+        audit::record_start(&data_dir, settings).await;
+    }
+
+    match args.command.take() {
+        Some(Commands::Pause { reason, minutes }) => ipc::pause(&data_dir, reason, minutes).await,
+        Some(Commands::Resume) => ipc::resume(&data_dir).await,
+        Some(Commands::Reload) => ipc::reload(&data_dir).await,
+        Some(Commands::UpgradeOsquery) => ipc::upgrade_osquery(&data_dir).await,
+        Some(Commands::Doctor { output }) => ipc::doctor(&data_dir, &args.server, output.format()).await,
+        Some(Commands::PurgeDb { reason }) => database::request_purge(&data_dir, reason).await,
+        Some(Commands::Status {
+            watch: true,
+            interval,
+            ..
+        }) => dashboard::run(&data_dir, Duration::from_secs(interval)).await,
+        Some(Commands::Status { output, .. }) => ipc::status(&data_dir, output.format()).await,
+        Some(Commands::Replay { list: true, output }) => spool::list(&data_dir, output.format()),
+        Some(Commands::Replay { output, .. }) => ipc::replay(&data_dir, output.format()).await,
+        Some(Commands::Explain { code, output }) => errors::explain(code.as_deref(), output.format()),
+        Some(Commands::SupportBundle { file, upload }) => {
+            ipc::support_bundle(&data_dir, &args.server, args.org_token.as_deref(), file, upload).await
+        }
+        Some(Commands::Benchmark {
+            kind,
+            output,
+            report,
+            upload,
+        }) => {
+            let osqueryd_path = command_osqueryd(&args, &data_dir).await?;
+            benchmark::command(&data_dir, &osqueryd_path, kind, output.format(), report, upload).await
+        }
+        Some(Commands::Inventory {
+            action: Some(action), ..
+        }) => match action {
+            InventoryAction::Diff { from, to, output } => snapshots::diff(&data_dir, &from, &to, output.format()),
+            InventoryAction::Snapshots { output } => snapshots::list(&data_dir, output.format()),
+        },
+        Some(Commands::Inventory { upload: true, .. }) => ipc::inventory(&data_dir).await,
+        Some(Commands::Inventory {
+            format, file, output, ..
+        }) => {
+            let osqueryd_path = command_osqueryd(&args, &data_dir).await?;
+            inventory::command(&data_dir, &osqueryd_path, format, output.given(), file).await
+        }
+        Some(Commands::Package {
+            format,
+            binary,
+            output_dir,
+        }) => {
+            let binary = match binary {
+                Some(binary) => binary,
+                None => std::env::current_exe().context("Failed to locate the shadow binary")?,
+            };
+            package::build(package::PackageConfig {
+                format,
+                binary,
+                settings: service::settings(&Args::command(), &matches, &["help", "version"]),
+                output_dir,
+            })
+            .await
+        }
+        Some(Commands::Audit { action }) => match action {
+            AuditAction::Verify => audit::verify(&data_dir).await,
+        },
+        Some(Commands::Profile { action }) => match action {
+            ProfileAction::Add { name } => profiles::add(&data_dir, &name, &Args::command(), &matches).await,
+            ProfileAction::Remove { name } => profiles::remove(&data_dir, &name).await,
+            ProfileAction::List { output } => profiles::list(&data_dir, &Args::command(), output.format()).await,
+        },
+        Some(Commands::Service { action }) => {
+            let audited = action.audit();
+            match action {
+                ServiceAction::Install { user, no_start } => {
+                    let config = service::ServiceConfig::new(&Args::command(), &matches, user, !no_start)?;
+                    service::install(config).await
+                }
+                ServiceAction::Uninstall => service::uninstall().await,
+                ServiceAction::Start => service::start().await,
+                ServiceAction::Stop => service::stop().await,
+                ServiceAction::Restart => service::restart().await,
+                ServiceAction::Status => service::status().await,
+                ServiceAction::Profile { kind, output_dir } => {
+                    service::profile(kind, &matches, &output_dir).await
+                }
+                ServiceAction::Run => service::run(Box::pin(run(args, data_dir.clone(), platform, config_hash))),
+            }?;
+            match audited {
+                Some((action, detail)) => audit::record(&data_dir, action, detail).await,
+                None => Ok(()),
+            }
+        }
+        None => run(args, data_dir, platform, config_hash).await,
+    }
+}
+
+/// osqueryd for commands that query the host themselves: the one given, or
+/// the one the agent provisions
+async fn command_osqueryd(args: &Args, data_dir: &Path) -> Result<PathBuf> {
+    match &args.osqueryd_path {
+        Some(path) => Ok(path.clone()),
+        None => {
+            OsqueryProvisioner::new(data_dir.to_path_buf())
+                .skip_verification(args.skip_verify)
+                .ensure_provisioned()
+                .await
+        }
+    }
+}
+
+/// Run the agent: provision osquery, enroll, and supervise osqueryd.
+/// `config_hash` identifies its settings in heartbeats.
+async fn run(
+    args: Args,
+    data_dir: PathBuf,
+    platform: virt::Platform,
+    config_hash: Option<String>,
+) -> Result<()> {
+    if !args.no_log_file {
+        // A data directory that's full or read-only is no reason to stop
+        // (see `storage`), so the log just isn't kept in a file then
+        if let Err(e) = logfile::start(logfile::LogFileConfig {
+            path: data_dir.join(logfile::FILE_NAME),
+            max_size: args.log_file_max_mb * 1024 * 1024,
+            rotation: args.log_file_rotation,
+            keep: args.log_file_keep,
+        }) {
+            warning!("Not writing the agent's log to a file: {:#}", e);
+        }
+    }
+    panics::install(&data_dir);
+
+    let org_token = match args.standalone {
+        true => None,
+        false => Some(
+            args.org_token
+                .as_deref()
+                .context("--org-token is required")
+                .context(errors::ORG_TOKEN_MISSING)?,
+        ),
+    };
+
+    info!("Shadow Agent v{}", env!("CARGO_PKG_VERSION"));
+    match args.standalone {
+        true => info!("  Server:    none (standalone)"),
+        false => info!("  Server:    {}", args.server),
+    }
+    info!("  Data dir:  {}", data_dir.display());
+    if let Some(profile) = &args.profile {
+        info!("  Profile:   {}", profile);
+    }
+    info!("  Platform:  {}", platform);
+    if args.unprivileged {
+        info!("  Mode:      unprivileged (reduced coverage)");
+    }
+    if args.harden_osqueryd {
+        info!("  Hardening: osqueryd restricted");
+    }
 
-    println!("Shadow Agent v{}", env!("CARGO_PKG_VERSION"));
-    println!("─────────────────────────────────────");
-    println!("  Server:    {}", args.server);
-    println!("  Data dir:  {}", data_dir.display());
+    let pod = args.k8s_mode.then(|| k8s::PodConfig {
+        hostfs: args.k8s_hostfs.clone(),
+        node_name: args.k8s_node_name.clone(),
+        podinfo_dir: args.k8s_podinfo.clone(),
+    });
+    if let Some(pod) = &pod {
+        info!(
+            "  Node:      {} (Kubernetes)",
+            pod.node_name.as_deref().unwrap_or("unknown")
+        );
+    }
+
+    if let Some(target) = &args.syslog {
+        syslog::start(SyslogConfig {
+            target: target.clone(),
+            facility: args.syslog_facility,
+            severities: args.syslog_severity.clone(),
+            ca_cert: args.syslog_ca_cert.clone(),
+        })
+        .await?;
+    }
+
+    eventlog::report(
+        Event::Started,
+        &format!("Shadow agent {} started", env!("CARGO_PKG_VERSION")),
+    );
+    let agent_limits = AgentLimits {
+        memory_mb: args.agent_memory_limit_mb,
+        cpu_percent: args.agent_cpu_limit_percent,
+    };
+    if let Err(e) = agent_limits.apply() {
+        warning!("{:#}. The agent's limits are only checked periodically.", e);
+    }
+
+    let status = SharedStatus::new(&data_dir, AgentState::Provisioning);
+    agent_limits.watch(data_dir.clone(), status.clone());
+    status.spawn_refresh(Duration::from_secs(args.status_interval.max(1)));
+    notify::spawn(status.clone());
+    // The bus name is the host's, so it's left to the agent itself
+    if args.profile.is_none() {
+        dbus::spawn(status.clone());
+    }
+    telemetry::observe(status.clone());
+    panics::set_status(status.clone());
+    if let Some(addr) = args.health_listen {
+        health::spawn(addr, status.clone()).await;
+    }
+    let (requests, requests_rx) = tokio::sync::mpsc::channel(8);
+    let api_cell = Arc::new(OnceLock::new());
+    let agent = ipc::Agent {
+        data_dir: data_dir.clone(),
+        status: status.clone(),
+        server: args.server.clone(),
+        requests: requests.clone(),
+        api: api_cell.clone(),
+        osqueryd_path: Arc::new(OnceLock::new()),
+        replayer: Arc::new(OnceLock::new()),
+        org_token: org_token.map(str::to_string),
+    };
+    ipc::spawn(agent.clone());
+    // The profiles run beside the agent, whether or not it gets going itself
+    let profiles = args.profile.is_none().then(|| profiles::spawn(data_dir.clone()));
+    let record_error = |e: &anyhow::Error| status.set_failure(e);
+
+    if let Some(timeout) = args.wait_for_network.filter(|_| !args.standalone) {
+        network::wait_for_network(&args.server, Duration::from_secs(timeout)).await;
+    }
 
     // Get osqueryd path - either user-provided or auto-provisioned
-    let osqueryd_path = match args.osqueryd_path {
+    let mut provisioner = None;
+    let osqueryd_path = match &args.osqueryd_path {
         Some(path) => {
             // User provided a path - verify it exists
             if !path.exists() {
-                anyhow::bail!("osqueryd not found at {:?}", path);
+                return Err(anyhow::anyhow!("osqueryd not found at {:?}", path).context(errors::OSQUERY_NOT_FOUND));
             }
-            println!("  osquery:   {} (user-provided)", path.display());
-            path
+            info!("  osquery:   {} (user-provided)", path.display());
+            path.clone()
         }
         None => {
             // Auto-provision osquery
-            let provisioner =
-                OsqueryProvisioner::new(data_dir.clone()).skip_verification(args.skip_verify);
-            provisioner.ensure_provisioned().await?
+            let p = provisioner.insert(
+                OsqueryProvisioner::new(data_dir.clone())
+                    .skip_verification(args.skip_verify)
+                    .allow_releases(args.allow_osquery_version.clone()),
+            );
+            p.ensure_provisioned().await.inspect_err(record_error)?
         }
     };
 
+    gatekeeper::check(&osqueryd_path, provisioner.is_some())
+        .await
+        .inspect_err(record_error)?;
+    compat::check(&osqueryd_path)
+        .await
+        .context(errors::OSQUERY_INCOMPATIBLE)
+        .inspect_err(record_error)?;
+    let _ = agent.osqueryd_path.set(osqueryd_path.clone());
+
     // Create log directory
     let log_path = data_dir.join("osquery_logs");
     fs::create_dir_all(&log_path)
         .await
         .context("Failed to create log directory")?;
+    janitor::spawn(
+        log_path.clone(),
+        janitor::LogRetention {
+            max_bytes: (args.osquery_logs_max_mb > 0).then(|| args.osquery_logs_max_mb * 1024 * 1024),
+            max_age: (args.osquery_logs_max_age_days > 0)
+                .then(|| Duration::from_secs(args.osquery_logs_max_age_days * 24 * 60 * 60)),
+        },
+    );
+    statuslog::spawn(log_path.clone());
+    // With instance IDs the database holds the host's identity
+    let keep_database = args.host_identifier == HostIdentifier::Instance;
+    if keep_database && args.osquery_db_purge_mb.is_some() {
+        warning!(
+            "--osquery-db-purge-mb is ignored with --host-identifier instance. \
+             osquery's database holds this host's instance ID, so a fresh one would make it a new host on the server."
+        );
+    }
+    database::spawn(
+        data_dir.clone(),
+        status.clone(),
+        database::DbLimits {
+            warn_bytes: (args.osquery_db_warn_mb > 0).then(|| args.osquery_db_warn_mb * 1024 * 1024),
+            purge_bytes: args
+                .osquery_db_purge_mb
+                .filter(|_| !keep_database)
+                .map(|mb| mb * 1024 * 1024),
+        },
+    );
+
+    if args.encrypt_at_rest {
+        atrest::init(&data_dir).inspect_err(record_error)?;
+        info!("  At rest:   encrypted");
+    }
 
     // Get host identifier from osquery
-    print!("  Host ID:   ");
-    let host_id = get_host_identifier(&osqueryd_path, &args.host_identifier, &data_dir).await?;
-    println!("{} ({})", host_id, args.host_identifier);
-    println!();
+    let host_id = get_host_identifier(&osqueryd_path, &args.host_identifier, &data_dir)
+        .await
+        .inspect_err(record_error)?;
+    info!("  Host ID:   {} ({})", host_id, args.host_identifier);
+    logging::set_host_id(&host_id);
+    hooks::init(args.hook.clone(), &host_id);
+
+    let mut tags = match &pod {
+        Some(pod) => {
+            k8s::check_pod(pod);
+            k8s::enroll_tags(pod).await
+        }
+        None => Default::default(),
+    };
+    tags.extend(platform.enroll_tags());
+    if args.unprivileged {
+        tags.extend(unprivileged::enroll_tags());
+    }
+    tags.extend(args.tag.iter().map(|tag| (tag.key.clone(), tag.value.clone())));
+    let mut api = ApiClient::new(&args.server, args.ca_cert.as_deref(), &host_id).await?;
 
     // Enroll with the server
-    println!("Enrolling with server...");
-
-    let enroll_url = format!("https://{}/api/shadow/enroll", args.server);
-    let mut map = HashMap::new();
-    map.insert("host_id", host_id.as_str());
-    map.insert("org_token", args.org_token.as_str());
-
-    let client = if let Some(ca_path) = &args.ca_cert {
-        let cert_pem = fs::read(&ca_path).await?;
-        let cert = reqwest::Certificate::from_pem(&cert_pem)?;
-        reqwest::Client::builder()
-            .add_root_certificate(cert)
-            .build()?
-    } else {
-        reqwest::Client::new()
+    let enroll_secret = match org_token {
+        Some(org_token) => {
+            info!("Enrolling with server...");
+            status.set_state(AgentState::Enrolling);
+            Some(match api.enroll(org_token, &tags).await {
+                Ok(enroll_secret) => {
+                    info!("Enrolled successfully!");
+                    audit::record_agent(
+                        &data_dir,
+                        "enroll",
+                        serde_json::json!({ "server": args.server, "host_id": host_id, "tags": tags }),
+                    )
+                    .await;
+                    lastconfig::save_enrollment(&data_dir, &args.server, &host_id, &enroll_secret);
+                    enroll_secret
+                }
+                // Collection carries on with the last config until the server is back
+                Err(e) if matches!(errors::code_of(&e), Some(errors::SERVER_UNREACHABLE | errors::ENROLL_SERVER_ERROR)) => {
+                    let Some((enroll_secret, enrolled_at)) =
+                        lastconfig::load_enrollment(&data_dir, &args.server, &host_id)
+                    else {
+                        record_error(&e);
+                        return Err(e);
+                    };
+                    warning!(
+                        "Couldn't enroll, so starting with the enrollment from {} and the last config: {:#}",
+                        enrolled_at.to_rfc3339(),
+                        e
+                    );
+                    api.reuse_enrollment(enroll_secret.clone());
+                    enroll_secret
+                }
+                Err(e) => {
+                    record_error(&e);
+                    return Err(e);
+                }
+            })
+        }
+        None => {
+            info!("Running standalone with the built-in query packs, without a server");
+            status.update(|status| status.builtin_config = true);
+            None
+        }
     };
-    let response = client
-        .post(&enroll_url)
-        .json(&map)
-        .send()
-        .await
-        .context("Failed to connect to server")?;
+    if enroll_secret.is_some() {
+        let _ = api_cell.set(api.clone());
+        tokio::spawn(panics::send_pending(
+            api.clone(),
+            data_dir.clone(),
+            !args.no_panic_upload,
+            args.sentry_dsn.clone(),
+        ));
+        eventlog::report(
+            Event::Enrolled,
+            &format!("Enrolled with {} as {}", args.server, host_id),
+        );
+        hooks::fire(
+            HookEvent::Enrolled,
+            serde_json::json!({ "server": args.server, "tags": tags }),
+        );
+    }
+
+    // osqueryd flags
+    let mut flags = Flags::default();
 
-    let status = response.status();
-    if !status.is_success() {
-        let body = response.text().await.unwrap_or_default();
-        anyhow::bail!("Enrollment failed ({}): {}", status, body);
+    // Local policy for results and live queries, shared by the relay and the
+    // live query channel
+    let redactor = args.redaction_rules.as_deref().map(redact::load).transpose()?.map(Arc::new);
+    let denylist = Some(denylist::Denylist::new(&args.deny_table, &args.deny_query)?)
+        .filter(|denylist| !denylist.is_empty())
+        .map(Arc::new);
+    let carver = Arc::new(match args.carve {
+        true => carve::Carver::new(
+            data_dir.clone(),
+            &args.carve_path,
+            args.carve_max_mb * 1024 * 1024,
+            args.carve_encryption_key.clone(),
+        ),
+        false => carve::Carver::disabled(),
+    });
+    let yara_rules = args.yara_sync.then(|| Arc::new(yara::Rules::new(&data_dir)));
+    if args.carve && args.carve_path.is_empty() {
+        warning!("--carve without --carve-path lets the server carve any file on the host");
     }
 
-    let res: EnrollResponse = response
-        .json()
-        .await
-        .context("Failed to parse enrollment response")?;
+    if args.standalone {
+        // Nothing goes to a server: osqueryd runs the built-in packs and
+        // writes its results to osqueryd.results.log, which it rotates itself
+        flags.arg("--config_plugin").arg("filesystem");
+        flags.arg("--config_path").arg(packs::write(&data_dir)?);
+        flags.arg("--logger_plugin").arg("filesystem");
+        flags.arg("--logger_rotate").arg("true");
+        flags.arg("--logger_rotate_size")
+            .arg((args.results_file_max_mb.max(1) * 1024 * 1024).to_string());
+        flags.arg("--logger_rotate_max_files")
+            .arg(args.results_file_keep.max(1).to_string());
+        flags.arg("--disable_distributed").arg("true");
+    } else {
+        // TLS configuration
+        flags.arg("--config_plugin").arg("tls");
+        if args.tls_relay {
+            // osqueryd trusts only the relay, which checks the server's certificate
+            let options = relay::Options {
+                headers: args.relay_header.clone(),
+                spool: (args.relay_spool_max_mb > 0)
+                    .then(|| spool::Spool::open(&data_dir, args.relay_spool_max_mb * 1024 * 1024))
+                    .transpose()?,
+                live_spool: (args.relay_live_spool_max_mb > 0)
+                    .then(|| spool::Spool::open_live(&data_dir, args.relay_live_spool_max_mb * 1024 * 1024))
+                    .transpose()?,
+                compress: args.osquery_log_compress,
+                redactor: redactor.clone(),
+                denylist: denylist.clone(),
+                sinks: match args.result_sink.is_empty() {
+                    true => None,
+                    false => {
+                        let ca_certs: Vec<&Path> = [args.ca_cert.as_deref(), args.syslog_ca_cert.as_deref()]
+                            .into_iter()
+                            .flatten()
+                            .collect();
+                        Some(sinks::start(&args.result_sink, &host_id, &ca_certs).await?)
+                    }
+                },
+                limiter: Some(ratelimit::Limiter::new(&args.event_rate_limit, &args.event_sample))
+                    .filter(|limiter| !limiter.is_empty()),
+                dedup: args
+                    .dedup_results
+                    .then(|| dedup::Dedup::open(&data_dir, args.dedup_results_max_rows.max(1)))
+                    .transpose()?,
+                routes: routing::Routes::new(&args.data_endpoint, &args.region_server, &args.region_tag, &tags),
+                result_bandwidth: args.result_upload_limit.map(bandwidth::TokenBucket::new),
+                carve_bandwidth: args.carve_upload_limit.map(bandwidth::TokenBucket::new),
+                decorators: args.decorator.clone(),
+                carver: carver.clone(),
+                yara: yara_rules.clone(),
+                fim: args.fim_paths.as_deref().map(fim::load).transpose()?,
+            };
+            if let Some(redactor) = &options.redactor {
+                info!("Redacting results with {} rules", redactor.len());
+            }
+            if let Some(fim) = &options.fim {
+                info!("Adding {} local paths to osqueryd's file integrity monitoring", fim.len());
+            }
+            if let Some(dedup) = &options.dedup {
+                info!("Deduplicating results, remembering {} rows sent before", dedup.len());
+            }
+            let relay = relay::spawn(api.clone(), &data_dir, status.clone(), options).await?;
+            let _ = agent.replayer.set(relay.replayer.clone());
+            flags.arg("--tls_hostname").arg(&relay.hostname);
+            flags.arg("--tls_server_certs").arg(&relay.cert_path);
+            flags.arg("--tls_client_cert").arg(&relay.client_cert_path);
+            flags.arg("--tls_client_key").arg(&relay.client_key_path);
+        } else {
+            flags.arg("--tls_hostname").arg(&args.server);
+            if let Some(ca_path) = &args.ca_cert {
+                flags.arg("--tls_server_certs").arg(ca_path);
+            } else {
+                let ca_certs = get_ca_certs_path();
+                if !ca_certs.is_empty() && std::path::Path::new(ca_certs).exists() {
+                    flags.arg("--tls_server_certs").arg(ca_certs);
+                }
+            }
+        }
 
-    println!("Enrolled successfully!");
-    println!();
+        // Enrollment
+        flags.arg("--enroll_tls_endpoint").arg("/api/osquery/enroll");
+        flags.arg("--config_tls_endpoint").arg("/api/osquery/config");
+        if !args.tls_relay {
+            // The relay keeps the last config itself
+            flags.arg("--config_enable_backup").arg("true");
+        }
+        flags.arg("--enroll_secret_env").arg(ENROLL_SECRET_ENV);
 
-    // Build osqueryd command
-    let mut cmd = Command::new(&osqueryd_path);
+        // Logging
+        if args.results_file {
+            // osquery's filesystem logger writes to --logger_path and rotates itself
+            flags.arg("--logger_plugin").arg("tls,filesystem");
+            flags.arg("--logger_rotate").arg("true");
+            flags.arg("--logger_rotate_size")
+                .arg((args.results_file_max_mb.max(1) * 1024 * 1024).to_string());
+            flags.arg("--logger_rotate_max_files")
+                .arg(args.results_file_keep.max(1).to_string());
+            if args.redaction_rules.is_some() || !args.deny_table.is_empty() || !args.deny_query.is_empty() {
+                warning!(
+                    "Results written to osqueryd.results.log aren't redacted or filtered by the relay. \
+                     Only the results sent to the server are."
+                );
+            }
+        } else {
+            flags.arg("--logger_plugin").arg("tls");
+        }
+        flags.arg("--logger_tls_endpoint").arg("/api/osquery/log");
+        // Compressing over loopback would be wasted, so the relay does it instead
+        if args.osquery_log_compress && !args.tls_relay {
+            flags.arg("--logger_tls_compress").arg("true");
+        }
+        if let Some(period) = args.osquery_log_period {
+            flags.arg("--logger_tls_period").arg(period.max(1).to_string());
+        }
+        if let Some(lines) = args.osquery_log_max_lines {
+            flags.arg("--logger_tls_max_lines").arg(lines.max(1).to_string());
+        }
 
-    // TLS configuration
-    cmd.arg("--config_plugin").arg("tls");
-    cmd.arg("--tls_hostname").arg(&args.server);
+        // Distributed queries
+        flags.arg("--disable_distributed").arg("false");
+        flags.arg("--distributed_plugin").arg("tls");
+        flags.arg("--distributed_interval")
+            .arg(args.distributed_interval.to_string());
+        flags.arg("--distributed_tls_max_attempts").arg("10");
+        // Each live query is logged as osqueryd starts it, with its ID, so slow
+        // ones can be traced on the host
+        flags.arg("--distributed_loginfo").arg("true");
+        flags.arg("--distributed_tls_read_endpoint")
+            .arg("/api/osquery/distributed/read");
+        flags.arg("--distributed_tls_write_endpoint")
+            .arg("/api/osquery/distributed/write");
 
-    if let Some(ca_path) = &args.ca_cert {
-        cmd.arg("--tls_server_certs").arg(ca_path);
-    } else {
-        let ca_certs = get_ca_certs_path();
-        if !ca_certs.is_empty() && std::path::Path::new(ca_certs).exists() {
-            cmd.arg("--tls_server_certs").arg(ca_certs);
+        // File carving, if the server's config turns it on, or through the relay
+        // only with --carve
+        flags.arg("--carver_start_endpoint").arg("/api/osquery/carve/begin");
+        flags.arg("--carver_continue_endpoint").arg("/api/osquery/carve/block");
+        if args.tls_relay {
+            for (flag, value) in carver.osqueryd_flags() {
+                flags.arg(flag).arg(value);
+            }
         }
     }
 
-    // Enrollment
-    cmd.arg("--enroll_tls_endpoint").arg("/api/osquery/enroll");
-    cmd.arg("--config_tls_endpoint").arg("/api/osquery/config");
-    cmd.arg("--enroll_secret_env").arg(ENROLL_SECRET_ENV);
-    cmd.env(ENROLL_SECRET_ENV, res.enroll_secret);
+    // Paths
+    flags.arg("--pidfile").arg(data_dir.join("osquery.pid"));
+    flags.arg("--logger_path").arg(&log_path);
+    flags.arg("--database_path").arg(data_dir.join(database::DIR_NAME));
 
-    // Logging
-    cmd.arg("--logger_plugin").arg("tls");
-    cmd.arg("--logger_tls_endpoint").arg("/api/osquery/log");
+    // Host identification - must match what we enrolled with
+    flags.arg("--host_identifier").arg(args.host_identifier.as_osquery_arg());
 
-    // Distributed queries
-    cmd.arg("--disable_distributed").arg("false");
-    cmd.arg("--distributed_plugin").arg("tls");
-    cmd.arg("--distributed_interval")
-        .arg(args.distributed_interval.to_string());
-    cmd.arg("--distributed_tls_max_attempts").arg("10");
-    cmd.arg("--distributed_tls_read_endpoint")
-        .arg("/api/osquery/distributed/read");
-    cmd.arg("--distributed_tls_write_endpoint")
-        .arg("/api/osquery/distributed/write");
+    if args.unprivileged {
+        for (flag, value) in unprivileged::osqueryd_flags(&data_dir) {
+            flags.arg(flag).arg(value);
+        }
+    } else {
+        preflight::check_permissions(&osqueryd_path);
+    }
 
-    // Paths
-    cmd.arg("--pidfile").arg(data_dir.join("osquery.pid"));
-    cmd.arg("--logger_path").arg(&log_path);
-    cmd.arg("--database_path").arg(data_dir.join("osquery.db"));
+    // Container tables
+    let hostfs = pod.as_ref().map(|pod| pod.hostfs.as_path());
+    for (kind, path) in [
+        (container::Socket::Docker, &args.docker_socket),
+        (container::Socket::Lxd, &args.lxd_socket),
+    ] {
+        let Some(path) = path.clone().or_else(|| container::default_socket(kind, hostfs)) else {
+            continue;
+        };
+        container::check_socket(kind, &path).await;
+        flags.arg(kind.flag()).arg(path);
+    }
 
-    // Host identification - must match what we enrolled with
-    cmd.arg("--host_identifier").arg(args.host_identifier.as_osquery_arg());
+    // Event-based tables
+    let events =
+        args.enable_events || args.events.is_some() || args.es_file_events || !args.windows_events.is_empty();
+    if events && args.unprivileged {
+        warning!("Event subsystems need root, so they stay disabled with --unprivileged");
+    } else if events {
+        let options = preflight::EventOptions {
+            linux: args.events.unwrap_or(platform.default_events()),
+            es_file_events: args.es_file_events,
+            windows: if args.windows_events.is_empty() {
+                vec![preflight::WindowsEvents::EventLog]
+            } else {
+                args.windows_events.clone()
+            },
+        };
+        for (flag, value) in preflight::event_flags(&osqueryd_path, &options) {
+            flags.arg(flag).arg(value);
+        }
+        if args.fim_paths.is_some() {
+            flags.arg("--enable_file_events").arg("true");
+        }
+    } else if args.fim_paths.is_some() {
+        warning!("--fim-paths needs --enable-events, so file_events stays empty");
+    }
+
+    // Event buffering, which is most of the database on busy hosts
+    if let Some(expiry) = args.osquery_events_expiry {
+        flags.arg("--events_expiry").arg(expiry.to_string());
+    }
+    if let Some(max) = args.osquery_events_max {
+        flags.arg("--events_max").arg(max.to_string());
+    }
+
+    // The agent reads schedule statistics through the extensions socket
+    let extensions_socket = extensions::socket_path(&data_dir);
+    if !args.unprivileged {
+        extensions::prepare(&data_dir)?;
+        flags.arg("--extensions_socket").arg(&extensions_socket);
+        tokio::spawn(extensions::watch(
+            extensions_socket.clone(),
+            status.clone(),
+            data_dir.clone(),
+            args.allow_extension.clone(),
+        ));
+    } else if !args.allow_extension.is_empty() {
+        warning!("--allow-extension is ignored with --unprivileged, which disables extensions");
+    }
+
+    let hardening = args.harden_osqueryd.then(|| hardening::Hardening {
+        data_dir: data_dir.clone(),
+        allow_bpf: args.events == Some(preflight::LinuxEvents::Bpf),
+    });
+    if hardening.is_some() && !hardening::Hardening::is_supported() {
+        warning!("--harden-osqueryd is only supported on Linux and macOS, so osqueryd runs unrestricted");
+    }
 
     // Verbose logging
     if args.verbose {
-        cmd.arg("--verbose").arg("true");
-        cmd.arg("--logger_stderr").arg("true");
+        flags.arg("--verbose").arg("true");
+        flags.arg("--logger_stderr").arg("true");
+        info!("(verbose mode enabled)");
     }
 
-    println!("Starting osqueryd...");
-    if args.verbose {
-        println!("(verbose mode enabled)");
+    status.set_osquery_version(get_osquery_version(&osqueryd_path).await.ok());
+
+    // Without a server there's nothing to report to or wait on; with one, the
+    // heartbeat runs independently of osqueryd so the server can tell a broken
+    // osqueryd apart from an offline host
+    if !args.standalone {
+        let commands = args.command_key.clone().map(|key| {
+            let policy = remote::Policy {
+                key,
+                allowed: args.allow_remote_command.clone(),
+                osquery_versions: std::iter::once(osquery::OSQUERY_VERSION.to_string())
+                    .chain(args.allow_osquery_version.iter().map(|release| release.version.clone()))
+                    .collect(),
+            };
+            remote::spawn(api.clone(), agent, policy)
+        });
+        tokio::spawn(heartbeat::run(
+            api.clone(),
+            status.clone(),
+            data_dir.clone(),
+            Duration::from_secs(args.heartbeat_interval.max(1)),
+            config_hash,
+            debug::spawn(status.clone(), data_dir.clone(), requests.clone()),
+            commands.clone(),
+        ));
+        if let (Some(rules), Some(key)) = (yara_rules, args.yara_key.clone()) {
+            tokio::spawn(yara::run(
+                api.clone(),
+                rules,
+                key,
+                Duration::from_secs(args.yara_sync_interval.max(60)),
+                status.clone(),
+                data_dir.clone(),
+                requests.clone(),
+            ));
+        }
+        if args.inventory_interval > 0 {
+            tokio::spawn(inventory::run(
+                api.clone(),
+                data_dir.clone(),
+                osqueryd_path.clone(),
+                Duration::from_secs(args.inventory_interval.max(60)),
+            ));
+        }
+        // Extensions are disabled when unprivileged
+        if args.schedule_report_interval > 0 && !args.unprivileged {
+            tokio::spawn(schedule::run(
+                api.clone(),
+                status.clone(),
+                extensions_socket.clone(),
+                Duration::from_secs(args.schedule_report_interval),
+            ));
+        }
+        let thresholds = schedule::SlowQueryThresholds {
+            wall_time_ms: (args.slow_query_wall_ms > 0).then_some(args.slow_query_wall_ms),
+            cpu_time_ms: (args.slow_query_cpu_ms > 0).then_some(args.slow_query_cpu_ms),
+        };
+        if thresholds.is_set() && !args.unprivileged {
+            tokio::spawn(schedule::watch_slow(
+                api.clone(),
+                status.clone(),
+                extensions_socket.clone(),
+                thresholds,
+            ));
+        }
+        if args.live_channel {
+            if args.unprivileged || !cfg!(unix) {
+                warning!(
+                    "The live query channel needs osqueryd's extensions socket, which isn't available here. \
+                     Live queries arrive with osqueryd's polling only."
+                );
+            } else {
+                tokio::spawn(live::run(
+                    api.clone(),
+                    extensions_socket,
+                    live::Options {
+                        denylist,
+                        redactor,
+                        carver: args.tls_relay.then_some(carver),
+                        commands,
+                    },
+                ));
+            }
+        }
     }
 
-    cmd.spawn()
-        .context("Failed to start osqueryd")?
-        .wait()
-        .await?;
+    let maintenance = MaintenancePolicy::new(
+        args.maintenance_window,
+        args.max_uptime_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+    );
 
-    Ok(())
+    let limits = ChildLimits {
+        memory_mb: args.osqueryd_memory_limit_mb,
+        cpu_percent: args.osqueryd_cpu_limit_percent,
+    };
+    if limits.is_set() && !cfg!(windows) {
+        warning!("osqueryd resource limits are only enforced on Windows");
+    }
+
+    let power = power::watch(status.clone());
+
+    let mut supervisor = Supervisor::new(osqueryd_path, data_dir, flags.0, api, status)
+        .maintenance(maintenance)
+        .limits(limits)
+        .backoff(BackoffPolicy {
+            initial: Duration::from_secs(args.restart_delay),
+            multiplier: args.restart_multiplier,
+            max: Duration::from_secs(args.restart_max_delay),
+            reset_after: Duration::from_secs(args.restart_reset_after),
+        })
+        .power(power)
+        .keep_database(keep_database)
+        .standalone(args.standalone)
+        .requests(requests_rx);
+    if let Some(enroll_secret) = enroll_secret {
+        supervisor = supervisor.env(ENROLL_SECRET_ENV, enroll_secret);
+    }
+    if let Some(provisioner) = provisioner {
+        supervisor = supervisor.provisioner(provisioner);
+    }
+    if let Some(hardening) = hardening {
+        supervisor = supervisor.hardening(hardening);
+    }
+    let result = supervisor.run().await;
+    if let Some(profiles) = profiles {
+        profiles.stop().await;
+    }
+    result?;
+    limits::check_agent()
 }
@@ -0,0 +1,101 @@
+//! Scheduled osqueryd maintenance restarts
+//!
+//! Long-lived osqueryd processes slowly grow RocksDB and memory usage. The
+//! maintenance policy restarts osqueryd inside a daily local-time window, after
+//! a maximum uptime, or both (at the first window after the uptime is reached).
+
+use chrono::{DateTime, Days, Local, NaiveTime, TimeZone};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// A daily local-time window, e.g. `03:00-04:00`. May cross midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    /// Length of the window
+    fn len(&self) -> chrono::Duration {
+        let len = self.end - self.start;
+        if len <= chrono::Duration::zero() {
+            len + chrono::Duration::days(1)
+        } else {
+            len
+        }
+    }
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got '{}'", s))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("invalid time '{}': {}", t.trim(), e))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+/// When osqueryd should be restarted for maintenance
+#[derive(Debug, Clone, Default)]
+pub struct MaintenancePolicy {
+    window: Option<MaintenanceWindow>,
+    max_uptime: Option<Duration>,
+    /// Offset into the window, so a fleet doesn't restart in lockstep
+    jitter: chrono::Duration,
+}
+
+impl MaintenancePolicy {
+    pub fn new(window: Option<MaintenanceWindow>, max_uptime: Option<Duration>) -> Self {
+        let jitter = window
+            .map(|w| chrono::Duration::seconds(rand::random_range(0..w.len().num_seconds().max(1))))
+            .unwrap_or_else(chrono::Duration::zero);
+        Self {
+            window,
+            max_uptime,
+            jitter,
+        }
+    }
+
+    /// Next maintenance restart for an osqueryd started at `started`
+    pub fn next_restart(&self, started: DateTime<Local>) -> Option<DateTime<Local>> {
+        let max_uptime = self
+            .max_uptime
+            .and_then(|d| chrono::Duration::from_std(d).ok());
+        let earliest = match max_uptime {
+            Some(uptime) => started + uptime,
+            None => started,
+        };
+
+        let Some(window) = self.window else {
+            return max_uptime.map(|_| earliest);
+        };
+
+        // Start a day early in case a window that crosses midnight is open
+        let mut date = earliest.date_naive().checked_sub_days(Days::new(1))?;
+        for _ in 0..3 {
+            if let Some(start) = Local.from_local_datetime(&date.and_time(window.start)).earliest() {
+                let at = start + self.jitter;
+                if max_uptime.is_some() {
+                    if start + window.len() > earliest {
+                        return Some(at.max(earliest));
+                    }
+                } else if start > earliest {
+                    // Never restart twice within the same window
+                    return Some(at);
+                }
+            }
+            date = date.checked_add_days(Days::new(1))?;
+        }
+        None
+    }
+}
@@ -0,0 +1,106 @@
+//! Wait-for-network gating
+//!
+//! Agents started early in boot, or in containers whose network is set up late,
+//! would otherwise fail to provision or enroll immediately and burn through
+//! their service manager's restart budget. We wait (up to a timeout) for a
+//! default route and for the server's hostname to resolve.
+
+use crate::logging::{info, warning};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often to re-check the network while waiting
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether the host has a default route. Only checked on Linux; elsewhere DNS
+/// resolution alone is used as the signal.
+async fn has_default_route() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        // IPv4: destination 00000000 in /proc/net/route
+        if let Ok(routes) = tokio::fs::read_to_string("/proc/net/route").await {
+            let found = routes
+                .lines()
+                .skip(1)
+                .any(|line| line.split_whitespace().nth(1) == Some("00000000"));
+            if found {
+                return true;
+            }
+        }
+        // IPv6: ::/0 in /proc/net/ipv6_route, ignoring the loopback device
+        if let Ok(routes) = tokio::fs::read_to_string("/proc/net/ipv6_route").await {
+            return routes.lines().any(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                fields.len() >= 10
+                    && fields[0] == "00000000000000000000000000000000"
+                    && fields[1] == "00"
+                    && fields[9] != "lo"
+            });
+        }
+        false
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        true
+    }
+}
+
+/// The server's address, with the HTTPS port unless one is given
+fn server_addr(server: &str) -> String {
+    if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:443", server)
+    }
+}
+
+/// Whether the server's hostname resolves
+async fn server_resolves(server: &str) -> bool {
+    match tokio::net::lookup_host(server_addr(server)).await {
+        Ok(mut addrs) => addrs.next().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Wait until the network looks usable or the timeout expires. Returns whether
+/// the network came up; callers carry on either way so the real error surfaces.
+pub async fn wait_for_network(server: &str, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
+
+    loop {
+        let route = has_default_route().await;
+        if route && server_resolves(server).await {
+            if announced {
+                info!("Network is up");
+            }
+            return true;
+        }
+
+        if Instant::now() >= deadline {
+            warning!(
+                "Network not ready after {}s ({}), continuing anyway",
+                timeout.as_secs(),
+                if route { "server does not resolve" } else { "no default route" }
+            );
+            return false;
+        }
+
+        if !announced {
+            info!("Waiting for network...");
+            announced = true;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Open a TCP connection to the server, returning how long it took
+pub async fn connect_time(server: &str, timeout: Duration) -> Result<Duration, String> {
+    let started = Instant::now();
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect(server_addr(server))).await {
+        Ok(Ok(_)) => Ok(started.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no connection after {}s", timeout.as_secs())),
+    }
+}
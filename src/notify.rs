@@ -0,0 +1,153 @@
+//! systemd readiness and watchdog notifications
+//!
+//! Under a `Type=notify` unit, tells systemd the agent is ready once osqueryd
+//! has stayed up for a little while (shadow has already enrolled by then), and
+//! publishes the agent's state as the unit's status text. When the unit sets
+//! `WatchdogSec=`, keep-alives are only sent while the agent passes a health
+//! check, so systemd restarts an agent that has wedged. Does nothing when
+//! `NOTIFY_SOCKET` isn't set.
+
+use crate::logging::warning;
+use crate::status::{secs_since, AgentState, AgentStatus, SharedStatus};
+use std::time::Duration;
+
+/// How long osqueryd must stay up before the agent counts as ready
+const READY_AFTER: Duration = Duration::from_secs(10);
+
+/// How often to check readiness and state changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the status may stay locked before the agent counts as wedged
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Send readiness, status, and watchdog notifications to systemd until the
+/// agent exits
+pub fn spawn(status: SharedStatus) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let watchdog = watchdog_interval();
+    tokio::spawn(async move {
+        let mut ready = false;
+        let mut last_state = None;
+        let mut next_ping = tokio::time::Instant::now();
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+
+        loop {
+            ticker.tick().await;
+            let snapshot = check_health(&status).await;
+
+            if let Some(snapshot) = &snapshot {
+                let mut message = String::new();
+                if !ready && is_ready(snapshot) {
+                    ready = true;
+                    message.push_str("READY=1\n");
+                }
+                if last_state != Some(snapshot.state) {
+                    last_state = Some(snapshot.state);
+                    message.push_str(&format!("STATUS=osquery {}\n", snapshot.state));
+                }
+                if !message.is_empty() {
+                    send(&socket, &message);
+                }
+            }
+
+            if let Some(interval) = watchdog {
+                if tokio::time::Instant::now() >= next_ping {
+                    if snapshot.is_some() {
+                        send(&socket, "WATCHDOG=1");
+                        next_ping += interval;
+                    } else {
+                        warning!("Health check failed, withholding systemd watchdog keep-alive");
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Half the unit's `WatchdogSec=`, if systemd asked us for keep-alives
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+/// Whether startup is over: osqueryd has stayed up, or the agent has settled
+/// into a state where restarting it wouldn't help
+fn is_ready(status: &AgentStatus) -> bool {
+    match status.state {
+        AgentState::Running => status
+            .child_started_at
+            .is_some_and(|started| secs_since(started) >= READY_AFTER.as_secs()),
+        AgentState::CrashLoop | AgentState::Paused | AgentState::Stopped => true,
+        AgentState::Provisioning | AgentState::Enrolling | AgentState::Backoff => false,
+    }
+}
+
+/// The agent's status, or None if the agent looks wedged: the status is stuck
+/// locked, or osqueryd is gone without the supervisor noticing
+pub async fn check_health(status: &SharedStatus) -> Option<AgentStatus> {
+    let snapshot = read_status(status).await?;
+    match running_child(&snapshot) {
+        Some(pid) if !process_exists(pid) => {
+            // The supervisor may be reaping it right now, so give it a moment
+            tokio::time::sleep(LOCK_TIMEOUT).await;
+            let snapshot = read_status(status).await?;
+            if running_child(&snapshot) == Some(pid) {
+                return None;
+            }
+            Some(snapshot)
+        }
+        _ => Some(snapshot),
+    }
+}
+
+async fn read_status(status: &SharedStatus) -> Option<AgentStatus> {
+    let status = status.clone();
+    tokio::time::timeout(LOCK_TIMEOUT, tokio::task::spawn_blocking(move || status.snapshot()))
+        .await
+        .ok()?
+        .ok()
+}
+
+fn running_child(status: &AgentStatus) -> Option<u32> {
+    status.child_pid.filter(|_| status.state == AgentState::Running)
+}
+
+fn process_exists(pid: u32) -> bool {
+    use sysinfo::{Pid, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    system.process(pid).is_some()
+}
+
+#[cfg(target_os = "linux")]
+fn send(socket: &std::ffi::OsStr, message: &str) {
+    use crate::logging::error;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    let bytes = socket.as_bytes();
+    let addr = match bytes.strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(socket),
+    };
+    let result = addr.and_then(|addr| {
+        let sock = UnixDatagram::unbound()?;
+        sock.send_to_addr(message.as_bytes(), &addr)
+    });
+    if let Err(e) = result {
+        error!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send(_socket: &std::ffi::OsStr, _message: &str) {}
@@ -2,12 +2,20 @@
 //!
 //! Downloads and manages osquery binaries from official GitHub releases.
 
+use crate::audit;
+use crate::errors;
+use crate::eventlog::{self, Event};
+use crate::logging::{info, warning};
+use crate::telemetry;
 use anyhow::{Context, Result};
 use clap::ValueEnum;
 use futures_util::StreamExt;
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
 use std::fmt;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
@@ -41,8 +49,74 @@ impl HostIdentifier {
     }
 }
 
+/// Environment variables passed through to osqueryd; everything else is dropped
+/// so credentials in shadow's environment never reach the child process
+#[cfg(not(target_os = "windows"))]
+const INHERITED_ENV: &[&str] = &["PATH", "HOME", "LANG", "LC_ALL", "LC_CTYPE", "TZ"];
+
+#[cfg(target_os = "windows")]
+const INHERITED_ENV: &[&str] = &[
+    "PATH",
+    "SystemRoot",
+    "SystemDrive",
+    "windir",
+    "ProgramData",
+    "TEMP",
+    "TMP",
+];
+
+/// Fallback PATH when shadow itself was started without one
+#[cfg(not(target_os = "windows"))]
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Build a command for osqueryd with a minimal, explicit environment
+pub fn osqueryd_command(osqueryd_path: &Path) -> tokio::process::Command {
+    child_command(osqueryd_path)
+}
+
+/// Build a command for a program the agent runs (osqueryd itself, a wrapper like
+/// sandbox-exec, or a hook) with a minimal, explicit environment
+pub fn child_command(program: &Path) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new(program);
+    cmd.env_clear();
+
+    for key in INHERITED_ENV {
+        if let Some(value) = std::env::var_os(key) {
+            cmd.env(key, value);
+        }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    if std::env::var_os("PATH").is_none() {
+        cmd.env("PATH", DEFAULT_PATH);
+    }
+
+    // The agent's own memory cap isn't meant for osqueryd
+    #[cfg(target_os = "linux")]
+    if let Some(limit) = crate::limits::inherited_data_limit() {
+        use std::os::unix::process::CommandExt;
+
+        // SAFETY: the closure only calls setrlimit, which is async-signal safe
+        unsafe {
+            cmd.as_std_mut().pre_exec(move || {
+                if libc::setrlimit(libc::RLIMIT_DATA, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // Running as a service there's no console (session 0), so don't let
+    // osqueryd allocate one
+    #[cfg(target_os = "windows")]
+    cmd.creation_flags(windows_sys::Win32::System::Threading::CREATE_NO_WINDOW);
+
+    cmd
+}
+
 /// Current osquery version to download
-const OSQUERY_VERSION: &str = "5.20.0";
+pub const OSQUERY_VERSION: &str = "5.20.0";
 
 /// GitHub release URL template
 const GITHUB_RELEASE_URL: &str = "https://github.com/osquery/osquery/releases/download";
@@ -50,15 +124,18 @@ const GITHUB_RELEASE_URL: &str = "https://github.com/osquery/osquery/releases/do
 /// Platform-specific download info
 struct PlatformInfo {
     /// Filename to download from GitHub releases
-    download_filename: &'static str,
-    /// Expected SHA256 hash (from osquery releases)
-    sha256: &'static str,
+    download_filename: String,
+    /// Expected SHA256 hash of [`OSQUERY_VERSION`]'s archive (from osquery
+    /// releases)
+    pinned_sha256: &'static str,
     /// Archive type
     archive_type: ArchiveType,
     /// Path to osqueryd binary within the archive
     binary_path: &'static str,
 }
 
+// Only the current platform's variant is ever constructed
+#[allow(dead_code)]
 #[derive(Clone, Copy)]
 enum ArchiveType {
     TarGz,
@@ -66,16 +143,16 @@ enum ArchiveType {
     Zip,    // Windows
 }
 
-/// Get platform-specific download info
-fn get_platform_info() -> Result<PlatformInfo> {
+/// Get platform-specific download info for an osquery version
+fn get_platform_info(version: &str) -> Result<PlatformInfo> {
     // These hashes are from osquery 5.20.0 release
     // https://github.com/osquery/osquery/releases/tag/5.20.0
     
     #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
     {
         Ok(PlatformInfo {
-            download_filename: "osquery-5.20.0_1.linux_x86_64.tar.gz",
-            sha256: "4f0e4e23c864a72dcb20bf4661ea0d2719358c938ec342105a633cc732dc03c3",
+            download_filename: format!("osquery-{}_1.linux_x86_64.tar.gz", version),
+            pinned_sha256: "4f0e4e23c864a72dcb20bf4661ea0d2719358c938ec342105a633cc732dc03c3",
             archive_type: ArchiveType::TarGz,
             binary_path: "opt/osquery/bin/osqueryd",
         })
@@ -84,8 +161,8 @@ fn get_platform_info() -> Result<PlatformInfo> {
     #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
     {
         Ok(PlatformInfo {
-            download_filename: "osquery-5.20.0_1.linux_aarch64.tar.gz",
-            sha256: "cb8d942943c765ebd87c5a3b01fc09988c8ad31acf094207fc49e7acf88ec573",
+            download_filename: format!("osquery-{}_1.linux_aarch64.tar.gz", version),
+            pinned_sha256: "cb8d942943c765ebd87c5a3b01fc09988c8ad31acf094207fc49e7acf88ec573",
             archive_type: ArchiveType::TarGz,
             binary_path: "opt/osquery/bin/osqueryd",
         })
@@ -94,8 +171,8 @@ fn get_platform_info() -> Result<PlatformInfo> {
     #[cfg(target_os = "macos")]
     {
         Ok(PlatformInfo {
-            download_filename: "osquery-5.20.0.pkg",
-            sha256: "569751a8bc4fdd3aba94071a4b840003066b2cff8e1b0ef9abf46c7a482173c0",
+            download_filename: format!("osquery-{}.pkg", version),
+            pinned_sha256: "569751a8bc4fdd3aba94071a4b840003066b2cff8e1b0ef9abf46c7a482173c0",
             archive_type: ArchiveType::Pkg,
             binary_path: "opt/osquery/lib/osquery.app/Contents/MacOS/osqueryd",
         })
@@ -104,8 +181,8 @@ fn get_platform_info() -> Result<PlatformInfo> {
     #[cfg(target_os = "windows")]
     {
         Ok(PlatformInfo {
-            download_filename: "osquery-5.20.0.windows_x86_64.zip",
-            sha256: "af66cb90537c52459539141f183ae8abb3073f29089b5d1f68245381d80967e1",
+            download_filename: format!("osquery-{}.windows_x86_64.zip", version),
+            pinned_sha256: "af66cb90537c52459539141f183ae8abb3073f29089b5d1f68245381d80967e1",
             archive_type: ArchiveType::Zip,
             binary_path: "osqueryd/osqueryd.exe",
         })
@@ -122,12 +199,65 @@ fn get_platform_info() -> Result<PlatformInfo> {
     }
 }
 
+/// An osquery release that may be installed: its version and the SHA256 of
+/// its archive for this platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub version: String,
+    pub sha256: String,
+}
+
+impl Release {
+    /// The release this agent ships with
+    fn pinned() -> Result<Self> {
+        Ok(Self {
+            version: OSQUERY_VERSION.to_string(),
+            sha256: get_platform_info(OSQUERY_VERSION)?.pinned_sha256.to_string(),
+        })
+    }
+}
+
+impl FromStr for Release {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (version, sha256) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected VERSION=SHA256, got '{}'", s))?;
+        let valid_version = !version.is_empty() && version.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+        if !valid_version {
+            return Err(format!("'{}' isn't an osquery version", version));
+        }
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("expected the SHA256 of osquery {}'s archive, got '{}'", version, sha256));
+        }
+        Ok(Self {
+            version: version.to_string(),
+            sha256: sha256.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Manifest written next to the provisioned binary
+const MANIFEST_FILE: &str = "osquery.json";
+
+/// Records what was provisioned, so a missing binary can be restored exactly
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ProvisionManifest {
+    version: String,
+    archive: String,
+    sha256: String,
+}
+
 /// Manages osquery binary provisioning
+#[derive(Clone)]
 pub struct OsqueryProvisioner {
     /// Directory where osquery will be stored
     data_dir: PathBuf,
     /// Skip hash verification (for development)
     skip_verify: bool,
+    /// Releases besides [`OSQUERY_VERSION`] that may be installed
+    allowed: Vec<Release>,
 }
 
 impl OsqueryProvisioner {
@@ -135,7 +265,32 @@ impl OsqueryProvisioner {
         Self {
             data_dir,
             skip_verify: false,
+            allowed: Vec::new(),
+        }
+    }
+
+    /// Allow upgrading to these releases, verified against their hashes
+    pub fn allow_releases(mut self, allowed: Vec<Release>) -> Self {
+        self.allowed = allowed;
+        self
+    }
+
+    /// The release of `version` that may be installed: the one this agent
+    /// ships with, or one allowed with its hash
+    fn release(&self, version: &str) -> Result<Release> {
+        if version == OSQUERY_VERSION {
+            return Release::pinned();
         }
+        self.allowed
+            .iter()
+            .find(|release| release.version == version)
+            .cloned()
+            .with_context(|| {
+                format!(
+                    "osquery {} isn't the version this shadow ships with ({}) or allowed by --allow-osquery-version",
+                    version, OSQUERY_VERSION
+                )
+            })
     }
 
     /// Allow skipping hash verification (useful during development or when hashes aren't available)
@@ -172,11 +327,9 @@ impl OsqueryProvisioner {
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
-            if let Ok(metadata) = std::fs::metadata(&path) {
-                let perms = metadata.permissions();
-                return perms.mode() & 0o111 != 0;
-            }
-            return false;
+            std::fs::metadata(&path)
+                .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
         }
         
         #[cfg(not(unix))]
@@ -186,46 +339,98 @@ impl OsqueryProvisioner {
     }
 
     /// Provision osquery - download if not present
+    #[tracing::instrument(name = "provision", skip_all)]
     pub async fn ensure_provisioned(&self) -> Result<PathBuf> {
         if self.is_provisioned().await {
-            println!("  osquery:   {} (cached)", self.osqueryd_path().display());
+            info!("  osquery:   {} (cached)", self.osqueryd_path().display());
             return Ok(self.osqueryd_path());
         }
 
-        println!("  osquery:   Downloading...");
-        self.download_and_extract().await?;
+        info!("  osquery:   Downloading...");
+        self.download_and_extract(&Release::pinned()?).await?;
         
         Ok(self.osqueryd_path())
     }
 
-    /// Download osquery from GitHub releases and extract
-    async fn download_and_extract(&self) -> Result<()> {
-        let platform_info = get_platform_info()?;
+    /// Restore osqueryd after it disappeared (cleanup tooling, AV quarantine).
+    /// The recorded manifest must match a release this agent may install, so
+    /// the restored binary is verified against the same hash as the original.
+    pub async fn reprovision(&self) -> Result<PathBuf> {
+        let manifest_path = self.data_dir.join("bin").join(MANIFEST_FILE);
+        let release = match fs::read(&manifest_path).await {
+            Ok(data) => {
+                let manifest: ProvisionManifest =
+                    serde_json::from_slice(&data).context("Invalid provisioning manifest")?;
+                match self.release(&manifest.version) {
+                    Ok(release) if release.sha256 == manifest.sha256 => release,
+                    _ => anyhow::bail!(
+                        "Provisioned osquery {} ({}) doesn't match this agent's osquery {} or an allowed version",
+                        manifest.version,
+                        manifest.archive,
+                        OSQUERY_VERSION
+                    ),
+                }
+            }
+            Err(_) => Release::pinned()?,
+        };
+
+        info!("  osquery:   Re-provisioning missing binary...");
+        self.download_and_extract(&release).await?;
+        Ok(self.osqueryd_path())
+    }
+
+    /// Install `version` of osquery, which must be the one this agent ships
+    /// with or an allowed one, if a different one was provisioned (e.g. by an
+    /// older agent). osqueryd must not be running. Returns whether anything
+    /// was installed.
+    pub async fn upgrade(&self, version: &str) -> Result<bool> {
+        let release = self.release(version)?;
+        let manifest_path = self.data_dir.join("bin").join(MANIFEST_FILE);
+        let current = match fs::read(&manifest_path).await {
+            Ok(data) => serde_json::from_slice::<ProvisionManifest>(&data)
+                .ok()
+                .is_some_and(|manifest| manifest.version == release.version && manifest.sha256 == release.sha256),
+            Err(_) => false,
+        };
+        if current && self.is_provisioned().await {
+            return Ok(false);
+        }
+
+        info!("  osquery:   Upgrading to {}...", release.version);
+        self.download_and_extract(&release).await?;
+        Ok(true)
+    }
+
+    /// Download a release of osquery from GitHub releases and extract
+    async fn download_and_extract(&self, release: &Release) -> Result<()> {
+        let platform_info = get_platform_info(&release.version)?;
         
         let download_url = format!(
             "{}/{}/{}",
-            GITHUB_RELEASE_URL, OSQUERY_VERSION, platform_info.download_filename
+            GITHUB_RELEASE_URL, release.version, platform_info.download_filename
         );
 
-        println!("             Downloading from GitHub releases...");
-        println!("             URL: {}", download_url);
+        info!("Downloading osquery {} from {}", release.version, download_url);
 
         // Create temp file for download
         let temp_dir = self.data_dir.join("tmp");
         fs::create_dir_all(&temp_dir).await?;
-        let temp_file = temp_dir.join(platform_info.download_filename);
+        let temp_file = temp_dir.join(&platform_info.download_filename);
 
         // Download with progress
-        self.download_file(&download_url, &temp_file).await?;
+        self.download_file(&download_url, &temp_file)
+            .await
+            .context(errors::OSQUERY_DOWNLOAD)?;
 
         // Verify hash (unless skipped)
+        let archive_sha256 = file_sha256(&temp_file).await?;
         if !self.skip_verify {
-            println!("             Verifying checksum...");
-            self.verify_hash(&temp_file, platform_info.sha256).await?;
+            info!("Verifying checksum...");
+            verify_hash(&archive_sha256, &release.sha256).context(errors::OSQUERY_CHECKSUM)?;
         }
 
         // Extract based on archive type
-        println!("             Extracting...");
+        info!("Extracting...");
         let bin_dir = self.data_dir.join("bin");
         fs::create_dir_all(&bin_dir).await?;
 
@@ -260,61 +465,67 @@ impl OsqueryProvisioner {
             std::fs::set_permissions(&osqueryd_path, perms)?;
         }
 
-        println!("             Done! osqueryd installed at {:?}", osqueryd_path);
+        let manifest = ProvisionManifest {
+            version: release.version.clone(),
+            archive: platform_info.download_filename.clone(),
+            sha256: release.sha256.clone(),
+        };
+        fs::write(
+            self.data_dir.join("bin").join(MANIFEST_FILE),
+            serde_json::to_vec_pretty(&manifest)?,
+        )
+        .await?;
+
+        info!("osqueryd installed at {}", osqueryd_path.display());
+        let detail = serde_json::json!({
+            "version": release.version,
+            "url": download_url,
+            "archive_sha256": archive_sha256,
+            "verified": !self.skip_verify,
+            "path": osqueryd_path,
+            "osqueryd_sha256": file_sha256(&osqueryd_path).await?,
+        });
+        audit::record_agent(&self.data_dir, "osquery_install", detail).await;
+        eventlog::report(
+            Event::OsqueryInstalled,
+            &format!("Installed osquery {} at {}", release.version, osqueryd_path.display()),
+        );
         Ok(())
     }
 
-    /// Download a file with progress indication
+    /// Download a file with progress indication, recording how long it took
+    /// and from where
     async fn download_file(&self, url: &str, dest: &Path) -> Result<()> {
-        let client = reqwest::Client::new();
-        let response = client
-            .get(url)
-            .send()
-            .await
-            .context("Failed to start download")?;
-
-        if !response.status().is_success() {
-            anyhow::bail!("Download failed with status: {}", response.status());
-        }
-
-        let total_size = response.content_length().unwrap_or(0);
-        let mut file = tokio::fs::File::create(dest).await?;
-        let mut downloaded: u64 = 0;
-        let mut stream = response.bytes_stream();
-
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.context("Error downloading chunk")?;
-            file.write_all(&chunk).await?;
-            downloaded += chunk.len() as u64;
-
-            // Simple progress indicator
-            if total_size > 0 {
-                let percent = (downloaded * 100) / total_size;
-                print!("\r             Downloaded: {}%   ", percent);
-            }
-        }
-        println!();
-
-        file.flush().await?;
-        Ok(())
-    }
-
-    /// Verify SHA256 hash of downloaded file
-    async fn verify_hash(&self, file: &Path, expected: &str) -> Result<()> {
-        let data = fs::read(file).await?;
-        let mut hasher = Sha256::new();
-        hasher.update(&data);
-        let result = hasher.finalize();
-        let hash = format!("{:x}", result);
-
-        if hash != expected {
-            anyhow::bail!(
-                "Hash mismatch!\n  Expected: {}\n  Got: {}",
-                expected,
-                hash
-            );
-        }
-        Ok(())
+        let started = Instant::now();
+        let mut fetched = Fetched {
+            host: reqwest::Url::parse(url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_default(),
+            status: None,
+            bytes: 0,
+        };
+        let result = fetch(url, dest, &mut fetched).await;
+        let elapsed = started.elapsed();
+
+        let outcome = match (&result, fetched.status) {
+            (Ok(()), _) => "ok".to_string(),
+            (Err(_), Some(status)) if !status.is_success() => status.as_u16().to_string(),
+            (Err(_), _) => "error".to_string(),
+        };
+        telemetry::record_download(elapsed, fetched.bytes, &fetched.host, &outcome);
+        info!(
+            server = %fetched.host,
+            result = %outcome,
+            bytes = fetched.bytes,
+            duration_ms = elapsed.as_millis() as u64,
+            "Download from {} finished in {:.1}s ({}, {} MB)",
+            fetched.host,
+            elapsed.as_secs_f64(),
+            outcome,
+            fetched.bytes / (1024 * 1024)
+        );
+        result
     }
 
     /// Extract osqueryd from a .tar.gz archive
@@ -440,38 +651,181 @@ impl OsqueryProvisioner {
     }
 }
 
+/// osquery's name for this host's platform, as in pack and query `platform`s
+pub const PLATFORM: &str = if cfg!(target_os = "windows") {
+    "windows"
+} else if cfg!(target_os = "macos") {
+    "darwin"
+} else {
+    "linux"
+};
+
+/// How long a single host identifier query may run before it is killed
+const HOST_ID_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of attempts at querying the host identifier
+const HOST_ID_ATTEMPTS: u32 = 3;
+
+/// How long a query in osqueryd's shell may take
+const SHELL_QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// SHA256 of a file, as hex
+async fn file_sha256(file: &Path) -> Result<String> {
+    let data = fs::read(file).await?;
+    Ok(format!("{:x}", Sha256::digest(&data)))
+}
+
+/// How far a download got
+struct Fetched {
+    /// Host that served it, after redirects
+    host: String,
+    status: Option<reqwest::StatusCode>,
+    bytes: u64,
+}
+
+async fn fetch(url: &str, dest: &Path, fetched: &mut Fetched) -> Result<()> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .context("Failed to start download")?;
+    fetched.status = Some(response.status());
+    if let Some(host) = response.url().host_str() {
+        fetched.host = host.to_string();
+    }
+
+    if !response.status().is_success() {
+        anyhow::bail!("Download failed with status: {}", response.status());
+    }
+
+    let total_size = response.content_length().unwrap_or(0);
+    let mut file = tokio::fs::File::create(dest).await?;
+    let mut reported = 0;
+    let mut stream = response.bytes_stream();
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.context("Error downloading chunk")?;
+        file.write_all(&chunk).await?;
+        fetched.bytes += chunk.len() as u64;
+
+        // Progress in quarters, to keep the log readable
+        if let Some(percent) = (fetched.bytes * 100).checked_div(total_size) {
+            if percent >= reported + 25 {
+                reported = percent - percent % 25;
+                info!("Downloaded {}%", reported);
+            }
+        }
+    }
+
+    file.flush().await?;
+    Ok(())
+}
+
+/// Check a downloaded file's SHA256 against the expected one
+fn verify_hash(hash: &str, expected: &str) -> Result<()> {
+    if hash != expected {
+        anyhow::bail!(
+            "Hash mismatch!\n  Expected: {}\n  Got: {}",
+            expected,
+            hash
+        );
+    }
+    Ok(())
+}
+
 /// Query osquery for the host identifier based on the selected mode
 ///
 /// - `uuid`: Returns the hardware UUID from `system_info.uuid`
 /// - `instance`: Returns the osquery instance ID from `osquery_info.instance_id`
 ///
 /// For `instance` mode, osquery needs a database path to generate/persist the instance ID.
+///
+/// Each attempt is killed if it doesn't finish within [`HOST_ID_TIMEOUT`] (e.g. when
+/// osquery hangs on a stale RocksDB lock). In `uuid` mode, retries use a fresh
+/// temporary database. `instance` mode always uses the real database, since a
+/// fresh one would mint a new instance ID and change the host's identity.
 pub async fn get_host_identifier(
     osqueryd_path: &Path,
     mode: &HostIdentifier,
     data_dir: &Path,
 ) -> Result<String> {
-    use std::collections::HashMap;
+    let mut last_error = None;
+
+    for attempt in 1..=HOST_ID_ATTEMPTS {
+        let temp_db = match mode {
+            HostIdentifier::Uuid if attempt > 1 => {
+                let temp_dir = data_dir.join("tmp");
+                fs::create_dir_all(&temp_dir).await?;
+                let path = temp_dir.join(format!("hostid-{}.db", attempt));
+                let _ = fs::remove_dir_all(&path).await;
+                Some(path)
+            }
+            _ => None,
+        };
+        let database_path = match mode {
+            HostIdentifier::Instance => Some(data_dir.join("osquery.db")),
+            HostIdentifier::Uuid => temp_db.clone(),
+        };
+
+        let result = tokio::time::timeout(
+            HOST_ID_TIMEOUT,
+            query_host_identifier(osqueryd_path, mode, database_path.as_deref()),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(anyhow::anyhow!(
+                "osquery did not respond within {}s",
+                HOST_ID_TIMEOUT.as_secs()
+            ))
+        });
+
+        if let Some(temp_db) = temp_db {
+            let _ = fs::remove_dir_all(&temp_db).await;
+        }
+
+        match result {
+            Ok(host_id) => return Ok(host_id),
+            Err(e) => {
+                if attempt < HOST_ID_ATTEMPTS {
+                    warning!("Host identifier query failed (attempt {}): {:#}", attempt, e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no attempts made")))
+        .with_context(|| format!("Failed to get host identifier after {} attempts", HOST_ID_ATTEMPTS))
+        .context(errors::HOST_ID)
+}
+
+/// Run a single host identifier query
+async fn query_host_identifier(
+    osqueryd_path: &Path,
+    mode: &HostIdentifier,
+    database_path: Option<&Path>,
+) -> Result<String> {
     use std::process::Stdio;
-    use tokio::process::Command;
 
     let (query, field) = match mode {
         HostIdentifier::Uuid => ("SELECT uuid FROM system_info;", "uuid"),
         HostIdentifier::Instance => ("SELECT instance_id FROM osquery_info;", "instance_id"),
     };
 
-    let mut cmd = Command::new(osqueryd_path);
+    let mut cmd = osqueryd_command(osqueryd_path);
     cmd.arg("-S"); // Shell mode
     cmd.arg("--json");
 
-    // For instance mode, we need to specify the database path so osquery can
-    // generate/retrieve a persistent instance_id
-    if *mode == HostIdentifier::Instance {
-        cmd.arg("--database_path").arg(data_dir.join("osquery.db"));
+    if let Some(database_path) = database_path {
+        cmd.arg("--database_path").arg(database_path);
     }
 
     cmd.arg(query);
 
+    // Make sure a timed-out query doesn't leave osquery running
+    cmd.kill_on_drop(true);
+
     let output = cmd
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -496,3 +850,37 @@ pub async fn get_host_identifier(
         .map(|s| s.to_string())
         .with_context(|| format!("No {} found in osquery output", field))
 }
+
+/// Run `sql` in osqueryd's shell, for the rows it returns
+pub async fn shell_query(osqueryd_path: &Path, sql: &str) -> Result<Vec<HashMap<String, String>>> {
+    use std::process::Stdio;
+
+    let mut cmd = osqueryd_command(osqueryd_path);
+    cmd.arg("-S").arg("--json").arg(sql);
+    cmd.kill_on_drop(true);
+    let output = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).output();
+    let output = tokio::time::timeout(SHELL_QUERY_TIMEOUT, output)
+        .await
+        .context("osquery didn't answer in time")?
+        .context("Failed to run osquery")?;
+    if !output.status.success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    serde_json::from_slice(&output.stdout).context("Failed to parse osquery output")
+}
+
+/// Get the version of an osqueryd binary (`osqueryd version 5.20.0`)
+pub async fn get_osquery_version(osqueryd_path: &Path) -> Result<String> {
+    let output = osqueryd_command(osqueryd_path)
+        .arg("--version")
+        .output()
+        .await
+        .context("Failed to run osqueryd --version")?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout
+        .split_whitespace()
+        .last()
+        .map(|s| s.to_string())
+        .context("No version in osqueryd --version output")
+}
@@ -0,0 +1,81 @@
+//! Output formats
+//!
+//! Commands that print records (`shadow status`, `explain`, `doctor`, and
+//! `inventory`) print them through here, so `--output` means the same for each:
+//! `table` for people, or `json`, `yaml`, or `ndjson` (one compact JSON object
+//! per line) for scripts. The structured formats serialize the same records;
+//! only the table is laid out by the command itself.
+
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned text for people
+    #[default]
+    Table,
+    /// Pretty-printed JSON
+    Json,
+    Yaml,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// `--output`, for the commands that take it
+#[derive(Args, Debug, Clone)]
+pub struct OutputArgs {
+    /// Output format
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    output: Option<OutputFormat>,
+
+    /// Same as --output json
+    #[arg(long, conflicts_with = "output")]
+    json: bool,
+}
+
+impl OutputArgs {
+    pub fn format(&self) -> OutputFormat {
+        self.given().unwrap_or_default()
+    }
+
+    /// The format asked for, if any, for commands whose default output isn't
+    /// a list of records
+    pub fn given(&self) -> Option<OutputFormat> {
+        match self.json {
+            true => Some(OutputFormat::Json),
+            false => self.output,
+        }
+    }
+}
+
+/// Print one record, e.g. the agent's status
+pub fn one<T: Serialize>(format: OutputFormat, record: &T, table: impl FnOnce(&T)) -> Result<()> {
+    match format {
+        OutputFormat::Table => table(record),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(record)?),
+        OutputFormat::Yaml => print!("{}", serde_norway::to_string(record)?),
+        OutputFormat::Ndjson => println!("{}", serde_json::to_string(record)?),
+    }
+    Ok(())
+}
+
+/// Print a list of records, e.g. doctor's checks. A JSON or YAML list is one
+/// document; NDJSON has a line per record.
+pub fn list<T: Serialize>(
+    format: OutputFormat,
+    records: &[T],
+    table: impl FnOnce(&[T]),
+) -> Result<()> {
+    match format {
+        OutputFormat::Table => table(records),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        OutputFormat::Yaml => print!("{}", serde_norway::to_string(records)?),
+        OutputFormat::Ndjson => {
+            for record in records {
+                println!("{}", serde_json::to_string(record)?);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,586 @@
+//! Native installers
+//!
+//! `shadow package` builds a .deb, .rpm, .pkg, or .msi containing a shadow
+//! binary and the settings this command was given. Installing it installs and
+//! starts the service exactly as `shadow service install` would, so MSPs can
+//! build one installer per customer from a single command. The org token can
+//! be baked in or left out and supplied at install time.
+//!
+//! .deb packages are assembled directly; the other formats are built with the
+//! platform's own tools (rpmbuild, pkgbuild, WiX or msitools).
+
+use crate::service::Setting;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+const NAME: &str = "hyprwatch-shadow";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+
+/// Where packages install the binary and the packaged settings. Not under
+/// /etc/shadow, which is the password file.
+const LINUX_BINARY: &str = "/usr/bin/shadow";
+const LINUX_SETTINGS: &str = "/etc/hyprwatch/shadow-package.env";
+const MACOS_BINARY: &str = "/usr/local/bin/shadow";
+const MACOS_SETTINGS: &str = "/Library/Application Support/Hyprwatch/shadow-package.env";
+
+/// Identifies the product across MSI versions, so upgrades replace it
+const MSI_UPGRADE_CODE: &str = "E6DB5BB3-FBF3-4CC7-96F7-4AAEB93EFB1C";
+
+/// Installer format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Debian and Ubuntu
+    Deb,
+    /// RHEL, Fedora, and SUSE (needs rpmbuild)
+    Rpm,
+    /// macOS (needs pkgbuild, so must be built on a Mac)
+    Pkg,
+    /// Windows (needs WiX or msitools)
+    Msi,
+}
+
+/// What to package
+#[derive(Debug)]
+pub struct PackageConfig {
+    pub format: Format,
+    /// The shadow binary for the target platform
+    pub binary: PathBuf,
+    /// Settings for the service
+    pub settings: Vec<Setting>,
+    /// Directory to write the installer to
+    pub output_dir: PathBuf,
+}
+
+/// CPU architecture of the packaged binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arch {
+    X86_64,
+    Aarch64,
+}
+
+impl Arch {
+    fn deb(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "amd64",
+            Arch::Aarch64 => "arm64",
+        }
+    }
+
+    fn rpm(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+        }
+    }
+
+    fn msi(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x64",
+            Arch::Aarch64 => "arm64",
+        }
+    }
+}
+
+/// `shadow package`
+pub async fn build(config: PackageConfig) -> Result<()> {
+    let binary = fs::read(&config.binary)
+        .await
+        .with_context(|| format!("Failed to read {}", config.binary.display()))?;
+    let arch = binary_arch(config.format, &binary)
+        .with_context(|| format!("{} can't go in a .{} package", config.binary.display(), extension(config.format)))?;
+    fs::create_dir_all(&config.output_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", config.output_dir.display()))?;
+
+    if !config.settings.iter().any(|setting| setting.id == "org_token") {
+        println!("No org token given; it will have to be supplied at install time.");
+    }
+    let path = match config.format {
+        Format::Deb => deb(&config, &binary, arch).await?,
+        Format::Rpm => rpm(&config, arch).await?,
+        Format::Pkg => pkg(&config).await?,
+        Format::Msi => msi(&config, arch).await?,
+    };
+    println!("Wrote {}", path.display());
+    Ok(())
+}
+
+fn extension(format: Format) -> &'static str {
+    match format {
+        Format::Deb => "deb",
+        Format::Rpm => "rpm",
+        Format::Pkg => "pkg",
+        Format::Msi => "msi",
+    }
+}
+
+/// Check the binary is for the package's platform, and find its architecture
+fn binary_arch(format: Format, binary: &[u8]) -> Result<Arch> {
+    let u16_at = |offset: usize| {
+        binary
+            .get(offset..offset + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    };
+    match format {
+        Format::Deb | Format::Rpm => {
+            if binary.get(..4) != Some(b"\x7fELF") {
+                anyhow::bail!("it isn't a Linux executable; pass the Linux build with --binary");
+            }
+            match u16_at(18) {
+                Some(0x3e) => Ok(Arch::X86_64),
+                Some(0xb7) => Ok(Arch::Aarch64),
+                _ => anyhow::bail!("it isn't built for x86_64 or aarch64"),
+            }
+        }
+        Format::Msi => {
+            let pe = u16_at(0x3c).map(usize::from);
+            let machine = pe.filter(|&pe| binary.get(pe..pe + 4) == Some(b"PE\0\0"));
+            if binary.get(..2) != Some(b"MZ") || machine.is_none() {
+                anyhow::bail!("it isn't a Windows executable; pass the Windows build with --binary");
+            }
+            match machine.and_then(|pe| u16_at(pe + 4)) {
+                Some(0x8664) => Ok(Arch::X86_64),
+                Some(0xaa64) => Ok(Arch::Aarch64),
+                _ => anyhow::bail!("it isn't built for x64 or arm64"),
+            }
+        }
+        Format::Pkg => {
+            // Thin 64-bit or universal Mach-O
+            let magic = binary.get(..4).unwrap_or_default();
+            if magic != b"\xcf\xfa\xed\xfe" && magic != b"\xca\xfe\xba\xbe" {
+                anyhow::bail!("it isn't a macOS executable; pass the macOS build with --binary");
+            }
+            // pkgbuild doesn't record an architecture
+            Ok(Arch::X86_64)
+        }
+    }
+}
+
+/// Quote a value for sh
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// The settings file: settings with an environment variable, sourced by the
+/// install script
+fn settings_file(config: &PackageConfig) -> String {
+    let mut contents = String::from("# Settings for `shadow service install`, applied when the package is installed\n");
+    for (key, value) in config.settings.iter().filter_map(Setting::env_value) {
+        contents.push_str(&format!("{}={}\n", key, shell_quote(&value)));
+    }
+    contents
+}
+
+/// Install script body: install the service with the packaged settings, and
+/// restart it on upgrade so the new binary runs. `$UPGRADE` is set by the
+/// format-specific preamble.
+fn install_script(config: &PackageConfig, binary: &str, settings: &str) -> String {
+    let mut command = shell_quote(binary);
+    for arg in config
+        .settings
+        .iter()
+        .filter(|setting| setting.env_value().is_none())
+        .flat_map(Setting::args)
+    {
+        command.push(' ');
+        command.push_str(&shell_quote(&arg));
+    }
+    format!(
+        r#"ln -sf {binary} {ctl}
+set -a
+. {settings}
+set +a
+if [ -z "$SHADOW_ORG_TOKEN" ]; then
+    echo "shadow: no org token, so the service wasn't installed." >&2
+    echo "shadow: Add SHADOW_ORG_TOKEN to {settings_display} and reinstall, or set it in the installer's environment." >&2
+    exit 0
+fi
+{command} service install
+if [ -n "$UPGRADE" ]; then
+    {command} service restart
+fi
+"#,
+        settings = shell_quote(settings),
+        settings_display = settings,
+        binary = shell_quote(binary),
+        ctl = shell_quote(&ctl_path(binary)),
+    )
+}
+
+/// Where packages link `shadowctl`, next to the binary
+fn ctl_path(binary: &str) -> String {
+    format!("{}ctl", binary)
+}
+
+/// Remove script body: uninstall the service
+fn remove_script(binary: &str) -> String {
+    format!(
+        "{} service uninstall || true\nrm -f {}\n",
+        shell_quote(binary),
+        shell_quote(&ctl_path(binary))
+    )
+}
+
+/// Files in a package: path, mode, contents
+type Files<'a> = Vec<(&'a str, u32, Vec<u8>)>;
+
+/// A gzipped tarball of files with their parent directories, as dpkg expects
+fn tar_gz(files: &Files) -> Result<Vec<u8>> {
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let mut tar = tar::Builder::new(encoder);
+    let mut dirs = std::collections::BTreeSet::new();
+    for (path, mode, data) in files {
+        let path = format!(".{}", path);
+        let parents: Vec<&Path> = Path::new(&path).ancestors().skip(1).collect();
+        for dir in parents.into_iter().rev() {
+            if dir.as_os_str().is_empty() || !dirs.insert(dir.to_path_buf()) {
+                continue;
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_mtime(mtime);
+            header.set_size(0);
+            tar.append_data(&mut header, format!("{}/", dir.display()), std::io::empty())?;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(*mode);
+        header.set_mtime(mtime);
+        header.set_size(data.len() as u64);
+        tar.append_data(&mut header, &path, data.as_slice())?;
+    }
+    Ok(tar.into_inner()?.finish()?)
+}
+
+/// An ar archive, the container format of a .deb
+fn ar(members: &[(&str, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = b"!<arch>\n".to_vec();
+    for (name, data) in members {
+        let header = format!("{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n", name, 0, 0, 0, 100644, data.len());
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            archive.push(b'\n');
+        }
+    }
+    archive
+}
+
+async fn deb(config: &PackageConfig, binary: &[u8], arch: Arch) -> Result<PathBuf> {
+    let control = format!(
+        "Package: {NAME}\n\
+         Version: {VERSION}\n\
+         Architecture: {arch}\n\
+         Maintainer: Hyprwatch\n\
+         Section: admin\n\
+         Priority: optional\n\
+         Description: {DESCRIPTION}\n",
+        arch = arch.deb(),
+    );
+    // configure is given the previous version when upgrading
+    let postinst = format!(
+        "#!/bin/sh\nset -e\n[ \"$1\" = configure ] || exit 0\nUPGRADE=\"$2\"\n{}",
+        install_script(config, LINUX_BINARY, LINUX_SETTINGS)
+    );
+    let prerm = format!(
+        "#!/bin/sh\n[ \"$1\" = remove ] || exit 0\n{}",
+        remove_script(LINUX_BINARY)
+    );
+    let control_tar = tar_gz(&vec![
+        ("/control", 0o644, control.into_bytes()),
+        ("/conffiles", 0o644, format!("{}\n", LINUX_SETTINGS).into_bytes()),
+        ("/postinst", 0o755, postinst.into_bytes()),
+        ("/prerm", 0o755, prerm.into_bytes()),
+    ])?;
+    let data_tar = tar_gz(&vec![
+        (LINUX_BINARY, 0o755, binary.to_vec()),
+        (LINUX_SETTINGS, 0o600, settings_file(config).into_bytes()),
+    ])?;
+    let package = ar(&[
+        ("debian-binary", b"2.0\n".to_vec()),
+        ("control.tar.gz", control_tar),
+        ("data.tar.gz", data_tar),
+    ]);
+
+    let path = config
+        .output_dir
+        .join(format!("{}_{}_{}.deb", NAME, VERSION, arch.deb()));
+    fs::write(&path, package)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+/// Run a packaging tool, explaining what to install if it's missing
+async fn run_tool(program: &str, args: &[&std::ffi::OsStr], missing: &str) -> Result<()> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => anyhow::anyhow!("{} isn't installed. {}", program, missing),
+            _ => anyhow::anyhow!("Failed to run {}: {}", program, e),
+        })?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed ({}):\n{}{}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(())
+}
+
+/// A scratch directory for a packaging tool, removed when dropped
+struct WorkDir(PathBuf);
+
+impl WorkDir {
+    async fn new(output_dir: &Path) -> Result<Self> {
+        let path = output_dir.join(format!(".shadow-package-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&path).await;
+        fs::create_dir_all(&path).await?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn write_mode(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(path, contents)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(())
+}
+
+async fn rpm(config: &PackageConfig, arch: Arch) -> Result<PathBuf> {
+    let work = WorkDir::new(&config.output_dir).await?;
+    let sources = work.0.join("SOURCES");
+    fs::create_dir_all(&sources).await?;
+    fs::copy(&config.binary, sources.join("shadow")).await?;
+    write_mode(&sources.join("shadow-package.env"), settings_file(config).as_bytes(), 0o600).await?;
+
+    // %post gets 1 on install and 2 on upgrade; %preun 0 on removal
+    let spec = format!(
+        r#"Name: {NAME}
+Version: {VERSION}
+Release: 1
+Summary: {DESCRIPTION}
+License: MIT
+%global debug_package %{{nil}}
+%global __os_install_post %{{nil}}
+
+%description
+{DESCRIPTION}
+
+%install
+install -D -m 0755 {sources}/shadow %{{buildroot}}{LINUX_BINARY}
+install -D -m 0600 {sources}/shadow-package.env %{{buildroot}}{LINUX_SETTINGS}
+
+%files
+{LINUX_BINARY}
+%config(noreplace) {LINUX_SETTINGS}
+
+%post
+UPGRADE=""
+[ "$1" -ge 2 ] && UPGRADE=1
+{install}
+%preun
+[ "$1" -eq 0 ] || exit 0
+{remove}"#,
+        sources = sources.display(),
+        install = install_script(config, LINUX_BINARY, LINUX_SETTINGS),
+        remove = remove_script(LINUX_BINARY),
+    );
+    let spec_path = work.0.join("shadow.spec");
+    fs::write(&spec_path, spec).await?;
+
+    let topdir = format!("_topdir {}", work.0.display());
+    run_tool(
+        "rpmbuild",
+        &[
+            "-bb".as_ref(),
+            "--target".as_ref(),
+            arch.rpm().as_ref(),
+            "--define".as_ref(),
+            topdir.as_ref(),
+            spec_path.as_os_str(),
+        ],
+        "Install rpm-build (or `rpm` on Debian and Ubuntu).",
+    )
+    .await?;
+
+    let name = format!("{}-{}-1.{}.rpm", NAME, VERSION, arch.rpm());
+    let path = config.output_dir.join(&name);
+    fs::copy(work.0.join("RPMS").join(arch.rpm()).join(&name), &path).await?;
+    Ok(path)
+}
+
+async fn pkg(config: &PackageConfig) -> Result<PathBuf> {
+    let work = WorkDir::new(&config.output_dir).await?;
+    let root = work.0.join("root");
+    let binary = fs::read(&config.binary).await?;
+    write_mode(&root.join(&MACOS_BINARY[1..]), &binary, 0o755).await?;
+    write_mode(&root.join(&MACOS_SETTINGS[1..]), settings_file(config).as_bytes(), 0o600).await?;
+    // The installer runs postinstall on every install, including upgrades
+    let scripts = work.0.join("scripts");
+    let postinstall = format!(
+        "#!/bin/sh\nset -e\nUPGRADE=1\n{}",
+        install_script(config, MACOS_BINARY, MACOS_SETTINGS)
+    );
+    write_mode(&scripts.join("postinstall"), postinstall.as_bytes(), 0o755).await?;
+
+    let path = config.output_dir.join(format!("{}-{}.pkg", NAME, VERSION));
+    run_tool(
+        "pkgbuild",
+        &[
+            "--root".as_ref(),
+            root.as_os_str(),
+            "--scripts".as_ref(),
+            scripts.as_os_str(),
+            "--identifier".as_ref(),
+            "cloud.hyprwatch.shadow".as_ref(),
+            "--version".as_ref(),
+            VERSION.as_ref(),
+            "--install-location".as_ref(),
+            "/".as_ref(),
+            path.as_os_str(),
+        ],
+        "It comes with macOS, so build .pkg installers on a Mac.",
+    )
+    .await?;
+    Ok(path)
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Quote an argument for a Windows command line, and escape it from MSI's
+/// [Property] formatting
+fn msi_quote(value: &str) -> String {
+    let quoted = format!("\"{}\"", value.replace('"', "\\\""));
+    quoted.replace('[', "[\\[]").replace(']', "[\\]]")
+}
+
+async fn msi(config: &PackageConfig, arch: Arch) -> Result<PathBuf> {
+    let work = WorkDir::new(&config.output_dir).await?;
+
+    // Settings are passed as arguments, since custom actions can't set the
+    // environment. The org token comes from the ORG_TOKEN property, so it can
+    // be given to msiexec instead.
+    let mut command = String::from("--org-token \"[ORG_TOKEN]\"");
+    let mut token = None;
+    for setting in &config.settings {
+        if setting.id == "org_token" {
+            token = setting.values.first();
+            continue;
+        }
+        for arg in setting.args() {
+            command.push(' ');
+            command.push_str(&msi_quote(&arg));
+        }
+    }
+    let token = match token {
+        Some(token) => format!(" Value=\"{}\"", xml_escape(token)),
+        None => String::new(),
+    };
+
+    let wxs = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Wix xmlns="http://schemas.microsoft.com/wix/2006/wi">
+  <Product Id="*" Name="Hyprwatch Shadow Agent" Language="1033" Version="{VERSION}" Manufacturer="Hyprwatch" UpgradeCode="{MSI_UPGRADE_CODE}">
+    <Package InstallerVersion="500" Compressed="yes" InstallScope="perMachine" Platform="{platform}" Description="{description}" />
+    <MajorUpgrade DowngradeErrorMessage="A newer version of the Hyprwatch Shadow Agent is already installed." />
+    <MediaTemplate EmbedCab="yes" />
+    <Property Id="ORG_TOKEN" Secure="yes" Hidden="yes"{token} />
+    <Directory Id="TARGETDIR" Name="SourceDir">
+      <Directory Id="ProgramFiles64Folder">
+        <Directory Id="HyprwatchFolder" Name="Hyprwatch">
+          <Directory Id="INSTALLDIR" Name="Shadow">
+            <Component Id="ShadowExe" Guid="*" Win64="yes">
+              <File Id="ShadowExe" Name="shadow.exe" Source="{binary}" KeyPath="yes" />
+            </Component>
+          </Directory>
+        </Directory>
+      </Directory>
+    </Directory>
+    <Feature Id="Main" Level="1">
+      <ComponentRef Id="ShadowExe" />
+    </Feature>
+    <CustomAction Id="InstallService" FileKey="ShadowExe" ExeCommand="{command} service install" Execute="deferred" Impersonate="no" Return="check" HideTarget="yes" />
+    <CustomAction Id="UninstallService" FileKey="ShadowExe" ExeCommand="service uninstall" Execute="deferred" Impersonate="no" Return="ignore" />
+    <InstallExecuteSequence>
+      <Custom Action="UninstallService" Before="RemoveFiles">REMOVE="ALL"</Custom>
+      <Custom Action="InstallService" After="InstallFiles">NOT REMOVE="ALL" AND ORG_TOKEN</Custom>
+    </InstallExecuteSequence>
+  </Product>
+</Wix>
+"#,
+        platform = arch.msi(),
+        description = xml_escape(DESCRIPTION),
+        binary = xml_escape(&std::path::absolute(&config.binary)?.to_string_lossy()),
+        command = xml_escape(&command),
+    );
+    let wxs_path = work.0.join("shadow.wxs");
+    fs::write(&wxs_path, wxs).await?;
+
+    let path = config
+        .output_dir
+        .join(format!("{}-{}-{}.msi", NAME, VERSION, arch.msi()));
+    const MISSING: &str = "Install WiX Toolset v3 (candle and light) on Windows, or msitools (wixl) elsewhere.";
+    let wixl = run_tool(
+        "wixl",
+        &["--arch".as_ref(), arch.msi().as_ref(), "-o".as_ref(), path.as_os_str(), wxs_path.as_os_str()],
+        MISSING,
+    )
+    .await;
+    if wixl.is_err() && which("candle") {
+        let object = work.0.join("shadow.wixobj");
+        run_tool(
+            "candle",
+            &["-arch".as_ref(), arch.msi().as_ref(), "-out".as_ref(), object.as_os_str(), wxs_path.as_os_str()],
+            MISSING,
+        )
+        .await?;
+        run_tool("light", &["-out".as_ref(), path.as_os_str(), object.as_os_str()], MISSING).await?;
+    } else {
+        wixl?;
+    }
+    Ok(path)
+}
+
+/// Whether a program is on the PATH
+fn which(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        dir.join(program).is_file() || dir.join(format!("{}.exe", program)).is_file()
+    })
+}
@@ -0,0 +1,220 @@
+//! Built-in query packs
+//!
+//! Without a config from the server osqueryd runs nothing, which makes an
+//! agent on a disconnected evaluation host look broken. The agent ships two
+//! small packs of its own: `shadow-baseline`, an inventory of the host (OS,
+//! hardware, users, network, software), and `shadow-hygiene`, security
+//! settings worth a look (disk encryption, firewall, startup items, SSH keys,
+//! sudoers). They're osqueryd's whole config with `--standalone`, and the
+//! TLS relay gives them to osqueryd when the server can't give a config and
+//! none was saved. Every query is a snapshot, so each result is the complete
+//! picture at the time.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// The config file written for osqueryd's filesystem config plugin
+const CONFIG_FILE: &str = "standalone.conf";
+
+struct Query {
+    name: &'static str,
+    sql: &'static str,
+    /// Seconds between runs
+    interval: u64,
+    /// osquery's platform name, or "all"
+    platform: &'static str,
+    description: &'static str,
+}
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+const BASELINE: &[Query] = &[
+    Query {
+        name: "os_version",
+        sql: "SELECT name, version, major, minor, patch, build, platform, arch FROM os_version",
+        interval: DAY,
+        platform: "all",
+        description: "Operating system and version",
+    },
+    Query {
+        name: "system_info",
+        sql: "SELECT hostname, cpu_brand, cpu_physical_cores, physical_memory, hardware_vendor, hardware_model, \
+              hardware_serial FROM system_info",
+        interval: DAY,
+        platform: "all",
+        description: "Hardware and host name",
+    },
+    Query {
+        name: "uptime",
+        sql: "SELECT days, hours, total_seconds FROM uptime",
+        interval: HOUR,
+        platform: "all",
+        description: "Time since the host booted",
+    },
+    Query {
+        name: "users",
+        sql: "SELECT uid, gid, username, description, directory, shell FROM users",
+        interval: HOUR,
+        platform: "all",
+        description: "Local user accounts",
+    },
+    Query {
+        name: "interface_addresses",
+        sql: "SELECT interface, address, mask FROM interface_addresses \
+              WHERE address NOT LIKE '127.%' AND address != '::1'",
+        interval: HOUR,
+        platform: "all",
+        description: "Network addresses, without loopback",
+    },
+    Query {
+        name: "listening_ports",
+        sql: "SELECT DISTINCT p.name, p.path, lp.port, lp.protocol, lp.address \
+              FROM listening_ports lp LEFT JOIN processes p USING (pid) WHERE lp.port != 0",
+        interval: HOUR,
+        platform: "all",
+        description: "Processes listening on the network",
+    },
+    Query {
+        name: "deb_packages",
+        sql: "SELECT name, version, arch FROM deb_packages",
+        interval: DAY,
+        platform: "linux",
+        description: "Installed Debian packages",
+    },
+    Query {
+        name: "rpm_packages",
+        sql: "SELECT name, version, release, arch FROM rpm_packages",
+        interval: DAY,
+        platform: "linux",
+        description: "Installed RPM packages",
+    },
+    Query {
+        name: "apps",
+        sql: "SELECT name, bundle_identifier, bundle_short_version, path FROM apps",
+        interval: DAY,
+        platform: "darwin",
+        description: "Installed applications",
+    },
+    Query {
+        name: "programs",
+        sql: "SELECT name, version, publisher, install_date FROM programs",
+        interval: DAY,
+        platform: "windows",
+        description: "Installed programs",
+    },
+];
+
+const HYGIENE: &[Query] = &[
+    Query {
+        name: "disk_encryption",
+        sql: "SELECT name, uuid, encrypted, type FROM disk_encryption",
+        interval: HOUR,
+        platform: "posix",
+        description: "Whether each disk is encrypted",
+    },
+    Query {
+        name: "bitlocker_info",
+        sql: "SELECT drive_letter, protection_status, encryption_method FROM bitlocker_info",
+        interval: HOUR,
+        platform: "windows",
+        description: "Whether each drive is protected by BitLocker",
+    },
+    Query {
+        name: "windows_security_center",
+        sql: "SELECT firewall, autoupdate, antivirus, user_account_control FROM windows_security_center",
+        interval: HOUR,
+        platform: "windows",
+        description: "Firewall, updates, antivirus, and UAC as Windows reports them",
+    },
+    Query {
+        name: "sip_config",
+        sql: "SELECT config_flag, enabled FROM sip_config",
+        interval: HOUR,
+        platform: "darwin",
+        description: "System Integrity Protection",
+    },
+    Query {
+        name: "gatekeeper",
+        sql: "SELECT assessments_enabled, dev_id_enabled FROM gatekeeper",
+        interval: HOUR,
+        platform: "darwin",
+        description: "Whether Gatekeeper checks applications",
+    },
+    Query {
+        name: "alf",
+        sql: "SELECT global_state, stealth_enabled, logging_enabled FROM alf",
+        interval: HOUR,
+        platform: "darwin",
+        description: "Application firewall",
+    },
+    Query {
+        name: "startup_items",
+        sql: "SELECT name, path, source, status, username FROM startup_items",
+        interval: HOUR,
+        platform: "all",
+        description: "What runs at boot or login",
+    },
+    Query {
+        name: "authorized_keys",
+        sql: "SELECT u.username, ak.key_file, ak.algorithm FROM users u CROSS JOIN authorized_keys ak USING (uid)",
+        interval: HOUR,
+        platform: "posix",
+        description: "SSH keys that can log in as each user",
+    },
+    Query {
+        name: "sudoers",
+        sql: "SELECT source, header, rule_details FROM sudoers",
+        interval: HOUR,
+        platform: "posix",
+        description: "Who may run what as root",
+    },
+    Query {
+        name: "crontab",
+        sql: "SELECT command, path, minute, hour, day_of_month, month, day_of_week FROM crontab",
+        interval: HOUR,
+        platform: "posix",
+        description: "Scheduled jobs",
+    },
+];
+
+/// osqueryd's config with just the built-in packs
+pub fn config() -> Value {
+    serde_json::json!({
+        "options": {
+            "schedule_splay_percent": 10,
+        },
+        "packs": {
+            "shadow-baseline": pack(BASELINE),
+            "shadow-hygiene": pack(HYGIENE),
+        },
+    })
+}
+
+fn pack(queries: &[Query]) -> Value {
+    let queries: Map<String, Value> = queries
+        .iter()
+        .map(|query| {
+            let mut spec = serde_json::json!({
+                "query": query.sql,
+                "interval": query.interval,
+                "snapshot": true,
+                "description": query.description,
+            });
+            if query.platform != "all" {
+                spec["platform"] = Value::from(query.platform);
+            }
+            (query.name.to_string(), spec)
+        })
+        .collect();
+    serde_json::json!({ "queries": queries })
+}
+
+/// Write the config for osqueryd's filesystem config plugin, returning its path
+pub fn write(data_dir: &Path) -> Result<PathBuf> {
+    let path = data_dir.join(CONFIG_FILE);
+    let config = serde_json::to_vec_pretty(&config())?;
+    std::fs::write(&path, config).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
@@ -0,0 +1,255 @@
+//! Agent panic reports
+//!
+//! A panic in the agent is easy to miss: a panicking background task just
+//! stops, and a panic on the main task takes the agent down until the service
+//! manager restarts it. A panic hook writes a report (the message, where it
+//! happened, a backtrace, the agent's version, and its status at the time) to
+//! `panics/` in the data directory, keeping only the most recent few. On the
+//! next start, once enrolled, reports not yet sent are posted to the server,
+//! and to a Sentry-compatible endpoint with `--sentry-dsn`, so crashes across
+//! a fleet become visible.
+
+use crate::api::ApiClient;
+use crate::logging::{error, info, warning};
+use crate::status::{AgentStatus, SharedStatus};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// Report directory within the data directory
+const PANIC_DIR: &str = "panics";
+
+/// Number of reports to keep
+const MAX_REPORTS: usize = 5;
+
+/// Status to include in reports, once the agent has one
+static STATUS: OnceLock<SharedStatus> = OnceLock::new();
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PanicReport {
+    /// Random identifier, also used as the Sentry event ID
+    pub id: String,
+    pub time: DateTime<Utc>,
+    pub agent_version: String,
+    /// OS and architecture the agent was built for
+    pub target: String,
+    pub thread: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+    pub status: Option<AgentStatus>,
+    /// Whether every destination has accepted the report
+    #[serde(default)]
+    pub reported: bool,
+}
+
+/// Sentry (or compatible, e.g. GlitchTip) project to send reports to, from a
+/// DSN like `https://<key>@sentry.example.com/<project>`
+#[derive(Debug, Clone)]
+pub struct SentryDsn {
+    store_url: Url,
+    key: String,
+}
+
+impl FromStr for SentryDsn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let dsn = Url::parse(s).map_err(|e| format!("invalid DSN: {}", e))?;
+        let key = dsn.username().to_string();
+        if key.is_empty() {
+            return Err("DSN has no public key".to_string());
+        }
+        let path = dsn.path().trim_end_matches('/');
+        let (prefix, project) = path.rsplit_once('/').unwrap_or(("", path));
+        if project.is_empty() {
+            return Err("DSN has no project ID".to_string());
+        }
+        let mut store_url = dsn.clone();
+        let _ = store_url.set_username("");
+        let _ = store_url.set_password(None);
+        store_url.set_path(&format!("{}/api/{}/store/", prefix, project));
+        Ok(SentryDsn { store_url, key })
+    }
+}
+
+/// Write a report whenever the agent panics
+pub fn install(data_dir: &Path) {
+    let dir = data_dir.join(PANIC_DIR);
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        write_report(&dir, info);
+        previous(info);
+    }));
+}
+
+/// Include the agent's status in reports
+pub fn set_status(status: SharedStatus) {
+    let _ = STATUS.set(status);
+}
+
+fn write_report(dir: &Path, info: &PanicHookInfo<'_>) {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Box<dyn Any>".to_string(),
+        },
+    };
+    let location = info.location().map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()));
+    error!(
+        "Agent panicked: {}{}",
+        message,
+        location.as_ref().map(|l| format!(" at {}", l)).unwrap_or_default()
+    );
+
+    let report = PanicReport {
+        id: format!("{:032x}", rand::random::<u128>()),
+        time: Utc::now(),
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        target: format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH),
+        thread: std::thread::current().name().map(str::to_string),
+        message,
+        location,
+        backtrace: std::backtrace::Backtrace::force_capture().to_string(),
+        // The panic may have happened while the status was locked
+        status: STATUS.get().and_then(|status| status.try_snapshot()),
+        reported: false,
+    };
+    let path = dir.join(format!("{}.json", report.time.format("%Y%m%dT%H%M%S%.3fZ")));
+    let written = fs::create_dir_all(dir)
+        .and_then(|_| fs::write(&path, serde_json::to_vec_pretty(&report).unwrap_or_default()));
+    match written {
+        Ok(()) => {
+            error!("Panic report written to {}", path.display());
+            rotate(dir);
+        }
+        Err(e) => error!("Failed to write panic report: {}", e),
+    }
+}
+
+/// Report files, oldest first
+fn list(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    // Names are the panic time, so this sorts oldest first
+    paths.sort();
+    paths
+}
+
+/// Delete all but the newest reports
+fn rotate(dir: &Path) {
+    let paths = list(dir);
+    for path in &paths[..paths.len().saturating_sub(MAX_REPORTS)] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[derive(Serialize)]
+struct Upload<'a> {
+    host_id: &'a str,
+    #[serde(flatten)]
+    report: &'a PanicReport,
+}
+
+async fn send_to_sentry(client: &reqwest::Client, dsn: &SentryDsn, host_id: &str, report: &PanicReport) -> Result<()> {
+    let event = serde_json::json!({
+        "event_id": report.id,
+        "timestamp": report.time.to_rfc3339(),
+        "platform": "native",
+        "level": "fatal",
+        "logger": "shadow",
+        "release": format!("shadow@{}", report.agent_version),
+        "tags": {
+            "host_id": host_id,
+            "target": report.target,
+            "state": report.status.as_ref().map(|status| status.state.to_string()),
+        },
+        "exception": {
+            "values": [{ "type": "panic", "value": report.message }],
+        },
+        "extra": {
+            "location": report.location,
+            "thread": report.thread,
+            "backtrace": report.backtrace,
+            "status": report.status,
+        },
+    });
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_key={}, sentry_client=shadow/{}",
+        dsn.key,
+        env!("CARGO_PKG_VERSION")
+    );
+    client
+        .post(dsn.store_url.clone())
+        .header("X-Sentry-Auth", auth)
+        .json(&event)
+        .send()
+        .await
+        .context("Failed to connect to Sentry")?
+        .error_for_status()
+        .context("Sentry rejected the report")?;
+    Ok(())
+}
+
+/// Send reports left by earlier panics
+pub async fn send_pending(api: ApiClient, data_dir: PathBuf, upload: bool, sentry: Option<SentryDsn>) {
+    if !upload && sentry.is_none() {
+        return;
+    }
+    let client = reqwest::Client::new();
+    for path in list(&data_dir.join(PANIC_DIR)) {
+        let Ok(data) = tokio::fs::read(&path).await else {
+            continue;
+        };
+        let mut report: PanicReport = match serde_json::from_slice(&data) {
+            Ok(report) => report,
+            Err(e) => {
+                warning!("Ignoring unreadable panic report {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        if report.reported {
+            continue;
+        }
+
+        let mut reported = true;
+        if upload {
+            let upload = Upload {
+                host_id: api.host_id(),
+                report: &report,
+            };
+            if let Err(e) = api.post("/api/shadow/panic", &upload).await {
+                error!("Failed to send panic report: {:#}", e);
+                reported = false;
+            }
+        }
+        if let Some(dsn) = &sentry {
+            if let Err(e) = send_to_sentry(&client, dsn, api.host_id(), &report).await {
+                error!("Failed to send panic report to Sentry: {:#}", e);
+                reported = false;
+            }
+        }
+        if !reported {
+            continue;
+        }
+
+        info!("Sent panic report from {}", report.time.to_rfc3339());
+        report.reported = true;
+        if let Ok(data) = serde_json::to_vec_pretty(&report) {
+            let _ = tokio::fs::write(&path, data).await;
+        }
+    }
+}
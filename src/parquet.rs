@@ -0,0 +1,490 @@
+//! Parquet files
+//!
+//! Just enough of the Parquet format for the result sink to write query
+//! results as columns: one row group of optional string and 64-bit integer
+//! columns, each in a single gzipped data page with plain encoding, which
+//! DuckDB, Spark, pandas, and other Parquet readers load directly. The file
+//! metadata is in Thrift's compact protocol, written by hand here as it's the
+//! only Thrift the agent needs.
+
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Parquet's enums
+const TYPE_INT64: i32 = 2;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const CODEC_GZIP: i32 = 2;
+const PAGE_DATA: i32 = 0;
+
+// Thrift compact protocol types
+const T_I32: u8 = 5;
+const T_I64: u8 = 6;
+const T_BINARY: u8 = 8;
+const T_LIST: u8 = 9;
+const T_STRUCT: u8 = 12;
+
+/// A column's values by row, None for null
+pub enum Values {
+    Strings(Vec<Option<String>>),
+    Int64(Vec<Option<i64>>),
+}
+
+pub struct Column {
+    pub name: String,
+    pub values: Values,
+}
+
+impl Values {
+    fn len(&self) -> usize {
+        match self {
+            Values::Strings(values) => values.len(),
+            Values::Int64(values) => values.len(),
+        }
+    }
+
+    fn physical_type(&self) -> i32 {
+        match self {
+            Values::Strings(_) => TYPE_BYTE_ARRAY,
+            Values::Int64(_) => TYPE_INT64,
+        }
+    }
+
+    /// Definition levels (1 for a value, 0 for null) and the plain-encoded
+    /// values that aren't null
+    fn encode(&self) -> (Vec<bool>, Vec<u8>) {
+        let mut defined = Vec::with_capacity(self.len());
+        let mut data = Vec::new();
+        match self {
+            Values::Strings(values) => {
+                for value in values {
+                    defined.push(value.is_some());
+                    if let Some(value) = value {
+                        data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                        data.extend_from_slice(value.as_bytes());
+                    }
+                }
+            }
+            Values::Int64(values) => {
+                for value in values {
+                    defined.push(value.is_some());
+                    if let Some(value) = value {
+                        data.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+        (defined, data)
+    }
+}
+
+/// Where a column chunk ended up, for the file metadata
+struct Chunk {
+    offset: i64,
+    uncompressed: i64,
+    compressed: i64,
+}
+
+/// A Parquet file of `columns`, which must all have the same number of rows
+pub fn write(columns: &[Column]) -> Result<Vec<u8>> {
+    let rows = columns.first().map_or(0, |column| column.values.len());
+    if columns.iter().any(|column| column.values.len() != rows) {
+        anyhow::bail!("Parquet columns have different numbers of rows");
+    }
+
+    let mut file = MAGIC.to_vec();
+    let mut chunks = Vec::new();
+    for column in columns {
+        let (defined, data) = column.values.encode();
+        let levels = rle_levels(&defined);
+        let mut page = Vec::with_capacity(4 + levels.len() + data.len());
+        page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+        page.extend_from_slice(&levels);
+        page.extend_from_slice(&data);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&page)?;
+        let compressed = encoder.finish()?;
+
+        let mut header = Compact::new();
+        header.i32(1, PAGE_DATA);
+        header.i32(2, page.len() as i32);
+        header.i32(3, compressed.len() as i32);
+        header.begin_struct(5);
+        header.i32(1, rows as i32);
+        header.i32(2, ENCODING_PLAIN);
+        header.i32(3, ENCODING_RLE);
+        header.i32(4, ENCODING_RLE);
+        header.end_struct();
+        let header = header.finish();
+
+        chunks.push(Chunk {
+            offset: file.len() as i64,
+            uncompressed: (header.len() + page.len()) as i64,
+            compressed: (header.len() + compressed.len()) as i64,
+        });
+        file.extend_from_slice(&header);
+        file.extend_from_slice(&compressed);
+    }
+
+    let mut meta = Compact::new();
+    meta.i32(1, 1);
+    meta.begin_list(2, T_STRUCT, columns.len() + 1);
+    meta.begin_element();
+    meta.binary(4, b"schema");
+    meta.i32(5, columns.len() as i32);
+    meta.end_struct();
+    for column in columns {
+        meta.begin_element();
+        meta.i32(1, column.values.physical_type());
+        meta.i32(3, REPETITION_OPTIONAL);
+        meta.binary(4, column.name.as_bytes());
+        if let Values::Strings(_) = column.values {
+            meta.i32(6, CONVERTED_UTF8);
+        }
+        meta.end_struct();
+    }
+    meta.i64(3, rows as i64);
+    meta.begin_list(4, T_STRUCT, 1);
+    meta.begin_element();
+    meta.begin_list(1, T_STRUCT, columns.len());
+    for (column, chunk) in columns.iter().zip(&chunks) {
+        meta.begin_element();
+        meta.i64(2, chunk.offset);
+        meta.begin_struct(3);
+        meta.i32(1, column.values.physical_type());
+        meta.begin_list(2, T_I32, 2);
+        meta.list_i32(ENCODING_PLAIN);
+        meta.list_i32(ENCODING_RLE);
+        meta.begin_list(3, T_BINARY, 1);
+        meta.list_binary(column.name.as_bytes());
+        meta.i32(4, CODEC_GZIP);
+        meta.i64(5, rows as i64);
+        meta.i64(6, chunk.uncompressed);
+        meta.i64(7, chunk.compressed);
+        meta.i64(9, chunk.offset);
+        meta.end_struct();
+        meta.end_struct();
+    }
+    meta.i64(2, chunks.iter().map(|chunk| chunk.uncompressed).sum());
+    meta.i64(3, rows as i64);
+    meta.end_struct();
+    meta.binary(6, format!("shadow version {}", env!("CARGO_PKG_VERSION")).as_bytes());
+    let meta = meta.finish();
+
+    file.extend_from_slice(&meta);
+    file.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    file.extend_from_slice(MAGIC);
+    Ok(file)
+}
+
+/// Definition levels of bit width 1 in the RLE/bit-packing hybrid, as runs
+fn rle_levels(defined: &[bool]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < defined.len() {
+        let run = defined[i..].iter().take_while(|&&d| d == defined[i]).count();
+        varint(&mut out, (run as u64) << 1);
+        out.push(defined[i] as u8);
+        i += run;
+    }
+    out
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A struct in Thrift's compact protocol
+struct Compact {
+    out: Vec<u8>,
+    /// Last field ID written in each struct being written
+    last_ids: Vec<i16>,
+}
+
+impl Compact {
+    fn new() -> Self {
+        Compact {
+            out: Vec::new(),
+            last_ids: vec![0],
+        }
+    }
+
+    fn field(&mut self, id: i16, kind: u8) {
+        let last = self.last_ids.last_mut().expect("inside a struct");
+        let delta = id - *last;
+        if (1..=15).contains(&delta) {
+            self.out.push(((delta as u8) << 4) | kind);
+        } else {
+            self.out.push(kind);
+            varint(&mut self.out, zigzag(id as i64));
+        }
+        *last = id;
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, T_I32);
+        varint(&mut self.out, zigzag(value as i64));
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, T_I64);
+        varint(&mut self.out, zigzag(value));
+    }
+
+    fn binary(&mut self, id: i16, value: &[u8]) {
+        self.field(id, T_BINARY);
+        self.list_binary(value);
+    }
+
+    fn begin_struct(&mut self, id: i16) {
+        self.field(id, T_STRUCT);
+        self.last_ids.push(0);
+    }
+
+    fn end_struct(&mut self) {
+        self.out.push(0);
+        self.last_ids.pop();
+    }
+
+    fn begin_list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, T_LIST);
+        if len < 15 {
+            self.out.push(((len as u8) << 4) | kind);
+        } else {
+            self.out.push(0xf0 | kind);
+            varint(&mut self.out, len as u64);
+        }
+    }
+
+    /// Start a struct that's an element of a list
+    fn begin_element(&mut self) {
+        self.last_ids.push(0);
+    }
+
+    fn list_i32(&mut self, value: i32) {
+        varint(&mut self.out, zigzag(value as i64));
+    }
+
+    fn list_binary(&mut self, value: &[u8]) {
+        varint(&mut self.out, value.len() as u64);
+        self.out.extend_from_slice(value);
+    }
+
+    /// End the outermost struct
+    fn finish(mut self) -> Vec<u8> {
+        self.out.push(0);
+        self.out
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::collections::BTreeMap;
+    use std::io::Read;
+
+    /// A Thrift compact protocol value, as far as the writer uses them
+    #[derive(Debug, PartialEq)]
+    enum Thrift {
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<Thrift>),
+        Struct(BTreeMap<i16, Thrift>),
+    }
+
+    impl Thrift {
+        fn int(&self) -> i64 {
+            match self {
+                Thrift::Int(value) => *value,
+                other => panic!("expected an integer, got {:?}", other),
+            }
+        }
+
+        fn text(&self) -> &str {
+            match self {
+                Thrift::Binary(value) => std::str::from_utf8(value).unwrap(),
+                other => panic!("expected binary, got {:?}", other),
+            }
+        }
+
+        fn list(&self) -> &[Thrift] {
+            match self {
+                Thrift::List(values) => values,
+                other => panic!("expected a list, got {:?}", other),
+            }
+        }
+
+        fn field(&self, id: i16) -> &Thrift {
+            match self {
+                Thrift::Struct(fields) => fields.get(&id).unwrap_or_else(|| panic!("no field {}", id)),
+                other => panic!("expected a struct, got {:?}", other),
+            }
+        }
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_zigzag(bytes: &[u8], pos: &mut usize) -> i64 {
+        let value = read_varint(bytes, pos);
+        (value >> 1) as i64 ^ -((value & 1) as i64)
+    }
+
+    fn read_value(bytes: &[u8], pos: &mut usize, kind: u8) -> Thrift {
+        match kind {
+            T_I32 | T_I64 => Thrift::Int(read_zigzag(bytes, pos)),
+            T_BINARY => {
+                let len = read_varint(bytes, pos) as usize;
+                *pos += len;
+                Thrift::Binary(bytes[*pos - len..*pos].to_vec())
+            }
+            T_LIST => {
+                let header = bytes[*pos];
+                *pos += 1;
+                let len = match header >> 4 {
+                    15 => read_varint(bytes, pos) as usize,
+                    len => len as usize,
+                };
+                Thrift::List((0..len).map(|_| read_value(bytes, pos, header & 0x0f)).collect())
+            }
+            T_STRUCT => read_struct(bytes, pos),
+            other => panic!("unexpected Thrift type {}", other),
+        }
+    }
+
+    fn read_struct(bytes: &[u8], pos: &mut usize) -> Thrift {
+        let mut fields = BTreeMap::new();
+        let mut id = 0;
+        loop {
+            let header = bytes[*pos];
+            *pos += 1;
+            if header == 0 {
+                return Thrift::Struct(fields);
+            }
+            id = match header >> 4 {
+                0 => read_zigzag(bytes, pos) as i16,
+                delta => id + delta as i16,
+            };
+            fields.insert(id, read_value(bytes, pos, header & 0x0f));
+        }
+    }
+
+    #[test]
+    fn writes_a_readable_file() {
+        let columns = [Column {
+            name: "username".to_string(),
+            values: Values::Strings(vec![Some("root".to_string()), None, Some("zoë".to_string())]),
+        }];
+        let file = write(&columns).unwrap();
+        assert_eq!(&file[..4], MAGIC);
+        assert_eq!(&file[file.len() - 4..], MAGIC);
+
+        // Footer
+        let footer_len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap()) as usize;
+        let footer_start = file.len() - 8 - footer_len;
+        let mut pos = footer_start;
+        let meta = read_struct(&file, &mut pos);
+        assert_eq!(pos, file.len() - 8);
+        assert_eq!(meta.field(1).int(), 1);
+        assert_eq!(meta.field(3).int(), 3);
+        assert_eq!(meta.field(6).text(), format!("shadow version {}", env!("CARGO_PKG_VERSION")));
+        let schema = meta.field(2).list();
+        assert_eq!(schema.len(), 2);
+        assert_eq!(schema[0].field(4).text(), "schema");
+        assert_eq!(schema[0].field(5).int(), 1);
+        assert_eq!(schema[1].field(1).int(), TYPE_BYTE_ARRAY as i64);
+        assert_eq!(schema[1].field(3).int(), REPETITION_OPTIONAL as i64);
+        assert_eq!(schema[1].field(4).text(), "username");
+        assert_eq!(schema[1].field(6).int(), CONVERTED_UTF8 as i64);
+
+        let row_groups = meta.field(4).list();
+        assert_eq!(row_groups.len(), 1);
+        assert_eq!(row_groups[0].field(3).int(), 3);
+        let chunks = row_groups[0].field(1).list();
+        assert_eq!(chunks.len(), 1);
+        let column = chunks[0].field(3);
+        assert_eq!(column.field(1).int(), TYPE_BYTE_ARRAY as i64);
+        assert_eq!(column.field(3).list(), [Thrift::Binary(b"username".to_vec())]);
+        assert_eq!(column.field(4).int(), CODEC_GZIP as i64);
+        assert_eq!(column.field(5).int(), 3);
+        let offset = column.field(9).int() as usize;
+        assert_eq!(offset, MAGIC.len());
+        assert_eq!(chunks[0].field(2).int() as usize, offset);
+
+        // The column chunk runs from its page header up to the footer
+        let mut pos = offset;
+        let header = read_struct(&file, &mut pos);
+        assert_eq!(header.field(1).int(), PAGE_DATA as i64);
+        let compressed_len = header.field(3).int() as usize;
+        assert_eq!(pos + compressed_len, footer_start);
+        assert_eq!(column.field(7).int() as usize, footer_start - offset);
+        assert_eq!(row_groups[0].field(2).int(), column.field(6).int());
+        let data_page = header.field(5);
+        assert_eq!(data_page.field(1).int(), 3);
+        assert_eq!(data_page.field(2).int(), ENCODING_PLAIN as i64);
+
+        // Definition levels and values
+        let mut page = Vec::new();
+        GzDecoder::new(&file[pos..footer_start]).read_to_end(&mut page).unwrap();
+        assert_eq!(header.field(2).int() as usize, page.len());
+        let mut expected = vec![6, 0, 0, 0];
+        // Runs of one value, one null, and one value
+        expected.extend_from_slice(&[0x02, 1, 0x02, 0, 0x02, 1]);
+        expected.extend_from_slice(&4u32.to_le_bytes());
+        expected.extend_from_slice(b"root");
+        expected.extend_from_slice(&4u32.to_le_bytes());
+        expected.extend_from_slice("zoë".as_bytes());
+        assert_eq!(page, expected);
+    }
+
+    #[test]
+    fn refuses_uneven_columns() {
+        let columns = [
+            Column {
+                name: "a".to_string(),
+                values: Values::Strings(vec![None]),
+            },
+            Column {
+                name: "b".to_string(),
+                values: Values::Int64(vec![]),
+            },
+        ];
+        assert!(write(&columns).is_err());
+    }
+
+    #[test]
+    fn encodes_compact_fields() {
+        let mut compact = Compact::new();
+        compact.i32(1, -1);
+        compact.i64(20, 300);
+        compact.binary(21, b"ab");
+        assert_eq!(compact.finish(), [0x15, 0x01, 0x06, 0x28, 0xd8, 0x04, 0x18, 0x02, b'a', b'b', 0x00]);
+    }
+}
@@ -0,0 +1,306 @@
+//! System sleep, resume, and shutdown notifications
+//!
+//! Subscribes to the platform's power events (logind on Linux, IOKit on macOS,
+//! power broadcasts on Windows) and publishes them to the supervisor. Before
+//! the system sleeps the agent status is flushed; on resume osqueryd is
+//! restarted, since its network connections and event publishers rarely survive
+//! a suspend, and exits across a suspend don't count towards crash-loop
+//! detection. On shutdown osqueryd is stopped instead of being restarted.
+
+use crate::logging::info;
+use crate::status::SharedStatus;
+use chrono::Utc;
+use std::sync::OnceLock;
+use tokio::sync::watch;
+
+/// Lets [`request_shutdown`] publish a shutdown without a platform event
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+/// Most recent power event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerEvent {
+    /// No event since startup
+    Awake,
+    /// The system is about to sleep
+    Suspending,
+    /// The system woke up
+    Resumed,
+    /// The system is shutting down
+    ShuttingDown,
+}
+
+/// Publishes power events and keeps the status file up to date
+#[derive(Clone)]
+struct Notifier {
+    tx: watch::Sender<PowerEvent>,
+    status: SharedStatus,
+}
+
+impl Notifier {
+    fn suspend(&self) {
+        info!("System is going to sleep");
+        self.status.update(|status| status.last_sleep = Some(Utc::now()));
+        self.tx.send_replace(PowerEvent::Suspending);
+    }
+
+    fn resume(&self) {
+        info!("System resumed from sleep");
+        self.tx.send_replace(PowerEvent::Resumed);
+    }
+
+    #[cfg_attr(not(any(target_os = "linux", windows)), allow(dead_code))]
+    fn shutdown(&self) {
+        info!("System is shutting down");
+        self.tx.send_replace(PowerEvent::ShuttingDown);
+    }
+}
+
+/// Start watching for power events
+pub fn watch(status: SharedStatus) -> watch::Receiver<PowerEvent> {
+    let (tx, rx) = watch::channel(PowerEvent::Awake);
+    let notifier = Notifier { tx, status };
+    let _ = NOTIFIER.set(notifier.clone());
+    platform::subscribe(notifier);
+    rx
+}
+
+/// Stop osqueryd as if the system were shutting down, e.g. when the Windows
+/// service is stopped or the agent exceeded its limits
+pub fn request_shutdown() {
+    if let Some(notifier) = NOTIFIER.get() {
+        notifier.tx.send_replace(PowerEvent::ShuttingDown);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Notifier;
+    use crate::logging::{error, warning};
+    use crate::status::AgentState;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use zbus::zvariant::OwnedFd;
+
+    /// How long to hold off shutdown while osqueryd is stopped
+    const SHUTDOWN_GRACE: Duration = Duration::from_secs(15);
+
+    #[zbus::proxy(
+        interface = "org.freedesktop.login1.Manager",
+        default_service = "org.freedesktop.login1",
+        default_path = "/org/freedesktop/login1"
+    )]
+    trait Manager {
+        fn inhibit(&self, what: &str, who: &str, why: &str, mode: &str) -> zbus::Result<OwnedFd>;
+
+        #[zbus(signal)]
+        fn prepare_for_sleep(&self, start: bool) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        fn prepare_for_shutdown(&self, start: bool) -> zbus::Result<()>;
+    }
+
+    pub fn subscribe(notifier: Notifier) {
+        tokio::spawn(async move {
+            if let Err(e) = run(notifier).await {
+                warning!("Not watching for sleep/shutdown, logind unavailable: {}", e);
+            }
+        });
+    }
+
+    /// Take a delay inhibitor so sleep and shutdown wait until we're ready
+    async fn inhibit(manager: &ManagerProxy<'_>) -> Option<OwnedFd> {
+        manager
+            .inhibit("sleep:shutdown", "shadow", "Stopping osqueryd", "delay")
+            .await
+            .inspect_err(|e| error!("Failed to take sleep inhibitor lock: {}", e))
+            .ok()
+    }
+
+    async fn run(notifier: Notifier) -> zbus::Result<()> {
+        let connection = zbus::Connection::system().await?;
+        let manager = ManagerProxy::new(&connection).await?;
+        let mut sleep = manager.receive_prepare_for_sleep().await?;
+        let mut shutdown = manager.receive_prepare_for_shutdown().await?;
+        let mut lock = inhibit(&manager).await;
+
+        loop {
+            tokio::select! {
+                Some(signal) = sleep.next() => {
+                    if signal.args()?.start {
+                        notifier.suspend();
+                        // Dropping the lock lets the system sleep
+                        lock = None;
+                    } else {
+                        lock = inhibit(&manager).await;
+                        notifier.resume();
+                    }
+                }
+                Some(signal) = shutdown.next() => {
+                    if signal.args()?.start {
+                        notifier.shutdown();
+                        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+                        while notifier.status.snapshot().state != AgentState::Stopped
+                            && tokio::time::Instant::now() < deadline
+                        {
+                            tokio::time::sleep(Duration::from_millis(250)).await;
+                        }
+                        drop(lock.take());
+                    }
+                }
+                else => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::Notifier;
+    use crate::logging::warning;
+    use std::ffi::c_void;
+
+    type IoConnect = u32;
+    type IoObject = u32;
+    type IoNotificationPortRef = *mut c_void;
+    type CfRunLoopRef = *mut c_void;
+    type CfRunLoopSourceRef = *mut c_void;
+    type CfStringRef = *const c_void;
+    type IoServiceInterestCallback =
+        extern "C" fn(refcon: *mut c_void, service: IoObject, message: u32, argument: *mut c_void);
+
+    const IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe000_0270;
+    const IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe000_0280;
+    const IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe000_0300;
+
+    #[link(name = "IOKit", kind = "framework")]
+    extern "C" {
+        fn IORegisterForSystemPower(
+            refcon: *mut c_void,
+            port: *mut IoNotificationPortRef,
+            callback: IoServiceInterestCallback,
+            notifier: *mut IoObject,
+        ) -> IoConnect;
+        fn IONotificationPortGetRunLoopSource(port: IoNotificationPortRef) -> CfRunLoopSourceRef;
+        fn IOAllowPowerChange(root_port: IoConnect, notification: isize) -> i32;
+    }
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFRunLoopDefaultMode: CfStringRef;
+        fn CFRunLoopGetCurrent() -> CfRunLoopRef;
+        fn CFRunLoopAddSource(run_loop: CfRunLoopRef, source: CfRunLoopSourceRef, mode: CfStringRef);
+        fn CFRunLoopRun();
+    }
+
+    struct Context {
+        notifier: Notifier,
+        root_port: IoConnect,
+    }
+
+    extern "C" fn callback(refcon: *mut c_void, _service: IoObject, message: u32, argument: *mut c_void) {
+        // SAFETY: refcon is the leaked Context, which lives for the rest of the process
+        let context = unsafe { &*(refcon as *const Context) };
+        match message {
+            IO_MESSAGE_CAN_SYSTEM_SLEEP => unsafe {
+                IOAllowPowerChange(context.root_port, argument as isize);
+            },
+            IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+                context.notifier.suspend();
+                unsafe { IOAllowPowerChange(context.root_port, argument as isize) };
+            }
+            IO_MESSAGE_SYSTEM_HAS_POWERED_ON => context.notifier.resume(),
+            _ => {}
+        }
+    }
+
+    /// Shutdown arrives as SIGTERM from launchd rather than through IOKit
+    pub fn subscribe(notifier: Notifier) {
+        std::thread::spawn(move || {
+            let context = Box::leak(Box::new(Context {
+                notifier,
+                root_port: 0,
+            }));
+            let mut port: IoNotificationPortRef = std::ptr::null_mut();
+            let mut notifier_object: IoObject = 0;
+            // SAFETY: the out-pointers are valid, and the run loop keeps this
+            // thread alive for as long as the callback can fire
+            unsafe {
+                context.root_port = IORegisterForSystemPower(
+                    context as *mut Context as *mut c_void,
+                    &mut port,
+                    callback,
+                    &mut notifier_object,
+                );
+                if context.root_port == 0 {
+                    warning!("Not watching for sleep, IORegisterForSystemPower failed");
+                    return;
+                }
+                CFRunLoopAddSource(
+                    CFRunLoopGetCurrent(),
+                    IONotificationPortGetRunLoopSource(port),
+                    kCFRunLoopDefaultMode,
+                );
+                CFRunLoopRun();
+            }
+        });
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::Notifier;
+    use crate::logging::warning;
+    use std::ffi::c_void;
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    use windows_sys::Win32::System::Power::{
+        PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS,
+    };
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND,
+    };
+
+    unsafe extern "system" fn callback(context: *const c_void, kind: u32, _setting: *const c_void) -> u32 {
+        // SAFETY: context is the leaked Notifier, which lives for the rest of the process
+        let notifier = unsafe { &*(context as *const Notifier) };
+        match kind {
+            PBT_APMSUSPEND => notifier.suspend(),
+            PBT_APMRESUMEAUTOMATIC => notifier.resume(),
+            _ => {}
+        }
+        ERROR_SUCCESS
+    }
+
+    pub fn subscribe(notifier: Notifier) {
+        let shutdown = notifier.clone();
+        tokio::spawn(async move {
+            if let Ok(mut signal) = tokio::signal::windows::ctrl_shutdown() {
+                signal.recv().await;
+                shutdown.shutdown();
+            }
+        });
+
+        let params = Box::leak(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+            Callback: Some(callback),
+            Context: Box::leak(Box::new(notifier)) as *mut Notifier as *mut c_void,
+        }));
+        let mut handle = std::ptr::null_mut();
+        // SAFETY: params is leaked, so it outlives the registration
+        let result = unsafe {
+            PowerRegisterSuspendResumeNotification(
+                DEVICE_NOTIFY_CALLBACK,
+                params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as _,
+                &mut handle,
+            )
+        };
+        if result != ERROR_SUCCESS {
+            warning!("Not watching for sleep, registration failed (error {})", result);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::Notifier;
+
+    pub fn subscribe(_notifier: Notifier) {}
+}
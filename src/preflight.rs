@@ -0,0 +1,474 @@
+//! Pre-flight checks for osquery's permissions and event subsystems
+//!
+//! Event-based tables depend on OS facilities that other software may already
+//! own or that the kernel may lack: only one process can hold the Linux audit
+//! netlink socket, eBPF needs a recent kernel with tracefs, two osquery agents
+//! fight over the same publishers, and EndpointSecurity on macOS needs a recent
+//! OS, an entitled osqueryd, and Full Disk Access. Before enabling events, look
+//! for these conflicts, set the flags for subsystems that can work, and explain
+//! what to do about the rest.
+//!
+//! On Windows, the PowerShell and NTFS journal publishers also depend on host
+//! settings (the PowerShell log channel and script block logging, the volume's
+//! change journal) that are off on many machines.
+//!
+//! On macOS, many file and browser tables also need Full Disk Access, so it's
+//! checked at every startup.
+
+use crate::logging::warning;
+use clap::ValueEnum;
+use std::path::Path;
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+/// Other osquery-based agents that run their own osqueryd
+const OTHER_AGENTS: &[&str] = &["orbit", "launcher", "osqueryd"];
+
+/// EndpointSecurity client entitlement, which Apple grants to signed clients
+#[cfg(target_os = "macos")]
+const ES_ENTITLEMENT: &str = "com.apple.developer.endpoint-security.client";
+
+/// How osquery collects process and socket events on Linux
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LinuxEvents {
+    /// The kernel audit subsystem (process_events, socket_events)
+    Audit,
+    /// eBPF probes (bpf_process_events, bpf_socket_events)
+    Bpf,
+    /// No process or socket events
+    Off,
+}
+
+/// Which of osquery's event publishers to enable on Windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WindowsEvents {
+    /// The System, Application, Setup, and Security event logs (windows_events)
+    EventLog,
+    /// PowerShell script blocks (powershell_events)
+    Powershell,
+    /// The NTFS change journal (ntfs_journal_events)
+    Ntfs,
+}
+
+/// Which event subsystems to enable
+#[derive(Debug, Clone)]
+pub struct EventOptions {
+    /// Process and socket events on Linux
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub linux: LinuxEvents,
+    /// EndpointSecurity file events on macOS
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub es_file_events: bool,
+    /// Event publishers on Windows
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub windows: Vec<WindowsEvents>,
+}
+
+/// osqueryd flags enabling the event subsystems that can work on this host
+pub fn event_flags(osqueryd_path: &Path, options: &EventOptions) -> Vec<(&'static str, &'static str)> {
+    let mut system = System::new();
+    system.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        true,
+        ProcessRefreshKind::nothing().with_exe(UpdateKind::OnlyIfNotSet),
+    );
+
+    check_other_agents(&system, osqueryd_path);
+
+    let mut flags = vec![("--disable_events", "false")];
+    flags.extend(platform_flags(&system, osqueryd_path, options));
+    flags
+}
+
+/// Check the OS permissions osqueryd needs beyond running as root, and say
+/// exactly what's missing
+#[cfg(target_os = "macos")]
+pub fn check_permissions(osqueryd_path: &Path) {
+    if has_full_disk_access() {
+        return;
+    }
+    let exe = std::env::current_exe().unwrap_or_else(|_| "shadow".into());
+    warn(
+        "shadow does not have Full Disk Access, so osquery's file, browser, and EndpointSecurity tables will be missing data",
+        &format!(
+            "Grant Full Disk Access to {} and {} in System Settings > Privacy & Security, \
+             or deploy `shadow service profile tcc` with your MDM, then restart shadow.",
+            exe.display(),
+            osqueryd_path.display()
+        ),
+    );
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn check_permissions(_osqueryd_path: &Path) {}
+
+/// Whether we have Full Disk Access, which osqueryd inherits from us as its
+/// responsible process. The system TCC database is only readable with it.
+#[cfg(target_os = "macos")]
+fn has_full_disk_access() -> bool {
+    const TCC_DB: &str = "/Library/Application Support/com.apple.TCC/TCC.db";
+    !matches!(
+        std::fs::File::open(TCC_DB),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied
+    )
+}
+
+fn warn(message: &str, fix: &str) {
+    warning!("{}. {}", message, fix);
+}
+
+/// Another osquery agent on the host competes for the same event publishers
+fn check_other_agents(system: &System, osqueryd_path: &Path) {
+    let ours = std::fs::canonicalize(osqueryd_path).ok();
+    for process in system.processes().values() {
+        let name = process.name().to_string_lossy();
+        let name = name.trim_end_matches(".exe");
+        if !OTHER_AGENTS.contains(&name) {
+            continue;
+        }
+        // Our own osqueryd left over from a previous run is replaced at startup
+        let exe = process.exe().and_then(|exe| std::fs::canonicalize(exe).ok());
+        if exe.is_some() && exe == ours {
+            continue;
+        }
+        warn(
+            &format!(
+                "another osquery agent is running ({}, pid {})",
+                process.exe().unwrap_or(Path::new(name)).display(),
+                process.pid()
+            ),
+            "Event tables may miss events or fail to start; remove the other agent.",
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn platform_flags(
+    system: &System,
+    _osqueryd_path: &Path,
+    options: &EventOptions,
+) -> Vec<(&'static str, &'static str)> {
+    match options.linux {
+        LinuxEvents::Audit => audit_flags(system),
+        LinuxEvents::Bpf => bpf_flags(),
+        LinuxEvents::Off => Vec::new(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn audit_flags(system: &System) -> Vec<(&'static str, &'static str)> {
+    const DISABLED: &str = "audit-based tables (process_events, socket_events) are disabled";
+
+    if !is_root() {
+        warn(
+            &format!("{}: reading the audit netlink socket needs root", DISABLED),
+            "Run shadow as root, or use `--events off`.",
+        );
+        return Vec::new();
+    }
+    // Only present when the kernel is built with syscall auditing
+    if !Path::new("/proc/self/loginuid").exists() {
+        warn(
+            &format!("{}: the kernel was built without audit support", DISABLED),
+            "Use `--events bpf` instead, or a kernel with CONFIG_AUDITSYSCALL.",
+        );
+        return Vec::new();
+    }
+    if system.processes().values().any(|p| p.name() == "auditd") {
+        warn(
+            &format!("auditd owns the audit netlink socket, {}", DISABLED),
+            "Stop and disable auditd to collect them, e.g. `systemctl disable --now auditd`, or use `--events bpf`.",
+        );
+        return Vec::new();
+    }
+    vec![
+        ("--disable_audit", "false"),
+        ("--audit_allow_config", "true"),
+        ("--audit_persist", "true"),
+        ("--audit_allow_process_events", "true"),
+        ("--audit_allow_sockets", "true"),
+    ]
+}
+
+#[cfg(target_os = "linux")]
+fn bpf_flags() -> Vec<(&'static str, &'static str)> {
+    const DISABLED: &str = "eBPF tables (bpf_process_events, bpf_socket_events) are disabled";
+
+    if !is_root() {
+        warn(
+            &format!("{}: loading eBPF probes needs root", DISABLED),
+            "Run shadow as root, or use `--events off`.",
+        );
+        return Vec::new();
+    }
+    let version = System::kernel_version().unwrap_or_default();
+    let mut parts = version
+        .split(['.', '-'])
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let (major, minor) = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    if (major, minor) < (4, 18) {
+        warn(
+            &format!("{}: kernel {} is older than 4.18", DISABLED, version),
+            "Upgrade the kernel, or use `--events audit`.",
+        );
+        return Vec::new();
+    }
+    // osquery attaches its probes through tracefs
+    let tracefs = ["/sys/kernel/tracing/kprobe_events", "/sys/kernel/debug/tracing/kprobe_events"];
+    if !tracefs.iter().any(|path| Path::new(path).exists()) {
+        warn(
+            &format!("{}: tracefs isn't mounted, or the kernel lacks kprobes", DISABLED),
+            "Mount it with `mount -t tracefs nodev /sys/kernel/tracing`, or use `--events audit`.",
+        );
+        return Vec::new();
+    }
+    vec![("--enable_bpf_events", "true")]
+}
+
+/// Whether we run as root, going by who owns our /proc entry
+#[cfg(target_os = "linux")]
+fn is_root() -> bool {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata("/proc/self").is_ok_and(|meta| meta.uid() == 0)
+}
+
+#[cfg(target_os = "macos")]
+fn platform_flags(
+    _system: &System,
+    osqueryd_path: &Path,
+    options: &EventOptions,
+) -> Vec<(&'static str, &'static str)> {
+    const DISABLED: &str = "EndpointSecurity tables (es_process_events, es_process_file_events) are disabled";
+
+    // The framework arrived in macOS 10.15
+    let version = System::os_version().unwrap_or_default();
+    let mut parts = version.split('.').map(|part| part.parse::<u32>().unwrap_or(0));
+    let (major, minor) = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    if (major, minor) < (10, 15) {
+        warn(
+            &format!("{}: macOS {} doesn't have EndpointSecurity", DISABLED, version),
+            "Upgrade to macOS 10.15 or later.",
+        );
+        return Vec::new();
+    }
+
+    // Unentitled clients are only allowed with System Integrity Protection off
+    if !has_es_entitlement(osqueryd_path) {
+        if sip_enabled() {
+            warn(
+                &format!(
+                    "{}: {} isn't signed with the {} entitlement",
+                    DISABLED,
+                    osqueryd_path.display(),
+                    ES_ENTITLEMENT
+                ),
+                "Use the official osquery package (remove --osqueryd-path, or point it at /opt/osquery/lib/osquery.app/Contents/MacOS/osqueryd).",
+            );
+            return Vec::new();
+        }
+        warn(
+            &format!("{} isn't entitled for EndpointSecurity", osqueryd_path.display()),
+            "It's only allowed because System Integrity Protection is off.",
+        );
+    }
+
+    if !has_full_disk_access() {
+        warn(
+            &format!("{} without Full Disk Access", DISABLED),
+            "See the Full Disk Access warning above.",
+        );
+        return Vec::new();
+    }
+
+    let mut flags = vec![("--disable_endpointsecurity", "false")];
+    if options.es_file_events {
+        flags.push(("--disable_endpointsecurity_fim", "false"));
+    }
+    flags
+}
+
+/// Whether osqueryd is signed with the EndpointSecurity client entitlement
+#[cfg(target_os = "macos")]
+fn has_es_entitlement(osqueryd_path: &Path) -> bool {
+    std::process::Command::new("codesign")
+        .args(["-d", "--entitlements", "-", "--xml"])
+        .arg(osqueryd_path)
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(ES_ENTITLEMENT))
+        .unwrap_or(false)
+}
+
+/// Whether System Integrity Protection is on, assuming it is if we can't tell
+#[cfg(target_os = "macos")]
+fn sip_enabled() -> bool {
+    std::process::Command::new("csrutil")
+        .arg("status")
+        .output()
+        .map(|output| !String::from_utf8_lossy(&output.stdout).contains("disabled."))
+        .unwrap_or(true)
+}
+
+#[cfg(windows)]
+fn platform_flags(
+    _system: &System,
+    _osqueryd_path: &Path,
+    options: &EventOptions,
+) -> Vec<(&'static str, &'static str)> {
+    const DISABLED: &str = "Windows event tables (windows_events, powershell_events, ntfs_journal_events) are disabled";
+    const POWERSHELL_CHANNEL: &str = "Microsoft-Windows-PowerShell/Operational";
+
+    // The Security log and raw volume handles are only open to administrators
+    if !is_elevated() {
+        warn(
+            &format!("{}: reading the Security log and the NTFS journal needs an elevated process", DISABLED),
+            "Run shadow as a service (`shadow service install`) or from an elevated prompt.",
+        );
+        return Vec::new();
+    }
+
+    let event_log = options.windows.contains(&WindowsEvents::EventLog);
+    let powershell = options.windows.contains(&WindowsEvents::Powershell) && powershell_ready(POWERSHELL_CHANNEL);
+    let ntfs = options.windows.contains(&WindowsEvents::Ntfs) && ntfs_ready();
+
+    let mut flags = Vec::new();
+    if event_log || powershell {
+        flags.push(("--enable_windows_events_publisher", "true"));
+        let channels = match (event_log, powershell) {
+            (true, true) => "System,Application,Setup,Security,Microsoft-Windows-PowerShell/Operational",
+            (true, false) => "System,Application,Setup,Security",
+            _ => POWERSHELL_CHANNEL,
+        };
+        flags.push(("--windows_event_channels", channels));
+    }
+    if event_log {
+        flags.push(("--enable_windows_events_subscriber", "true"));
+    }
+    if powershell {
+        flags.push(("--enable_powershell_events_subscriber", "true"));
+    }
+    if ntfs {
+        flags.push(("--enable_ntfs_event_publisher", "true"));
+    }
+    flags
+}
+
+/// Whether the PowerShell log channel is on, so powershell_events can read it
+#[cfg(windows)]
+fn powershell_ready(channel: &str) -> bool {
+    const DISABLED: &str = "powershell_events is disabled";
+
+    if !channel_enabled(channel) {
+        warn(
+            &format!("{}: the {} event log is turned off", DISABLED, channel),
+            &format!("Turn it on with `wevtutil sl {} /e:true`, or leave powershell out of --windows-events.", channel),
+        );
+        return false;
+    }
+    // Without the policy PowerShell only logs script blocks it finds suspicious
+    const POLICY: &str = r"SOFTWARE\Policies\Microsoft\Windows\PowerShell\ScriptBlockLogging";
+    if read_policy_dword(POLICY, "EnableScriptBlockLogging") != Some(1) {
+        warn(
+            "script block logging is off, so powershell_events only has the script blocks PowerShell finds suspicious",
+            "Turn on \"Turn on PowerShell Script Block Logging\" under Administrative Templates > Windows Components > \
+             Windows PowerShell in Group Policy.",
+        );
+    }
+    true
+}
+
+/// Whether the system drive has an NTFS change journal for ntfs_journal_events
+#[cfg(windows)]
+fn ntfs_ready() -> bool {
+    let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+    let active = std::process::Command::new("fsutil")
+        .args(["usn", "queryjournal", &drive])
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !active {
+        warn(
+            &format!("ntfs_journal_events is disabled: {} has no change journal", drive),
+            &format!("Create one with `fsutil usn createjournal m=33554432 a=4194304 {}`.", drive),
+        );
+    }
+    active
+}
+
+/// Whether an event log channel is enabled, going by `wevtutil gl`
+#[cfg(windows)]
+fn channel_enabled(channel: &str) -> bool {
+    std::process::Command::new("wevtutil")
+        .args(["gl", channel])
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .any(|line| line.trim().eq_ignore_ascii_case("enabled: true"))
+        })
+        .unwrap_or(false)
+}
+
+/// A DWORD policy value under HKEY_LOCAL_MACHINE, if it's set
+#[cfg(windows)]
+fn read_policy_dword(key: &str, name: &str) -> Option<u32> {
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    use windows_sys::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD};
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    let (key, name) = (wide(key), wide(name));
+    let mut value = 0u32;
+    let mut size = std::mem::size_of::<u32>() as u32;
+    // SAFETY: the key and value names are NUL-terminated, and `size` is the
+    // size of `value` in bytes
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            name.as_ptr(),
+            RRF_RT_REG_DWORD,
+            std::ptr::null_mut(),
+            (&mut value as *mut u32).cast(),
+            &mut size,
+        )
+    };
+    (status == ERROR_SUCCESS).then_some(value)
+}
+
+/// Whether our token is elevated, i.e. we run as an administrator or SYSTEM
+#[cfg(windows)]
+fn is_elevated() -> bool {
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY};
+    use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+    let mut token = std::ptr::null_mut();
+    // SAFETY: the pseudo-handle of the current process is always valid
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return false;
+    }
+    let mut elevation = TOKEN_ELEVATION { TokenIsElevated: 0 };
+    let mut size = 0u32;
+    // SAFETY: `token` was opened for querying above, and the buffer is a
+    // TOKEN_ELEVATION of the size given
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            (&mut elevation as *mut TOKEN_ELEVATION).cast(),
+            std::mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut size,
+        )
+    };
+    unsafe { CloseHandle(token) };
+    ok != 0 && elevation.TokenIsElevated != 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn platform_flags(
+    _system: &System,
+    _osqueryd_path: &Path,
+    _options: &EventOptions,
+) -> Vec<(&'static str, &'static str)> {
+    Vec::new()
+}
@@ -0,0 +1,426 @@
+//! Profiles
+//!
+//! One host can report to more than one tenant, e.g. an MSP's jump host or a
+//! lab machine shared between customers. `shadow profile add NAME` saves the
+//! settings it's given (a server, an org token, and anything else) as a named
+//! profile with its own data directory under `profiles/`, and the agent runs a
+//! separate shadow for each profile beside itself, each with its own osqueryd,
+//! enrollment, spools, and control socket. Profiles added, changed, or removed
+//! are picked up within half a minute, and a profile's shadow is restarted if
+//! it exits. Commands given `--profile NAME` act on that profile's shadow.
+
+use crate::audit;
+use crate::logging::{info, warning};
+use crate::osquery;
+use crate::output::{self, OutputFormat};
+use crate::service;
+use crate::status;
+use crate::supervisor;
+use anyhow::{Context, Result};
+use clap::{ArgMatches, Command};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use sysinfo::{Pid, ProcessesToUpdate, System};
+use tokio::process::Child;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// Directory under the agent's data directory holding the profiles' own
+const DIR_NAME: &str = "profiles";
+
+/// A profile's settings, in its data directory
+const SETTINGS_FILE: &str = "profile.json";
+
+/// How often the agent looks for profiles added, changed, or removed, and
+/// restarts those whose shadow exited
+const SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Arguments that aren't part of a profile's settings
+const SKIPPED_ARGS: &[&str] = &["data_dir", "profile", "help", "version"];
+
+/// Longest profile name
+const MAX_NAME: usize = 32;
+
+/// What a profile's shadow is run with
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+struct Settings {
+    /// Settings with an environment variable, including the org token
+    env: Vec<(String, String)>,
+    /// Settings without one, passed as arguments
+    args: Vec<String>,
+}
+
+/// Check a profile name, which becomes a directory name
+pub fn parse_name(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && !name.starts_with('-')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_');
+    match valid {
+        true => Ok(name.to_string()),
+        false => Err(format!(
+            "a profile name is up to {} lowercase letters, digits, '-', and '_'",
+            MAX_NAME
+        )),
+    }
+}
+
+/// Data directory of the profile `name`, under the agent's
+pub fn dir(base: &Path, name: &str) -> PathBuf {
+    base.join(DIR_NAME).join(name)
+}
+
+/// The profile whose data directory this is, if it's one
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn name_of(data_dir: &Path) -> Option<&str> {
+    if data_dir.parent()?.file_name()? != DIR_NAME {
+        return None;
+    }
+    data_dir.file_name()?.to_str()
+}
+
+/// The names of the profiles, sorted
+fn names(base: &Path) -> Result<Vec<String>> {
+    let entries = match std::fs::read_dir(base.join(DIR_NAME)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("Failed to read the profiles"),
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(SETTINGS_FILE).is_file())
+        .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+        .filter(|name| parse_name(name).is_ok())
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// A profile's settings, or None if it has been removed
+fn load(dir: &Path) -> Result<Option<Settings>> {
+    let path = dir.join(SETTINGS_FILE);
+    let data = match std::fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Write a profile's settings, readable only by the agent's user since they
+/// hold the org token
+fn save(dir: &Path, settings: &Settings) -> Result<()> {
+    let path = dir.join(SETTINGS_FILE);
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, serde_json::to_vec_pretty(settings)?)
+        .with_context(|| format!("Failed to write {}", temp.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::fs::rename(&temp, &path).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Give a profile's data directory and settings to whoever owns the agent's,
+/// so an agent running as its own user can use a profile added by root
+#[cfg(unix)]
+fn give_to_agent(base: &Path, dir: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let owner = std::fs::metadata(base)?;
+    for path in [base.join(DIR_NAME), dir.to_path_buf(), dir.join(SETTINGS_FILE)] {
+        std::os::unix::fs::chown(&path, Some(owner.uid()), Some(owner.gid()))
+            .with_context(|| format!("Failed to give {} to the agent's user", path.display()))?;
+    }
+    Ok(())
+}
+
+/// `shadow profile add`: save the settings given to the command as the
+/// profile `name`, replacing any it had
+pub async fn add(base: &Path, name: &str, command: &Command, matches: &ArgMatches) -> Result<()> {
+    if matches.value_source("org_token").is_none() {
+        anyhow::bail!("--org-token is required to add a profile");
+    }
+    let mut settings = Settings::default();
+    for setting in service::settings(command, matches, SKIPPED_ARGS) {
+        match setting.env_value() {
+            Some(pair) => settings.env.push(pair),
+            None => settings.args.extend(setting.args()),
+        }
+    }
+
+    let dir = dir(base, name);
+    let replaced = load(&dir)?.is_some();
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    save(&dir, &settings)?;
+    #[cfg(unix)]
+    give_to_agent(base, &dir)?;
+    audit::record(base, "profile_add", serde_json::json!({ "profile": name, "replaced": replaced })).await?;
+
+    match replaced {
+        true => println!("Updated profile {}", name),
+        false => println!("Added profile {}, with its data in {}", name, dir.display()),
+    }
+    println!(
+        "The running agent starts its shadow within {} seconds; check on it with `shadow --profile {} status`.",
+        SCAN_INTERVAL.as_secs(),
+        name
+    );
+    Ok(())
+}
+
+/// `shadow profile remove`: remove the profile `name`. Its data directory is
+/// left in place, so adding it again picks up where it left off.
+pub async fn remove(base: &Path, name: &str) -> Result<()> {
+    let dir = dir(base, name);
+    let path = dir.join(SETTINGS_FILE);
+    match std::fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => anyhow::bail!("There's no profile named {}", name),
+        Err(e) => return Err(e).with_context(|| format!("Failed to remove {}", path.display())),
+    }
+    audit::record(base, "profile_remove", serde_json::json!({ "profile": name })).await?;
+
+    println!("Removed profile {}", name);
+    println!(
+        "The running agent stops its shadow within {} seconds. Its data directory, {}, is left in place.",
+        SCAN_INTERVAL.as_secs(),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// A profile as `shadow profile list` shows it
+#[derive(Serialize)]
+struct Listed {
+    name: String,
+    server: String,
+    data_dir: PathBuf,
+    /// The state of its shadow, if that's running
+    state: Option<String>,
+    last_error: Option<String>,
+}
+
+/// The server a profile reports to: its own, or the default
+fn server(command: &Command, settings: &Settings) -> String {
+    let Some(arg) = command.get_arguments().find(|arg| arg.get_id() == "server") else {
+        return String::new();
+    };
+    let env = arg.get_env().and_then(|env| env.to_str());
+    settings
+        .env
+        .iter()
+        .find(|(name, _)| Some(name.as_str()) == env)
+        .map(|(_, value)| value.clone())
+        .or_else(|| {
+            arg.get_default_values()
+                .first()
+                .map(|value| value.to_string_lossy().into_owned())
+        })
+        .unwrap_or_default()
+}
+
+/// `shadow profile list`
+pub async fn list(base: &Path, command: &Command, format: OutputFormat) -> Result<()> {
+    let mut listed = Vec::new();
+    for name in names(base)? {
+        let dir = dir(base, &name);
+        let Some(settings) = load(&dir)? else {
+            continue;
+        };
+        let status = status::read(&dir).await.ok().filter(status::agent_alive);
+        listed.push(Listed {
+            server: server(command, &settings),
+            state: status.as_ref().map(|status| status.state.to_string()),
+            last_error: status.and_then(|status| status.last_error),
+            data_dir: dir,
+            name,
+        });
+    }
+    output::list(format, &listed, |listed| {
+        if listed.is_empty() {
+            println!("No profiles; add one with `shadow --org-token TOKEN --server HOST profile add NAME`");
+            return;
+        }
+        println!("{:<20} {:<32} STATE", "PROFILE", "SERVER");
+        for profile in listed {
+            println!(
+                "{:<20} {:<32} {}",
+                profile.name,
+                profile.server,
+                profile.state.as_deref().unwrap_or("not running")
+            );
+        }
+    })
+}
+
+/// A profile's shadow, as the agent runs it
+struct Running {
+    child: Child,
+    settings: Settings,
+}
+
+/// The profiles' shadows, run by the agent until it stops
+pub struct Profiles {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Profiles {
+    /// Stop the profiles' shadows
+    pub async fn stop(self) {
+        let _ = self.stop.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Run a shadow for each profile under `base`, the agent's data directory
+pub fn spawn(base: PathBuf) -> Profiles {
+    let (stop, mut stopped) = watch::channel(false);
+    let task = tokio::spawn(async move {
+        let exe = match std::env::current_exe() {
+            Ok(exe) => exe,
+            Err(e) => {
+                warning!("Can't run the profiles, failed to locate the shadow binary ({})", e);
+                return;
+            }
+        };
+        let mut running = HashMap::new();
+        let mut ticker = tokio::time::interval(SCAN_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => scan(&base, &exe, &mut running).await,
+                _ = stopped.changed() => break,
+            }
+        }
+        for (name, mut profile) in running {
+            stop_profile(&base, &name, &mut profile.child).await;
+        }
+    });
+    Profiles { stop, task }
+}
+
+/// Bring the running shadows in line with the profiles
+async fn scan(base: &Path, exe: &Path, running: &mut HashMap<String, Running>) {
+    let mut wanted = HashMap::new();
+    for name in names(base).unwrap_or_default() {
+        match load(&dir(base, &name)) {
+            Ok(Some(settings)) => {
+                wanted.insert(name, settings);
+            }
+            Ok(None) => {}
+            Err(e) => warning!("Skipping profile {}: {:#}", name, e),
+        }
+    }
+
+    let stale: Vec<String> = running
+        .iter()
+        .filter(|(name, profile)| wanted.get(*name) != Some(&profile.settings))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in stale {
+        let Some(mut profile) = running.remove(&name) else {
+            continue;
+        };
+        match wanted.contains_key(&name) {
+            true => info!("Restarting profile {} with its new settings", name),
+            false => info!("Stopping profile {}, which was removed", name),
+        }
+        stop_profile(base, &name, &mut profile.child).await;
+    }
+
+    for (name, settings) in wanted {
+        let dir = dir(base, &name);
+        match running.get_mut(&name) {
+            Some(profile) => match profile.child.try_wait() {
+                Ok(Some(exit)) => {
+                    warning!(
+                        "The shadow for profile {} exited with {}, restarting it. See shadow.log in {}.",
+                        name,
+                        exit,
+                        dir.display()
+                    );
+                    // It may not have stopped its osqueryd
+                    supervisor::stop_recorded_child(&dir).await;
+                }
+                _ => continue,
+            },
+            None => stop_leftover(&dir, &name, exe).await,
+        }
+        match start(exe, base, &name, &settings) {
+            Ok(child) => {
+                info!("Started the shadow for profile {} (pid {})", name, child.id().unwrap_or_default());
+                running.insert(name, Running { child, settings });
+            }
+            Err(e) => {
+                running.remove(&name);
+                warning!("Failed to start the shadow for profile {}: {:#}", name, e);
+            }
+        }
+    }
+}
+
+/// Start a profile's shadow: this binary, with the profile's settings, and
+/// nothing of the agent's own
+fn start(exe: &Path, base: &Path, name: &str, settings: &Settings) -> Result<Child> {
+    let mut cmd = osquery::child_command(exe);
+    cmd.args(&settings.args)
+        .envs(settings.env.iter().map(|(name, value)| (name, value)))
+        .env("SHADOW_DATA_DIR", base)
+        .env("SHADOW_PROFILE", name)
+        // It logs to shadow.log in its data directory
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    cmd.spawn().context("Failed to run shadow")
+}
+
+/// Stop a shadow for the profile left running by a previous agent, e.g. one
+/// stopped by an init system that signals only its main process, so two
+/// don't run at once
+async fn stop_leftover(dir: &Path, name: &str, exe: &Path) {
+    let Ok(status) = status::read(dir).await else {
+        return;
+    };
+    if !is_shadow(status.agent_pid, exe) {
+        return;
+    }
+    info!(
+        "Stopping the shadow for profile {} left over from a previous run (pid {})",
+        name, status.agent_pid
+    );
+    supervisor::stop_pid(status.agent_pid, &format!("The shadow for profile {}", name)).await;
+    supervisor::stop_recorded_child(dir).await;
+}
+
+/// Whether the process `pid` is running this binary, rather than being one
+/// that reused the PID
+fn is_shadow(pid: u32, exe: &Path) -> bool {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    let (Some(process), Some(exe_name)) = (system.process(pid), exe.file_name()) else {
+        return false;
+    };
+    // Linux truncates process names to 15 bytes
+    let process_name = process.name().as_encoded_bytes();
+    !process_name.is_empty() && exe_name.as_encoded_bytes().starts_with(process_name)
+}
+
+/// Stop a profile's shadow and the osqueryd it ran
+async fn stop_profile(base: &Path, name: &str, child: &mut Child) {
+    if let Err(e) = supervisor::stop_process(child, &format!("The shadow for profile {}", name)).await {
+        warning!("Failed to stop the shadow for profile {}: {:#}", name, e);
+    }
+    // Stopped with a signal, it doesn't stop its osqueryd itself
+    supervisor::stop_recorded_child(&dir(base, name)).await;
+}
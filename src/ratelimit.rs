@@ -0,0 +1,275 @@
+//! Event rate limits
+//!
+//! Event tables like `process_events` can produce a storm of results on a
+//! busy build server, swamping the server and the host's uplink. With
+//! `--event-rate-limit` and `--event-sample` and the TLS relay, the agent
+//! keeps only a fraction of a query's results, or none past a number per
+//! minute, before they're sent. Limits name a scheduled query, as it appears
+//! in results, or a table, applying to every scheduled query whose SQL reads
+//! it (learned from the configs osqueryd fetches). What was dropped is counted
+//! and summarized to the server every minute, so the gap is visible.
+
+use crate::telemetry;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The rate limits' window
+pub const WINDOW: Duration = Duration::from_secs(60);
+
+/// Most results a query may send per minute, as NAME=N
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Query or table
+    pub name: String,
+    pub per_minute: u64,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, limit) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or_else(|| format!("expected QUERY_OR_TABLE=N, got '{}'", s))?;
+        let per_minute = limit
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid number of results per minute '{}'", limit))?;
+        Ok(RateLimit {
+            name: name.trim().to_string(),
+            per_minute,
+        })
+    }
+}
+
+/// Fraction of a query's results to keep, as NAME=RATIO
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Query or table
+    pub name: String,
+    pub ratio: f64,
+}
+
+impl FromStr for Sample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, ratio) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+            .ok_or_else(|| format!("expected QUERY_OR_TABLE=RATIO, got '{}'", s))?;
+        let ratio: f64 = ratio
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid ratio '{}'", ratio))?;
+        if !(ratio > 0.0 && ratio <= 1.0) {
+            return Err(format!("ratio must be more than 0 and at most 1, got {}", ratio));
+        }
+        Ok(Sample {
+            name: name.trim().to_string(),
+            ratio,
+        })
+    }
+}
+
+/// Results dropped from one query in a window
+#[derive(Debug, Clone, Serialize)]
+pub struct Dropped {
+    pub name: String,
+    /// Rows over the rate limit
+    pub limited: u64,
+    /// Rows left out by sampling
+    pub sampled: u64,
+}
+
+/// The limits on one query, the strictest of those that apply to it
+#[derive(Debug, Clone, Copy, Default)]
+struct Rule {
+    per_minute: Option<u64>,
+    ratio: Option<f64>,
+}
+
+impl Rule {
+    fn tighten(&mut self, per_minute: Option<u64>, ratio: Option<f64>) {
+        if let Some(limit) = per_minute {
+            self.per_minute = Some(self.per_minute.map_or(limit, |current| current.min(limit)));
+        }
+        if let Some(ratio) = ratio {
+            self.ratio = Some(self.ratio.map_or(ratio, |current| current.min(ratio)));
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counter {
+    window: Option<Instant>,
+    sent: u64,
+    /// Results seen, for spreading samples evenly
+    seen: u64,
+    limited: u64,
+    sampled: u64,
+}
+
+struct State {
+    /// Tables each scheduled query reads, from the last config
+    tables: HashMap<String, Vec<String>>,
+    counters: HashMap<String, Counter>,
+}
+
+/// Limits on how many results each query sends
+pub struct Limiter {
+    /// By query or table name
+    limits: HashMap<String, u64>,
+    samples: HashMap<String, f64>,
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    pub fn new(limits: &[RateLimit], samples: &[Sample]) -> Self {
+        Limiter {
+            limits: limits.iter().map(|l| (l.name.clone(), l.per_minute)).collect(),
+            samples: samples.iter().map(|s| (s.name.clone(), s.ratio)).collect(),
+            state: Mutex::new(State {
+                tables: HashMap::new(),
+                counters: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty() && self.samples.is_empty()
+    }
+
+    /// Learn which tables each scheduled query reads from a config
+    pub fn learn_config(&self, body: &[u8]) -> Result<()> {
+        let config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        let mut tables = HashMap::new();
+        let mut learn = |prefix: &str, queries: &serde_json::Map<String, Value>| {
+            for (name, query) in queries {
+                let sql = query.get("query").and_then(Value::as_str).unwrap_or_default();
+                tables.insert(format!("{}{}", prefix, name), self.tables_in(sql));
+            }
+        };
+        if let Some(schedule) = config.get("schedule").and_then(Value::as_object) {
+            learn("", schedule);
+        }
+        if let Some(packs) = config.get("packs").and_then(Value::as_object) {
+            for (pack, contents) in packs {
+                if let Some(queries) = contents.get("queries").and_then(Value::as_object) {
+                    learn(&format!("pack_{}_", pack), queries);
+                }
+            }
+        }
+        self.state.lock().unwrap().tables = tables;
+        Ok(())
+    }
+
+    /// Limited or sampled tables a query's SQL reads
+    fn tables_in(&self, sql: &str) -> Vec<String> {
+        let sql = sql.to_ascii_lowercase();
+        let mut tables: Vec<String> = sql
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .filter(|word| self.limits.contains_key(*word) || self.samples.contains_key(*word))
+            .map(str::to_string)
+            .collect();
+        tables.sort_unstable();
+        tables.dedup();
+        tables
+    }
+
+    fn rule(&self, name: &str, tables: &HashMap<String, Vec<String>>) -> Rule {
+        let mut rule = Rule::default();
+        let names = std::iter::once(name).chain(tables.get(name).into_iter().flatten().map(String::as_str));
+        for name in names {
+            rule.tighten(self.limits.get(name).copied(), self.samples.get(name).copied());
+        }
+        rule
+    }
+
+    /// Drop results over their query's limits from a logger request
+    pub fn apply(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        if request.get("log_type").and_then(Value::as_str) != Some("result") {
+            return Ok(body.to_vec());
+        }
+        let Some(items) = request.get_mut("data").and_then(Value::as_array_mut) else {
+            return Ok(body.to_vec());
+        };
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let State { tables, counters } = &mut *state;
+        let before = items.len();
+        items.retain(|item| {
+            let name = item.get("name").and_then(Value::as_str).unwrap_or_default();
+            let rule = self.rule(name, tables);
+            if rule.per_minute.is_none() && rule.ratio.is_none() {
+                return true;
+            }
+            let counter = counters.entry(name.to_string()).or_default();
+            if counter.window.is_none_or(|start| now.duration_since(start) >= WINDOW) {
+                counter.window = Some(now);
+                counter.sent = 0;
+            }
+            let rows = rows(item);
+            if let Some(ratio) = rule.ratio {
+                // Keep every 1/ratio-th result rather than a random few, so
+                // the sample is spread evenly
+                counter.seen += 1;
+                if (counter.seen as f64 * ratio).floor() == ((counter.seen - 1) as f64 * ratio).floor() {
+                    counter.sampled += rows;
+                    telemetry::record_dropped_results(name, "sampled", rows);
+                    return false;
+                }
+            }
+            if rule.per_minute.is_some_and(|limit| counter.sent + rows > limit) {
+                counter.limited += rows;
+                telemetry::record_dropped_results(name, "rate_limited", rows);
+                return false;
+            }
+            counter.sent += rows;
+            true
+        });
+        if items.len() == before {
+            return Ok(body.to_vec());
+        }
+        Ok(serde_json::to_vec(&request)?)
+    }
+
+    /// What was dropped since the last call, by query
+    pub fn take_dropped(&self) -> Vec<Dropped> {
+        let mut state = self.state.lock().unwrap();
+        let dropped: BTreeMap<String, Dropped> = state
+            .counters
+            .iter_mut()
+            .filter(|(_, counter)| counter.limited > 0 || counter.sampled > 0)
+            .map(|(name, counter)| {
+                let dropped = Dropped {
+                    name: name.clone(),
+                    limited: std::mem::take(&mut counter.limited),
+                    sampled: std::mem::take(&mut counter.sampled),
+                };
+                (name.clone(), dropped)
+            })
+            .collect();
+        dropped.into_values().collect()
+    }
+}
+
+/// Rows in a result: one for an event, or each in a snapshot or batch of
+/// differences
+fn rows(item: &Value) -> u64 {
+    let count = |value: Option<&Value>| value.and_then(Value::as_array).map_or(0, |rows| rows.len() as u64);
+    if let Some(snapshot) = item.get("snapshot") {
+        return count(Some(snapshot));
+    }
+    if let Some(diff) = item.get("diffResults") {
+        return count(diff.get("added")) + count(diff.get("removed"));
+    }
+    1
+}
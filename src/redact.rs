@@ -0,0 +1,160 @@
+//! Result redaction
+//!
+//! Some deployments can't let usernames, command lines, and the like leave the
+//! host. With `--redaction-rules` and the TLS relay, every result log and live
+//! query result osqueryd sends is rewritten by the rules before it's forwarded
+//! (or spooled). A rule names a column, optionally the queries it applies to
+//! (a regex over the scheduled query's name, or a live query's ID), and
+//! optionally a regex for the part of the value to replace; without one the
+//! whole value is. osquery's results don't carry table names, so rules match
+//! queries rather than tables. Decorations are treated as columns. Requests
+//! the relay can't parse aren't forwarded, so nothing gets past the rules.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// What a value is replaced with by default
+const REDACTED: &str = "[redacted]";
+
+/// A rule as written in the rules file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    /// Regex over the query's name or ID, every query if not given
+    query: Option<String>,
+    column: String,
+    /// Regex for what to replace in the value, all of it if not given
+    pattern: Option<String>,
+    replace: Option<String>,
+}
+
+struct Rule {
+    query: Option<Regex>,
+    column: String,
+    pattern: Option<Regex>,
+    replace: String,
+}
+
+/// Rules applied to results on their way to the server
+pub struct Redactor {
+    rules: Vec<Rule>,
+}
+
+/// Load rules from a YAML file, a list like
+/// `[{query: "^pack_.*_processes$", column: cmdline, pattern: " .*", replace: ""}]`
+pub fn load(path: &Path) -> Result<Redactor> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let specs: Vec<RuleSpec> = serde_norway::from_str(&text)
+        .with_context(|| format!("Invalid redaction rules in {}", path.display()))?;
+    let regex = |pattern: Option<String>, column: &str| {
+        pattern
+            .map(|p| Regex::new(&p))
+            .transpose()
+            .with_context(|| format!("Invalid regex in the redaction rule for {}", column))
+    };
+    let rules = specs
+        .into_iter()
+        .map(|spec| {
+            Ok(Rule {
+                query: regex(spec.query, &spec.column)?,
+                pattern: regex(spec.pattern, &spec.column)?,
+                replace: spec.replace.unwrap_or_else(|| REDACTED.to_string()),
+                column: spec.column,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Redactor { rules })
+}
+
+impl Redactor {
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Redact a logger request or live query results, by the endpoint's name
+    /// (`log` or `distributed/write`)
+    pub fn apply(&self, endpoint: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let mut request: Value = serde_json::from_slice(body).context("Request isn't JSON")?;
+        match endpoint {
+            "log" => self.logs(&mut request),
+            "distributed/write" => self.live_results(&mut request),
+            _ => {}
+        }
+        Ok(serde_json::to_vec(&request)?)
+    }
+
+    fn logs(&self, request: &mut Value) {
+        if request.get("log_type").and_then(Value::as_str) != Some("result") {
+            return;
+        }
+        let Some(items) = request.get_mut("data").and_then(Value::as_array_mut) else {
+            return;
+        };
+        for item in items {
+            let name = item.get("name").and_then(Value::as_str).unwrap_or_default().to_string();
+            let rules: Vec<&Rule> = self.matching(&name).collect();
+            if rules.is_empty() {
+                continue;
+            }
+            // Event format has `columns`, snapshots `snapshot`, and batch
+            // format `diffResults`
+            let mut rows: Vec<&mut Value> = Vec::new();
+            let Some(item) = item.as_object_mut() else {
+                continue;
+            };
+            for (key, value) in item.iter_mut() {
+                match key.as_str() {
+                    "columns" | "decorations" => rows.push(value),
+                    "snapshot" => rows.extend(value.as_array_mut().into_iter().flatten()),
+                    "diffResults" => {
+                        for diff in value.as_object_mut().into_iter().flat_map(|d| d.values_mut()) {
+                            rows.extend(diff.as_array_mut().into_iter().flatten());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            for row in rows {
+                if let Some(row) = row.as_object_mut() {
+                    redact_row(row, &rules);
+                }
+            }
+        }
+    }
+
+    fn live_results(&self, request: &mut Value) {
+        let Some(queries) = request.get_mut("queries").and_then(Value::as_object_mut) else {
+            return;
+        };
+        for (id, rows) in queries.iter_mut() {
+            let rules: Vec<&Rule> = self.matching(id).collect();
+            for row in rows.as_array_mut().into_iter().flatten() {
+                if let Some(row) = row.as_object_mut() {
+                    redact_row(row, &rules);
+                }
+            }
+        }
+    }
+
+    fn matching<'a>(&'a self, query: &'a str) -> impl Iterator<Item = &'a Rule> {
+        self.rules
+            .iter()
+            .filter(move |rule| rule.query.as_ref().is_none_or(|q| q.is_match(query)))
+    }
+}
+
+fn redact_row(row: &mut Map<String, Value>, rules: &[&Rule]) {
+    for rule in rules {
+        let Some(Value::String(value)) = row.get_mut(&rule.column) else {
+            continue;
+        };
+        *value = match &rule.pattern {
+            Some(pattern) => pattern.replace_all(value, rule.replace.as_str()).into_owned(),
+            None => rule.replace.clone(),
+        };
+    }
+}
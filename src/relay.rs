@@ -0,0 +1,1228 @@
+//! Local TLS relay
+//!
+//! With `--tls-relay`, osqueryd talks to a listener the agent runs on
+//! localhost instead of to the server, and the agent forwards its enroll,
+//! config, logger, and distributed requests on. That puts the agent on
+//! osqueryd's traffic, which it otherwise can't see: `--relay-header` adds
+//! headers to every forwarded request (e.g. for a gateway in front of the
+//! server), `--osquery-log-compress` gzips osqueryd's logs here rather than in
+//! osqueryd, and each request is counted and timed per endpoint in the
+//! `shadow.relay.*` metrics, and live queries are timed from read to write
+//! (see [`crate::distributed`]). The listener uses a certificate generated at
+//! startup, which is written to the data directory for osqueryd's
+//! `--tls_server_certs`; its key never leaves memory. A client certificate is
+//! generated with it, and written with its key for osqueryd's
+//! `--tls_client_cert` and `--tls_client_key`, readable only by the agent's
+//! user. Connections that don't present it are refused, so other local users
+//! can't send results or use `--relay-header` through the relay. Only
+//! osquery's endpoints are forwarded. Denied queries are kept from osqueryd (see
+//! [`crate::denylist`]), results can be redacted on the way (see
+//! [`crate::redact`]), and logs and live query results the server can't take
+//! are spooled to disk and sent later (see [`crate::spool`]).
+
+use crate::api::{ApiClient, Tag};
+use crate::bandwidth::TokenBucket;
+use crate::carve::{Carver, Sealing};
+use crate::decorators;
+use crate::dedup::{Dedup, Pending};
+use crate::denylist::{self, Blocked, Denylist};
+use crate::distributed;
+use crate::fim::Overlay;
+use crate::lastconfig::{self, LastConfig};
+use crate::logging::{debug, error, info, warning};
+use crate::packs;
+use crate::ratelimit::{self, Limiter};
+use crate::redact::Redactor;
+use crate::routing::Routes;
+use crate::sinks::Sinks;
+use crate::spool::{Batch, Replayed, Spool};
+use crate::status::SharedStatus;
+use crate::telemetry::{self, Header};
+use crate::yara::Rules;
+use anyhow::{Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderMap, HeaderName, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::BTreeSet;
+use std::convert::Infallible;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+use tokio_rustls::rustls::{self, CertificateError, DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// The certificate osqueryd trusts for the relay, in the data directory
+const CERT_FILE: &str = "relay.pem";
+
+/// The certificate and key osqueryd presents to the relay, in the data
+/// directory
+const CLIENT_CERT_FILE: &str = "relay-client.pem";
+const CLIENT_KEY_FILE: &str = "relay-client.key";
+
+/// The endpoints forwarded
+const OSQUERY_PREFIX: &str = "/api/osquery/";
+
+/// osqueryd's logger endpoint, whose requests can be spooled
+const LOG_ENDPOINT: &str = "log";
+
+/// osqueryd's config endpoint
+const CONFIG_ENDPOINT: &str = "config";
+
+/// osqueryd's carve endpoints: one starts a carve, the other sends its blocks
+const CARVE_PREFIX: &str = "carve/";
+const CARVE_BEGIN_ENDPOINT: &str = "carve/begin";
+const CARVE_BLOCK_ENDPOINT: &str = "carve/block";
+
+/// osqueryd's endpoint for live queries
+const READ_ENDPOINT: &str = "distributed/read";
+
+/// Endpoints whose replies carry queries for osqueryd to run
+const DENYLIST_ENDPOINTS: &[&str] = &[CONFIG_ENDPOINT, READ_ENDPOINT];
+
+/// Where results dropped by rate limits are summarized
+const DROPPED_PATH: &str = "/api/shadow/dropped-results";
+
+/// osqueryd's endpoint for live query results, which can be spooled too
+const WRITE_ENDPOINT: &str = "distributed/write";
+
+/// Endpoints whose requests carry results
+const REDACTED_ENDPOINTS: &[&str] = &[LOG_ENDPOINT, WRITE_ENDPOINT];
+
+/// How often the deduplication cache is saved
+const DEDUP_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often spooled logs are retried
+const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before retrying spooled live query results, doubled while the server
+/// can't take them
+const LIVE_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_LIVE_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Largest request forwarded, well above osqueryd's biggest log batches
+const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// Headers that describe one connection rather than the request
+const HOP_BY_HOP: &[HeaderName] = &[
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::HOST,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// A running relay, to point osqueryd at
+pub struct Relay {
+    /// What to pass as osqueryd's `--tls_hostname`
+    pub hostname: String,
+    /// What to pass as osqueryd's `--tls_server_certs`
+    pub cert_path: PathBuf,
+    /// What to pass as osqueryd's `--tls_client_cert` and `--tls_client_key`
+    pub client_cert_path: PathBuf,
+    pub client_key_path: PathBuf,
+    /// Sends the spools on request
+    pub replayer: Replayer,
+}
+
+/// Sends what's spooled straight away, for `shadow replay`
+#[derive(Clone)]
+pub struct Replayer(Arc<Forwarder>);
+
+impl Replayer {
+    /// Send each spool until it's empty, the server can't take a batch, or
+    /// `deadline` passes
+    pub async fn replay(&self, deadline: Instant) -> Vec<Replayed> {
+        let forwarder = &self.0;
+        let spools = [
+            (forwarder.spool.as_ref(), Forwarder::show_spool as fn(&Forwarder, &Spool)),
+            (forwarder.live_spool.as_ref(), Forwarder::show_live_spool),
+        ];
+        let mut replayed = Vec::new();
+        for (spool, show) in spools {
+            let Some(spool) = spool else {
+                continue;
+            };
+            let drained = drain(forwarder, spool, show, Some(deadline)).await;
+            let (left, left_bytes) = spool.usage();
+            replayed.push(Replayed {
+                spool: spool.what().to_string(),
+                sent: drained.sent,
+                rejected: drained.rejected,
+                left,
+                left_bytes,
+                reachable: drained.reachable,
+            });
+        }
+        replayed
+    }
+}
+
+struct Forwarder {
+    api: ApiClient,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    status: SharedStatus,
+    spool: Option<Arc<Spool>>,
+    live_spool: Option<Arc<Spool>>,
+    /// Wakes the live query result retries once the server takes results again
+    live_retry: Notify,
+    /// Held while a spool is sent, so a batch is never sent twice at once
+    draining: tokio::sync::Mutex<()>,
+    compress: bool,
+    redactor: Option<Arc<Redactor>>,
+    denylist: Option<Arc<Denylist>>,
+    sinks: Option<Sinks>,
+    limiter: Option<Limiter>,
+    dedup: Option<Dedup>,
+    routes: Routes,
+    result_bandwidth: Option<TokenBucket>,
+    carve_bandwidth: Option<TokenBucket>,
+    decorators: Vec<Tag>,
+    carver: Arc<Carver>,
+    yara: Option<Arc<Rules>>,
+    fim: Option<Overlay>,
+    last_config: LastConfig,
+    /// Scheduled queries the denylist took out of the last config
+    blocked: Mutex<BTreeSet<String>>,
+}
+
+/// What the relay does to osqueryd's requests besides forwarding them
+#[derive(Default)]
+pub struct Options {
+    /// Added to every request
+    pub headers: Vec<Header>,
+    /// Where logs go when the server can't take them
+    pub spool: Option<Spool>,
+    /// Where live query results go when the server can't take them
+    pub live_spool: Option<Spool>,
+    /// Gzip logger requests osqueryd didn't
+    pub compress: bool,
+    /// Rewrites results before they're sent or spooled
+    pub redactor: Option<Arc<Redactor>>,
+    /// Queries kept from osqueryd
+    pub denylist: Option<Arc<Denylist>>,
+    /// Also get a copy of every result
+    pub sinks: Option<Sinks>,
+    /// Drops results past their query's rate limits
+    pub limiter: Option<Limiter>,
+    /// Drops rows the server already has
+    pub dedup: Option<Dedup>,
+    /// Where each category of data goes, if not to the server
+    pub routes: Routes,
+    /// Caps on upload throughput for results and logs, and for carves
+    pub result_bandwidth: Option<TokenBucket>,
+    pub carve_bandwidth: Option<TokenBucket>,
+    /// Added to the decorations of every result
+    pub decorators: Vec<Tag>,
+    /// What osqueryd may carve, nothing by default
+    pub carver: Arc<Carver>,
+    /// YARA rules to add to configs
+    pub yara: Option<Arc<Rules>>,
+    /// File paths to add to configs
+    pub fim: Option<Overlay>,
+}
+
+/// Start relaying to the server `api` talks to until the agent exits
+pub async fn spawn(
+    api: ApiClient,
+    data_dir: &Path,
+    status: SharedStatus,
+    options: Options,
+) -> Result<Relay> {
+    let headers = options
+        .headers
+        .iter()
+        .map(|h| {
+            let name = HeaderName::try_from(h.name.as_str())
+                .with_context(|| format!("Invalid relay header name '{}'", h.name))?;
+            let value = HeaderValue::try_from(h.value.as_str())
+                .with_context(|| format!("Invalid value for relay header '{}'", h.name))?;
+            Ok((name, value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (config, cert_pem, client) = tls_config()?;
+    let cert_path = data_dir.join(CERT_FILE);
+    tokio::fs::write(&cert_path, cert_pem)
+        .await
+        .with_context(|| format!("Failed to write {}", cert_path.display()))?;
+    let client_cert_path = data_dir.join(CLIENT_CERT_FILE);
+    let client_key_path = data_dir.join(CLIENT_KEY_FILE);
+    write_private(&client_cert_path, client.cert.pem().as_bytes())?;
+    write_private(&client_key_path, client.signing_key.serialize_pem().as_bytes())?;
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .context("Failed to start the TLS relay")?;
+    let addr = listener.local_addr()?;
+    info!("Relaying osqueryd's requests to the server through {}", addr);
+
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let spool = options.spool.map(Arc::new);
+    let live_spool = options.live_spool.map(Arc::new);
+    let forwarder = Arc::new(Forwarder {
+        api,
+        headers,
+        status,
+        spool: spool.clone(),
+        live_spool: live_spool.clone(),
+        live_retry: Notify::new(),
+        draining: tokio::sync::Mutex::new(()),
+        compress: options.compress,
+        redactor: options.redactor,
+        denylist: options.denylist,
+        sinks: options.sinks,
+        limiter: options.limiter,
+        dedup: options.dedup,
+        routes: options.routes,
+        result_bandwidth: options.result_bandwidth,
+        carve_bandwidth: options.carve_bandwidth,
+        decorators: options.decorators,
+        carver: options.carver,
+        yara: options.yara,
+        fim: options.fim,
+        last_config: LastConfig::new(data_dir),
+        blocked: Mutex::new(BTreeSet::new()),
+    });
+    if let Some(spool) = spool {
+        forwarder.show_spool(&spool);
+        tokio::spawn(replay(forwarder.clone(), spool));
+    }
+    if let Some(spool) = live_spool {
+        forwarder.show_live_spool(&spool);
+        tokio::spawn(retry_live(forwarder.clone(), spool));
+    }
+    if forwarder.limiter.is_some() {
+        tokio::spawn(report_dropped(forwarder.clone()));
+    }
+    if forwarder.dedup.is_some() {
+        tokio::spawn(save_dedup(forwarder.clone()));
+    }
+    tokio::spawn(serve(listener, acceptor, forwarder.clone()));
+    Ok(Relay {
+        hostname: addr.to_string(),
+        cert_path,
+        client_cert_path,
+        client_key_path,
+        replayer: Replayer(forwarder),
+    })
+}
+
+/// A server config with a new self-signed certificate for the loopback
+/// address, the certificate as PEM, and the only client certificate the
+/// config accepts
+fn tls_config() -> Result<(ServerConfig, String, rcgen::CertifiedKey<rcgen::KeyPair>)> {
+    let names = vec![Ipv4Addr::LOCALHOST.to_string(), "localhost".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names)
+        .context("Failed to generate the TLS relay's certificate")?;
+    let client = rcgen::generate_simple_self_signed(vec!["osqueryd".to_string()])
+        .context("Failed to generate osqueryd's certificate for the TLS relay")?;
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let verifier = Arc::new(ClientVerifier {
+        cert: CertificateDer::from(client.cert.der().to_vec()),
+        algorithms: provider.signature_verification_algorithms,
+    });
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![cert], key)
+        .context("Failed to set up the TLS relay's certificate")?;
+    Ok((config, certified.cert.pem(), client))
+}
+
+/// Accepts only the client certificate the relay generated for osqueryd
+#[derive(Debug)]
+struct ClientVerifier {
+    cert: CertificateDer<'static>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if end_entity.as_ref() != self.cert.as_ref() {
+            return Err(rustls::Error::InvalidCertificate(CertificateError::UnknownIssuer));
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Write `data` to `path`, readable only by the agent's user
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(data)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+async fn serve(listener: TcpListener, acceptor: TlsAcceptor, forwarder: Arc<Forwarder>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("TLS relay failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let forwarder = forwarder.clone();
+        tokio::spawn(async move {
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let service = hyper::service::service_fn(move |request| {
+                let forwarder = forwarder.clone();
+                async move { Ok::<_, Infallible>(forwarder.forward(request, peer).await) }
+            });
+            let _ = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
+    }
+}
+
+/// The endpoint a path is for, e.g. `distributed/read`, as a metric attribute
+fn endpoint(path: &str) -> &str {
+    path.strip_prefix(OSQUERY_PREFIX).unwrap_or("other")
+}
+
+/// The results in a logger request, for the sinks
+fn results(headers: &HeaderMap, body: &[u8]) -> Vec<serde_json::Value> {
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return Vec::new();
+    }
+    let Ok(mut request) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return Vec::new();
+    };
+    if request.get("log_type").and_then(serde_json::Value::as_str) != Some("result") {
+        return Vec::new();
+    }
+    match request.get_mut("data").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(items)) => items,
+        _ => Vec::new(),
+    }
+}
+
+fn gzip(data: &[u8]) -> std::io::Result<Bytes> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?.into())
+}
+
+fn reply(status: StatusCode, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+impl Forwarder {
+    async fn forward(&self, request: Request<Incoming>, peer: SocketAddr) -> Response<Full<Bytes>> {
+        let path = request.uri().path().to_string();
+        if !path.starts_with(OSQUERY_PREFIX) {
+            return reply(StatusCode::NOT_FOUND, "Not an osquery endpoint");
+        }
+        let path_and_query = request
+            .uri()
+            .path_and_query()
+            .map_or(path.clone(), |p| p.to_string());
+
+        let (parts, body) = request.into_parts();
+        let body = match Limited::new(body, MAX_BODY).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => {
+                error!("TLS relay couldn't read a request from {} to {}: {}", peer, path, e);
+                return reply(StatusCode::BAD_REQUEST, "Couldn't read the request");
+            }
+        };
+        let mut headers = parts.headers;
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+        let is_log = endpoint(&path) == LOG_ENDPOINT;
+        if endpoint(&path).starts_with(CARVE_PREFIX) {
+            let refused = match endpoint(&path) {
+                CARVE_BEGIN_ENDPOINT => self.carver.begin(&body).await.err(),
+                _ if !self.carver.is_enabled() => Some("carving is off (--carve)".to_string()),
+                _ => None,
+            };
+            if let Some(reason) = refused {
+                warning!("TLS relay refused a file carve: {}", reason);
+                return reply(StatusCode::FORBIDDEN, "Carve refused by the agent");
+            }
+        }
+        let (body, sealing) = match endpoint(&path) {
+            CARVE_BEGIN_ENDPOINT if self.carver.seals() => match self.carver.seal_begin(&body) {
+                Ok((body, sealing)) => (Bytes::from(body), Some(sealing)),
+                Err(e) => {
+                    error!("TLS relay couldn't set up encryption for a file carve, so it wasn't started: {:#}", e);
+                    return reply(StatusCode::BAD_GATEWAY, "Couldn't encrypt the carve");
+                }
+            },
+            CARVE_BLOCK_ENDPOINT if self.carver.seals() => match self.carver.seal_block(&body) {
+                // Nothing unencrypted gets past
+                Ok(sealed) => (Bytes::from(sealed), None),
+                Err(e) => {
+                    error!("TLS relay couldn't encrypt a file carve block, so it wasn't sent: {:#}", e);
+                    return reply(StatusCode::BAD_GATEWAY, "Couldn't encrypt the carve");
+                }
+            },
+            _ => (body, None),
+        };
+        let body = match &self.denylist {
+            Some(denylist) if is_log => {
+                let blocked = self.blocked.lock().unwrap().clone();
+                let filtered = match headers.contains_key(header::CONTENT_ENCODING) {
+                    true => Err(anyhow::anyhow!("Request is compressed")),
+                    false => denylist.filter_logs(&body, &blocked),
+                };
+                match filtered {
+                    Ok(filtered) => Bytes::from(filtered),
+                    Err(e) => {
+                        error!("TLS relay couldn't check a request to {} against the denylist, so it wasn't sent: {:#}", path, e);
+                        return reply(StatusCode::BAD_GATEWAY, "Couldn't check the request");
+                    }
+                }
+            }
+            _ => body,
+        };
+        let body = match &self.limiter {
+            Some(limiter) if is_log && !headers.contains_key(header::CONTENT_ENCODING) => {
+                match limiter.apply(&body) {
+                    Ok(limited) => Bytes::from(limited),
+                    Err(e) => {
+                        warning!("TLS relay couldn't apply rate limits to a request to {}: {:#}", path, e);
+                        body
+                    }
+                }
+            }
+            _ => body,
+        };
+        let body = match &self.redactor {
+            Some(redactor) if REDACTED_ENDPOINTS.contains(&endpoint(&path)) => {
+                // Nothing unredacted gets past, so what can't be read isn't sent
+                let redacted = match headers.contains_key(header::CONTENT_ENCODING) {
+                    true => Err(anyhow::anyhow!("Request is compressed")),
+                    false => redactor.apply(endpoint(&path), &body),
+                };
+                match redacted {
+                    Ok(redacted) => Bytes::from(redacted),
+                    Err(e) => {
+                        error!("TLS relay couldn't redact a request to {}, so it wasn't sent: {:#}", path, e);
+                        return reply(StatusCode::BAD_GATEWAY, "Couldn't redact the request");
+                    }
+                }
+            }
+            _ => body,
+        };
+        // After the limiter and redactor, so only rows that are sent are
+        // remembered as ones the server has
+        let (body, pending) = match &self.dedup {
+            Some(dedup) if is_log && !headers.contains_key(header::CONTENT_ENCODING) => {
+                match dedup.apply(&body) {
+                    Ok((deduped, pending, dropped)) => {
+                        if dropped > 0 {
+                            debug!("Dropped {} results the server already has", dropped);
+                        }
+                        (Bytes::from(deduped), Some(pending))
+                    }
+                    Err(e) => {
+                        warning!("TLS relay couldn't check a request to {} for duplicates: {:#}", path, e);
+                        (body, None)
+                    }
+                }
+            }
+            _ => (body, None),
+        };
+        let body = if is_log && !self.decorators.is_empty() {
+            // Results missing them would break routing downstream, so they
+            // aren't sent without
+            let decorated = match headers.contains_key(header::CONTENT_ENCODING) {
+                true => Err(anyhow::anyhow!("Request is compressed")),
+                false => decorators::apply(&self.decorators, &body),
+            };
+            match decorated {
+                Ok(decorated) => Bytes::from(decorated),
+                Err(e) => {
+                    error!("TLS relay couldn't decorate a request to {}, so it wasn't sent: {:#}", path, e);
+                    return reply(StatusCode::BAD_GATEWAY, "Couldn't decorate the request");
+                }
+            }
+        } else {
+            body
+        };
+        let server = self.routes.server(endpoint(&path), &body).map(str::to_string);
+        if let Some(sinks) = self.sinks.as_ref().filter(|_| is_log) {
+            sinks.send(results(&headers, &body));
+        }
+        let body = if is_log && self.compress && !headers.contains_key(header::CONTENT_ENCODING) {
+            match gzip(&body) {
+                Ok(compressed) => {
+                    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                    compressed
+                }
+                Err(e) => {
+                    error!("TLS relay couldn't compress osqueryd's logs: {}", e);
+                    body
+                }
+            }
+        } else {
+            body
+        };
+
+        if let Some(spool) = self.spool.as_ref().filter(|_| is_log) {
+            let response = self.spool_or_send(spool, path_and_query, server, headers, body).await;
+            self.remember(pending, &response);
+            return response;
+        }
+        if let Some(spool) = self.live_spool.as_ref().filter(|_| endpoint(&path) == WRITE_ENDPOINT) {
+            return self.send_or_keep(spool, path_and_query, server, headers, body).await;
+        }
+        let response = self
+            .send(parts.method, server.as_deref(), &path_and_query, headers, body)
+            .await;
+        let response = match response {
+            _ if endpoint(&path) == CONFIG_ENDPOINT => self.keep_config(response).await,
+            Some(response) => response,
+            None => return reply(StatusCode::BAD_GATEWAY, "Couldn't reach the server"),
+        };
+        self.remember(pending, &response);
+        let response = match sealing {
+            Some(sealing) => self.start_sealing(sealing, response).await,
+            None => response,
+        };
+        let response = match &self.limiter {
+            Some(limiter) if endpoint(&path) == CONFIG_ENDPOINT => self.learn(limiter, response).await,
+            _ => response,
+        };
+        let response = match &self.dedup {
+            Some(dedup) if endpoint(&path) == CONFIG_ENDPOINT => self.learn_events(dedup, response).await,
+            _ => response,
+        };
+        let response = match &self.denylist {
+            Some(denylist) if DENYLIST_ENDPOINTS.contains(&endpoint(&path)) => {
+                self.enforce(denylist, endpoint(&path), response).await
+            }
+            _ => response,
+        };
+        let response = match endpoint(&path) {
+            endpoint if DENYLIST_ENDPOINTS.contains(&endpoint) => self.guard_carves(endpoint, response).await,
+            _ => response,
+        };
+        let response = match &self.yara {
+            Some(rules) if endpoint(&path) == CONFIG_ENDPOINT => {
+                self.extend_config("the YARA rules", |body| rules.inject(body), response).await
+            }
+            _ => response,
+        };
+        match &self.fim {
+            Some(fim) if endpoint(&path) == CONFIG_ENDPOINT => {
+                self.extend_config("the local FIM paths", |body| fim.apply(body), response).await
+            }
+            _ => response,
+        }
+    }
+
+    /// Save a config from the server, or answer with the last one saved if
+    /// the server couldn't give one
+    async fn keep_config(&self, response: Option<Response<Full<Bytes>>>) -> Response<Full<Bytes>> {
+        let response = match response {
+            Some(response) if !response.status().is_server_error() => response,
+            response => {
+                let snapshot = self.status.snapshot();
+                let config = match self.last_config.load() {
+                    Some((config, saved_at)) => {
+                        if snapshot.cached_config_from.is_none() {
+                            warning!(
+                                "The server can't give osqueryd its config, so it gets the last one, from {}",
+                                saved_at.to_rfc3339()
+                            );
+                        }
+                        self.status.update(|status| status.cached_config_from = Some(saved_at));
+                        Some(config)
+                    }
+                    None => {
+                        if !snapshot.builtin_config {
+                            warning!("The server can't give osqueryd its config and none was saved, so it gets the built-in query packs");
+                        }
+                        self.status.update(|status| status.builtin_config = true);
+                        lastconfig::refreshing(packs::config())
+                    }
+                };
+                let Some(config) = config else {
+                    return response.unwrap_or_else(|| reply(StatusCode::BAD_GATEWAY, "Couldn't reach the server"));
+                };
+                let mut response = reply(StatusCode::OK, config);
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                return response;
+            }
+        };
+        let (parts, body) = response.into_parts();
+        if !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let saved = match parts.headers.contains_key(header::CONTENT_ENCODING) {
+            true => Err(anyhow::anyhow!("Config is compressed")),
+            false => self.last_config.save(&body),
+        };
+        if let Err(e) = saved {
+            warning!("TLS relay couldn't save the config for when the server is down: {:#}", e);
+        }
+        let snapshot = self.status.snapshot();
+        if snapshot.cached_config_from.is_some() || snapshot.builtin_config {
+            info!("osqueryd has the server's config again");
+            self.status.update(|status| {
+                status.cached_config_from = None;
+                status.builtin_config = false;
+            });
+        }
+        Response::from_parts(parts, Full::new(body))
+    }
+
+    /// Add what the agent has locally (`what`) to a config from the server
+    async fn extend_config(
+        &self,
+        what: &str,
+        extend: impl Fn(&[u8]) -> anyhow::Result<Vec<u8>>,
+        response: Response<Full<Bytes>>,
+    ) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        if !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let extended = match parts.headers.contains_key(header::CONTENT_ENCODING) {
+            true => Err(anyhow::anyhow!("Config is compressed")),
+            false => extend(&body),
+        };
+        let body = match extended {
+            Ok(extended) => Bytes::from(extended),
+            Err(e) => {
+                // The server's config is still good on its own
+                warning!("TLS relay couldn't add {} to the config: {:#}", what, e);
+                body
+            }
+        };
+        let mut parts = parts;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Full::new(body))
+    }
+
+    /// Remember the rows of a logger request once the server (or the spool)
+    /// has taken it, so a request osqueryd retries isn't dropped
+    fn remember(&self, pending: Option<Pending>, response: &Response<Full<Bytes>>) {
+        if let (Some(dedup), Some(pending)) = (&self.dedup, pending) {
+            if response.status().is_success() {
+                dedup.commit(pending);
+            }
+        }
+    }
+
+    /// Note which tables the queries in a config from the server read, for
+    /// rate limits by table
+    async fn learn(&self, limiter: &Limiter, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        if parts.status.is_success() && !parts.headers.contains_key(header::CONTENT_ENCODING) {
+            if let Err(e) = limiter.learn_config(&body) {
+                warning!("TLS relay couldn't read the config for rate limits by table: {:#}", e);
+            }
+        }
+        Response::from_parts(parts, Full::new(body))
+    }
+
+    /// Note which queries in a config from the server read an event table,
+    /// whose results aren't deduplicated
+    async fn learn_events(&self, dedup: &Dedup, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        if parts.status.is_success() && !parts.headers.contains_key(header::CONTENT_ENCODING) {
+            if let Err(e) = dedup.learn_config(&body) {
+                warning!("TLS relay couldn't read the config for deduplicating results: {:#}", e);
+            }
+        }
+        Response::from_parts(parts, Full::new(body))
+    }
+
+    /// Take denied queries out of a config or live queries from the server,
+    /// reporting what was blocked
+    async fn enforce(
+        &self,
+        denylist: &Denylist,
+        endpoint: &str,
+        response: Response<Full<Bytes>>,
+    ) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        if !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let live = endpoint != CONFIG_ENDPOINT;
+        let filtered = match (parts.headers.contains_key(header::CONTENT_ENCODING), live) {
+            (true, _) => Err(anyhow::anyhow!("Reply is compressed")),
+            (false, false) => denylist.filter_config(&body),
+            (false, true) => denylist.filter_live(&body),
+        };
+        let (body, blocked) = match filtered {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                // osqueryd keeps what it has until the next try
+                error!("TLS relay couldn't apply the denylist to {}, so it wasn't passed on: {:#}", endpoint, e);
+                return reply(StatusCode::BAD_GATEWAY, "Couldn't apply the denylist");
+            }
+        };
+
+        // Scheduled queries are reported when the set changes, live ones each time
+        let report = if live {
+            !blocked.is_empty()
+        } else {
+            let names: BTreeSet<String> = blocked.iter().map(|b| b.name.clone()).collect();
+            let mut previous = self.blocked.lock().unwrap();
+            let changed = *previous != names;
+            *previous = names;
+            self.status.update(|status| status.blocked_queries = previous.iter().cloned().collect());
+            changed
+        };
+        if report {
+            self.report_blocked(blocked);
+        }
+
+        let mut parts = parts;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Full::new(Bytes::from(body)))
+    }
+
+    /// Keep a carve's key for its blocks once the server has started it. If
+    /// the reply can't be read, osqueryd is told the carve failed, as its
+    /// blocks couldn't be encrypted.
+    async fn start_sealing(&self, sealing: Sealing, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        if !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let started = match parts.headers.contains_key(header::CONTENT_ENCODING) {
+            true => Err(anyhow::anyhow!("Reply is compressed")),
+            false => self.carver.started(sealing, &body),
+        };
+        if let Err(e) = started {
+            error!("TLS relay couldn't read the server's reply to a file carve, so it can't be encrypted: {:#}", e);
+            return reply(StatusCode::BAD_GATEWAY, "Couldn't encrypt the carve");
+        }
+        Response::from_parts(parts, Full::new(body))
+    }
+
+    /// Keep the carver's options out of a config, and live queries that carve
+    /// what they may not from osqueryd, reporting what was blocked
+    async fn guard_carves(&self, endpoint: &str, response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let (parts, body) = response.into_parts();
+        if !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+        let body = body.collect().await.map(|body| body.to_bytes()).unwrap_or_default();
+        let filtered = match (parts.headers.contains_key(header::CONTENT_ENCODING), endpoint) {
+            (true, _) => Err(anyhow::anyhow!("Reply is compressed")),
+            (false, CONFIG_ENDPOINT) => self.carver.filter_config(&body).map(|config| (config, Vec::new())),
+            (false, _) => self.carver.filter_live(&body),
+        };
+        let (body, blocked) = match filtered {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                error!("TLS relay couldn't check {} for file carves, so it wasn't passed on: {:#}", endpoint, e);
+                return reply(StatusCode::BAD_GATEWAY, "Couldn't check for file carves");
+            }
+        };
+        if !blocked.is_empty() {
+            self.report_blocked(blocked);
+        }
+        let mut parts = parts;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Response::from_parts(parts, Full::new(Bytes::from(body)))
+    }
+
+    /// Log queries kept from osqueryd and report them to the server
+    fn report_blocked(&self, blocked: Vec<Blocked>) {
+        for query in &blocked {
+            info!("Blocked {} query {} ({})", if query.live { "live" } else { "scheduled" }, query.name, query.reason);
+        }
+        let api = self.api.clone();
+        let report = serde_json::json!({
+            "host_id": api.host_id(),
+            "sent_at": chrono::Utc::now(),
+            "blocked": blocked,
+        });
+        tokio::spawn(async move {
+            if let Err(e) = api.post(denylist::REPORT_PATH, &report).await {
+                error!("Failed to report blocked queries: {:#}", e);
+            }
+        });
+    }
+
+    /// Send a request on to the server, or `server` if it's routed elsewhere,
+    /// with the relay's headers, returning its reply, or None if it couldn't
+    /// be reached
+    async fn send(
+        &self,
+        method: Method,
+        server: Option<&str>,
+        path_and_query: &str,
+        mut headers: HeaderMap,
+        body: Bytes,
+    ) -> Option<Response<Full<Bytes>>> {
+        let path = path_and_query.split('?').next().unwrap_or_default();
+        for (name, value) in &self.headers {
+            headers.insert(name.clone(), value.clone());
+        }
+
+        let bytes = body.len() as u64;
+        let bandwidth = match endpoint(path) {
+            LOG_ENDPOINT | WRITE_ENDPOINT => self.result_bandwidth.as_ref().map(|b| (b, "results")),
+            endpoint if endpoint.starts_with(CARVE_PREFIX) => self.carve_bandwidth.as_ref().map(|b| (b, "carves")),
+            _ => None,
+        };
+        if let Some((bucket, category)) = bandwidth {
+            bucket.acquire(category, bytes).await;
+        }
+        // Which live queries osqueryd answered, for their timings
+        let written = (endpoint(path) == WRITE_ENDPOINT && !headers.contains_key(header::CONTENT_ENCODING))
+            .then(|| body.clone());
+        let started = Instant::now();
+        let response = self
+            .api
+            .client()
+            .request(method, match server {
+                Some(server) => format!("https://{}{}", server, path_and_query),
+                None => self.api.url(path_and_query),
+            })
+            .headers(headers)
+            .body(body)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                telemetry::record_relay(endpoint(path), "error", started.elapsed(), bytes);
+                error!("TLS relay couldn't reach the server for {}: {:#}", path, e);
+                return None;
+            }
+        };
+        let status = response.status();
+        let mut headers = response.headers().clone();
+        let body = match response.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                telemetry::record_relay(endpoint(path), "error", started.elapsed(), bytes);
+                error!("TLS relay lost the server's reply to {}: {:#}", path, e);
+                return None;
+            }
+        };
+        telemetry::record_relay(endpoint(path), status.as_str(), started.elapsed(), bytes);
+        if status.is_success() {
+            match (endpoint(path), &written) {
+                (READ_ENDPOINT, _) if !headers.contains_key(header::CONTENT_ENCODING) => {
+                    distributed::read(&body, started.elapsed())
+                }
+                (WRITE_ENDPOINT, Some(written)) => distributed::written(written, started.elapsed()),
+                _ => {}
+            }
+        }
+
+        for name in HOP_BY_HOP {
+            headers.remove(name);
+        }
+        let mut reply = reply(status, body);
+        *reply.headers_mut() = headers;
+        Some(reply)
+    }
+
+    /// Send a logger request, or spool it if the server can't take it or
+    /// earlier ones are still spooled
+    async fn spool_or_send(
+        &self,
+        spool: &Spool,
+        path_and_query: String,
+        server: Option<String>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response<Full<Bytes>> {
+        let empty = spool.is_empty();
+        if empty {
+            let sent = self
+                .send(Method::POST, server.as_deref(), &path_and_query, headers.clone(), body.clone())
+                .await;
+            match sent {
+                Some(response) if !response.status().is_server_error() => return response,
+                _ => {}
+            }
+        }
+        if let Err(e) = spool.push(&batch(path_and_query, server, &headers, &body)) {
+            // osqueryd keeps the logs buffered itself
+            error!("Failed to spool osqueryd's logs: {:#}", e);
+            return reply(StatusCode::BAD_GATEWAY, "Couldn't reach the server");
+        }
+        if empty {
+            info!("The server can't take osqueryd's logs, so they're being spooled until it can");
+        }
+        self.show_spool(spool);
+        reply(StatusCode::OK, "{}")
+    }
+
+    /// Send live query results, or spool them to retry if the server can't
+    /// take them. Each query's results stand alone, so they don't wait behind
+    /// earlier ones.
+    async fn send_or_keep(
+        &self,
+        spool: &Spool,
+        path_and_query: String,
+        server: Option<String>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Response<Full<Bytes>> {
+        let sent = self
+            .send(Method::POST, server.as_deref(), &path_and_query, headers.clone(), body.clone())
+            .await;
+        match sent {
+            Some(response) if !response.status().is_server_error() => {
+                if response.status().is_success() && !spool.is_empty() {
+                    self.live_retry.notify_one();
+                }
+                return response;
+            }
+            _ => {}
+        }
+        let empty = spool.is_empty();
+        if let Err(e) = spool.push(&batch(path_and_query, server, &headers, &body)) {
+            // osqueryd retries a few times itself
+            error!("Failed to spool osqueryd's live query results: {:#}", e);
+            return reply(StatusCode::BAD_GATEWAY, "Couldn't reach the server");
+        }
+        if empty {
+            info!("The server can't take osqueryd's live query results, so they're spooled to retry");
+        }
+        self.show_live_spool(spool);
+        reply(StatusCode::OK, "{}")
+    }
+
+    fn show_spool(&self, spool: &Spool) {
+        let (batches, bytes) = spool.usage();
+        self.status.update(|status| {
+            status.spooled_log_batches = batches as u64;
+            status.spooled_log_bytes = bytes;
+        });
+    }
+
+    fn show_live_spool(&self, spool: &Spool) {
+        let (batches, bytes) = spool.usage();
+        self.status.update(|status| {
+            status.spooled_live_batches = batches as u64;
+            status.spooled_live_bytes = bytes;
+        });
+    }
+}
+
+/// A request to spool, with the headers osqueryd sent
+fn batch(path_and_query: String, server: Option<String>, headers: &HeaderMap, body: &[u8]) -> Batch {
+    Batch {
+        path_and_query,
+        server,
+        headers: headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        body: body.to_vec(),
+    }
+}
+
+/// Summarize results dropped by rate limits to the server every window
+async fn report_dropped(forwarder: Arc<Forwarder>) {
+    let Some(limiter) = &forwarder.limiter else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(ratelimit::WINDOW);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let dropped = limiter.take_dropped();
+        if dropped.is_empty() {
+            continue;
+        }
+        for query in &dropped {
+            info!(
+                "Dropped {} results of {} over its rate limit and {} by sampling",
+                query.limited, query.name, query.sampled
+            );
+        }
+        let report = serde_json::json!({
+            "host_id": forwarder.api.host_id(),
+            "sent_at": chrono::Utc::now(),
+            "window_seconds": ratelimit::WINDOW.as_secs(),
+            "dropped": dropped,
+        });
+        if let Err(e) = forwarder.api.post(DROPPED_PATH, &report).await {
+            error!("Failed to report results dropped by rate limits: {:#}", e);
+        }
+    }
+}
+
+/// Save the deduplication cache whenever it's changed
+async fn save_dedup(forwarder: Arc<Forwarder>) {
+    let Some(dedup) = &forwarder.dedup else {
+        return;
+    };
+    let mut ticker = tokio::time::interval(DEDUP_SAVE_INTERVAL);
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        match dedup.save() {
+            Ok(()) => failing = false,
+            Err(e) if !failing => {
+                error!("Failed to save the result deduplication cache: {:#}", e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Send spooled logger requests to the server in order, whenever it's reachable
+async fn replay(forwarder: Arc<Forwarder>, spool: Arc<Spool>) {
+    let mut ticker = tokio::time::interval(REPLAY_INTERVAL);
+    loop {
+        ticker.tick().await;
+        drain(&forwarder, &spool, Forwarder::show_spool, None).await;
+    }
+}
+
+/// Send spooled live query results to the server, backing off while it can't
+/// take them, and trying again straight away once it takes new ones
+async fn retry_live(forwarder: Arc<Forwarder>, spool: Arc<Spool>) {
+    let mut delay = LIVE_RETRY_DELAY;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = forwarder.live_retry.notified() => {}
+        }
+        delay = match drain(&forwarder, &spool, Forwarder::show_live_spool, None).await.reachable {
+            true => LIVE_RETRY_DELAY,
+            false => (delay * 2).min(MAX_LIVE_RETRY_DELAY),
+        };
+    }
+}
+
+/// What sending a spool got through
+struct Drained {
+    sent: usize,
+    rejected: usize,
+    /// False if the server couldn't take a batch
+    reachable: bool,
+}
+
+/// Send a spool's batches in order until it's empty, the server can't take
+/// one, or `deadline` passes
+async fn drain(
+    forwarder: &Forwarder,
+    spool: &Spool,
+    show: fn(&Forwarder, &Spool),
+    deadline: Option<Instant>,
+) -> Drained {
+    let _draining = forwarder.draining.lock().await;
+    let mut drained = Drained {
+        sent: 0,
+        rejected: 0,
+        reachable: true,
+    };
+    while let Some((seq, batch)) = spool.front() {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            break;
+        }
+        let headers: HeaderMap = batch
+            .headers
+            .iter()
+            .filter_map(|(name, value)| {
+                Some((HeaderName::try_from(name).ok()?, HeaderValue::try_from(value).ok()?))
+            })
+            .collect();
+        let response = forwarder
+            .send(Method::POST, batch.server.as_deref(), &batch.path_and_query, headers, batch.body.into())
+            .await;
+        match response.map(|response| response.status()) {
+            Some(status) if status.is_success() => drained.sent += 1,
+            // A batch the server won't ever take can't hold up the rest
+            Some(status) if status.is_client_error() => {
+                warning!(
+                    "The server rejected a spooled {} batch ({}), so it was dropped",
+                    spool.what(),
+                    status
+                );
+                drained.rejected += 1;
+            }
+            _ => {
+                drained.reachable = false;
+                return drained;
+            }
+        }
+        spool.remove(seq);
+        show(forwarder, spool);
+    }
+    if drained.sent > 0 && spool.is_empty() {
+        info!("Sent {} spooled {} batches, the {} spool is empty", drained.sent, spool.what(), spool.what());
+    }
+    drained
+}
@@ -0,0 +1,341 @@
+//! Remote commands
+//!
+//! The server can ask the agent to restart osqueryd, upgrade osquery to a
+//! version, fetch its config again, upload a support bundle, or pause and
+//! resume collection, by answering a heartbeat (or a live channel poll) with
+//! commands signed by its Ed25519 key, e.g.
+//! `{"commands": [{"payload": "<base64 JSON>", "signature": "<base64>"}]}`.
+//! The agent only runs commands it can verify with `--command-key`, addressed
+//! to this host, not expired, not seen before, and of a kind allowed locally
+//! with `--allow-remote-command`; a compromised server or a replayed reply
+//! can't do more than the host's own policy lets it. Each command goes through
+//! the same handlers as `shadow pause`, `shadow reload` and the rest, is
+//! recorded in the audit log, and its outcome is reported back to the server.
+//! Upgrades only go to the osquery the agent ships with, or a version allowed
+//! with its hash by `--allow-osquery-version`.
+
+use crate::api::ApiClient;
+use crate::audit;
+use crate::ipc::{self, Agent};
+use crate::logging::{debug, error, info, warning};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::mpsc;
+
+/// Where outcomes go
+const RESULTS_PATH: &str = "/api/shadow/commands/results";
+
+/// IDs of commands already carried out, with when they expire
+const SEEN_FILE: &str = "remote_commands.json";
+
+/// Where a record of IDs that can't be read is moved. Its modified time bounds
+/// when the commands it held were carried out.
+const LOST_FILE: &str = "remote_commands.json.corrupt";
+
+/// Furthest ahead a command may expire, which bounds how long its ID is kept
+const MAX_LIFETIME: chrono::Duration = chrono::Duration::hours(24);
+
+/// An Ed25519 public key the server signs with, given as 32 bytes in base64
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerKey(Vec<u8>);
+
+impl ServerKey {
+    /// Whether `signature` is this key's for `data`
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(&ED25519, &self.0).verify(data, signature).is_ok()
+    }
+}
+
+impl FromStr for ServerKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = BASE64
+            .decode(s.trim())
+            .map_err(|e| format!("expected a base64 Ed25519 public key: {}", e))?;
+        if key.len() != 32 {
+            return Err(format!("expected a 32-byte Ed25519 public key, got {} bytes", key.len()));
+        }
+        Ok(ServerKey(key))
+    }
+}
+
+/// A kind of command, for `--allow-remote-command`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    RestartOsqueryd,
+    UpgradeOsquery,
+    RefreshConfig,
+    SupportBundle,
+    Pause,
+    Resume,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A command as the server sends it
+#[derive(Debug, Clone, Deserialize)]
+pub struct SignedCommand {
+    /// The command's JSON in base64, exactly as signed
+    pub payload: String,
+    /// Ed25519 signature of the payload's bytes, in base64
+    pub signature: String,
+}
+
+/// What the server signs
+#[derive(Debug, Deserialize)]
+struct Command {
+    id: String,
+    host_id: String,
+    expires_at: DateTime<Utc>,
+    /// Who at the server asked for it, for the audit log
+    issued_by: Option<String>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Action {
+    RestartOsqueryd,
+    /// Install this osquery version, if it isn't already, and restart
+    /// osqueryd
+    UpgradeOsquery {
+        version: String,
+    },
+    RefreshConfig,
+    SupportBundle,
+    Pause {
+        reason: Option<String>,
+        minutes: Option<u64>,
+    },
+    Resume,
+}
+
+impl Action {
+    fn kind(&self) -> Kind {
+        match self {
+            Action::RestartOsqueryd => Kind::RestartOsqueryd,
+            Action::UpgradeOsquery { .. } => Kind::UpgradeOsquery,
+            Action::RefreshConfig => Kind::RefreshConfig,
+            Action::SupportBundle => Kind::SupportBundle,
+            Action::Pause { .. } => Kind::Pause,
+            Action::Resume => Kind::Resume,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Done,
+    Failed,
+    Refused,
+}
+
+/// Which commands the agent accepts
+pub struct Policy {
+    pub key: ServerKey,
+    pub allowed: Vec<Kind>,
+    /// osquery versions the server may upgrade to
+    pub osquery_versions: Vec<String>,
+}
+
+/// Carry out commands until the agent exits, returning where to send them
+pub fn spawn(api: ApiClient, agent: Agent, policy: Policy) -> mpsc::Sender<SignedCommand> {
+    if policy.allowed.is_empty() {
+        warning!("--command-key is set but no --allow-remote-command, so every command from the server is refused");
+    }
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(run(api, agent, Arc::new(policy), rx));
+    tx
+}
+
+/// Hand commands from a server reply to `commands`, or drop them if the agent
+/// doesn't take commands
+pub async fn pass_on(commands: Option<&mpsc::Sender<SignedCommand>>, received: Vec<SignedCommand>) {
+    if received.is_empty() {
+        return;
+    }
+    let Some(commands) = commands else {
+        debug!("Ignoring {} commands from the server, as --command-key isn't set", received.len());
+        return;
+    };
+    for command in received {
+        let _ = commands.send(command).await;
+    }
+}
+
+async fn run(api: ApiClient, agent: Agent, policy: Arc<Policy>, mut rx: mpsc::Receiver<SignedCommand>) {
+    // One at a time, so a restart and a pause don't race
+    while let Some(signed) = rx.recv().await {
+        let command = match verify(&policy.key, &signed) {
+            Ok(command) => command,
+            Err(e) => {
+                error!("Ignoring a command from the server: {:#}", e);
+                continue;
+            }
+        };
+        let kind = command.action.kind();
+        let (outcome, message) = match check(&api, &agent.data_dir, &policy, &command).await {
+            Ok(()) => {
+                info!("Running {} command {} from the server", kind, command.id);
+                match carry_out(&agent, &command).await {
+                    Ok(message) => (Outcome::Done, message),
+                    Err(message) => (Outcome::Failed, message),
+                }
+            }
+            Err(message) => {
+                warning!("Refused command {} from the server: {}", command.id, message);
+                (Outcome::Refused, message)
+            }
+        };
+        let version = match &command.action {
+            Action::UpgradeOsquery { version } => Some(version),
+            _ => None,
+        };
+        let detail = serde_json::json!({
+            "id": command.id,
+            "command": kind,
+            "version": version,
+            "issued_by": command.issued_by,
+            "outcome": outcome,
+            "message": message,
+        });
+        audit::record_agent(&agent.data_dir, "remote_command", detail.clone()).await;
+        let mut report = detail;
+        report["host_id"] = serde_json::json!(api.host_id());
+        report["finished_at"] = serde_json::json!(Utc::now());
+        if let Err(e) = api.post(RESULTS_PATH, &report).await {
+            error!("Failed to report the outcome of command {}: {:#}", command.id, e);
+        }
+    }
+}
+
+/// The command, if the server's key signed it
+fn verify(key: &ServerKey, signed: &SignedCommand) -> Result<Command> {
+    let payload = BASE64.decode(&signed.payload).context("Payload isn't base64")?;
+    let signature = BASE64.decode(&signed.signature).context("Signature isn't base64")?;
+    if !key.verify(&payload, &signature) {
+        anyhow::bail!("Signature doesn't match --command-key");
+    }
+    serde_json::from_slice(&payload).context("Invalid command")
+}
+
+/// Whether a verified command may run here, recording its ID if so
+async fn check(api: &ApiClient, data_dir: &Path, policy: &Policy, command: &Command) -> Result<(), String> {
+    let now = Utc::now();
+    if command.host_id != api.host_id() {
+        return Err(format!("it's for host {}", command.host_id));
+    }
+    if command.expires_at <= now {
+        return Err(format!("it expired at {}", command.expires_at.to_rfc3339()));
+    }
+    if command.expires_at > now + MAX_LIFETIME {
+        return Err(format!("it expires more than {}h ahead", MAX_LIFETIME.num_hours()));
+    }
+    let kind = command.action.kind();
+    if !policy.allowed.contains(&kind) {
+        return Err(format!("{} isn't allowed by --allow-remote-command", kind));
+    }
+    if let Action::UpgradeOsquery { version } = &command.action {
+        if !policy.osquery_versions.contains(version) {
+            return Err(format!(
+                "osquery {} isn't the version this shadow ships with or allowed by --allow-osquery-version",
+                version
+            ));
+        }
+    }
+    remember(data_dir, &command.id, command.expires_at).await
+}
+
+/// Record a command's ID, failing if it was seen before or might have been
+async fn remember(data_dir: &Path, id: &str, expires_at: DateTime<Utc>) -> Result<(), String> {
+    let path = data_dir.join(SEEN_FILE);
+    let lost = data_dir.join(LOST_FILE);
+    let mut seen: BTreeMap<String, DateTime<Utc>> = match fs::read(&path).await {
+        Ok(data) => match serde_json::from_slice(&data) {
+            Ok(seen) => seen,
+            Err(e) => {
+                error!(
+                    "{} is corrupt ({}), so it's moved to {} and commands it may have held are refused until they expire",
+                    path.display(),
+                    e,
+                    lost.display()
+                );
+                fs::rename(&path, &lost)
+                    .await
+                    .map_err(|e| format!("couldn't move the corrupt {} aside: {}", path.display(), e))?;
+                BTreeMap::new()
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(format!("couldn't read {}: {}", path.display(), e)),
+    };
+    let now = Utc::now();
+    // A command the lost record held was carried out before it was last
+    // written, so it expires within MAX_LIFETIME of that
+    if let Ok(meta) = fs::metadata(&lost).await {
+        let written = meta.modified().map(DateTime::<Utc>::from).unwrap_or(now);
+        let unsure_until = written + MAX_LIFETIME;
+        if now >= unsure_until {
+            let _ = fs::remove_file(&lost).await;
+        } else if expires_at <= unsure_until {
+            return Err(format!(
+                "the record of commands carried out was lost, and one expiring before {} may have been",
+                unsure_until.to_rfc3339()
+            ));
+        }
+    }
+    seen.retain(|_, expires_at| *expires_at > now);
+    if seen.contains_key(id) {
+        return Err("it was already carried out".to_string());
+    }
+    seen.insert(id.to_string(), expires_at);
+    // Without the record a replay can't be told apart, so don't run it
+    let data = serde_json::to_vec(&seen).map_err(|e| e.to_string())?;
+    fs::write(&path, data)
+        .await
+        .map_err(|e| format!("couldn't record it in {}: {}", path.display(), e))
+}
+
+async fn carry_out(agent: &Agent, command: &Command) -> Result<String, String> {
+    let user = match &command.issued_by {
+        Some(issued_by) => format!("server command {} ({})", command.id, issued_by),
+        None => format!("server command {}", command.id),
+    };
+    let request = match &command.action {
+        Action::RestartOsqueryd | Action::RefreshConfig => ipc::Request::Reload { user },
+        Action::UpgradeOsquery { version } => ipc::Request::UpgradeOsquery {
+            user,
+            version: Some(version.clone()),
+        },
+        Action::SupportBundle => ipc::Request::UploadSupportBundle { user },
+        Action::Pause { reason, minutes } => ipc::Request::Pause {
+            user,
+            reason: reason.clone(),
+            minutes: *minutes,
+        },
+        Action::Resume => ipc::Request::Resume { user },
+    };
+    ipc::handle(agent, request).await.message().map_err(|e| format!("{:#}", e))
+}
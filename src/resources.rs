@@ -0,0 +1,110 @@
+//! Process resource sampling
+//!
+//! Samples CPU, memory, and open file usage of shadow itself and the osqueryd
+//! child for reporting in heartbeats. osqueryd runs as a watcher with a worker
+//! (and any extensions) under it, and the worker does the actual work, so the
+//! child is sampled together with its descendants.
+
+use serde::Serialize;
+use std::path::Path;
+use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+
+/// Resource usage of a process, or of a process tree
+#[derive(Debug, Clone, Serialize)]
+pub struct ResourceUsage {
+    /// Resident set size in bytes
+    pub rss_bytes: u64,
+    /// CPU usage since the previous sample, in percent of one core
+    pub cpu_percent: f32,
+    /// Total CPU time consumed, in milliseconds
+    pub cpu_time_ms: u64,
+    /// Open file descriptors, where the OS reports them (Linux)
+    pub open_fds: Option<u64>,
+    /// Processes sampled: 1, or the process and its descendants
+    pub processes: usize,
+}
+
+/// Keeps process state between samples so CPU usage can be computed as a delta
+pub struct ResourceSampler {
+    system: System,
+}
+
+impl ResourceSampler {
+    pub fn new() -> Self {
+        Self {
+            system: System::new(),
+        }
+    }
+
+    /// Sample a process by PID, returning None if it no longer exists
+    pub fn sample(&mut self, pid: u32) -> Option<ResourceUsage> {
+        let pid = Pid::from_u32(pid);
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::Some(&[pid]),
+            true,
+            ProcessRefreshKind::nothing().with_cpu().with_memory(),
+        );
+        self.usage(&[pid])
+    }
+
+    /// Sample a process and all its descendants together, returning None if
+    /// it no longer exists
+    pub fn sample_tree(&mut self, pid: u32) -> Option<ResourceUsage> {
+        let root = Pid::from_u32(pid);
+        self.system.refresh_processes_specifics(
+            ProcessesToUpdate::All,
+            true,
+            ProcessRefreshKind::nothing().with_cpu().with_memory(),
+        );
+        self.system.process(root)?;
+
+        let mut tree = vec![root];
+        let mut index = 0;
+        while let Some(&parent) = tree.get(index) {
+            tree.extend(
+                self.system
+                    .processes()
+                    .iter()
+                    .filter(|(_, process)| process.parent() == Some(parent) && process.thread_kind().is_none())
+                    .map(|(&pid, _)| pid),
+            );
+            index += 1;
+        }
+        self.usage(&tree)
+    }
+
+    fn usage(&self, pids: &[Pid]) -> Option<ResourceUsage> {
+        let processes: Vec<_> = pids.iter().filter_map(|pid| self.system.process(*pid)).collect();
+        if processes.is_empty() {
+            return None;
+        }
+        let open_fds = processes
+            .iter()
+            .map(|process| process.open_files().map(|n| n as u64))
+            .sum();
+        Some(ResourceUsage {
+            rss_bytes: processes.iter().map(|process| process.memory()).sum(),
+            cpu_percent: processes.iter().map(|process| process.cpu_usage()).sum(),
+            cpu_time_ms: processes.iter().map(|process| process.accumulated_cpu_time()).sum(),
+            open_fds,
+            processes: processes.len(),
+        })
+    }
+}
+
+/// Total size of the files under a directory, e.g. osquery's RocksDB
+/// database, or None if it doesn't exist
+pub fn dir_size(path: &Path) -> Option<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(path).ok()?.flatten() {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            total += dir_size(&entry.path()).unwrap_or(0);
+        } else {
+            total += metadata.len();
+        }
+    }
+    Some(total)
+}
@@ -0,0 +1,166 @@
+//! Data routing
+//!
+//! Multinational deployments may have to keep a host's data in its region, or
+//! send some kinds of data to their own collectors. With the TLS relay, the
+//! agent can send each category of osqueryd's data (result logs, status logs,
+//! live query results, and file carves) to its own server with
+//! `--data-endpoint`, and with `--region-server` send all of it to the server
+//! for the region named by one of the host's tags, so one agent build and
+//! config serves every region. Enrollment, configs, and the agent's own
+//! reports still go to `--server`.
+
+use crate::logging::{info, warning};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// A kind of data osqueryd sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Category {
+    Result,
+    Status,
+    Live,
+    Carve,
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "result" => Ok(Category::Result),
+            "status" => Ok(Category::Status),
+            "live" => Ok(Category::Live),
+            "carve" => Ok(Category::Carve),
+            _ => Err(format!("unknown data category '{}' (result, status, live, carve)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Category::Result => "result",
+            Category::Status => "status",
+            Category::Live => "live",
+            Category::Carve => "carve",
+        })
+    }
+}
+
+/// A server given as host[:port], like `--server`
+fn parse_server(s: &str) -> Result<String, String> {
+    let server = s.trim().trim_start_matches("https://").trim_end_matches('/');
+    if server.is_empty() || server.contains('/') {
+        return Err(format!("expected host[:port], got '{}'", s));
+    }
+    Ok(server.to_string())
+}
+
+/// The server for one category of data, as CATEGORY=HOST[:PORT]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataEndpoint {
+    pub category: Category,
+    pub server: String,
+}
+
+impl FromStr for DataEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (category, server) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected CATEGORY=HOST[:PORT], got '{}'", s))?;
+        Ok(DataEndpoint {
+            category: category.parse()?,
+            server: parse_server(server)?,
+        })
+    }
+}
+
+/// The server for one region's data, as REGION=HOST[:PORT]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionServer {
+    pub region: String,
+    pub server: String,
+}
+
+impl FromStr for RegionServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (region, server) = s
+            .split_once('=')
+            .filter(|(region, _)| !region.trim().is_empty())
+            .ok_or_else(|| format!("expected REGION=HOST[:PORT], got '{}'", s))?;
+        Ok(RegionServer {
+            region: region.trim().to_string(),
+            server: parse_server(server)?,
+        })
+    }
+}
+
+/// Where each category of data goes, if not to `--server`
+#[derive(Debug, Default)]
+pub struct Routes {
+    servers: BTreeMap<Category, String>,
+}
+
+impl Routes {
+    /// Pick servers for this host: its region's, by its `region_tag` tag, for
+    /// everything, except categories given their own
+    pub fn new(
+        endpoints: &[DataEndpoint],
+        regions: &[RegionServer],
+        region_tag: &str,
+        tags: &BTreeMap<String, String>,
+    ) -> Self {
+        let mut servers = BTreeMap::new();
+        if !regions.is_empty() {
+            let region = tags.get(region_tag);
+            match region.and_then(|region| regions.iter().find(|r| r.region == *region)) {
+                Some(regional) => {
+                    info!("Sending osqueryd's data to {} for region {}", regional.server, regional.region);
+                    for category in [Category::Result, Category::Status, Category::Live, Category::Carve] {
+                        servers.insert(category, regional.server.clone());
+                    }
+                }
+                None => warning!(
+                    "No --region-server for this host's region ({}), so its data goes to --server. Set the host's '{}' tag with --tag {}=REGION.",
+                    region.map_or("no tag", String::as_str),
+                    region_tag,
+                    region_tag
+                ),
+            }
+        }
+        for endpoint in endpoints {
+            info!("Sending osqueryd's {} data to {}", endpoint.category, endpoint.server);
+            servers.insert(endpoint.category, endpoint.server.clone());
+        }
+        Routes { servers }
+    }
+
+    /// The server for a request to an osquery endpoint (e.g. `log`), if not
+    /// `--server`
+    pub fn server(&self, endpoint: &str, body: &[u8]) -> Option<&str> {
+        if self.servers.is_empty() {
+            return None;
+        }
+        let category = match endpoint {
+            "log" => {
+                // Status and result logs share osqueryd's logger endpoint
+                let request: Value = serde_json::from_slice(body).ok()?;
+                match request.get("log_type").and_then(Value::as_str)? {
+                    "result" => Category::Result,
+                    "status" => Category::Status,
+                    _ => return None,
+                }
+            }
+            "distributed/write" => Category::Live,
+            _ if endpoint.starts_with("carve/") => Category::Carve,
+            _ => return None,
+        };
+        self.servers.get(&category).map(String::as_str)
+    }
+}
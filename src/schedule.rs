@@ -0,0 +1,305 @@
+//! Scheduled query performance reports
+//!
+//! osqueryd keeps statistics on each query in its schedule: how often it ran,
+//! the wall and CPU time and memory it took, how much it output, and whether
+//! the watchdog denylisted it for using too much. They're in the
+//! `osquery_schedule` table, which only the running osqueryd can answer, so
+//! the agent queries it through the extensions socket and reports per-query
+//! averages to the server on an interval, for tuning packs on real numbers.
+//!
+//! The same table is checked every few minutes for slow queries: any whose
+//! runs since the last check averaged more wall or CPU time than the
+//! configured thresholds are logged, shown in `shadow status`, and reported to
+//! the server right away, so one pathological pack entry can be pulled before
+//! it burns CPU across the fleet. A query is reported again only after it has
+//! run under the thresholds in between.
+
+use crate::api::ApiClient;
+use crate::extensions::{self, Row};
+use crate::logging::{error, warning};
+use crate::status::SharedStatus;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often queries are checked against the slow query thresholds
+const SLOW_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Serialize)]
+struct ScheduleReport {
+    host_id: String,
+    sent_at: DateTime<Utc>,
+    osquery_version: Option<String>,
+    queries: Vec<QueryStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct QueryStats {
+    name: String,
+    interval_secs: u64,
+    executions: u64,
+    last_executed: Option<DateTime<Utc>>,
+    denylisted: bool,
+    /// Averages per execution, None until the query has run
+    avg_wall_time_ms: Option<f64>,
+    avg_user_time_ms: Option<f64>,
+    avg_system_time_ms: Option<f64>,
+    avg_output_bytes: Option<f64>,
+    /// Average memory osqueryd's worker used while running it
+    avg_memory_bytes: u64,
+    output_bytes: u64,
+}
+
+fn number(row: &Row, column: &str) -> u64 {
+    row.get(column)
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+/// Total wall time a query has taken, in milliseconds
+fn wall_time_ms(row: &Row) -> u64 {
+    // Older osquery only has wall_time, in seconds
+    match row.get("wall_time_ms") {
+        Some(_) => number(row, "wall_time_ms"),
+        None => number(row, "wall_time") * 1000,
+    }
+}
+
+impl QueryStats {
+    fn from_row(row: &Row) -> Self {
+        let number = |column: &str| number(row, column);
+        let executions = number("executions");
+        let average = |total: u64| (executions > 0).then(|| total as f64 / executions as f64);
+        let wall_time_ms = wall_time_ms(row);
+        let output_bytes = number("output_size");
+        QueryStats {
+            name: row.get("name").cloned().unwrap_or_default(),
+            interval_secs: number("interval"),
+            executions,
+            last_executed: DateTime::from_timestamp(number("last_executed") as i64, 0)
+                .filter(|_| executions > 0),
+            // Called blacklisted in older osquery
+            denylisted: ["denylisted", "blacklisted"]
+                .iter()
+                .any(|column| row.get(*column).is_some_and(|v| v == "1")),
+            avg_wall_time_ms: average(wall_time_ms),
+            avg_user_time_ms: average(number("user_time")),
+            avg_system_time_ms: average(number("system_time")),
+            avg_output_bytes: average(output_bytes),
+            avg_memory_bytes: number("average_memory"),
+            output_bytes,
+        }
+    }
+}
+
+/// Send a report, returning the denylisted queries
+async fn report(api: &ApiClient, status: &SharedStatus, socket: &Path) -> Result<BTreeSet<String>> {
+    let rows = extensions::query(socket, "SELECT * FROM osquery_schedule;").await?;
+    let queries: Vec<QueryStats> = rows.iter().map(QueryStats::from_row).collect();
+    let denylisted = queries
+        .iter()
+        .filter(|query| query.denylisted)
+        .map(|query| query.name.clone())
+        .collect();
+
+    let report = ScheduleReport {
+        host_id: api.host_id().to_string(),
+        sent_at: Utc::now(),
+        osquery_version: status.snapshot().osquery_version,
+        queries,
+    };
+    api.post("/api/shadow/schedule", &report).await?;
+    Ok(denylisted)
+}
+
+/// Report schedule statistics every `interval` while osqueryd runs
+pub async fn run(api: ApiClient, status: SharedStatus, socket: PathBuf, interval: Duration) {
+    let mut denylisted = BTreeSet::new();
+    loop {
+        // Statistics build up as queries run, so there's nothing to report at first
+        tokio::time::sleep(interval).await;
+        if status.snapshot().child_pid.is_none() {
+            continue;
+        }
+        match report(&api, &status, &socket).await {
+            Ok(now) => {
+                let new: Vec<&str> = now.difference(&denylisted).map(String::as_str).collect();
+                if !new.is_empty() {
+                    warning!(
+                        "osquery's watchdog denylisted scheduled queries for using too much: {}",
+                        new.join(", ")
+                    );
+                }
+                denylisted = now;
+            }
+            Err(e) => error!("Failed to report scheduled query performance: {:#}", e),
+        }
+    }
+}
+
+/// Average time per run above which a query is slow, each unlimited if None
+#[derive(Debug, Clone, Copy)]
+pub struct SlowQueryThresholds {
+    pub wall_time_ms: Option<u64>,
+    /// User and system time together
+    pub cpu_time_ms: Option<u64>,
+}
+
+impl SlowQueryThresholds {
+    pub fn is_set(&self) -> bool {
+        self.wall_time_ms.is_some() || self.cpu_time_ms.is_some()
+    }
+}
+
+/// A query's running totals from `osquery_schedule`
+#[derive(Debug, Clone, Copy, Default)]
+struct Totals {
+    executions: u64,
+    wall_time_ms: u64,
+    cpu_time_ms: u64,
+}
+
+impl Totals {
+    fn from_row(row: &Row) -> Self {
+        Totals {
+            executions: number(row, "executions"),
+            wall_time_ms: wall_time_ms(row),
+            cpu_time_ms: number(row, "user_time") + number(row, "system_time"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SlowQueryReport {
+    host_id: String,
+    sent_at: DateTime<Utc>,
+    osquery_version: Option<String>,
+    wall_time_threshold_ms: Option<u64>,
+    cpu_time_threshold_ms: Option<u64>,
+    queries: Vec<SlowQuery>,
+}
+
+#[derive(Debug, Serialize)]
+struct SlowQuery {
+    name: String,
+    interval_secs: u64,
+    /// Runs since the last check, which the averages cover
+    executions: u64,
+    avg_wall_time_ms: f64,
+    avg_cpu_time_ms: f64,
+    avg_memory_bytes: u64,
+    /// Which thresholds it went over, `wall_time` and/or `cpu_time`
+    exceeded: Vec<&'static str>,
+}
+
+/// Queries over the thresholds since the `previous` totals, which are updated
+fn slow_queries(
+    rows: &[Row],
+    previous: &mut HashMap<String, Totals>,
+    thresholds: SlowQueryThresholds,
+) -> Vec<SlowQuery> {
+    let mut slow = Vec::new();
+    for row in rows {
+        let name = row.get("name").cloned().unwrap_or_default();
+        let now = Totals::from_row(row);
+        // osqueryd's counters start again when it restarts
+        let before = previous
+            .insert(name.clone(), now)
+            .filter(|before| before.executions <= now.executions)
+            .unwrap_or_default();
+        let executions = now.executions - before.executions;
+        if executions == 0 {
+            continue;
+        }
+        let average = |now: u64, before: u64| now.saturating_sub(before) as f64 / executions as f64;
+        let avg_wall_time_ms = average(now.wall_time_ms, before.wall_time_ms);
+        let avg_cpu_time_ms = average(now.cpu_time_ms, before.cpu_time_ms);
+        let over = |threshold: Option<u64>, average: f64| threshold.is_some_and(|t| average > t as f64);
+        let mut exceeded = Vec::new();
+        if over(thresholds.wall_time_ms, avg_wall_time_ms) {
+            exceeded.push("wall_time");
+        }
+        if over(thresholds.cpu_time_ms, avg_cpu_time_ms) {
+            exceeded.push("cpu_time");
+        }
+        slow.push(SlowQuery {
+            name,
+            interval_secs: number(row, "interval"),
+            executions,
+            avg_wall_time_ms,
+            avg_cpu_time_ms,
+            avg_memory_bytes: number(row, "average_memory"),
+            exceeded,
+        });
+    }
+    slow
+}
+
+/// Check for slow queries every few minutes while osqueryd runs, reporting
+/// each to the server when it first goes over the thresholds
+pub async fn watch_slow(
+    api: ApiClient,
+    status: SharedStatus,
+    socket: PathBuf,
+    thresholds: SlowQueryThresholds,
+) {
+    let mut previous = HashMap::new();
+    let mut flagged: BTreeSet<String> = BTreeSet::new();
+    loop {
+        tokio::time::sleep(SLOW_CHECK_INTERVAL).await;
+        if status.snapshot().child_pid.is_none() {
+            continue;
+        }
+        let rows = match extensions::query(&socket, "SELECT * FROM osquery_schedule;").await {
+            Ok(rows) => rows,
+            Err(e) => {
+                error!("Failed to check for slow scheduled queries: {:#}", e);
+                continue;
+            }
+        };
+        let mut new = Vec::new();
+        for query in slow_queries(&rows, &mut previous, thresholds) {
+            if query.exceeded.is_empty() {
+                flagged.remove(&query.name);
+            } else if flagged.insert(query.name.clone()) {
+                new.push(query);
+            }
+        }
+        // Queries dropped from the schedule are no longer slow
+        let scheduled: BTreeSet<&String> = rows.iter().filter_map(|row| row.get("name")).collect();
+        flagged.retain(|name| scheduled.contains(name));
+        previous.retain(|name, _| scheduled.contains(name));
+        status.update(|status| status.slow_queries = flagged.iter().cloned().collect());
+        if new.is_empty() {
+            continue;
+        }
+
+        for query in &new {
+            warning!(
+                "Scheduled query {} is slow, averaging {:.0} ms wall time and {:.0} ms CPU time over {} runs",
+                query.name,
+                query.avg_wall_time_ms,
+                query.avg_cpu_time_ms,
+                query.executions
+            );
+        }
+        let report = SlowQueryReport {
+            host_id: api.host_id().to_string(),
+            sent_at: Utc::now(),
+            osquery_version: status.snapshot().osquery_version,
+            wall_time_threshold_ms: thresholds.wall_time_ms,
+            cpu_time_threshold_ms: thresholds.cpu_time_ms,
+            queries: new,
+        };
+        if let Err(e) = api.post("/api/shadow/slow-queries", &report).await {
+            error!("Failed to report slow scheduled queries: {:#}", e);
+            // Try again if they're still slow next time
+            for query in &report.queries {
+                flagged.remove(&query.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! launchd daemon installation
+
+use super::{command_output, run_command, ServiceConfig, ServiceState, ServiceStatus};
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+const LABEL: &str = "cloud.hyprwatch.shadow";
+const PLIST_PATH: &str = "/Library/LaunchDaemons/cloud.hyprwatch.shadow.plist";
+const LOG_DIR: &str = "/Library/Logs/shadow";
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn string(value: &str) -> String {
+    format!("<string>{}</string>", escape(value))
+}
+
+fn plist(config: &ServiceConfig) -> String {
+    let mut program = format!("\n        {}", string(&config.exe.to_string_lossy()));
+    for arg in &config.args {
+        program.push_str(&format!("\n        {}", string(arg)));
+    }
+
+    let mut env = String::new();
+    for (key, value) in &config.env {
+        env.push_str(&format!("\n        <key>{}</key>{}", escape(key), string(value)));
+    }
+
+    let user = if config.user == "root" {
+        String::new()
+    } else {
+        format!("\n    <key>UserName</key>{}", string(&config.user))
+    };
+
+    let log = Path::new(LOG_DIR).join("shadow.log");
+    let log = string(&log.to_string_lossy());
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>{label}
+    <key>ProgramArguments</key>
+    <array>{program}
+    </array>
+    <key>EnvironmentVariables</key>
+    <dict>{env}
+    </dict>{user}
+    <key>RunAtLoad</key><true/>
+    <key>KeepAlive</key><true/>
+    <key>ThrottleInterval</key><integer>10</integer>
+    <key>StandardOutPath</key>{log}
+    <key>StandardErrorPath</key>{log}
+</dict>
+</plist>
+"#,
+        label = string(LABEL),
+    )
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    fs::create_dir_all(&config.data_dir)
+        .await
+        .context("Failed to create data directory")?;
+    fs::create_dir_all(LOG_DIR)
+        .await
+        .with_context(|| format!("Failed to create {} (are you root?)", LOG_DIR))?;
+    if config.user != "root" {
+        let data_dir = config.data_dir.to_string_lossy();
+        run_command("chown", &["-R", &config.user, &data_dir, LOG_DIR]).await?;
+    }
+
+    // Replace a previously installed daemon
+    if Path::new(PLIST_PATH).exists() {
+        let _ = run_command("launchctl", &["bootout", &format!("system/{}", LABEL)]).await;
+    }
+
+    // The plist holds the org token, so only root may read it
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(PLIST_PATH)
+        .await
+        .with_context(|| format!("Failed to write {} (are you root?)", PLIST_PATH))?;
+    file.write_all(plist(config).as_bytes()).await?;
+    println!("Wrote {}", PLIST_PATH);
+
+    if config.start {
+        run_command("launchctl", &["bootstrap", "system", PLIST_PATH]).await?;
+        println!("shadow daemon loaded and started");
+    } else {
+        println!("shadow daemon will start at next boot");
+    }
+    Ok(())
+}
+
+/// Loading the daemon starts it (RunAtLoad)
+pub async fn start() -> Result<()> {
+    run_command("launchctl", &["bootstrap", "system", PLIST_PATH]).await
+}
+
+/// Unloading the daemon stops it; with KeepAlive, killing it would just
+/// restart it
+pub async fn stop() -> Result<()> {
+    run_command("launchctl", &["bootout", &format!("system/{}", LABEL)]).await
+}
+
+/// Kill and immediately restart the daemon
+pub async fn restart() -> Result<()> {
+    run_command("launchctl", &["kickstart", "-k", &format!("system/{}", LABEL)]).await
+}
+
+pub async fn status() -> Result<ServiceStatus> {
+    let state = if !Path::new(PLIST_PATH).exists() {
+        ServiceState::NotInstalled
+    } else {
+        let (loaded, output) =
+            command_output("launchctl", &["print", &format!("system/{}", LABEL)]).await?;
+        let property = |name: &str| {
+            output
+                .lines()
+                .find_map(|line| line.trim().strip_prefix(name)?.trim_start().strip_prefix('='))
+                .map(str::trim)
+        };
+        if loaded && property("state") == Some("running") {
+            ServiceState::Running {
+                pid: property("pid").and_then(|pid| pid.parse().ok()),
+            }
+        } else {
+            ServiceState::Stopped
+        }
+    };
+    Ok(ServiceStatus {
+        manager: "launchd",
+        state,
+    })
+}
+
+pub async fn uninstall() -> Result<()> {
+    if !Path::new(PLIST_PATH).exists() {
+        anyhow::bail!("shadow daemon is not installed");
+    }
+    let _ = run_command("launchctl", &["bootout", &format!("system/{}", LABEL)]).await;
+    fs::remove_file(PLIST_PATH)
+        .await
+        .with_context(|| format!("Failed to remove {} (are you root?)", PLIST_PATH))?;
+    println!("Removed {}", PLIST_PATH);
+    Ok(())
+}
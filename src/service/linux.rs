@@ -0,0 +1,238 @@
+//! Linux init systems
+//!
+//! Detects whether the host runs systemd, OpenRC, or plain sysvinit and
+//! dispatches to it. Also holds what they share: the service account, the data
+//! directory, and the settings file.
+
+use super::{run_command, ServiceConfig, ServiceStatus};
+use crate::dbus;
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use super::{openrc, systemd, sysv};
+
+/// Holds the settings, including the org token, readable only by root. Not
+/// under /etc/shadow, which is the password file.
+const CONFIG_DIR: &str = "/etc/hyprwatch";
+pub const ENV_FILE: &str = "/etc/hyprwatch/shadow.env";
+
+/// Log file for init systems without a journal
+pub const LOG_FILE: &str = "/var/log/shadow.log";
+
+/// Lets the agent own its D-Bus name and anyone read its status
+const DBUS_POLICY: &str = "/etc/dbus-1/system.d/cloud.hyprwatch.Shadow.conf";
+
+/// What osqueryd needs to see the whole system when not running as root
+pub const CAPABILITIES: &[&str] = &[
+    "CAP_DAC_READ_SEARCH",
+    "CAP_SYS_PTRACE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_AUDIT_READ",
+];
+
+#[derive(Debug, Clone, Copy)]
+enum InitSystem {
+    Systemd,
+    OpenRc,
+    SysV,
+}
+
+impl InitSystem {
+    fn detect() -> Self {
+        // The same check as sd_booted()
+        if Path::new("/run/systemd/system").exists() {
+            InitSystem::Systemd
+        } else if Path::new("/sbin/openrc-run").exists() || Path::new("/run/openrc").exists() {
+            InitSystem::OpenRc
+        } else {
+            InitSystem::SysV
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            InitSystem::Systemd => "systemd",
+            InitSystem::OpenRc => "OpenRC",
+            InitSystem::SysV => "sysvinit",
+        }
+    }
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    write_dbus_policy(&config.user).await?;
+    match InitSystem::detect() {
+        InitSystem::Systemd => systemd::install(config).await,
+        InitSystem::OpenRc => openrc::install(config).await,
+        InitSystem::SysV => sysv::install(config).await,
+    }
+}
+
+pub async fn uninstall() -> Result<()> {
+    match InitSystem::detect() {
+        InitSystem::Systemd => systemd::uninstall().await,
+        InitSystem::OpenRc => openrc::uninstall().await,
+        InitSystem::SysV => sysv::uninstall().await,
+    }?;
+    let _ = fs::remove_file(ENV_FILE).await;
+    let _ = fs::remove_file(DBUS_POLICY).await;
+    Ok(())
+}
+
+pub async fn start() -> Result<()> {
+    match InitSystem::detect() {
+        InitSystem::Systemd => systemd::start().await,
+        InitSystem::OpenRc => openrc::start().await,
+        InitSystem::SysV => sysv::start().await,
+    }
+}
+
+pub async fn stop() -> Result<()> {
+    match InitSystem::detect() {
+        InitSystem::Systemd => systemd::stop().await,
+        InitSystem::OpenRc => openrc::stop().await,
+        InitSystem::SysV => sysv::stop().await,
+    }
+}
+
+pub async fn restart() -> Result<()> {
+    match InitSystem::detect() {
+        InitSystem::Systemd => systemd::restart().await,
+        InitSystem::OpenRc => openrc::restart().await,
+        InitSystem::SysV => sysv::restart().await,
+    }
+}
+
+pub async fn status() -> Result<ServiceStatus> {
+    let init = InitSystem::detect();
+    let state = match init {
+        InitSystem::Systemd => systemd::status().await,
+        InitSystem::OpenRc => openrc::status().await,
+        InitSystem::SysV => sysv::status().await,
+    }?;
+    Ok(ServiceStatus {
+        manager: init.name(),
+        state,
+    })
+}
+
+/// Quote a value for sh
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Create the service account if it doesn't exist
+async fn ensure_user(user: &str) -> Result<()> {
+    let exists = tokio::process::Command::new("id")
+        .arg("-u")
+        .arg(user)
+        .output()
+        .await
+        .is_ok_and(|output| output.status.success());
+    if exists {
+        return Ok(());
+    }
+
+    println!("Creating system user {}", user);
+    let useradd = run_command(
+        "useradd",
+        &["--system", "--no-create-home", "--home-dir", "/nonexistent", "--shell", "/sbin/nologin", user],
+    )
+    .await;
+    if useradd.is_ok() {
+        return Ok(());
+    }
+    // BusyBox (e.g. Alpine)
+    run_command("addgroup", &["-S", user]).await?;
+    run_command("adduser", &["-S", "-D", "-H", "-h", "/nonexistent", "-s", "/sbin/nologin", "-G", user, user])
+        .await
+}
+
+/// Create the service account and a data directory it owns, plus any files it
+/// needs to write outside of it
+pub async fn prepare(config: &ServiceConfig, files: &[&str]) -> Result<()> {
+    if config.user != "root" {
+        ensure_user(&config.user).await?;
+    }
+
+    fs::create_dir_all(&config.data_dir)
+        .await
+        .context("Failed to create data directory")?;
+    for file in files {
+        fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(file)
+            .await
+            .with_context(|| format!("Failed to create {} (are you root?)", file))?;
+    }
+
+    if config.user != "root" {
+        let owner = format!("{0}:{0}", config.user);
+        let data_dir = config.data_dir.to_string_lossy();
+        run_command("chown", &["-R", &owner, &data_dir]).await?;
+        for file in files {
+            run_command("chown", &[&owner, file]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the settings so only root can read them, quoting values with `quote`
+pub async fn write_env_file(config: &ServiceConfig, quote: fn(&str) -> String) -> Result<()> {
+    fs::create_dir_all(CONFIG_DIR)
+        .await
+        .with_context(|| format!("Failed to create {} (are you root?)", CONFIG_DIR))?;
+    let mut contents = String::new();
+    for (key, value) in &config.env {
+        contents.push_str(&format!("{}={}\n", key, quote(value)));
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(ENV_FILE)
+        .await
+        .with_context(|| format!("Failed to write {}", ENV_FILE))?;
+    file.write_all(contents.as_bytes()).await?;
+    Ok(())
+}
+
+/// Write the system bus policy for the agent's status interface, if the host
+/// has D-Bus. The bus picks it up without a reload.
+async fn write_dbus_policy(user: &str) -> Result<()> {
+    if !Path::new("/etc/dbus-1").exists() && !Path::new("/usr/share/dbus-1").exists() {
+        return Ok(());
+    }
+    let policy = format!(
+        r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-BUS Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<!-- Written by `shadow service install` -->
+<busconfig>
+  <policy user="{user}">
+    <allow own="{name}"/>
+  </policy>
+  <policy context="default">
+    <allow send_destination="{name}" send_interface="{interface}"/>
+    <allow send_destination="{name}" send_interface="org.freedesktop.DBus.Properties" send_member="Get"/>
+    <allow send_destination="{name}" send_interface="org.freedesktop.DBus.Properties" send_member="GetAll"/>
+    <allow send_destination="{name}" send_interface="org.freedesktop.DBus.Introspectable"/>
+    <allow send_destination="{name}" send_interface="org.freedesktop.DBus.Peer"/>
+  </policy>
+</busconfig>
+"#,
+        user = user,
+        name = dbus::BUS_NAME,
+        interface = dbus::INTERFACE,
+    );
+    fs::create_dir_all("/etc/dbus-1/system.d")
+        .await
+        .context("Failed to create /etc/dbus-1/system.d (are you root?)")?;
+    fs::write(DBUS_POLICY, policy)
+        .await
+        .with_context(|| format!("Failed to write {}", DBUS_POLICY))?;
+    println!("Wrote {}", DBUS_POLICY);
+    Ok(())
+}
@@ -0,0 +1,355 @@
+//! Service installation
+//!
+//! `shadow service install` registers shadow with the host's init system
+//! (systemd, OpenRC, or sysvinit on Linux, launchd on macOS, the Service
+//! Control Manager on Windows) so it starts at boot and is restarted if it
+//! dies. The service runs with the same settings the install command was given,
+//! whether on the command line or through environment variables.
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+#[cfg(target_os = "macos")]
+mod launchd;
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+mod openrc;
+mod profile;
+#[cfg(target_os = "linux")]
+mod systemd;
+#[cfg(target_os = "linux")]
+mod sysv;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "macos")]
+use launchd as backend;
+#[cfg(target_os = "linux")]
+use linux as backend;
+#[cfg(windows)]
+use windows as backend;
+
+pub use profile::{uuid, ProfileKind};
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod backend {
+    use super::ServiceConfig;
+    use anyhow::Result;
+
+    const UNSUPPORTED: &str = "Running shadow as a service isn't supported on this platform";
+
+    pub async fn install(_config: &ServiceConfig) -> Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+    pub async fn uninstall() -> Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+    pub async fn start() -> Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+    pub async fn stop() -> Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+    pub async fn restart() -> Result<()> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+    pub async fn status() -> Result<super::ServiceStatus> {
+        anyhow::bail!(UNSUPPORTED)
+    }
+}
+
+/// Whether the service is installed and running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceState {
+    NotInstalled,
+    Stopped,
+    Running { pid: Option<u32> },
+}
+
+/// What the init system reports about the service
+#[derive(Debug)]
+pub struct ServiceStatus {
+    /// Which init system manages the service
+    pub manager: &'static str,
+    pub state: ServiceState,
+}
+
+/// The agent itself, as run by `shadow service run`
+pub type Agent = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// Arguments handled by the install command itself
+const SKIPPED_ARGS: &[&str] = &["data_dir", "help", "version"];
+
+/// How long the service waits at startup for the network, unless told
+/// otherwise. Init systems only order us after the network is configured,
+/// which on slow DHCP can still be before the server is reachable.
+const DEFAULT_NETWORK_WAIT: &str = "300";
+
+/// How the service should be set up
+#[derive(Debug)]
+pub struct ServiceConfig {
+    /// The shadow binary to run
+    pub exe: PathBuf,
+    /// Data directory for the service
+    pub data_dir: PathBuf,
+    /// Settings with an environment variable. These are kept out of the
+    /// service definition itself, since they include the org token.
+    pub env: Vec<(String, String)>,
+    /// Settings without an environment variable, passed as arguments
+    pub args: Vec<String>,
+    /// Account to run the service as
+    pub user: String,
+    /// Whether the agent encrypts at rest, so needs its key made on install
+    pub encrypt_at_rest: bool,
+    /// Start the service once installed
+    pub start: bool,
+}
+
+impl ServiceConfig {
+    /// Build the service configuration from the arguments shadow was run with
+    pub fn new(
+        command: &Command,
+        matches: &ArgMatches,
+        user: Option<String>,
+        start: bool,
+    ) -> Result<Self> {
+        if matches.value_source("org_token").is_none() && !matches.get_flag("standalone") {
+            anyhow::bail!("--org-token is required to install the service");
+        }
+
+        let mut env = Vec::new();
+        let mut args = Vec::new();
+        for setting in settings(command, matches, SKIPPED_ARGS) {
+            match setting.env_value() {
+                Some(pair) => env.push(pair),
+                None => args.extend(setting.args()),
+            }
+        }
+
+        let data_dir = matches
+            .get_one::<PathBuf>("data_dir")
+            .cloned()
+            .unwrap_or_else(default_data_dir);
+        env.push(("SHADOW_DATA_DIR".to_string(), data_dir.display().to_string()));
+        if matches.value_source("wait_for_network").is_none() {
+            env.push((
+                "SHADOW_WAIT_FOR_NETWORK".to_string(),
+                DEFAULT_NETWORK_WAIT.to_string(),
+            ));
+        }
+
+        Ok(Self {
+            exe: std::env::current_exe().context("Failed to locate the shadow binary")?,
+            data_dir,
+            env,
+            args,
+            user: user.unwrap_or_else(|| default_user().to_string()),
+            encrypt_at_rest: matches.get_flag("encrypt_at_rest"),
+            start,
+        })
+    }
+}
+
+/// A setting shadow was given, on the command line or through its environment
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub id: String,
+    pub env: Option<String>,
+    pub long: Option<String>,
+    pub values: Vec<String>,
+    delimiter: Option<char>,
+    takes_values: bool,
+}
+
+impl Setting {
+    /// The setting as an environment variable, if it has one and its values
+    /// fit in it: without a delimiter, only one does
+    pub fn env_value(&self) -> Option<(String, String)> {
+        let name = self.env.clone()?;
+        match (self.delimiter, self.values.as_slice()) {
+            (Some(delimiter), values) => Some((name, values.join(&delimiter.to_string()))),
+            (None, [value]) => Some((name, value.clone())),
+            (None, _) => None,
+        }
+    }
+
+    /// The setting as command-line arguments
+    pub fn args(&self) -> Vec<String> {
+        let Some(long) = &self.long else {
+            return Vec::new();
+        };
+        let mut args = Vec::new();
+        for value in &self.values {
+            args.push(format!("--{}", long));
+            if self.takes_values {
+                args.push(value.clone());
+            }
+        }
+        args
+    }
+}
+
+/// The settings shadow was given, other than those in `skipped`. Defaults are
+/// left out, so they follow whatever version of shadow ends up running.
+pub fn settings(command: &Command, matches: &ArgMatches, skipped: &[&str]) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if skipped.contains(&id)
+            || !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        {
+            continue;
+        }
+        let values: Vec<String> = matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|value| value.to_str())
+            .map(str::to_string)
+            .collect();
+        if values.is_empty() {
+            continue;
+        }
+        settings.push(Setting {
+            id: id.to_string(),
+            env: arg.get_env().map(|name| name.to_string_lossy().into_owned()),
+            long: arg.get_long().map(str::to_string),
+            values,
+            delimiter: arg.get_value_delimiter(),
+            takes_values: arg.get_action().takes_values(),
+        });
+    }
+    settings
+}
+
+/// Data directory for the service, unless one is given
+fn default_data_dir() -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"C:\ProgramData\shadow")
+    } else if cfg!(target_os = "macos") {
+        PathBuf::from("/Library/Application Support/shadow")
+    } else {
+        PathBuf::from("/var/lib/shadow")
+    }
+}
+
+/// Account to run the service as, unless one is given. Only Linux gets a
+/// dedicated account, created on install.
+fn default_user() -> &'static str {
+    if cfg!(target_os = "linux") {
+        "shadow"
+    } else {
+        "root"
+    }
+}
+
+/// `shadow service install`
+pub async fn install(config: ServiceConfig) -> Result<()> {
+    backend::install(&config).await
+}
+
+/// `shadow service uninstall`
+pub async fn uninstall() -> Result<()> {
+    backend::uninstall().await
+}
+
+/// `shadow service start`
+pub async fn start() -> Result<()> {
+    backend::start().await
+}
+
+/// `shadow service stop`
+pub async fn stop() -> Result<()> {
+    backend::stop().await
+}
+
+/// `shadow service restart`
+pub async fn restart() -> Result<()> {
+    backend::restart().await
+}
+
+/// `shadow service status`. Exits with status 3 if the service isn't running,
+/// like an LSB init script.
+pub async fn status() -> Result<()> {
+    let status = backend::status().await?;
+    match status.state {
+        ServiceState::NotInstalled => println!("Service:   not installed ({})", status.manager),
+        ServiceState::Stopped => println!("Service:   stopped ({})", status.manager),
+        ServiceState::Running { pid: Some(pid) } => {
+            println!("Service:   running (pid {}, {})", pid, status.manager)
+        }
+        ServiceState::Running { pid: None } => println!("Service:   running ({})", status.manager),
+    }
+    if !matches!(status.state, ServiceState::Running { .. }) {
+        std::process::exit(3);
+    }
+    Ok(())
+}
+
+/// `shadow service profile`: write an AppArmor, SELinux, or TCC profile for
+/// the agent as it would be installed with the same settings
+pub async fn profile(kind: ProfileKind, matches: &ArgMatches, dir: &std::path::Path) -> Result<()> {
+    let config = profile::Confinement {
+        exe: std::env::current_exe().context("Failed to locate the shadow binary")?,
+        data_dir: matches
+            .get_one::<PathBuf>("data_dir")
+            .cloned()
+            .unwrap_or_else(default_data_dir),
+        server: matches
+            .get_one::<String>("server")
+            .cloned()
+            .unwrap_or_default(),
+        osqueryd_path: matches.get_one::<PathBuf>("osqueryd_path").cloned(),
+    };
+    profile::write(kind, &config, dir).await
+}
+
+/// `shadow service run`, which the Windows Service Control Manager uses to
+/// start the agent
+pub fn run(agent: Agent) -> Result<()> {
+    #[cfg(windows)]
+    return windows::run(agent);
+
+    #[cfg(not(windows))]
+    {
+        drop(agent);
+        anyhow::bail!("`shadow service run` is only used by the Windows Service Control Manager")
+    }
+}
+
+/// Run an init system command and capture its output, returning whether it
+/// succeeded
+#[cfg_attr(not(unix), allow(dead_code))]
+async fn command_output(program: &str, args: &[&str]) -> Result<(bool, String)> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {}", program))?;
+    Ok((
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+}
+
+/// Run an init system command, failing if it does
+#[cfg_attr(not(unix), allow(dead_code))]
+async fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = tokio::process::Command::new(program)
+        .args(args)
+        .status()
+        .await
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !status.success() {
+        anyhow::bail!("`{} {}` failed ({})", program, args.join(" "), status);
+    }
+    Ok(())
+}
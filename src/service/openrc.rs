@@ -0,0 +1,110 @@
+//! OpenRC service installation
+
+use super::linux::{self, shell_quote, CAPABILITIES, ENV_FILE, LOG_FILE};
+use super::{command_output, run_command, ServiceConfig, ServiceState};
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use tokio::fs;
+
+const SCRIPT_PATH: &str = "/etc/init.d/shadow";
+
+fn script(config: &ServiceConfig) -> String {
+    let args: Vec<String> = config.args.iter().map(|arg| shell_quote(arg)).collect();
+
+    let mut user = String::new();
+    if config.user != "root" {
+        let capabilities: Vec<String> = CAPABILITIES
+            .iter()
+            .map(|cap| format!("^{}", cap.to_lowercase()))
+            .collect();
+        user = format!(
+            "command_user={}\ncapabilities=\"{}\"\n",
+            shell_quote(&config.user),
+            capabilities.join(",")
+        );
+    }
+
+    format!(
+        r#"#!/sbin/openrc-run
+
+description="Hyprwatch shadow agent"
+supervisor=supervise-daemon
+command={command}
+command_args={args}
+{user}output_log={LOG_FILE}
+error_log={LOG_FILE}
+respawn_delay=10
+
+depend() {{
+	need net
+	use dns
+	after firewall ntp-client chronyd ntpd
+}}
+
+start_pre() {{
+	set -a
+	. {ENV_FILE}
+	set +a
+}}
+"#,
+        command = shell_quote(&config.exe.to_string_lossy()),
+        args = shell_quote(&args.join(" ")),
+    )
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    linux::prepare(config, &[LOG_FILE]).await?;
+    linux::write_env_file(config, shell_quote).await?;
+
+    fs::write(SCRIPT_PATH, script(config))
+        .await
+        .with_context(|| format!("Failed to write {} (are you root?)", SCRIPT_PATH))?;
+    fs::set_permissions(SCRIPT_PATH, std::fs::Permissions::from_mode(0o755)).await?;
+    println!("Wrote {}", SCRIPT_PATH);
+
+    run_command("rc-update", &["add", "shadow", "default"]).await?;
+    if config.start {
+        start().await?;
+        println!("shadow service enabled and started");
+    } else {
+        println!("shadow service enabled");
+    }
+    Ok(())
+}
+
+pub async fn start() -> Result<()> {
+    run_command("rc-service", &["shadow", "start"]).await
+}
+
+pub async fn stop() -> Result<()> {
+    run_command("rc-service", &["shadow", "stop"]).await
+}
+
+pub async fn restart() -> Result<()> {
+    run_command("rc-service", &["shadow", "restart"]).await
+}
+
+pub async fn status() -> Result<ServiceState> {
+    if fs::metadata(SCRIPT_PATH).await.is_err() {
+        return Ok(ServiceState::NotInstalled);
+    }
+    let (running, _) = command_output("rc-service", &["shadow", "status"]).await?;
+    Ok(if running {
+        ServiceState::Running { pid: None }
+    } else {
+        ServiceState::Stopped
+    })
+}
+
+pub async fn uninstall() -> Result<()> {
+    if fs::metadata(SCRIPT_PATH).await.is_err() {
+        anyhow::bail!("shadow service is not installed");
+    }
+    let _ = stop().await;
+    let _ = run_command("rc-update", &["del", "shadow", "default"]).await;
+    fs::remove_file(SCRIPT_PATH)
+        .await
+        .with_context(|| format!("Failed to remove {} (are you root?)", SCRIPT_PATH))?;
+    println!("Removed {}", SCRIPT_PATH);
+    Ok(())
+}
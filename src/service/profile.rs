@@ -0,0 +1,430 @@
+//! Confinement and permission profiles
+//!
+//! `shadow service profile` writes an AppArmor profile or an SELinux policy
+//! module describing what the agent actually does: where its binary and data
+//! directory are, which server it talks to, and that it runs osqueryd, which
+//! reads the whole system. Hardened distros can then confine the agent instead
+//! of blocking it. On macOS it instead writes a configuration profile granting
+//! the agent and osqueryd Full Disk Access, for deployment through MDM.
+
+use crate::osquery::OsqueryProvisioner;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Which kind of profile to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProfileKind {
+    Apparmor,
+    Selinux,
+    /// macOS Privacy Preferences Policy Control (TCC) profile granting Full
+    /// Disk Access
+    Tcc,
+}
+
+/// What the profile has to allow
+#[derive(Debug)]
+pub struct Confinement {
+    /// The shadow binary
+    pub exe: PathBuf,
+    pub data_dir: PathBuf,
+    /// Server host, with an optional port
+    pub server: String,
+    /// osqueryd, if not the one shadow provisions into the data directory
+    pub osqueryd_path: Option<PathBuf>,
+}
+
+impl Confinement {
+    fn server_port(&self) -> u16 {
+        self.server
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or(443)
+    }
+
+    fn osqueryd(&self) -> PathBuf {
+        self.osqueryd_path
+            .clone()
+            .unwrap_or_else(|| OsqueryProvisioner::new(self.data_dir.clone()).osqueryd_path())
+    }
+}
+
+/// AppArmor profile. It's attached by name from the systemd unit rather than
+/// by path, so running `shadow` by hand (e.g. `shadow service install`) isn't
+/// confined by it.
+fn apparmor(config: &Confinement) -> String {
+    let data_dir = config.data_dir.display();
+    let osqueryd = config.osqueryd();
+    let osqueryd = osqueryd.display();
+    format!(
+        r#"# AppArmor profile for the Hyprwatch shadow agent, generated by
+# `shadow service profile apparmor`. The systemd unit installed by
+# `shadow service install` runs the agent under it once it is loaded.
+#
+# Server: {server} (port {port}). AppArmor can't restrict connections by host.
+
+abi <abi/3.0>,
+
+include <tunables/global>
+
+profile shadow flags=(attach_disconnected) {{
+  include <abstractions/base>
+  include <abstractions/nameservice>
+  include <abstractions/openssl>
+  include <abstractions/ssl_certs>
+
+  # Reading other processes' state and stopping osqueryd
+  capability dac_read_search,
+  capability sys_ptrace,
+  capability kill,
+  ptrace read,
+  signal send set=(term, kill) peer=shadow//osqueryd,
+
+  # The server, DNS, syslog relays, and route checks
+  network inet stream,
+  network inet6 stream,
+  network inet dgram,
+  network inet6 dgram,
+  network netlink raw,
+
+  {exe} mr,
+  @{{PROC}}/** r,
+  /sys/** r,
+  /etc/** r,
+
+  {data_dir}/ rw,
+  {data_dir}/** rwlk,
+  # Fallback when the data directory is full or read-only
+  /tmp/shadow/ rw,
+  /tmp/shadow/** rwlk,
+
+  # Crash artifacts
+  /var/lib/systemd/coredump/ r,
+  /var/lib/systemd/coredump/* r,
+  /var/crash/ r,
+  /var/crash/* r,
+
+  # systemd notifications, the journal, syslog, logind (sleep/shutdown), and
+  # the D-Bus status interface
+  /run/systemd/notify w,
+  /run/systemd/journal/socket w,
+  /dev/log w,
+  /run/dbus/system_bus_socket rw,
+  dbus bus=system,
+
+  {osqueryd} Cx -> osqueryd,
+
+  # osqueryd's tables read across the whole system
+  profile osqueryd flags=(attach_disconnected) {{
+    include <abstractions/base>
+    include <abstractions/nameservice>
+    include <abstractions/openssl>
+    include <abstractions/ssl_certs>
+
+    capability dac_read_search,
+    capability sys_ptrace,
+    capability audit_control,
+    capability audit_read,
+    capability net_admin,
+    ptrace read,
+    signal receive peer=shadow,
+
+    network,
+
+    / r,
+    /** r,
+    {osqueryd} mrix,
+    {data_dir}/** rwlk,
+    /tmp/shadow/** rwlk,
+  }}
+}}
+"#,
+        server = config.server,
+        port = config.server_port(),
+        exe = config.exe.display(),
+    )
+}
+
+/// Escape a path for an SELinux file context regex
+fn fc_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for c in path.display().to_string().chars() {
+        if r".^$*+?()[]{}|\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// SELinux type enforcement rules. The agent and osqueryd share one domain,
+/// entered when init runs the labelled binary.
+fn selinux_te(config: &Confinement) -> String {
+    let port = config.server_port();
+    let connect = if port == 443 {
+        "corenet_tcp_connect_http_port(shadow_t)".to_string()
+    } else {
+        format!(
+            "# Port {port} must be labelled: semanage port -a -t http_port_t -p tcp {port}\n\
+             corenet_tcp_connect_http_port(shadow_t)"
+        )
+    };
+    format!(
+        r#"# SELinux policy module for the Hyprwatch shadow agent, generated by
+# `shadow service profile selinux`.
+#
+# Server: {server}
+
+policy_module(shadow, 1.0.0)
+
+type shadow_t;
+type shadow_exec_t;
+init_daemon_domain(shadow_t, shadow_exec_t)
+
+type shadow_var_lib_t;
+files_type(shadow_var_lib_t)
+
+# Reading other processes' state, stopping osqueryd, and osquery's audit tables
+allow shadow_t self:capability {{ dac_read_search sys_ptrace kill audit_control net_admin }};
+allow shadow_t self:capability2 audit_read;
+allow shadow_t self:process {{ signal_perms getsched setsched }};
+allow shadow_t self:fifo_file rw_fifo_file_perms;
+allow shadow_t self:unix_stream_socket create_stream_socket_perms;
+allow shadow_t self:unix_dgram_socket create_socket_perms;
+allow shadow_t self:tcp_socket create_stream_socket_perms;
+allow shadow_t self:udp_socket create_socket_perms;
+allow shadow_t self:netlink_route_socket r_netlink_socket_perms;
+allow shadow_t self:netlink_audit_socket {{ create_netlink_socket_perms nlmsg_read nlmsg_write }};
+
+# The data directory, including osqueryd when shadow provisions it there
+manage_dirs_pattern(shadow_t, shadow_var_lib_t, shadow_var_lib_t)
+manage_files_pattern(shadow_t, shadow_var_lib_t, shadow_var_lib_t)
+manage_lnk_files_pattern(shadow_t, shadow_var_lib_t, shadow_var_lib_t)
+manage_sock_files_pattern(shadow_t, shadow_var_lib_t, shadow_var_lib_t)
+files_var_lib_filetrans(shadow_t, shadow_var_lib_t, dir)
+can_exec(shadow_t, shadow_var_lib_t)
+can_exec(shadow_t, shadow_exec_t)
+corecmd_exec_bin(shadow_t)
+
+# Fallback when the data directory is full or read-only
+files_manage_generic_tmp_dirs(shadow_t)
+files_manage_generic_tmp_files(shadow_t)
+
+# The server, DNS, and certificates
+{connect}
+sysnet_dns_name_resolve(shadow_t)
+miscfiles_read_generic_certs(shadow_t)
+auth_use_nsswitch(shadow_t)
+
+# osqueryd's tables read across the whole system
+kernel_read_system_state(shadow_t)
+kernel_read_network_state(shadow_t)
+kernel_read_all_sysctls(shadow_t)
+domain_read_all_domains_state(shadow_t)
+dev_read_sysfs(shadow_t)
+dev_read_urand(shadow_t)
+fs_getattr_all_fs(shadow_t)
+files_read_non_security_files(shadow_t)
+
+# The journal, syslog, and systemd notifications
+logging_send_syslog_msg(shadow_t)
+init_dgram_send(shadow_t)
+
+# Sleep and shutdown notifications from logind, and the status interface
+allow shadow_t self:dbus {{ acquire_svc send_msg }};
+optional_policy(`
+	dbus_system_bus_client(shadow_t)
+	systemd_dbus_chat_logind(shadow_t)
+')
+"#,
+        server = config.server,
+    )
+}
+
+/// SELinux file contexts
+fn selinux_fc(config: &Confinement) -> String {
+    let mut contexts = format!(
+        "{}\t--\tgen_context(system_u:object_r:shadow_exec_t,s0)\n\
+         {}(/.*)?\tgen_context(system_u:object_r:shadow_var_lib_t,s0)\n",
+        fc_escape(&config.exe),
+        fc_escape(&config.data_dir),
+    );
+    if let Some(path) = &config.osqueryd_path {
+        contexts.push_str(&format!(
+            "{}\t--\tgen_context(system_u:object_r:shadow_exec_t,s0)\n",
+            fc_escape(path)
+        ));
+    }
+    contexts
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A random UUID (version 4), as configuration profiles need for each payload
+/// and SBOMs for each document
+pub fn uuid() -> String {
+    let value = (rand::random::<u128>() & !(0xf << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    let hex = format!("{:032X}", value);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// The designated requirement of a signed binary, which a profile uses to
+/// make sure it grants access to the right code
+async fn code_requirement(path: &Path) -> Result<String> {
+    let output = tokio::process::Command::new("codesign")
+        .args(["-d", "-r", "-"])
+        .arg(path)
+        .output()
+        .await
+        .context("Failed to run codesign")?;
+    // Older versions of codesign print it on stderr
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    text.lines()
+        .find_map(|line| line.strip_prefix("designated => "))
+        .map(str::to_string)
+        .with_context(|| {
+            format!(
+                "{} isn't code signed, so a profile can't grant it access; grant Full Disk Access in System Settings instead",
+                path.display()
+            )
+        })
+}
+
+/// One Full Disk Access grant. Binaries inside an app bundle are identified by
+/// the bundle ID, others by path.
+async fn tcc_grant(path: &Path, comment: &str) -> Result<String> {
+    let requirement = code_requirement(path).await?;
+    let bundle_id = requirement
+        .strip_prefix("identifier \"")
+        .and_then(|rest| rest.split_once('"'))
+        .map(|(id, _)| id)
+        .filter(|_| path.to_string_lossy().contains(".app/Contents/MacOS/"));
+    let (identifier_type, identifier) = match bundle_id {
+        Some(id) => ("bundleID", id.to_string()),
+        None => ("path", path.display().to_string()),
+    };
+    Ok(format!(
+        r#"                    <dict>
+                        <key>Allowed</key><true/>
+                        <key>CodeRequirement</key><string>{}</string>
+                        <key>Comment</key><string>{}</string>
+                        <key>Identifier</key><string>{}</string>
+                        <key>IdentifierType</key><string>{}</string>
+                    </dict>
+"#,
+        xml_escape(&requirement),
+        xml_escape(comment),
+        xml_escape(&identifier),
+        identifier_type,
+    ))
+}
+
+/// Configuration profile granting the agent and osqueryd Full Disk Access
+async fn tcc(config: &Confinement) -> Result<String> {
+    if !cfg!(target_os = "macos") {
+        anyhow::bail!("TCC profiles are for macOS; run this on a Mac with shadow and osquery installed");
+    }
+    let osqueryd = config.osqueryd();
+    let grants = [
+        tcc_grant(&config.exe, "Hyprwatch shadow agent").await?,
+        tcc_grant(&osqueryd, "osqueryd, run by the Hyprwatch shadow agent").await?,
+    ];
+    Ok(format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>PayloadContent</key>
+    <array>
+        <dict>
+            <key>PayloadDisplayName</key><string>Hyprwatch Shadow Privacy Preferences</string>
+            <key>PayloadIdentifier</key><string>cloud.hyprwatch.shadow.tcc.{payload_uuid}</string>
+            <key>PayloadType</key><string>com.apple.TCC.configuration-profile-policy</string>
+            <key>PayloadUUID</key><string>{payload_uuid}</string>
+            <key>PayloadVersion</key><integer>1</integer>
+            <key>Services</key>
+            <dict>
+                <key>SystemPolicyAllFiles</key>
+                <array>
+{grants}                </array>
+            </dict>
+        </dict>
+    </array>
+    <key>PayloadDescription</key><string>Grants the Hyprwatch shadow agent and osqueryd Full Disk Access</string>
+    <key>PayloadDisplayName</key><string>Hyprwatch Shadow Full Disk Access</string>
+    <key>PayloadIdentifier</key><string>cloud.hyprwatch.shadow.tcc</string>
+    <key>PayloadScope</key><string>System</string>
+    <key>PayloadType</key><string>Configuration</string>
+    <key>PayloadUUID</key><string>{profile_uuid}</string>
+    <key>PayloadVersion</key><integer>1</integer>
+</dict>
+</plist>
+"#,
+        payload_uuid = uuid(),
+        profile_uuid = uuid(),
+        grants = grants.concat(),
+    ))
+}
+
+async fn write_file(dir: &Path, name: &str, contents: String) -> Result<PathBuf> {
+    let path = dir.join(name);
+    fs::write(&path, contents)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    println!("Wrote {}", path.display());
+    Ok(path)
+}
+
+/// Write the profile into `dir` and explain how to load it
+pub async fn write(kind: ProfileKind, config: &Confinement, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    match kind {
+        ProfileKind::Apparmor => {
+            let path = write_file(dir, "shadow.apparmor", apparmor(config)).await?;
+            println!();
+            println!("To load it:");
+            println!("  sudo cp {} /etc/apparmor.d/shadow", path.display());
+            println!("  sudo apparmor_parser -r /etc/apparmor.d/shadow");
+            println!("  sudo shadow service restart");
+            println!();
+            println!("The systemd unit applies the profile. Under other init systems, start");
+            println!("shadow with `aa-exec -p shadow --`.");
+        }
+        ProfileKind::Selinux => {
+            write_file(dir, "shadow.te", selinux_te(config)).await?;
+            write_file(dir, "shadow.fc", selinux_fc(config)).await?;
+            let mut paths = vec![config.exe.display().to_string(), config.data_dir.display().to_string()];
+            if let Some(path) = &config.osqueryd_path {
+                paths.push(path.display().to_string());
+            }
+            println!();
+            println!("To build and load it (needs selinux-policy-devel):");
+            println!("  cd {}", dir.display());
+            println!("  make -f /usr/share/selinux/devel/Makefile shadow.pp");
+            println!("  sudo semodule -i shadow.pp");
+            println!("  sudo restorecon -R {}", paths.join(" "));
+            println!("  sudo shadow service restart");
+        }
+        ProfileKind::Tcc => {
+            let profile = tcc(config).await?;
+            write_file(dir, "shadow-tcc.mobileconfig", profile).await?;
+            println!();
+            println!("Upload it to your MDM as a custom configuration profile. Privacy");
+            println!("Preferences Policy Control payloads only take effect when installed by MDM.");
+        }
+    }
+    Ok(())
+}
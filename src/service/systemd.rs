@@ -0,0 +1,156 @@
+//! systemd unit installation
+
+use super::linux::{self, CAPABILITIES, ENV_FILE};
+use super::{command_output, run_command, ServiceConfig, ServiceState};
+use crate::atrest;
+use anyhow::{Context, Result};
+use std::path::Path;
+use tokio::fs;
+
+const UNIT_NAME: &str = "shadow.service";
+const UNIT_PATH: &str = "/etc/systemd/system/shadow.service";
+
+/// Quote a value for an environment file
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Quote a value for ExecStart=, which also expands `$VAR` and `%` specifiers
+fn quote_exec(value: &str) -> String {
+    quote(&value.replace('$', "$$").replace('%', "%%"))
+}
+
+/// The unit for `config`, with systemd handing the agent the at-rest key from
+/// `credential` if it has one
+fn unit(config: &ServiceConfig, credential: Option<&Path>) -> String {
+    let mut exec_start = quote_exec(&config.exe.to_string_lossy());
+    for arg in &config.args {
+        exec_start.push(' ');
+        exec_start.push_str(&quote_exec(arg));
+    }
+
+    let mut service = format!(
+        "Type=notify\n\
+         NotifyAccess=main\n\
+         ExecStart={exec_start}\n\
+         EnvironmentFile={ENV_FILE}\n\
+         Restart=on-failure\n\
+         RestartSec=10\n\
+         TimeoutStartSec=10min\n\
+         TimeoutStopSec=30\n\
+         WatchdogSec=60\n\
+         NoNewPrivileges=yes\n\
+         ProtectSystem=strict\n\
+         ProtectHome=read-only\n\
+         PrivateTmp=yes\n\
+         ProtectControlGroups=yes\n\
+         ProtectKernelModules=yes\n\
+         RestrictSUIDSGID=yes\n\
+         LockPersonality=yes\n\
+         ReadWritePaths={data_dir}\n\
+         AppArmorProfile=-shadow\n",
+        data_dir = config.data_dir.to_string_lossy().replace('%', "%%"),
+    );
+    if let Some(credential) = credential {
+        service.push_str(&format!(
+            "LoadCredentialEncrypted={}:{}\n",
+            atrest::CREDENTIAL_NAME,
+            credential.to_string_lossy().replace('%', "%%"),
+        ));
+    }
+    if config.user != "root" {
+        service.push_str(&format!(
+            "User={user}\n\
+             Group={user}\n\
+             AmbientCapabilities={capabilities}\n\
+             CapabilityBoundingSet={capabilities}\n",
+            user = config.user,
+            capabilities = CAPABILITIES.join(" "),
+        ));
+    }
+
+    format!(
+        "[Unit]\n\
+         Description=Hyprwatch shadow agent\n\
+         Wants=network-online.target\n\
+         After=network-online.target nss-lookup.target time-sync.target\n\
+         StartLimitIntervalSec=0\n\
+         \n\
+         [Service]\n\
+         {service}\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n"
+    )
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    linux::prepare(config, &[]).await?;
+    linux::write_env_file(config, quote).await?;
+    // Made here, as root, since the agent's own account can't use the host's
+    // credential secret
+    let credential = match config.encrypt_at_rest {
+        true => Some(atrest::provision(&config.data_dir)?),
+        false => None,
+    };
+    fs::write(UNIT_PATH, unit(config, credential.as_deref()))
+        .await
+        .with_context(|| format!("Failed to write {} (are you root?)", UNIT_PATH))?;
+    println!("Wrote {}", UNIT_PATH);
+
+    run_command("systemctl", &["daemon-reload"]).await?;
+    if config.start {
+        run_command("systemctl", &["enable", "--now", UNIT_NAME]).await?;
+        println!("shadow service enabled and started");
+    } else {
+        run_command("systemctl", &["enable", UNIT_NAME]).await?;
+        println!("shadow service enabled");
+    }
+    Ok(())
+}
+
+pub async fn start() -> Result<()> {
+    run_command("systemctl", &["start", UNIT_NAME]).await
+}
+
+pub async fn stop() -> Result<()> {
+    run_command("systemctl", &["stop", UNIT_NAME]).await
+}
+
+pub async fn restart() -> Result<()> {
+    run_command("systemctl", &["restart", UNIT_NAME]).await
+}
+
+pub async fn status() -> Result<ServiceState> {
+    let (_, output) = command_output(
+        "systemctl",
+        &["show", UNIT_NAME, "--property=LoadState,ActiveState,MainPID"],
+    )
+    .await?;
+    let property = |name: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix('='))
+            .unwrap_or_default()
+    };
+    Ok(match property("LoadState") {
+        "not-found" => ServiceState::NotInstalled,
+        _ if property("ActiveState") == "active" => ServiceState::Running {
+            pid: property("MainPID").parse().ok().filter(|pid| *pid != 0),
+        },
+        _ => ServiceState::Stopped,
+    })
+}
+
+pub async fn uninstall() -> Result<()> {
+    if fs::metadata(UNIT_PATH).await.is_err() {
+        anyhow::bail!("shadow service is not installed");
+    }
+    run_command("systemctl", &["disable", "--now", UNIT_NAME]).await?;
+    fs::remove_file(UNIT_PATH)
+        .await
+        .with_context(|| format!("Failed to remove {} (are you root?)", UNIT_PATH))?;
+    run_command("systemctl", &["daemon-reload"]).await?;
+    println!("Removed {}", UNIT_PATH);
+    Ok(())
+}
@@ -0,0 +1,181 @@
+//! sysvinit script installation
+//!
+//! A basic LSB init script using start-stop-daemon. Unlike systemd and OpenRC,
+//! sysvinit doesn't restart shadow if it dies or grant capabilities to a
+//! non-root user.
+
+use super::linux::{self, shell_quote, ENV_FILE, LOG_FILE};
+use super::{command_output, run_command, ServiceConfig, ServiceState};
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tokio::fs;
+
+const SCRIPT_PATH: &str = "/etc/init.d/shadow";
+const PID_FILE: &str = "/var/run/shadow.pid";
+
+fn script(config: &ServiceConfig) -> String {
+    let mut command = shell_quote(&config.exe.to_string_lossy());
+    for arg in &config.args {
+        command.push(' ');
+        command.push_str(&shell_quote(arg));
+    }
+
+    format!(
+        r#"#!/bin/sh
+### BEGIN INIT INFO
+# Provides:          shadow
+# Required-Start:    $network $remote_fs $syslog
+# Required-Stop:     $network $remote_fs $syslog
+# Should-Start:      $named $time
+# Default-Start:     2 3 4 5
+# Default-Stop:      0 1 6
+# Short-Description: Hyprwatch shadow agent
+### END INIT INFO
+
+NAME=shadow
+PIDFILE={PID_FILE}
+
+is_running() {{
+	[ -f "$PIDFILE" ] && kill -0 "$(cat "$PIDFILE")" 2>/dev/null
+}}
+
+start() {{
+	if is_running; then
+		echo "$NAME is already running"
+		return 0
+	fi
+	echo "Starting $NAME"
+	set -a
+	. {ENV_FILE}
+	set +a
+	start-stop-daemon --start --background --make-pidfile --pidfile "$PIDFILE" \
+		--chuid {user} --startas /bin/sh -- \
+		-c 'exec "$0" "$@" >>{LOG_FILE} 2>&1' {command}
+}}
+
+stop() {{
+	if ! is_running; then
+		echo "$NAME is not running"
+		return 0
+	fi
+	echo "Stopping $NAME"
+	start-stop-daemon --stop --pidfile "$PIDFILE" --signal TERM
+	i=0
+	while is_running && [ $i -lt 30 ]; do
+		sleep 1
+		i=$((i + 1))
+	done
+	if is_running; then
+		kill -9 "$(cat "$PIDFILE")"
+	fi
+	rm -f "$PIDFILE"
+}}
+
+case "$1" in
+	start) start ;;
+	stop) stop ;;
+	restart) stop; start ;;
+	status)
+		if is_running; then
+			echo "$NAME is running (pid $(cat "$PIDFILE"))"
+		else
+			echo "$NAME is not running"
+			exit 3
+		fi
+		;;
+	*)
+		echo "Usage: $0 {{start|stop|restart|status}}"
+		exit 2
+		;;
+esac
+"#,
+        user = shell_quote(&config.user),
+    )
+}
+
+/// Register the script with the runlevels, with whichever tool the distro has
+async fn enable() -> Result<()> {
+    if Path::new("/usr/sbin/update-rc.d").exists() {
+        run_command("update-rc.d", &["shadow", "defaults"]).await
+    } else if Path::new("/sbin/chkconfig").exists() || Path::new("/usr/sbin/chkconfig").exists() {
+        run_command("chkconfig", &["--add", "shadow"]).await
+    } else {
+        eprintln!("Warning: neither update-rc.d nor chkconfig found, link {} into your runlevels", SCRIPT_PATH);
+        Ok(())
+    }
+}
+
+async fn disable() {
+    if Path::new("/usr/sbin/update-rc.d").exists() {
+        let _ = run_command("update-rc.d", &["-f", "shadow", "remove"]).await;
+    } else {
+        let _ = run_command("chkconfig", &["--del", "shadow"]).await;
+    }
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    if config.user != "root" {
+        eprintln!(
+            "Warning: sysvinit can't grant capabilities to {}, so osquery will see less of the system (use --user root)",
+            config.user
+        );
+    }
+    linux::prepare(config, &[LOG_FILE]).await?;
+    linux::write_env_file(config, shell_quote).await?;
+
+    fs::write(SCRIPT_PATH, script(config))
+        .await
+        .with_context(|| format!("Failed to write {} (are you root?)", SCRIPT_PATH))?;
+    fs::set_permissions(SCRIPT_PATH, std::fs::Permissions::from_mode(0o755)).await?;
+    println!("Wrote {}", SCRIPT_PATH);
+
+    enable().await?;
+    if config.start {
+        start().await?;
+        println!("shadow service enabled and started");
+    } else {
+        println!("shadow service enabled");
+    }
+    Ok(())
+}
+
+pub async fn start() -> Result<()> {
+    run_command(SCRIPT_PATH, &["start"]).await
+}
+
+pub async fn stop() -> Result<()> {
+    run_command(SCRIPT_PATH, &["stop"]).await
+}
+
+pub async fn restart() -> Result<()> {
+    run_command(SCRIPT_PATH, &["restart"]).await
+}
+
+pub async fn status() -> Result<ServiceState> {
+    if fs::metadata(SCRIPT_PATH).await.is_err() {
+        return Ok(ServiceState::NotInstalled);
+    }
+    let (running, _) = command_output(SCRIPT_PATH, &["status"]).await?;
+    if !running {
+        return Ok(ServiceState::Stopped);
+    }
+    let pid = fs::read_to_string(PID_FILE)
+        .await
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+    Ok(ServiceState::Running { pid })
+}
+
+pub async fn uninstall() -> Result<()> {
+    if fs::metadata(SCRIPT_PATH).await.is_err() {
+        anyhow::bail!("shadow service is not installed");
+    }
+    let _ = stop().await;
+    disable().await;
+    fs::remove_file(SCRIPT_PATH)
+        .await
+        .with_context(|| format!("Failed to remove {} (are you root?)", SCRIPT_PATH))?;
+    println!("Removed {}", SCRIPT_PATH);
+    Ok(())
+}
@@ -0,0 +1,345 @@
+//! Windows service registration and Service Control Manager integration
+//!
+//! The service runs `shadow.exe service run`, which hands control to the SCM
+//! dispatcher. Stop and shutdown controls stop osqueryd the same way a system
+//! shutdown does (see [`crate::power`]). Settings are stored in the service's
+//! `Environment` registry value rather than on its command line. As they
+//! include the org token, the service's registry key is first restricted to
+//! SYSTEM and Administrators, where Users could read it by default.
+
+use super::{Agent, ServiceConfig, ServiceStatus as AgentServiceStatus};
+use anyhow::{Context, Result};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+    ServiceDependency, ServiceErrorControl, ServiceExitCode, ServiceFailureActions,
+    ServiceFailureResetPeriod,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::{LocalFree, ERROR_SUCCESS};
+use windows_sys::Win32::Security::Authorization::{
+    ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows_sys::Win32::Security::{
+    DACL_SECURITY_INFORMATION, PROTECTED_DACL_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR,
+};
+use windows_sys::Win32::System::Registry::{
+    RegCloseKey, RegOpenKeyExW, RegSetKeySecurity, RegSetKeyValueW, HKEY, HKEY_LOCAL_MACHINE, KEY_ALL_ACCESS,
+    REG_MULTI_SZ,
+};
+
+const SERVICE_NAME: &str = "shadow";
+const DISPLAY_NAME: &str = "Hyprwatch Shadow Agent";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Who may use the service's registry key: SYSTEM and Administrators, with
+/// nothing inherited
+const KEY_SDDL: &str = "D:P(A;CI;KA;;;SY)(A;CI;KA;;;BA)";
+
+/// How long the SCM should wait for osqueryd to stop
+const STOP_WAIT_HINT: Duration = Duration::from_secs(30);
+
+/// The agent, handed from `main` to the thread the SCM runs the service on
+static AGENT: Mutex<Option<(tokio::runtime::Handle, Agent)>> = Mutex::new(None);
+
+fn wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn manager(access: ServiceManagerAccess) -> Result<ServiceManager> {
+    ServiceManager::local_computer(None::<&str>, access)
+        .context("Failed to connect to the Service Control Manager (run as Administrator)")
+}
+
+/// Restrict the service's registry key to SYSTEM and Administrators
+fn restrict_key(key: &[u16]) -> Result<()> {
+    let sddl = wide(KEY_SDDL);
+    let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+    // SAFETY: `sddl` is NUL-terminated, and the descriptor is freed below
+    let converted = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            std::ptr::null_mut(),
+        )
+    };
+    if converted == 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to build the service key's permissions");
+    }
+    let mut handle: HKEY = std::ptr::null_mut();
+    // SAFETY: `key` is NUL-terminated and `handle` is closed below
+    let mut result = unsafe { RegOpenKeyExW(HKEY_LOCAL_MACHINE, key.as_ptr(), 0, KEY_ALL_ACCESS, &mut handle) };
+    if result == ERROR_SUCCESS {
+        // SAFETY: `handle` is open and `descriptor` valid
+        result = unsafe {
+            RegSetKeySecurity(
+                handle,
+                DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                descriptor,
+            )
+        };
+        unsafe { RegCloseKey(handle) };
+    }
+    unsafe { LocalFree(descriptor) };
+    if result != ERROR_SUCCESS {
+        anyhow::bail!("Failed to restrict access to the service's settings (error {})", result);
+    }
+    Ok(())
+}
+
+/// Store settings in the service's `Environment` value, which the SCM passes
+/// to the service process, once only SYSTEM and Administrators can read it
+fn set_environment(env: &[(String, String)]) -> Result<()> {
+    let mut data: Vec<u16> = Vec::new();
+    for (key, value) in env {
+        data.extend(wide(&format!("{}={}", key, value)));
+    }
+    data.push(0);
+
+    let key = wide(&format!(r"SYSTEM\CurrentControlSet\Services\{}", SERVICE_NAME));
+    restrict_key(&key)?;
+    let name = wide("Environment");
+    // SAFETY: all pointers are valid, NUL-terminated buffers that outlive the call
+    let result = unsafe {
+        RegSetKeyValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            name.as_ptr(),
+            REG_MULTI_SZ,
+            data.as_ptr().cast(),
+            (data.len() * 2) as u32,
+        )
+    };
+    if result != ERROR_SUCCESS {
+        anyhow::bail!("Failed to store service settings (error {})", result);
+    }
+    Ok(())
+}
+
+pub async fn install(config: &ServiceConfig) -> Result<()> {
+    if config.user != "root" {
+        anyhow::bail!("The Windows service always runs as LocalSystem; omit --user");
+    }
+    tokio::fs::create_dir_all(&config.data_dir)
+        .await
+        .context("Failed to create data directory")?;
+
+    let manager = manager(ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)?;
+    let mut launch_arguments: Vec<OsString> = config.args.iter().map(OsString::from).collect();
+    launch_arguments.extend(["service".into(), "run".into()]);
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: DISPLAY_NAME.into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: config.exe.clone(),
+        launch_arguments,
+        dependencies: vec![ServiceDependency::Service("Tcpip".into())],
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .context("Failed to create the shadow service")?;
+    service.set_description("Runs osquery and reports to Hyprwatch")?;
+    // Start after the boot rush, once the network is more likely to be up
+    service.set_delayed_auto_start(true)?;
+
+    // Restart on failure, like Restart=on-failure under systemd
+    let restart = ServiceAction {
+        action_type: ServiceActionType::Restart,
+        delay: Duration::from_secs(10),
+    };
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![restart.clone(), restart.clone(), restart]),
+    })?;
+    service.set_failure_actions_on_non_crash_failures(true)?;
+
+    set_environment(&config.env)?;
+    if let Err(e) = crate::eventlog::register() {
+        eprintln!("Warning: {:#}, agent events won't show in the Event Log", e);
+    }
+    println!("Installed the {} service", SERVICE_NAME);
+
+    if config.start {
+        service.start(&[] as &[&OsStr]).context("Failed to start the shadow service")?;
+        println!("shadow service started");
+    }
+    Ok(())
+}
+
+pub async fn uninstall() -> Result<()> {
+    let manager = manager(ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )
+        .context("shadow service is not installed")?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        let _ = service.stop();
+        wait_for_state(&service, ServiceState::Stopped).await;
+    }
+    service.delete().context("Failed to delete the shadow service")?;
+    crate::eventlog::unregister();
+    println!("Removed the {} service", SERVICE_NAME);
+    Ok(())
+}
+
+pub async fn start() -> Result<()> {
+    let manager = manager(ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(SERVICE_NAME, ServiceAccess::START | ServiceAccess::QUERY_STATUS)
+        .context("shadow service is not installed")?;
+    service.start(&[] as &[&OsStr]).context("Failed to start the shadow service")?;
+    wait_for_state(&service, ServiceState::Running).await;
+    Ok(())
+}
+
+pub async fn stop() -> Result<()> {
+    let manager = manager(ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(SERVICE_NAME, ServiceAccess::STOP | ServiceAccess::QUERY_STATUS)
+        .context("shadow service is not installed")?;
+    service.stop().context("Failed to stop the shadow service")?;
+    wait_for_state(&service, ServiceState::Stopped).await;
+    Ok(())
+}
+
+pub async fn restart() -> Result<()> {
+    let manager = manager(ServiceManagerAccess::CONNECT)?;
+    let service = manager
+        .open_service(
+            SERVICE_NAME,
+            ServiceAccess::START | ServiceAccess::STOP | ServiceAccess::QUERY_STATUS,
+        )
+        .context("shadow service is not installed")?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop().context("Failed to stop the shadow service")?;
+        wait_for_state(&service, ServiceState::Stopped).await;
+    }
+    service.start(&[] as &[&OsStr]).context("Failed to start the shadow service")?;
+    wait_for_state(&service, ServiceState::Running).await;
+    Ok(())
+}
+
+pub async fn status() -> Result<AgentServiceStatus> {
+    let manager = manager(ServiceManagerAccess::CONNECT)?;
+    let state = match manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS) {
+        Err(_) => super::ServiceState::NotInstalled,
+        Ok(service) => {
+            let status = service.query_status()?;
+            match status.current_state {
+                ServiceState::Running => super::ServiceState::Running {
+                    pid: status.process_id,
+                },
+                _ => super::ServiceState::Stopped,
+            }
+        }
+    };
+    Ok(AgentServiceStatus {
+        manager: "Service Control Manager",
+        state,
+    })
+}
+
+/// Wait (bounded) for a service to reach a state
+async fn wait_for_state(service: &windows_service::service::Service, state: ServiceState) {
+    let deadline = tokio::time::Instant::now() + STOP_WAIT_HINT;
+    while tokio::time::Instant::now() < deadline {
+        if service.query_status().is_ok_and(|status| status.current_state == state) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// `shadow service run`: run the agent under the SCM. Blocks until the service
+/// stops.
+pub fn run(agent: Agent) -> Result<()> {
+    *AGENT.lock().unwrap() = Some((tokio::runtime::Handle::current(), agent));
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("Failed to connect to the Service Control Manager (`service run` is only for the SCM)")
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn scm_status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: state,
+        controls_accepted: match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code: ServiceExitCode::ServiceSpecific(exit_code),
+        checkpoint: 0,
+        wait_hint: match state {
+            ServiceState::StopPending => STOP_WAIT_HINT,
+            _ => Duration::ZERO,
+        },
+        process_id: None,
+    }
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let Some((runtime, agent)) = AGENT.lock().unwrap().take() else {
+        return;
+    };
+
+    let stop = Arc::new(Notify::new());
+    let handler_stop = stop.clone();
+    let handler = move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            handler_stop.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let Ok(handle) = service_control_handler::register(SERVICE_NAME, handler) else {
+        eprintln!("Failed to register the service control handler");
+        return;
+    };
+    let _ = handle.set_service_status(scm_status(ServiceState::Running, 0));
+
+    let result = runtime.block_on(async {
+        tokio::select! {
+            result = agent => result,
+            _ = async {
+                stop.notified().await;
+                let _ = handle.set_service_status(scm_status(ServiceState::StopPending, 0));
+                // The supervisor stops osqueryd and returns; this is a backstop
+                // for when it isn't running yet
+                crate::power::request_shutdown();
+                tokio::time::sleep(STOP_WAIT_HINT).await;
+            } => Ok(()),
+        }
+    });
+
+    let exit_code = match result {
+        Ok(()) => 0,
+        Err(e) => {
+            // A service has no console, so this goes to the Event Log and the
+            // log file
+            crate::logging::error!("Error: {:#}", e);
+            if let Some(code) = crate::errors::code_of(&e) {
+                crate::logging::error!("Hint ({}): {}", code, code.hint);
+            }
+            1
+        }
+    };
+    let _ = handle.set_service_status(scm_status(ServiceState::Stopped, exit_code));
+}
@@ -0,0 +1,337 @@
+//! Inventory snapshots
+//!
+//! Every time the inventory is listed, by `shadow inventory` or by the agent's
+//! scheduled upload, the packages are saved with the host's users, listening
+//! ports, and kernel modules (kernel extensions on macOS, drivers on Windows)
+//! as a snapshot in `inventory/` in the data directory, named by its time.
+//! `shadow inventory diff` compares two of them, showing what was added,
+//! removed, or changed in between, for change audits and spotting drift on a
+//! host without anything on the server. The newest snapshots are kept.
+
+use crate::inventory::Inventory;
+use crate::logging::warning;
+use crate::osquery;
+use crate::output::{self, OutputFormat};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+/// Directory in the data directory
+const DIR_NAME: &str = "inventory";
+
+/// Snapshots kept
+const MAX_SNAPSHOTS: usize = 50;
+
+/// Format of snapshot IDs, which sort by time
+const ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+const USERS_SQL: &str = "SELECT username, uid, gid, directory, shell FROM users";
+
+const PORTS_SQL: &str = "SELECT DISTINCT p.name, lp.port, lp.protocol, lp.address \
+                         FROM listening_ports lp LEFT JOIN processes p USING (pid) WHERE lp.port != 0";
+
+const MODULES_SQL: &str = if cfg!(target_os = "windows") {
+    "SELECT DISTINCT service AS name, version FROM drivers WHERE service != ''"
+} else if cfg!(target_os = "macos") {
+    "SELECT name, version FROM kernel_extensions"
+} else {
+    "SELECT name, '' AS version FROM kernel_modules"
+};
+
+/// What was on the host at one time, each section by key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub taken_at: DateTime<Utc>,
+    pub hostname: String,
+    /// Version by package, e.g. `deb_packages:libc6:amd64`
+    pub packages: BTreeMap<String, String>,
+    /// Account details by username
+    pub users: BTreeMap<String, String>,
+    /// Listening process by `protocol/address:port`
+    pub ports: BTreeMap<String, String>,
+    /// Version by module name
+    pub kernel_modules: BTreeMap<String, String>,
+}
+
+/// A saved snapshot, as `shadow inventory snapshots` lists it
+#[derive(Debug, Serialize)]
+struct Saved {
+    id: String,
+    taken_at: DateTime<Utc>,
+    packages: usize,
+    users: usize,
+    ports: usize,
+    kernel_modules: usize,
+}
+
+/// Take a snapshot with the packages in `inventory` and save it, warning if
+/// that fails; a snapshot is a side effect of listing the inventory
+pub async fn record(data_dir: &Path, osqueryd_path: &Path, inventory: &Inventory) {
+    let result = match take(osqueryd_path, inventory).await {
+        Ok(snapshot) => save(data_dir, &snapshot),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        warning!("Couldn't save an inventory snapshot: {:#}", e);
+    }
+}
+
+async fn take(osqueryd_path: &Path, inventory: &Inventory) -> Result<Snapshot> {
+    let users = osquery::shell_query(osqueryd_path, USERS_SQL)
+        .await
+        .context("Failed to list users")?
+        .into_iter()
+        .filter_map(|mut row| {
+            let username = row.remove("username").filter(|username| !username.is_empty())?;
+            let detail = ["uid", "gid", "directory", "shell"]
+                .iter()
+                .filter_map(|field| Some(format!("{}={}", field, row.get(*field).filter(|v| !v.is_empty())?)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            Some((username, detail))
+        })
+        .collect();
+    let ports = osquery::shell_query(osqueryd_path, PORTS_SQL)
+        .await
+        .context("Failed to list listening ports")?
+        .into_iter()
+        .map(|row| {
+            let protocol = match field(&row, "protocol") {
+                "6" => "tcp",
+                "17" => "udp",
+                other => other,
+            };
+            let key = format!("{}/{}:{}", protocol, field(&row, "address"), field(&row, "port"));
+            (key, field(&row, "name").to_string())
+        })
+        .collect();
+    let kernel_modules = osquery::shell_query(osqueryd_path, MODULES_SQL)
+        .await
+        .context("Failed to list kernel modules")?
+        .into_iter()
+        .map(|row| (field(&row, "name").to_string(), field(&row, "version").to_string()))
+        .collect();
+    Ok(Snapshot {
+        taken_at: Utc::now(),
+        hostname: inventory.hostname().to_string(),
+        packages: inventory.versions(),
+        users,
+        ports,
+        kernel_modules,
+    })
+}
+
+fn field<'a>(row: &'a HashMap<String, String>, name: &str) -> &'a str {
+    row.get(name).map_or("", String::as_str)
+}
+
+/// Save a snapshot, dropping the oldest beyond `MAX_SNAPSHOTS`
+fn save(data_dir: &Path, snapshot: &Snapshot) -> Result<()> {
+    let dir = data_dir.join(DIR_NAME);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(format!("{}.json", snapshot.taken_at.format(ID_FORMAT)));
+    let data = serde_json::to_vec(snapshot)?;
+    std::fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+    let ids = ids(data_dir)?;
+    for id in ids.iter().take(ids.len().saturating_sub(MAX_SNAPSHOTS)) {
+        let _ = std::fs::remove_file(dir.join(format!("{}.json", id)));
+    }
+    Ok(())
+}
+
+/// IDs of the saved snapshots, oldest first
+fn ids(data_dir: &Path) -> Result<Vec<String>> {
+    let dir = data_dir.join(DIR_NAME);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut ids: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let id = name.strip_suffix(".json")?;
+            chrono::NaiveDateTime::parse_from_str(id, ID_FORMAT).ok()?;
+            Some(id.to_string())
+        })
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Load a snapshot by ID, `latest`, `previous`, or path
+fn load(data_dir: &Path, name: &str) -> Result<(String, Snapshot)> {
+    let path = match name {
+        "latest" | "previous" => {
+            let ids = ids(data_dir)?;
+            let back = if name == "latest" { 1 } else { 2 };
+            let id = ids
+                .len()
+                .checked_sub(back)
+                .and_then(|i| ids.get(i))
+                .with_context(|| format!("There's no {} snapshot; run `shadow inventory` to take one", name))?;
+            data_dir.join(DIR_NAME).join(format!("{}.json", id))
+        }
+        name if Path::new(name).is_file() => PathBuf::from(name),
+        id => data_dir.join(DIR_NAME).join(format!("{}.json", id)),
+    };
+    if !path.exists() {
+        anyhow::bail!("No snapshot {}; `shadow inventory snapshots` lists them", name);
+    }
+    let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let snapshot = serde_json::from_slice(&data).with_context(|| format!("{} isn't a snapshot", path.display()))?;
+    let id = path.file_stem().map_or_else(|| name.to_string(), |stem| stem.to_string_lossy().into_owned());
+    Ok((id, snapshot))
+}
+
+/// `shadow inventory snapshots`
+pub fn list(data_dir: &Path, format: OutputFormat) -> Result<()> {
+    let mut saved = Vec::new();
+    for id in ids(data_dir)? {
+        let (id, snapshot) = load(data_dir, &id)?;
+        saved.push(Saved {
+            id,
+            taken_at: snapshot.taken_at,
+            packages: snapshot.packages.len(),
+            users: snapshot.users.len(),
+            ports: snapshot.ports.len(),
+            kernel_modules: snapshot.kernel_modules.len(),
+        });
+    }
+    output::list(format, &saved, |saved| {
+        if saved.is_empty() {
+            println!("No snapshots; run `shadow inventory` to take one");
+            return;
+        }
+        println!("{:<18} {:>9} {:>6} {:>6} {:>8}", "ID", "PACKAGES", "USERS", "PORTS", "MODULES");
+        for s in saved {
+            println!(
+                "{:<18} {:>9} {:>6} {:>6} {:>8}",
+                s.id, s.packages, s.users, s.ports, s.kernel_modules
+            );
+        }
+    })
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Changes {
+    added: Vec<Entry>,
+    removed: Vec<Entry>,
+    changed: Vec<Change>,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    detail: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Change {
+    key: String,
+    from: String,
+    to: String,
+}
+
+impl Changes {
+    fn between(from: &BTreeMap<String, String>, to: &BTreeMap<String, String>) -> Self {
+        let mut changes = Changes::default();
+        for (key, detail) in from {
+            match to.get(key) {
+                None => changes.removed.push(Entry {
+                    key: key.clone(),
+                    detail: detail.clone(),
+                }),
+                Some(now) if now != detail => changes.changed.push(Change {
+                    key: key.clone(),
+                    from: detail.clone(),
+                    to: now.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+        for (key, detail) in to {
+            if !from.contains_key(key) {
+                changes.added.push(Entry {
+                    key: key.clone(),
+                    detail: detail.clone(),
+                });
+            }
+        }
+        changes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    fn print(&self, title: &str) {
+        if self.is_empty() {
+            return;
+        }
+        println!(
+            "{} (+{} -{} ~{})",
+            title,
+            self.added.len(),
+            self.removed.len(),
+            self.changed.len()
+        );
+        for entry in &self.added {
+            println!("  + {}  {}", entry.key, entry.detail);
+        }
+        for entry in &self.removed {
+            println!("  - {}  {}", entry.key, entry.detail);
+        }
+        for change in &self.changed {
+            println!("  ~ {}  {} -> {}", change.key, change.from, change.to);
+        }
+        println!();
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Diff {
+    from: String,
+    to: String,
+    packages: Changes,
+    users: Changes,
+    ports: Changes,
+    kernel_modules: Changes,
+}
+
+/// `shadow inventory diff`
+pub fn diff(data_dir: &Path, from: &str, to: &str, format: OutputFormat) -> Result<()> {
+    let (from_id, from) = load(data_dir, from)?;
+    let (to_id, to) = load(data_dir, to)?;
+    if from.hostname != to.hostname {
+        warning!(
+            "Comparing snapshots of different hosts ({} and {})",
+            from.hostname, to.hostname
+        );
+    }
+    let diff = Diff {
+        from: from_id,
+        to: to_id,
+        packages: Changes::between(&from.packages, &to.packages),
+        users: Changes::between(&from.users, &to.users),
+        ports: Changes::between(&from.ports, &to.ports),
+        kernel_modules: Changes::between(&from.kernel_modules, &to.kernel_modules),
+    };
+    output::one(format, &diff, |diff| {
+        println!("{} -> {}", diff.from, diff.to);
+        println!();
+        diff.packages.print("Packages");
+        diff.users.print("Users");
+        diff.ports.print("Listening ports");
+        diff.kernel_modules.print("Kernel modules");
+        if [&diff.packages, &diff.users, &diff.ports, &diff.kernel_modules]
+            .iter()
+            .all(|changes| changes.is_empty())
+        {
+            println!("No changes");
+        }
+    })
+}
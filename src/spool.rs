@@ -0,0 +1,308 @@
+//! Offline log spool
+//!
+//! osqueryd buffers the result and status logs it can't send in its database,
+//! but only up to `--buffered_log_max` lines, so a laptop that's offline for
+//! days loses most of its scheduled query history. With the TLS relay (see
+//! [`crate::relay`]), logger requests the server can't take are written to a
+//! spool in the data directory instead and osqueryd is told they were
+//! delivered. The relay replays the spool in order once the server is back,
+//! spooling newer batches behind it meanwhile so nothing arrives out of order.
+//! The spool is capped in size, dropping the oldest batches first, and
+//! survives agent restarts. Live query results the server can't take are kept
+//! in a spool of their own the same way, and retried with backoff rather than
+//! left to osqueryd's few quick retries. `shadow replay` lists what's spooled
+//! and has the agent try to send it straight away.
+
+use crate::atrest;
+use crate::dashboard;
+use crate::logging::warning;
+use crate::output::{self, OutputFormat};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Directory for logs in the data directory
+pub const DIR_NAME: &str = "log_spool";
+
+/// Directory for live query results in the data directory
+pub const LIVE_DIR_NAME: &str = "live_spool";
+
+/// Extension of spooled batches
+const EXTENSION: &str = "batch";
+
+/// A logger or distributed write request, as osqueryd sent it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Batch {
+    pub path_and_query: String,
+    /// Where it's routed, if not to the server
+    #[serde(default)]
+    pub server: Option<String>,
+    /// osqueryd's own headers, e.g. its content type and encoding
+    pub headers: Vec<(String, String)>,
+    #[serde(skip)]
+    pub body: Vec<u8>,
+}
+
+/// Batches waiting for the server, oldest first
+pub struct Spool {
+    dir: PathBuf,
+    /// What's spooled and the flag that caps it, for warnings
+    what: &'static str,
+    flag: &'static str,
+    max_bytes: u64,
+    queue: Mutex<Queue>,
+}
+
+#[derive(Default)]
+struct Queue {
+    /// Sequence number and size of each batch
+    entries: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next: u64,
+}
+
+impl Spool {
+    /// Open the log spool in `data_dir`, picking up batches left by an earlier run
+    pub fn open(data_dir: &Path, max_bytes: u64) -> Result<Self> {
+        Self::open_in(data_dir.join(DIR_NAME), "log", "--relay-spool-max-mb", max_bytes)
+    }
+
+    /// Open the live query result spool in `data_dir`
+    pub fn open_live(data_dir: &Path, max_bytes: u64) -> Result<Self> {
+        Self::open_in(data_dir.join(LIVE_DIR_NAME), "live query result", "--relay-live-spool-max-mb", max_bytes)
+    }
+
+    fn open_in(dir: PathBuf, what: &'static str, flag: &'static str, max_bytes: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut queue = Queue::default();
+        let mut entries: Vec<(u64, u64)> = std::fs::read_dir(&dir)?
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != EXTENSION {
+                    // Left over from an interrupted write
+                    let _ = std::fs::remove_file(&path);
+                    return None;
+                }
+                let seq = path.file_stem()?.to_str()?.parse().ok()?;
+                Some((seq, entry.metadata().ok()?.len()))
+            })
+            .collect();
+        entries.sort_unstable();
+        queue.next = entries.last().map_or(0, |(seq, _)| seq + 1);
+        queue.bytes = entries.iter().map(|(_, size)| size).sum();
+        queue.entries = entries.into();
+        Ok(Spool {
+            dir,
+            what,
+            flag,
+            max_bytes,
+            queue: Mutex::new(queue),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, EXTENSION))
+    }
+
+    /// Batches waiting, and their total size
+    pub fn usage(&self) -> (usize, u64) {
+        let queue = self.queue.lock().unwrap();
+        (queue.entries.len(), queue.bytes)
+    }
+
+    /// What's spooled, e.g. `log`
+    pub fn what(&self) -> &'static str {
+        self.what
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().entries.is_empty()
+    }
+
+    /// Add a batch to the end, dropping the oldest ones if that goes over the cap
+    pub fn push(&self, batch: &Batch) -> Result<()> {
+        let mut data = serde_json::to_vec(batch)?;
+        data.push(b'\n');
+        data.extend_from_slice(&batch.body);
+        let data = atrest::seal(&data)?;
+
+        let mut queue = self.queue.lock().unwrap();
+        let seq = queue.next;
+        let path = self.path(seq);
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, &data)
+            .and_then(|_| std::fs::rename(&temp, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        queue.next += 1;
+        queue.entries.push_back((seq, data.len() as u64));
+        queue.bytes += data.len() as u64;
+
+        let mut dropped = 0;
+        while queue.bytes > self.max_bytes && queue.entries.len() > 1 {
+            if let Some((seq, size)) = queue.entries.pop_front() {
+                let _ = std::fs::remove_file(self.path(seq));
+                queue.bytes -= size;
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            warning!(
+                "The {} spool is full, so the oldest {} batches were dropped. Raise {} to keep more while the server is unreachable.",
+                self.what,
+                dropped,
+                self.flag
+            );
+        }
+        Ok(())
+    }
+
+    /// The oldest batch, with its sequence number for [`Spool::remove`]
+    pub fn front(&self) -> Option<(u64, Batch)> {
+        loop {
+            let (seq, _) = *self.queue.lock().unwrap().entries.front()?;
+            match self.read(seq) {
+                Ok(batch) => return Some((seq, batch)),
+                Err(e) => {
+                    warning!("Dropping an unreadable batch from the {} spool: {:#}", self.what, e);
+                    self.remove(seq);
+                }
+            }
+        }
+    }
+
+    fn read(&self, seq: u64) -> Result<Batch> {
+        let path = self.path(seq);
+        let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let data = atrest::open(data).with_context(|| format!("Failed to decrypt {}", path.display()))?;
+        let split = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .with_context(|| format!("{} is truncated", path.display()))?;
+        let mut batch: Batch = serde_json::from_slice(&data[..split])
+            .with_context(|| format!("{} is corrupt", path.display()))?;
+        batch.body = data[split + 1..].to_vec();
+        Ok(batch)
+    }
+
+    /// Remove a batch once it's been delivered (or can't ever be)
+    pub fn remove(&self, seq: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(idx) = queue.entries.iter().position(|(s, _)| *s == seq) {
+            if let Some((_, size)) = queue.entries.remove(idx) {
+                queue.bytes -= size;
+            }
+            let _ = std::fs::remove_file(self.path(seq));
+        }
+    }
+}
+
+/// A spooled batch, as `shadow replay --list` shows it
+#[derive(Debug, Serialize)]
+pub struct Spooled {
+    /// Which spool it's in, `log` or `live query result`
+    pub spool: &'static str,
+    pub id: u64,
+    pub bytes: u64,
+    pub spooled_at: DateTime<Utc>,
+}
+
+/// What's in the spools in `data_dir`, oldest first in each
+pub fn spooled(data_dir: &Path) -> Result<Vec<Spooled>> {
+    let mut spooled = Vec::new();
+    for (name, what) in [(DIR_NAME, "log"), (LIVE_DIR_NAME, "live query result")] {
+        let dir = data_dir.join(name);
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+        };
+        let mut batches: Vec<Spooled> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != EXTENSION {
+                    return None;
+                }
+                let metadata = entry.metadata().ok()?;
+                Some(Spooled {
+                    spool: what,
+                    id: path.file_stem()?.to_str()?.parse().ok()?,
+                    bytes: metadata.len(),
+                    spooled_at: metadata.modified().ok()?.into(),
+                })
+            })
+            .collect();
+        batches.sort_unstable_by_key(|batch| batch.id);
+        spooled.extend(batches);
+    }
+    Ok(spooled)
+}
+
+/// One line per spool with batches in it, e.g. for `shadow replay`
+pub fn summarize(spooled: &[Spooled]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for what in ["log", "live query result"] {
+        let batches: Vec<&Spooled> = spooled.iter().filter(|batch| batch.spool == what).collect();
+        let Some(oldest) = batches.iter().map(|batch| batch.spooled_at).min() else {
+            continue;
+        };
+        let bytes: u64 = batches.iter().map(|batch| batch.bytes).sum();
+        lines.push(format!(
+            "The {} spool has {} batches ({} MB), the oldest from {} ago",
+            what,
+            batches.len(),
+            bytes.div_ceil(1024 * 1024),
+            dashboard::duration(age(oldest))
+        ));
+    }
+    lines
+}
+
+fn age(time: DateTime<Utc>) -> u64 {
+    (Utc::now() - time).num_seconds().max(0) as u64
+}
+
+/// `shadow replay --list`
+pub fn list(data_dir: &Path, format: OutputFormat) -> Result<()> {
+    let spooled = spooled(data_dir)?;
+    output::list(format, &spooled, |spooled| {
+        if spooled.is_empty() {
+            println!("Nothing is spooled");
+            return;
+        }
+        println!("{:<18} {:>20} {:>10} {:>8}", "SPOOL", "ID", "SIZE (KB)", "AGE");
+        for batch in spooled {
+            println!(
+                "{:<18} {:>20} {:>10} {:>8}",
+                batch.spool,
+                batch.id,
+                batch.bytes.div_ceil(1024),
+                dashboard::duration(age(batch.spooled_at))
+            );
+        }
+        println!();
+        for line in summarize(spooled) {
+            println!("{}", line);
+        }
+    })
+}
+
+/// What a replay got through in one spool
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replayed {
+    /// Which spool, `log` or `live query result`
+    pub spool: String,
+    pub sent: usize,
+    /// Batches the server rejected, which were dropped
+    pub rejected: usize,
+    /// Batches still spooled, and their size
+    pub left: usize,
+    pub left_bytes: u64,
+    /// Whether the server took every batch it was sent
+    pub reachable: bool,
+}
@@ -0,0 +1,230 @@
+//! StatsD export
+//!
+//! With `--statsd`, the agent's metrics (the same ones sent over OTLP) are
+//! also sent as StatsD over UDP, for shops that run StatsD, Telegraf, or a
+//! Datadog agent rather than an OpenTelemetry collector. It's a metric exporter
+//! for the OpenTelemetry SDK, so instruments are shared: gauges are sent as
+//! gauges, counters as the increase since the last flush, and histograms as
+//! one averaged sample per flush with a sample rate standing in for the count.
+//! DogStatsD (the default) carries attributes and `--statsd-tag` as tags; plain
+//! StatsD has no tags, so attribute values are appended to the metric name.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::error::{OTelSdkError, OTelSdkResult};
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData, ResourceMetrics};
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::Temporality;
+use std::fmt::Display;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Largest datagram sent, small enough not to fragment on a typical MTU
+const MAX_PACKET: usize = 1432;
+
+/// Metric names start with this, replaced by `--statsd-prefix`
+const NAMESPACE: &str = "shadow.";
+
+/// StatsD dialect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Etsy StatsD, without tags
+    Statsd,
+    /// DogStatsD, with tags (also understood by Telegraf and statsd_exporter)
+    Dogstatsd,
+}
+
+/// Where and how to send
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// host:port of the StatsD server
+    pub address: String,
+    /// Prepended to metric names in place of `shadow`, empty for none
+    pub prefix: String,
+    /// Tags sent with every metric, e.g. env:prod
+    pub tags: Vec<String>,
+    pub format: Format,
+    /// How often metrics are sent
+    pub interval: Duration,
+}
+
+#[derive(Debug)]
+pub struct StatsdExporter {
+    config: StatsdConfig,
+}
+
+impl StatsdExporter {
+    pub fn new(config: StatsdConfig) -> Self {
+        StatsdExporter { config }
+    }
+
+    /// The StatsD name for an OpenTelemetry metric name
+    fn name(&self, name: &str) -> String {
+        let name = name.strip_prefix(NAMESPACE).unwrap_or(name);
+        match self.config.prefix.trim_end_matches('.') {
+            "" => name.to_string(),
+            prefix => format!("{}.{}", prefix, name),
+        }
+    }
+
+    /// One StatsD line
+    fn line(
+        &self,
+        name: &str,
+        attributes: &[&KeyValue],
+        value: impl Display,
+        kind: &str,
+        rate: Option<f64>,
+    ) -> String {
+        let mut line = name.to_string();
+        if self.config.format == Format::Statsd {
+            for attribute in attributes {
+                line.push('.');
+                line.push_str(&sanitize(&attribute.value.as_str()).replace('.', "_"));
+            }
+        }
+        line.push_str(&format!(":{}|{}", value, kind));
+        if let Some(rate) = rate {
+            line.push_str(&format!("|@{}", rate));
+        }
+        if self.config.format == Format::Dogstatsd {
+            let tags: Vec<String> = attributes
+                .iter()
+                .map(|attribute| {
+                    format!("{}:{}", attribute.key, sanitize(&attribute.value.as_str()))
+                })
+                .chain(self.config.tags.iter().cloned())
+                .collect();
+            if !tags.is_empty() {
+                line.push_str("|#");
+                line.push_str(&tags.join(","));
+            }
+        }
+        line
+    }
+
+    fn lines<T: Value>(&self, name: &str, unit: &str, data: &MetricData<T>, out: &mut Vec<String>) {
+        let name = self.name(name);
+        match data {
+            MetricData::Gauge(gauge) => {
+                for point in gauge.data_points() {
+                    let attributes: Vec<&KeyValue> = point.attributes().collect();
+                    out.push(self.line(&name, &attributes, point.value(), "g", None));
+                }
+            }
+            MetricData::Sum(sum) => {
+                let kind = if sum.is_monotonic() { "c" } else { "g" };
+                for point in sum.data_points() {
+                    let attributes: Vec<&KeyValue> = point.attributes().collect();
+                    out.push(self.line(&name, &attributes, point.value(), kind, None));
+                }
+            }
+            MetricData::Histogram(histogram) => {
+                for point in histogram.data_points().filter(|point| point.count() > 0) {
+                    let attributes: Vec<&KeyValue> = point.attributes().collect();
+                    let average = point.sum().to_f64() / point.count() as f64;
+                    let rate = (point.count() > 1).then(|| 1.0 / point.count() as f64);
+                    let line = match self.config.format {
+                        Format::Dogstatsd => self.line(&name, &attributes, average, "h", rate),
+                        // Plain StatsD's timers are in milliseconds
+                        Format::Statsd if unit == "s" => {
+                            self.line(&name, &attributes, average * 1000.0, "ms", rate)
+                        }
+                        Format::Statsd => self.line(&name, &attributes, average, "ms", rate),
+                    };
+                    out.push(line);
+                }
+            }
+            MetricData::ExponentialHistogram(_) => {}
+        }
+    }
+
+    /// Send lines, packing as many into each datagram as fit. The address is
+    /// resolved each time, so a StatsD server that moves is followed.
+    fn send(&self, lines: &[String]) -> Result<()> {
+        let address = self
+            .config
+            .address
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {}", self.config.address))?
+            .next()
+            .with_context(|| format!("{} has no addresses", self.config.address))?;
+        let local: SocketAddr = match address {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+                socket.send_to(packet.as_bytes(), address)?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(line);
+        }
+        if !packet.is_empty() {
+            socket.send_to(packet.as_bytes(), address)?;
+        }
+        Ok(())
+    }
+}
+
+impl PushMetricExporter for StatsdExporter {
+    async fn export(&self, metrics: &ResourceMetrics) -> OTelSdkResult {
+        let mut lines = Vec::new();
+        for metric in metrics.scope_metrics().flat_map(|scope| scope.metrics()) {
+            match metric.data() {
+                AggregatedMetrics::F64(data) => {
+                    self.lines(metric.name(), metric.unit(), data, &mut lines)
+                }
+                AggregatedMetrics::U64(data) => {
+                    self.lines(metric.name(), metric.unit(), data, &mut lines)
+                }
+                AggregatedMetrics::I64(_) => {}
+            }
+        }
+        self.send(&lines).map_err(|e| {
+            OTelSdkError::InternalFailure(format!("Failed to send StatsD metrics: {:#}", e))
+        })
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        Ok(())
+    }
+
+    fn shutdown_with_timeout(&self, _timeout: Duration) -> OTelSdkResult {
+        Ok(())
+    }
+
+    /// Counters are sent as increments
+    fn temporality(&self) -> Temporality {
+        Temporality::Delta
+    }
+}
+
+/// A metric value we can send
+trait Value: Copy + Display {
+    fn to_f64(self) -> f64;
+}
+
+impl Value for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+
+impl Value for u64 {
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+/// Keep StatsD's separators out of names and tags
+fn sanitize(value: &str) -> String {
+    value.replace([':', '|', '@', ',', '#', '\n'], "_")
+}
@@ -0,0 +1,448 @@
+//! Agent status and state machine
+//!
+//! The agent moves through explicit states (provisioning, enrolling, running,
+//! backoff, crash-loop, paused) and records when it entered each one and the
+//! last error seen. The status is shared with the heartbeat and persisted to
+//! `status.json` in the data directory on every change and on an interval,
+//! which is what `shadow status` reads. The file is replaced atomically, so
+//! readers never see a partial write, and a stale `updated_at` (or file
+//! modification time) shows the agent has hung or died without a network
+//! check.
+
+use crate::debug::DebugSession;
+use crate::errors;
+use crate::extensions::Extension;
+use crate::output::{self, OutputFormat};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Status filename within the data directory
+const STATUS_FILE: &str = "status.json";
+
+/// Where the agent is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentState {
+    /// Downloading or verifying osquery
+    Provisioning,
+    /// Enrolling with the server
+    Enrolling,
+    /// osqueryd is running
+    Running,
+    /// Waiting to restart osqueryd after it exited
+    Backoff,
+    /// osqueryd keeps exiting shortly after being started
+    CrashLoop,
+    /// Collection paused by an operator
+    Paused,
+    /// osqueryd exited cleanly and won't be restarted
+    Stopped,
+}
+
+impl fmt::Display for AgentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgentState::Provisioning => write!(f, "provisioning"),
+            AgentState::Enrolling => write!(f, "enrolling"),
+            AgentState::Running => write!(f, "running"),
+            AgentState::Backoff => write!(f, "backoff"),
+            AgentState::CrashLoop => write!(f, "crash-loop"),
+            AgentState::Paused => write!(f, "paused"),
+            AgentState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Snapshot of the agent's status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    /// PID of the shadow process that wrote this status
+    pub agent_pid: u32,
+    /// Version of the shadow process that wrote this status
+    #[serde(default)]
+    pub agent_version: String,
+    /// osqueryd's version, once known
+    #[serde(default)]
+    pub osquery_version: Option<String>,
+    /// When the status was last written
+    #[serde(default)]
+    pub updated_at: DateTime<Utc>,
+    /// When shadow itself started
+    pub agent_started_at: DateTime<Utc>,
+    /// Current state
+    pub state: AgentState,
+    /// When the current state was entered
+    pub state_since: DateTime<Utc>,
+    /// Most recent error, if any
+    pub last_error: Option<String>,
+    /// Its code from the error catalog, if it has one
+    #[serde(default)]
+    pub last_error_code: Option<String>,
+    /// PID of the running osqueryd, if any
+    pub child_pid: Option<u32>,
+    /// When the running osqueryd was started
+    pub child_started_at: Option<DateTime<Utc>>,
+    /// Number of times osqueryd has been restarted
+    pub restarts: u32,
+    /// Why osqueryd was last restarted
+    pub last_restart_reason: Option<String>,
+    /// Crash artifacts collected in the data directory, oldest first
+    #[serde(default)]
+    pub crash_artifacts: Vec<String>,
+    /// When the system last went to sleep
+    #[serde(default)]
+    pub last_sleep: Option<DateTime<Utc>>,
+    /// Why osqueryd is running in degraded mode, if it is
+    #[serde(default)]
+    pub degraded: Option<String>,
+    /// Size of osquery's database, once measured
+    #[serde(default)]
+    pub osquery_db_bytes: Option<u64>,
+    /// Times osqueryd failed to send results or status logs to the server.
+    /// Unsent logs stay buffered in osquery's database until a send succeeds.
+    #[serde(default)]
+    pub log_upload_failures: u64,
+    /// Why the most recent send failed
+    #[serde(default)]
+    pub last_log_upload_failure: Option<String>,
+    #[serde(default)]
+    pub last_log_upload_failure_at: Option<DateTime<Utc>>,
+    /// Debugging the server turned on, if any
+    #[serde(default)]
+    pub debug: Option<DebugSession>,
+    /// Scheduled queries over the slow query thresholds
+    #[serde(default)]
+    pub slow_queries: Vec<String>,
+    /// osqueryd's log batches spooled by the TLS relay until the server takes them
+    #[serde(default)]
+    pub spooled_log_batches: u64,
+    #[serde(default)]
+    pub spooled_log_bytes: u64,
+    /// Live query results the TLS relay is retrying
+    #[serde(default)]
+    pub spooled_live_batches: u64,
+    #[serde(default)]
+    pub spooled_live_bytes: u64,
+    /// Scheduled queries the local denylist keeps from osqueryd
+    #[serde(default)]
+    pub blocked_queries: Vec<String>,
+    /// When the saved config osqueryd was last given, while the server
+    /// couldn't give one, was fetched
+    #[serde(default)]
+    pub cached_config_from: Option<DateTime<Utc>>,
+    /// Whether osqueryd has the built-in packs instead of the server's config
+    #[serde(default)]
+    pub builtin_config: bool,
+    /// Extensions attached to osqueryd
+    #[serde(default)]
+    pub extensions: Vec<Extension>,
+}
+
+impl AgentStatus {
+    /// Whether collection is paused
+    pub fn paused(&self) -> bool {
+        self.state == AgentState::Paused
+    }
+}
+
+/// Seconds elapsed since `time`
+pub fn secs_since(time: DateTime<Utc>) -> u64 {
+    (Utc::now() - time).num_seconds().max(0) as u64
+}
+
+/// Status shared between the supervisor, heartbeat, and status file
+#[derive(Clone)]
+pub struct SharedStatus {
+    inner: Arc<Mutex<AgentStatus>>,
+    path: PathBuf,
+}
+
+impl SharedStatus {
+    pub fn new(data_dir: &Path, state: AgentState) -> Self {
+        let now = Utc::now();
+        let status = Self {
+            inner: Arc::new(Mutex::new(AgentStatus {
+                agent_pid: std::process::id(),
+                agent_version: env!("CARGO_PKG_VERSION").to_string(),
+                osquery_version: None,
+                updated_at: now,
+                agent_started_at: now,
+                state,
+                state_since: now,
+                last_error: None,
+                last_error_code: None,
+                child_pid: None,
+                child_started_at: None,
+                restarts: 0,
+                last_restart_reason: None,
+                crash_artifacts: crate::crash::list(data_dir),
+                last_sleep: None,
+                degraded: None,
+                osquery_db_bytes: None,
+                log_upload_failures: 0,
+                last_log_upload_failure: None,
+                last_log_upload_failure_at: None,
+                debug: None,
+                slow_queries: Vec::new(),
+                spooled_log_batches: 0,
+                spooled_log_bytes: 0,
+                spooled_live_batches: 0,
+                spooled_live_bytes: 0,
+                blocked_queries: Vec::new(),
+                cached_config_from: None,
+                builtin_config: false,
+                extensions: Vec::new(),
+            })),
+            path: data_dir.join(STATUS_FILE),
+        };
+        status.update(|_| {});
+        status
+    }
+
+    pub fn snapshot(&self) -> AgentStatus {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// Snapshot without waiting, or None if the status is locked
+    pub fn try_snapshot(&self) -> Option<AgentStatus> {
+        self.inner.try_lock().ok().map(|status| status.clone())
+    }
+
+    /// Modify the status and persist it
+    pub fn update(&self, f: impl FnOnce(&mut AgentStatus)) {
+        let mut status = self.inner.lock().unwrap();
+        f(&mut status);
+        status.updated_at = Utc::now();
+        if let Ok(data) = serde_json::to_vec_pretty(&*status) {
+            if let Err(e) = write_atomic(&self.path, &data) {
+                eprintln!("Failed to write status file: {}", e);
+            }
+        }
+    }
+
+    /// Rewrite the status file on an interval, so its age shows whether the
+    /// agent is still alive
+    pub fn spawn_refresh(&self, interval: Duration) {
+        let status = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let status = status.clone();
+                let _ = tokio::task::spawn_blocking(move || status.update(|_| {})).await;
+            }
+        });
+    }
+
+    /// Record osqueryd's version
+    pub fn set_osquery_version(&self, version: Option<String>) {
+        self.update(|status| status.osquery_version = version);
+    }
+
+    /// Enter a new state
+    pub fn set_state(&self, state: AgentState) {
+        self.update(|status| {
+            if status.state != state {
+                status.state = state;
+                status.state_since = Utc::now();
+            }
+        });
+    }
+
+    /// Record osqueryd failing to send logs to the server
+    pub fn log_upload_failed(&self, message: String) {
+        self.update(|status| {
+            status.log_upload_failures += 1;
+            status.last_log_upload_failure = Some(message);
+            status.last_log_upload_failure_at = Some(Utc::now());
+        });
+    }
+
+    /// Record an error without changing state
+    pub fn set_error(&self, error: impl Into<String>) {
+        let error = error.into();
+        self.update(|status| {
+            status.last_error = Some(error);
+            status.last_error_code = None;
+        });
+    }
+
+    /// Record an error and its code without changing state
+    pub fn set_failure(&self, error: &anyhow::Error) {
+        let code = errors::code_of(error);
+        self.update(|status| {
+            status.last_error = Some(format!("{:#}", error));
+            status.last_error_code = code.map(|code| code.code.to_string());
+        });
+    }
+}
+
+/// Replace a file by writing a temporary file beside it and renaming it over
+/// the original
+fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, path)
+}
+
+/// Read the status written by the agent
+pub async fn read(data_dir: &Path) -> Result<AgentStatus> {
+    let data = tokio::fs::read(data_dir.join(STATUS_FILE))
+        .await
+        .context("No status found - has shadow been started with this data directory?")?;
+    serde_json::from_slice(&data).context("Failed to parse status file")
+}
+
+/// Whether the agent process that wrote the status is still alive
+pub fn agent_alive(status: &AgentStatus) -> bool {
+    use sysinfo::{Pid, ProcessesToUpdate, System};
+
+    let pid = Pid::from_u32(status.agent_pid);
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    system.process(pid).is_some()
+}
+
+/// `shadow status` from the status file, when the agent isn't answering on
+/// its control socket
+pub async fn print(data_dir: &Path, format: OutputFormat) -> Result<()> {
+    let status = read(data_dir).await?;
+    let alive = agent_alive(&status);
+    show(&status, alive, format)
+}
+
+/// A status as printed, with whether the agent that wrote it is running
+#[derive(Serialize)]
+struct Shown<'a> {
+    #[serde(flatten)]
+    status: &'a AgentStatus,
+    agent_running: bool,
+}
+
+/// Print a status, noting whether the agent that wrote it is running
+pub fn show(status: &AgentStatus, alive: bool, format: OutputFormat) -> Result<()> {
+    let shown = Shown {
+        status,
+        agent_running: alive,
+    };
+    output::one(format, &shown, |shown| table(shown.status, shown.agent_running))
+}
+
+fn table(status: &AgentStatus, alive: bool) {
+    let ago = |time: DateTime<Utc>| format!("{} ({}s ago)", time.to_rfc3339(), secs_since(time));
+
+    if alive {
+        println!("Agent:     running (pid {})", status.agent_pid);
+    } else {
+        println!("Agent:     not running (last pid {})", status.agent_pid);
+    }
+    if !status.agent_version.is_empty() {
+        println!("  Version:   {}", status.agent_version);
+    }
+    println!("  Started:   {}", ago(status.agent_started_at));
+    println!("  Updated:   {}", ago(status.updated_at));
+    println!("  State:     {}", status.state);
+    println!("  Since:     {}", ago(status.state_since));
+    if let Some(version) = &status.osquery_version {
+        println!("  osquery:   {}", version);
+    }
+    if let Some(pid) = status.child_pid {
+        println!("  osqueryd:  pid {}", pid);
+    }
+    if let Some(started) = status.child_started_at {
+        println!("             started {}", ago(started));
+    }
+    if let Some(degraded) = &status.degraded {
+        println!("  Degraded:  {}", degraded);
+    }
+    if let Some(bytes) = status.osquery_db_bytes {
+        println!("  Database:  {} MB", bytes / (1024 * 1024));
+    }
+    if let Some(at) = status.last_log_upload_failure_at {
+        println!(
+            "  Log upload failures: {} (latest {})",
+            status.log_upload_failures,
+            ago(at)
+        );
+        if let Some(message) = &status.last_log_upload_failure {
+            println!("             {}", message);
+        }
+    }
+    if let Some(from) = status.cached_config_from {
+        println!("  Config:    saved {}, as the server can't give one", ago(from));
+    } else if status.builtin_config {
+        println!("  Config:    built-in query packs");
+    }
+    if !status.blocked_queries.is_empty() {
+        println!("  Blocked queries: {}", status.blocked_queries.join(", "));
+    }
+    for extension in &status.extensions {
+        println!(
+            "  Extension: {} {} ({})",
+            extension.name,
+            extension.version,
+            extension
+                .binary
+                .as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "binary unknown".to_string())
+        );
+    }
+    if status.spooled_log_batches > 0 {
+        println!(
+            "  Log spool: {} batches ({} MB) waiting for the server",
+            status.spooled_log_batches,
+            status.spooled_log_bytes.div_ceil(1024 * 1024)
+        );
+    }
+    if status.spooled_live_batches > 0 {
+        println!(
+            "  Live query result spool: {} batches ({} MB) waiting for the server",
+            status.spooled_live_batches,
+            status.spooled_live_bytes.div_ceil(1024 * 1024)
+        );
+    }
+    if let Some(debug) = &status.debug {
+        println!(
+            "  Debugging: until {} (log filter `{}`{})",
+            debug.until.to_rfc3339(),
+            debug.log_filter,
+            if debug.osqueryd_verbose { ", osqueryd verbose" } else { "" }
+        );
+    }
+    if !status.slow_queries.is_empty() {
+        println!("  Slow queries: {}", status.slow_queries.join(", "));
+    }
+    println!("  Restarts:  {}", status.restarts);
+    if let Some(reason) = &status.last_restart_reason {
+        println!("  Last restart: {}", reason);
+    }
+    if let Some(latest) = status.crash_artifacts.last() {
+        println!(
+            "  Crash artifacts: {} (latest {})",
+            status.crash_artifacts.len(),
+            latest
+        );
+    }
+    if let Some(sleep) = status.last_sleep {
+        println!("  Last sleep:   {}", ago(sleep));
+    }
+    if let Some(error) = &status.last_error {
+        println!("  Last error:   {}", error);
+    }
+    if let Some(code) = status.last_error_code.as_deref().and_then(errors::lookup) {
+        println!("  Hint:         {}", code.hint);
+    }
+}
@@ -0,0 +1,247 @@
+//! osqueryd status log events
+//!
+//! osqueryd's status logs in `osquery_logs/` record things operators want to
+//! chart and alert on, which otherwise sit on disk as text: config refreshes,
+//! enrollment, queries denylisted after the watchdog killed a worker running
+//! them, and event publishers that failed. A task follows the newest INFO file
+//! (glog writes warnings and errors there too) and turns those lines into
+//! structured log events, with an `event` field and the query or publisher
+//! they concern, and the `shadow.osqueryd.events` metric, counted by event.
+//! Reading starts at the end of the file osqueryd was writing when the agent
+//! started, and at the beginning of each new file it opens after a restart.
+
+use crate::logging::{debug, info, warning};
+use crate::telemetry;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How often the log is read
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most read from a file in one go, so a flood can't hold a blocking thread
+const MAX_READ: u64 = 4 * 1024 * 1024;
+
+/// Status log files, e.g. `osqueryd.INFO.20261016-151500.1234`
+const INFO_PREFIX: &str = "osqueryd.INFO";
+
+/// osqueryd fetching its config from the server
+const CONFIG_REFRESH_MARKERS: &[&str] = &["Refreshing configuration state", "Config refresh"];
+
+/// osqueryd receiving its node key
+const ENROLLED_MARKERS: &[&str] = &["Successfully enrolled", "Enrolled with node key"];
+
+/// A scheduled query being skipped after it took a worker down
+const DENYLIST_MARKERS: &[&str] = &["denylist", "blacklist"];
+
+/// An event publisher failing to set up or stopping
+const PUBLISHER_PREFIX: &str = "Event publisher ";
+
+/// Notable status log events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    ConfigRefresh,
+    Enrolled,
+    QueryDenylisted,
+    PublisherFailed,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::ConfigRefresh => "config_refresh",
+            Kind::Enrolled => "enrolled",
+            Kind::QueryDenylisted => "query_denylisted",
+            Kind::PublisherFailed => "publisher_failed",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Event {
+    kind: Kind,
+    /// The query or publisher concerned
+    subject: Option<String>,
+    message: String,
+}
+
+/// Where reading got to
+#[derive(Default)]
+struct Position {
+    path: Option<PathBuf>,
+    offset: u64,
+    /// The start of a line osqueryd hasn't finished writing
+    partial: Vec<u8>,
+}
+
+/// Follow osqueryd's status logs in `dir` until the agent exits
+pub fn spawn(dir: PathBuf) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(INTERVAL);
+        let mut position: Option<Position> = None;
+        loop {
+            ticker.tick().await;
+            let dir = dir.clone();
+            let polled = tokio::task::spawn_blocking(move || {
+                let mut position = position.unwrap_or_else(|| start(&dir));
+                let lines = read(&dir, &mut position);
+                (position, lines)
+            })
+            .await;
+            let Ok((next, lines)) = polled else {
+                return;
+            };
+            position = Some(next);
+            for event in lines.iter().filter_map(|line| parse_line(line)) {
+                record(event);
+            }
+        }
+    });
+}
+
+/// The newest status log, following none of glog's symlinks
+fn newest(dir: &Path) -> Option<PathBuf> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(INFO_PREFIX))
+        .filter_map(|entry| {
+            let metadata = std::fs::symlink_metadata(entry.path()).ok()?;
+            metadata.is_file().then(|| {
+                (
+                    metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    entry.path(),
+                )
+            })
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+/// Skip what was logged before the agent started
+fn start(dir: &Path) -> Position {
+    let path = newest(dir);
+    let offset = path
+        .as_ref()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .map_or(0, |metadata| metadata.len());
+    Position {
+        path,
+        offset,
+        partial: Vec::new(),
+    }
+}
+
+/// Complete lines written since the last read
+fn read(dir: &Path, position: &mut Position) -> Vec<String> {
+    let Some(path) = newest(dir) else {
+        return Vec::new();
+    };
+    if position.path.as_ref() != Some(&path) {
+        *position = Position {
+            path: Some(path.clone()),
+            ..Position::default()
+        };
+    }
+    let Ok(mut file) = std::fs::File::open(&path) else {
+        return Vec::new();
+    };
+    let len = file.metadata().map_or(0, |metadata| metadata.len());
+    if len < position.offset {
+        // Truncated, so start over
+        position.offset = 0;
+        position.partial.clear();
+    }
+    if file.seek(SeekFrom::Start(position.offset)).is_err() {
+        return Vec::new();
+    }
+    let mut data = std::mem::take(&mut position.partial);
+    let Ok(read) = file.take(MAX_READ).read_to_end(&mut data) else {
+        return Vec::new();
+    };
+    position.offset += read as u64;
+
+    let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |idx| idx + 1);
+    position.partial = data.split_off(complete);
+    String::from_utf8_lossy(&data)
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Strip the glog prefix (`I0102 03:04:05.678901 1234 config.cpp:123] `)
+fn log_message(line: &str) -> &str {
+    match line.find("] ") {
+        Some(idx) => &line[idx + 2..],
+        None => line,
+    }
+}
+
+/// The name after the last `: `, e.g. the query in `Denylisting query: pack_foo_bar`
+fn last_field(message: &str) -> Option<String> {
+    let (_, name) = message.rsplit_once(": ")?;
+    let name = name.trim();
+    (!name.is_empty() && !name.contains(' ')).then(|| name.to_string())
+}
+
+fn parse_line(line: &str) -> Option<Event> {
+    let message = log_message(line).trim();
+    let event = |kind, subject| {
+        Some(Event {
+            kind,
+            subject,
+            message: message.to_string(),
+        })
+    };
+
+    if let Some(rest) = message.strip_prefix(PUBLISHER_PREFIX) {
+        // `Event publisher failed setup: inotify: ...` or
+        // `Event publisher auditeventpublisher run loop terminated for reason: ...`
+        if let Some(rest) = rest.strip_prefix("failed setup: ") {
+            let publisher = rest.split(':').next().map(|name| name.trim().to_string());
+            return event(Kind::PublisherFailed, publisher);
+        }
+        if let Some((publisher, _)) = rest.split_once(" run loop terminated") {
+            return event(Kind::PublisherFailed, Some(publisher.trim().to_string()));
+        }
+        return None;
+    }
+    let lowercase = message.to_ascii_lowercase();
+    if DENYLIST_MARKERS.iter().any(|m| lowercase.contains(m)) {
+        return event(Kind::QueryDenylisted, last_field(message));
+    }
+    if ENROLLED_MARKERS.iter().any(|m| message.contains(m)) {
+        return event(Kind::Enrolled, None);
+    }
+    if CONFIG_REFRESH_MARKERS.iter().any(|m| message.contains(m)) {
+        return event(Kind::ConfigRefresh, None);
+    }
+    None
+}
+
+fn record(event: Event) {
+    telemetry::record_osqueryd_event(event.kind.as_str());
+    let subject = event.subject.as_deref().unwrap_or_default();
+    match event.kind {
+        Kind::ConfigRefresh => debug!(
+            event = event.kind.as_str(),
+            "osqueryd refreshed its config"
+        ),
+        Kind::Enrolled => info!(
+            event = event.kind.as_str(),
+            "osqueryd enrolled with the server"
+        ),
+        Kind::QueryDenylisted => warning!(
+            event = event.kind.as_str(),
+            query = subject,
+            "osqueryd denylisted a scheduled query: {}",
+            event.message
+        ),
+        Kind::PublisherFailed => warning!(
+            event = event.kind.as_str(),
+            publisher = subject,
+            "An osqueryd event publisher failed: {}",
+            event.message
+        ),
+    }
+}
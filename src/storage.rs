@@ -0,0 +1,90 @@
+//! Data directory health
+//!
+//! osqueryd keeps its RocksDB database and logs in the data directory, and
+//! crash-loops when it fills up or is remounted read-only. The data directory is
+//! probed before each start and periodically while osqueryd runs; while it is
+//! unwritable osqueryd runs in a degraded mode with an in-memory database, a
+//! capped result buffer, and logs in the temp directory, and it is restarted
+//! normally once the directory recovers.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Duration;
+use tokio::fs;
+
+/// Probe file written to check the data directory
+const PROBE_FILE: &str = ".write-probe";
+
+/// Bytes written by the probe, enough to catch a nearly-full filesystem
+const PROBE_SIZE: usize = 64 * 1024;
+
+/// How often to re-check while osqueryd is running
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Results to buffer in memory while degraded, before the oldest are dropped
+const DEGRADED_BUFFERED_LOG_MAX: &str = "10000";
+
+/// Whether the data directory can be written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    Writable,
+    /// ENOSPC or out of quota
+    Full,
+    /// EROFS
+    ReadOnly,
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Storage::Writable => write!(f, "data directory is writable"),
+            Storage::Full => write!(f, "data directory is full"),
+            Storage::ReadOnly => write!(f, "data directory is read-only"),
+        }
+    }
+}
+
+/// Check whether the data directory can be written to
+pub async fn check(data_dir: &Path) -> Storage {
+    let path = data_dir.join(PROBE_FILE);
+    let result = fs::write(&path, [0u8; PROBE_SIZE]).await;
+    let _ = fs::remove_file(&path).await;
+    match result {
+        Err(e) if e.kind() == ErrorKind::StorageFull || e.kind() == ErrorKind::QuotaExceeded => {
+            Storage::Full
+        }
+        Err(e) if e.kind() == ErrorKind::ReadOnlyFilesystem => Storage::ReadOnly,
+        // Anything else (e.g. permissions) won't be fixed by degrading
+        _ => Storage::Writable,
+    }
+}
+
+/// Wait until the data directory's condition differs from `current`
+pub async fn wait_for_change(data_dir: &Path, current: Storage) -> Storage {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let storage = check(data_dir).await;
+        if storage != current {
+            return storage;
+        }
+    }
+}
+
+/// Extra osqueryd flags for running without a writable data directory. These
+/// come after the normal flags, so they take precedence.
+pub fn degraded_flags() -> Vec<OsString> {
+    let fallback = std::env::temp_dir().join("shadow");
+    let _ = std::fs::create_dir_all(fallback.join("osquery_logs"));
+    vec![
+        "--disable_database".into(),
+        "true".into(),
+        "--buffered_log_max".into(),
+        DEGRADED_BUFFERED_LOG_MAX.into(),
+        "--logger_path".into(),
+        fallback.join("osquery_logs").into(),
+        "--pidfile".into(),
+        fallback.join("osquery.pid").into(),
+    ]
+}
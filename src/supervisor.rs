@@ -0,0 +1,870 @@
+//! osqueryd supervision
+//!
+//! Runs osqueryd as a child process, forwards its output through the watchdog
+//! monitor, and restarts it when it exits. How it restarts depends on why it
+//! exited: config/TLS failures retry slowly and alert the server, resource kills
+//! retry under the restrictive watchdog, and clean exits aren't restarted.
+//! Repeated abnormal exits within a short window put the agent in the crash-loop
+//! state. State changes are published through [`crate::status`].
+//!
+//! Maintenance restarts (see [`crate::maintenance`]) stop osqueryd gracefully
+//! and start it again immediately. While collection is paused (see
+//! [`crate::control`]) osqueryd is stopped and not restarted.
+//!
+//! The running child is recorded in the data directory so that when shadow
+//! itself restarts (e.g. during a service upgrade) it can stop the osqueryd left
+//! behind instead of starting a second instance next to it.
+//!
+//! When the system resumes from sleep (see [`crate::power`]) osqueryd is
+//! restarted, and exits across a sleep don't count towards the crash loop. On
+//! system shutdown osqueryd is stopped and not restarted.
+//!
+//! While the data directory is full or read-only (see [`crate::storage`])
+//! osqueryd runs in a degraded mode, and it is restarted whenever that changes.
+//!
+//! After a crash or resource kill, any crash artifacts the OS produced are
+//! collected (see [`crate::crash`]).
+//!
+//! When a purge of osquery's database is requested (see [`crate::database`])
+//! osqueryd is stopped, the database deleted, and osqueryd started again.
+//!
+//! Reloads and osquery upgrades asked for through the control socket (see
+//! [`crate::ipc`]) restart osqueryd, upgrading it in between.
+
+use crate::api::ApiClient;
+use crate::audit;
+use crate::control;
+use crate::crash;
+use crate::database;
+use crate::errors;
+use crate::eventlog::{self, Event};
+use crate::hardening::Hardening;
+use crate::hooks::{self, HookEvent};
+use crate::limits::ChildLimits;
+use crate::logging::{error, info, warning};
+use crate::maintenance::MaintenancePolicy;
+use crate::osquery::{get_osquery_version, osqueryd_command, OsqueryProvisioner, OSQUERY_VERSION};
+use crate::power::PowerEvent;
+use crate::status::{AgentState, SharedStatus};
+use crate::storage::{self, Storage};
+use crate::watchdog::{self, LogSummary};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, SystemTime};
+use sysinfo::{Pid, ProcessesToUpdate, Signal, System};
+use tokio::fs;
+use tokio::process::Child;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio::time::Instant;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Exponential backoff between osqueryd restarts
+#[derive(Debug, Clone, Copy)]
+pub struct BackoffPolicy {
+    /// Delay after the first failure
+    pub initial: Duration,
+    /// Factor applied to the delay after each further failure
+    pub multiplier: f64,
+    /// Upper bound on the delay; also used for config/TLS failures, which
+    /// rarely fix themselves quickly
+    pub max: Duration,
+    /// A run at least this long resets the backoff
+    pub reset_after: Duration,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(5),
+            multiplier: 2.0,
+            max: Duration::from_secs(300),
+            reset_after: Duration::from_secs(600),
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// Delay before restart number `failures` (1-based) in a row
+    fn delay(&self, failures: u32) -> Duration {
+        let factor = self.multiplier.max(1.0).powi(failures.saturating_sub(1) as i32);
+        Duration::try_from_secs_f64(self.initial.as_secs_f64() * factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+/// Abnormal exits within [`CRASH_LOOP_WINDOW`] that count as a crash loop
+const CRASH_LOOP_THRESHOLD: usize = 5;
+
+/// Window over which abnormal exits are counted for crash-loop detection
+const CRASH_LOOP_WINDOW: Duration = Duration::from_secs(600);
+
+/// File in the data directory recording the running osqueryd
+const CHILD_RECORD_FILE: &str = "osqueryd.json";
+
+/// How long osqueryd gets to shut down after SIGTERM before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why osquery can't be upgraded when its binary was given
+const UNMANAGED_OSQUERY: &str = "osqueryd was given with --osqueryd-path, so shadow doesn't manage its version";
+
+/// osquery's exit code for unrecoverable initialization failures (EX_CONFIG)
+const EXIT_CATASTROPHIC: i32 = 78;
+
+/// Something the supervisor can be asked to do while osqueryd runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Restart osqueryd, so it enrolls and fetches its config again
+    Reload,
+    /// Stop osqueryd, install an osquery version (the one this agent ships
+    /// with unless given), and start it again
+    Upgrade(Option<String>),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Reload => write!(f, "reload"),
+            Action::Upgrade(_) => write!(f, "upgrade"),
+        }
+    }
+}
+
+/// A request for an [`Action`], answered with a message for the requester
+#[derive(Debug)]
+pub struct Request {
+    pub action: Action,
+    pub requested_by: String,
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
+
+/// Why osqueryd exited
+#[derive(Debug)]
+pub enum ExitClass {
+    /// Exited successfully, e.g. after a requested shutdown
+    Clean,
+    /// Couldn't enroll, fetch its config, or talk TLS to the server
+    ConfigError(String),
+    /// Killed for exceeding resource limits (OOM killer or watchdog)
+    ResourceKill(String),
+    /// Any other abnormal exit
+    Crash(String),
+    /// Stopped by us for a scheduled maintenance restart
+    Maintenance(String),
+    /// Stopped because collection was paused
+    Paused,
+    /// Stopped after the system resumed, or exited across a system sleep
+    Resumed(String),
+    /// Stopped because the system is shutting down
+    Shutdown,
+    /// Stopped because the data directory became unwritable or recovered
+    Storage(String),
+    /// Stopped so its database can be purged
+    Purge,
+    /// Stopped for a reload or upgrade
+    Requested(Request),
+}
+
+impl ExitClass {
+    fn classify(exit_status: ExitStatus, logs: &LogSummary) -> Self {
+        if exit_status.success() {
+            return ExitClass::Clean;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            if exit_status.signal() == Some(9) {
+                return ExitClass::ResourceKill(format!(
+                    "osqueryd was killed ({}), likely by the OOM killer",
+                    exit_status
+                ));
+            }
+        }
+
+        let config_error = logs.recent_config_error();
+        if exit_status.code() == Some(EXIT_CATASTROPHIC) || config_error.is_some() {
+            let detail = config_error.unwrap_or("failed to initialize");
+            return ExitClass::ConfigError(format!("osqueryd exited with {}: {}", exit_status, detail));
+        }
+
+        if logs.watchdog_kills > 0 {
+            return ExitClass::ResourceKill(format!(
+                "osqueryd exited with {} after {} watchdog kill(s)",
+                exit_status, logs.watchdog_kills
+            ));
+        }
+
+        ExitClass::Crash(format!("osqueryd exited with {}", exit_status))
+    }
+}
+
+/// Identifies a running osqueryd across shadow restarts. The start time guards
+/// against the PID having been reused by an unrelated process.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChildRecord {
+    pid: u32,
+    start_time: u64,
+}
+
+/// Look up a process, returning None if it doesn't exist
+fn find_process(system: &mut System, pid: u32) -> Option<&sysinfo::Process> {
+    let pid = Pid::from_u32(pid);
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    system.process(pid)
+}
+
+/// Wait for the system to resume from sleep or start shutting down
+async fn wait_for_power_event(power: &mut Option<watch::Receiver<PowerEvent>>) -> PowerEvent {
+    if let Some(power) = power {
+        while power.changed().await.is_ok() {
+            let event = *power.borrow_and_update();
+            if matches!(event, PowerEvent::Resumed | PowerEvent::ShuttingDown) {
+                return event;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Stop our osqueryd child, giving it a chance to shut down cleanly
+async fn stop_child(child: &mut Child) -> Result<ExitStatus> {
+    stop_process(child, "osqueryd").await
+}
+
+/// Stop a child process, `name` in messages, giving it a chance to shut down
+/// cleanly
+pub async fn stop_process(child: &mut Child, name: &str) -> Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        let mut system = System::new();
+        if let Some(process) = find_process(&mut system, pid) {
+            process.kill_with(Signal::Term);
+        }
+        if let Ok(status) = tokio::time::timeout(STOP_TIMEOUT, child.wait()).await {
+            return Ok(status?);
+        }
+        warning!("{} (pid {}) did not stop in time, killing it", name, pid);
+    }
+    child.kill().await?;
+    Ok(child.wait().await?)
+}
+
+/// Stop the osqueryd a shadow process recorded in `data_dir`, if it was left
+/// running when that process exited
+pub async fn stop_recorded_child(data_dir: &Path) {
+    let path = data_dir.join(CHILD_RECORD_FILE);
+    let Ok(data) = fs::read(&path).await else {
+        return;
+    };
+    let _ = fs::remove_file(&path).await;
+    let Ok(record) = serde_json::from_slice::<ChildRecord>(&data) else {
+        return;
+    };
+
+    let mut system = System::new();
+    let Some(process) = find_process(&mut system, record.pid) else {
+        return;
+    };
+    if process.start_time() != record.start_time
+        || !process.name().to_string_lossy().contains("osqueryd")
+    {
+        return;
+    }
+
+    info!("Stopping osqueryd left over from a previous run (pid {})", record.pid);
+    stop_pid(record.pid, "osqueryd").await;
+}
+
+/// Stop a process that isn't our child, `name` in messages, giving it a
+/// chance to shut down cleanly
+pub async fn stop_pid(pid: u32, name: &str) {
+    let mut system = System::new();
+    let Some(process) = find_process(&mut system, pid) else {
+        return;
+    };
+    if process.kill_with(Signal::Term).is_none() {
+        // No graceful signal on this platform
+        process.kill();
+    }
+
+    let deadline = tokio::time::Instant::now() + STOP_TIMEOUT;
+    while tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        if find_process(&mut system, pid).is_none() {
+            return;
+        }
+    }
+
+    if let Some(process) = find_process(&mut system, pid) {
+        warning!("{} (pid {}) did not stop in time, killing it", name, pid);
+        process.kill();
+    }
+}
+
+#[derive(Serialize)]
+struct Alert<'a> {
+    host_id: &'a str,
+    kind: &'static str,
+    detail: &'a str,
+}
+
+/// Spawns and restarts osqueryd
+pub struct Supervisor {
+    osqueryd_path: PathBuf,
+    data_dir: PathBuf,
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    api: ApiClient,
+    status: SharedStatus,
+    maintenance: MaintenancePolicy,
+    limits: ChildLimits,
+    provisioner: Option<OsqueryProvisioner>,
+    hardening: Option<Hardening>,
+    backoff: BackoffPolicy,
+    power: Option<watch::Receiver<PowerEvent>>,
+    keep_database: bool,
+    requests: Option<Mutex<mpsc::Receiver<Request>>>,
+    standalone: bool,
+}
+
+impl Supervisor {
+    pub fn new(
+        osqueryd_path: PathBuf,
+        data_dir: PathBuf,
+        args: Vec<OsString>,
+        api: ApiClient,
+        status: SharedStatus,
+    ) -> Self {
+        Self {
+            osqueryd_path,
+            data_dir,
+            args,
+            env: Vec::new(),
+            api,
+            status,
+            maintenance: MaintenancePolicy::default(),
+            limits: ChildLimits::default(),
+            provisioner: None,
+            hardening: None,
+            backoff: BackoffPolicy::default(),
+            power: None,
+            keep_database: false,
+            requests: None,
+            standalone: false,
+        }
+    }
+
+    /// Delays between restarts
+    pub fn backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// React to system sleep, resume, and shutdown
+    pub fn power(mut self, power: watch::Receiver<PowerEvent>) -> Self {
+        self.power = Some(power);
+        self
+    }
+
+    /// Re-provision osqueryd with this provisioner if the binary goes missing
+    pub fn provisioner(mut self, provisioner: OsqueryProvisioner) -> Self {
+        self.provisioner = Some(provisioner);
+        self
+    }
+
+    /// Run osqueryd with the restrictions of `--harden-osqueryd`
+    pub fn hardening(mut self, hardening: Hardening) -> Self {
+        self.hardening = Some(hardening);
+        self
+    }
+
+    /// Cap osqueryd's memory and CPU (Windows only)
+    pub fn limits(mut self, limits: ChildLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Decline database purges, when the database holds the host's identity
+    pub fn keep_database(mut self, keep: bool) -> Self {
+        self.keep_database = keep;
+        self
+    }
+
+    /// Run without a server, so there's nowhere to send alerts
+    pub fn standalone(mut self, standalone: bool) -> Self {
+        self.standalone = standalone;
+        self
+    }
+
+    /// Take reloads and upgrades from the control socket
+    pub fn requests(mut self, requests: mpsc::Receiver<Request>) -> Self {
+        self.requests = Some(Mutex::new(requests));
+        self
+    }
+
+    /// Restart osqueryd on a schedule
+    pub fn maintenance(mut self, policy: MaintenancePolicy) -> Self {
+        self.maintenance = policy;
+        self
+    }
+
+    /// Pass an additional environment variable to osqueryd
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    fn child_record_path(&self) -> PathBuf {
+        self.data_dir.join(CHILD_RECORD_FILE)
+    }
+
+    /// Record the running osqueryd so a restarted shadow can find it
+    async fn record_child(&self, pid: u32) {
+        let mut system = System::new();
+        let Some(process) = find_process(&mut system, pid) else {
+            return;
+        };
+        let record = ChildRecord {
+            pid,
+            start_time: process.start_time(),
+        };
+        if let Ok(data) = serde_json::to_vec(&record) {
+            if let Err(e) = fs::write(self.child_record_path(), data).await {
+                error!("Failed to record osqueryd pid: {}", e);
+            }
+        }
+    }
+
+    /// Send an alert to the server, if there is one
+    async fn alert(&self, kind: &'static str, detail: &str) {
+        if self.standalone {
+            return;
+        }
+        let alert = Alert {
+            host_id: self.api.host_id(),
+            kind,
+            detail,
+        };
+        if let Err(e) = self.api.post("/api/shadow/alert", &alert).await {
+            error!("Failed to send alert: {:#}", e);
+        }
+    }
+
+    /// Publish the data directory's condition, alerting when it degrades
+    async fn report_storage(&self, storage: Storage) {
+        let degraded = (storage != Storage::Writable).then(|| storage.to_string());
+        let was_degraded = self.status.snapshot().degraded.is_some();
+        self.status.update(|status| status.degraded = degraded.clone());
+
+        match degraded {
+            Some(detail) if !was_degraded => {
+                warning!("{}, running osqueryd without its database", detail);
+                self.status.set_error(detail.clone());
+                self.alert("storage", &detail).await;
+            }
+            None if was_degraded => info!("Data directory is writable again"),
+            _ => {}
+        }
+    }
+
+    /// Run osqueryd until it exits, returning why it exited
+    #[tracing::instrument(name = "osqueryd", skip_all)]
+    async fn run_once(&self, reduced_limits: bool) -> Result<ExitClass> {
+        let storage = storage::check(&self.data_dir).await;
+        self.report_storage(storage).await;
+
+        let mut cmd = match &self.hardening {
+            Some(hardening) => hardening.command(&self.osqueryd_path),
+            None => osqueryd_command(&self.osqueryd_path),
+        };
+        cmd.args(&self.args);
+        if reduced_limits {
+            // Restrictive watchdog: lower CPU/memory limits so the watcher
+            // recycles the worker before the kernel kills the whole process
+            cmd.arg("--watchdog_level").arg("1");
+        }
+        if storage != Storage::Writable {
+            cmd.args(storage::degraded_flags());
+        }
+        if self.status.snapshot().debug.is_some_and(|debug| debug.osqueryd_verbose) {
+            cmd.args(["--verbose", "true", "--logger_stderr", "true"]);
+        }
+        cmd.envs(self.env.iter().map(|(k, v)| (k, v)));
+
+        // Capture stderr so watchdog kills can be detected and reported
+        cmd.stderr(Stdio::piped());
+
+        let spawned_at = SystemTime::now();
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => match &self.provisioner {
+                // The binary was removed or damaged underneath us
+                Some(provisioner) if !provisioner.is_provisioned().await => {
+                    error!("Failed to start osqueryd ({}), binary is missing", e);
+                    provisioner.reprovision().await?;
+                    cmd.spawn().context("Failed to start osqueryd")?
+                }
+                _ => return Err(e).context("Failed to start osqueryd"),
+            },
+        };
+        let _job = match self.limits.apply(&child) {
+            Ok(job) => job,
+            Err(e) => {
+                error!("Failed to apply resource limits to osqueryd: {:#}", e);
+                None
+            }
+        };
+        if let Some(pid) = child.id() {
+            self.record_child(pid).await;
+        }
+        let child_pid = child.id();
+        if let Some(pid) = child_pid {
+            tracing::Span::current().set_attribute("pid", pid as i64);
+        }
+        let child_started_at = chrono::Utc::now();
+        self.status.update(|status| {
+            status.child_pid = child_pid;
+            status.child_started_at = Some(child_started_at);
+            status.state = AgentState::Running;
+            status.state_since = chrono::Utc::now();
+        });
+
+        let monitor = child
+            .stderr
+            .take()
+            .map(|stderr| {
+                tokio::spawn(watchdog::monitor_stderr(stderr, self.api.clone(), self.standalone, self.status.clone()))
+            });
+
+        let restart_at = self.maintenance.next_restart(chrono::Local::now());
+        if let Some(at) = restart_at {
+            info!("Next maintenance restart of osqueryd at {}", at.format("%Y-%m-%d %H:%M %Z"));
+        }
+        let until_restart = async {
+            match restart_at {
+                Some(at) => {
+                    let delay = (at - chrono::Local::now()).to_std().unwrap_or_default();
+                    tokio::time::sleep(delay).await
+                }
+                None => std::future::pending().await,
+            }
+        };
+
+        let mut power = self.power.clone();
+        if let Some(power) = &mut power {
+            power.borrow_and_update();
+        }
+
+        let purge_requested = async {
+            match self.keep_database {
+                true => std::future::pending().await,
+                false => database::wait_for_purge(&self.data_dir).await,
+            }
+        };
+
+        let requested = async {
+            let Some(requests) = &self.requests else {
+                return std::future::pending().await;
+            };
+            let mut requests = requests.lock().await;
+            loop {
+                let Some(request) = requests.recv().await else {
+                    return std::future::pending().await;
+                };
+                // Refused without stopping osqueryd
+                if matches!(request.action, Action::Upgrade(_)) && self.provisioner.is_none() {
+                    let _ = request.reply.send(Err(UNMANAGED_OSQUERY.to_string()));
+                    continue;
+                }
+                return request;
+            }
+        };
+
+        let mut stopped_for = None;
+        let exit_status = tokio::select! {
+            exit_status = child.wait() => exit_status?,
+            _ = until_restart => {
+                info!("Restarting osqueryd for scheduled maintenance");
+                stopped_for = Some(ExitClass::Maintenance(
+                    "scheduled maintenance restart".to_string(),
+                ));
+                stop_child(&mut child).await?
+            }
+            _ = control::wait_for_pause(&self.data_dir) => {
+                info!("Stopping osqueryd, collection paused");
+                stopped_for = Some(ExitClass::Paused);
+                stop_child(&mut child).await?
+            }
+            _ = purge_requested => {
+                info!("Stopping osqueryd to purge its database");
+                stopped_for = Some(ExitClass::Purge);
+                stop_child(&mut child).await?
+            }
+            request = requested => {
+                info!("Stopping osqueryd, {} requested by {}", request.action, request.requested_by);
+                stopped_for = Some(ExitClass::Requested(request));
+                stop_child(&mut child).await?
+            }
+            storage = storage::wait_for_change(&self.data_dir, storage) => {
+                info!("Restarting osqueryd, {}", storage);
+                stopped_for = Some(ExitClass::Storage(storage.to_string()));
+                stop_child(&mut child).await?
+            }
+            event = wait_for_power_event(&mut power) => {
+                stopped_for = Some(match event {
+                    PowerEvent::ShuttingDown => {
+                        info!("Stopping osqueryd, system is shutting down");
+                        ExitClass::Shutdown
+                    }
+                    _ => {
+                        info!("Restarting osqueryd after system sleep");
+                        ExitClass::Resumed("system resumed from sleep".to_string())
+                    }
+                });
+                stop_child(&mut child).await?
+            }
+        };
+
+        // Let the monitor drain any remaining output
+        let logs = match monitor {
+            Some(monitor) => monitor.await.unwrap_or_default(),
+            None => LogSummary::default(),
+        };
+
+        let _ = fs::remove_file(self.child_record_path()).await;
+
+        self.status.update(|status| {
+            status.child_pid = None;
+            status.child_started_at = None;
+        });
+
+        let exit = stopped_for.unwrap_or_else(|| ExitClass::classify(exit_status, &logs));
+        tracing::Span::current().set_attribute("exit", format!("{:?}", exit));
+        if matches!(exit, ExitClass::Crash(_) | ExitClass::ResourceKill(_))
+            && crash::collect(&self.data_dir, child_pid, spawned_at).await > 0
+        {
+            let artifacts = crash::list(&self.data_dir);
+            self.status.update(|status| status.crash_artifacts = artifacts);
+        }
+
+        // The system may have killed osqueryd before we got to it
+        if self
+            .power
+            .as_ref()
+            .is_some_and(|power| *power.borrow() == PowerEvent::ShuttingDown)
+        {
+            return Ok(ExitClass::Shutdown);
+        }
+        let slept = self
+            .status
+            .snapshot()
+            .last_sleep
+            .is_some_and(|at| at >= child_started_at);
+        Ok(match exit {
+            ExitClass::Crash(reason) | ExitClass::ResourceKill(reason) if slept => {
+                ExitClass::Resumed(format!("{} across system sleep", reason))
+            }
+            exit => exit,
+        })
+    }
+
+    /// Install an osquery version while osqueryd is stopped
+    async fn upgrade_osquery(&self, provisioner: &OsqueryProvisioner, version: Option<&str>) -> Result<String, String> {
+        let version = version.unwrap_or(OSQUERY_VERSION);
+        match provisioner.upgrade(version).await {
+            Ok(true) => {
+                let installed = get_osquery_version(&self.osqueryd_path).await.ok();
+                hooks::fire(
+                    HookEvent::UpgradeApplied,
+                    serde_json::json!({
+                        "from": self.status.snapshot().osquery_version,
+                        "to": installed,
+                    }),
+                );
+                self.status.set_osquery_version(installed);
+                Ok(format!("Upgraded osquery to {}, restarting osqueryd", version))
+            }
+            Ok(false) => Ok(format!(
+                "osquery is already at {}, restarting osqueryd",
+                version
+            )),
+            Err(e) => {
+                error!("Failed to upgrade osquery: {:#}", e);
+                Err(format!("Failed to upgrade osquery: {:#}", e))
+            }
+        }
+    }
+
+    /// Supervise osqueryd, restarting it according to why it exited
+    pub async fn run(&self) -> Result<()> {
+        let mut reduced_limits = false;
+        let mut recent_failures: VecDeque<Instant> = VecDeque::new();
+        let mut consecutive_failures = 0;
+
+        stop_recorded_child(&self.data_dir).await;
+
+        loop {
+            if let Some(pause) = control::pause_state(&self.data_dir).await {
+                info!(
+                    "Collection paused by {}{}",
+                    pause.paused_by,
+                    pause.reason.map(|r| format!(": {}", r)).unwrap_or_default()
+                );
+                self.status.set_state(AgentState::Paused);
+                control::wait_for_resume(&self.data_dir).await;
+                info!("Collection resumed");
+            }
+            match database::purge_request(&self.data_dir).await {
+                Some(_) if self.keep_database => {
+                    warning!(
+                        "Not purging osquery's database, it holds this host's instance ID. \
+                         With --host-identifier instance, a fresh database would make this a new host on the server."
+                    );
+                    database::discard_request(&self.data_dir).await;
+                }
+                Some(request) => {
+                    if let Err(e) = database::purge(&self.data_dir, &request).await {
+                        error!("Failed to purge osquery's database: {:#}", e);
+                    }
+                }
+                None => {}
+            }
+
+            info!("Starting osqueryd...");
+            let started = Instant::now();
+            let exit = match self.run_once(reduced_limits).await {
+                Ok(exit) => exit,
+                Err(e) => {
+                    self.status.set_error(format!("{:#}", e));
+                    return Err(e);
+                }
+            };
+
+            if started.elapsed() >= self.backoff.reset_after {
+                consecutive_failures = 0;
+            }
+
+            if matches!(
+                exit,
+                ExitClass::ConfigError(_) | ExitClass::ResourceKill(_) | ExitClass::Crash(_)
+            ) {
+                consecutive_failures += 1;
+                let now = Instant::now();
+                recent_failures.push_back(now);
+                while recent_failures
+                    .front()
+                    .is_some_and(|t| now.duration_since(*t) > CRASH_LOOP_WINDOW)
+                {
+                    recent_failures.pop_front();
+                }
+            }
+
+            let (reason, delay) = match exit {
+                ExitClass::Clean => {
+                    info!("osqueryd exited cleanly, not restarting");
+                    self.status.set_state(AgentState::Stopped);
+                    return Ok(());
+                }
+                ExitClass::ConfigError(reason) => {
+                    warning!("{}", reason);
+                    self.alert("config_error", &reason).await;
+                    (reason, self.backoff.max)
+                }
+                ExitClass::ResourceKill(reason) => {
+                    eventlog::report(Event::OsqueryCrashed, &reason);
+                    hooks::fire(
+                        HookEvent::OsquerydCrashed,
+                        serde_json::json!({ "reason": reason, "resource_kill": true }),
+                    );
+                    if !reduced_limits {
+                        info!("Enabling restrictive watchdog limits");
+                        reduced_limits = true;
+                    }
+                    (reason, self.backoff.delay(consecutive_failures))
+                }
+                ExitClass::Crash(reason) => {
+                    eventlog::report(Event::OsqueryCrashed, &reason);
+                    hooks::fire(
+                        HookEvent::OsquerydCrashed,
+                        serde_json::json!({ "reason": reason, "resource_kill": false }),
+                    );
+                    (reason, self.backoff.delay(consecutive_failures))
+                }
+                ExitClass::Maintenance(reason) => (reason, Duration::ZERO),
+                ExitClass::Paused | ExitClass::Purge => continue,
+                ExitClass::Resumed(reason) => {
+                    // Failures from before the sleep say nothing about now
+                    recent_failures.clear();
+                    consecutive_failures = 0;
+                    (reason, Duration::ZERO)
+                }
+                ExitClass::Shutdown => {
+                    self.status.set_state(AgentState::Stopped);
+                    return Ok(());
+                }
+                ExitClass::Storage(reason) => (reason, Duration::ZERO),
+                ExitClass::Requested(request) => {
+                    let result = match &request.action {
+                        Action::Reload => Ok("Restarting osqueryd".to_string()),
+                        Action::Upgrade(version) => match &self.provisioner {
+                            Some(provisioner) => self.upgrade_osquery(provisioner, version.as_deref()).await,
+                            None => Err(UNMANAGED_OSQUERY.to_string()),
+                        },
+                    };
+                    let _ = request.reply.send(result);
+                    let reason = format!("{} requested by {}", request.action, request.requested_by);
+                    (reason, Duration::ZERO)
+                }
+            };
+
+            info!("{}, restarting in {}s", reason, delay.as_secs());
+            audit::record_agent(
+                &self.data_dir,
+                "osqueryd_restart",
+                serde_json::json!({ "reason": reason, "delay_secs": delay.as_secs() }),
+            )
+            .await;
+            let crash_loop = recent_failures.len() >= CRASH_LOOP_THRESHOLD;
+            if crash_loop {
+                let message = format!(
+                    "{}: osqueryd is crash-looping ({} failures in {}s)",
+                    errors::CRASH_LOOP,
+                    recent_failures.len(),
+                    CRASH_LOOP_WINDOW.as_secs()
+                );
+                warning!("{}", message);
+                if self.status.snapshot().state != AgentState::CrashLoop {
+                    eventlog::report(Event::CrashLoop, &message);
+                    hooks::fire(
+                        HookEvent::CrashLoop,
+                        serde_json::json!({
+                            "failures": recent_failures.len(),
+                            "window_secs": CRASH_LOOP_WINDOW.as_secs(),
+                            "last_reason": reason,
+                        }),
+                    );
+                }
+            }
+            self.status.update(|status| {
+                status.restarts += 1;
+                if !delay.is_zero() {
+                    status.last_error = Some(reason.clone());
+                    status.last_error_code = crash_loop.then(|| errors::CRASH_LOOP.code.to_string());
+                    status.state = if crash_loop {
+                        AgentState::CrashLoop
+                    } else {
+                        AgentState::Backoff
+                    };
+                    status.state_since = chrono::Utc::now();
+                }
+                status.last_restart_reason = Some(reason);
+            });
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Support bundles
+//!
+//! `shadow support-bundle` gathers what support usually asks for into one
+//! gzipped tarball: the agent's status, the doctor's checks, the audit log, the
+//! end of `shadow.log` and of osqueryd's newest status logs, and a manifest
+//! with the host's OS and versions. osquery's database, crash dumps, and query
+//! results are left out, and the org token is replaced wherever it appears.
+//! With `--upload`, the running agent builds the bundle, also redacting its
+//! enroll secret, and sends it to the server with its enrollment credentials,
+//! so support gets it without anyone copying files off the host.
+
+use crate::api::ApiClient;
+use crate::audit::{self, AUDIT_LOG_FILE};
+use crate::doctor::{self, Check};
+use crate::logfile;
+use crate::status::{AgentStatus, SharedStatus};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Where bundles are uploaded
+pub const UPLOAD_PATH: &str = "/api/shadow/support-bundle";
+
+/// Most of each file included, from the end
+const MAX_FILE: u64 = 5 * 1024 * 1024;
+
+/// osqueryd status logs included, newest first
+const OSQUERY_LOGS: usize = 6;
+
+/// Query results in `osquery_logs/`, which stay out of bundles
+const RESULTS_FILES: &[&str] = &["osqueryd.results", "osqueryd.snapshots"];
+
+/// Stands in for secrets
+const REDACTED: &str = "[redacted]";
+
+#[derive(Serialize)]
+struct Manifest {
+    created_at: DateTime<Utc>,
+    agent_version: &'static str,
+    os_version: Option<String>,
+    kernel_version: Option<String>,
+    arch: &'static str,
+    /// Crash artifacts in the data directory, which aren't included
+    crash_artifacts: Vec<String>,
+    files: Vec<String>,
+}
+
+/// Build a bundle from the data directory, the agent's status (if it has
+/// one), and the doctor's checks, with `secrets` redacted
+pub fn collect(
+    data_dir: &Path,
+    status: Option<&AgentStatus>,
+    checks: &[Check],
+    secrets: &[&str],
+) -> Result<Vec<u8>> {
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+    if let Some(status) = status {
+        files.push((
+            "status.json".to_string(),
+            serde_json::to_vec_pretty(status)?,
+        ));
+    }
+    files.push((
+        "doctor.json".to_string(),
+        serde_json::to_vec_pretty(checks)?,
+    ));
+    let rotated = format!("{}.1", logfile::FILE_NAME);
+    for name in [AUDIT_LOG_FILE, logfile::FILE_NAME, &rotated] {
+        if let Some(data) = tail(&data_dir.join(name)) {
+            files.push((name.to_string(), data));
+        }
+    }
+    for path in osquery_logs(&data_dir.join("osquery_logs")) {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if let Some(data) = tail(&path) {
+            files.push((format!("osquery_logs/{}", name.to_string_lossy()), data));
+        }
+    }
+
+    let secrets: Vec<&str> = secrets.iter().copied().filter(|s| !s.is_empty()).collect();
+    let mut files: Vec<(String, Vec<u8>)> = files
+        .into_iter()
+        .map(|(name, data)| (name, redact(data, &secrets)))
+        .collect();
+    let manifest = Manifest {
+        created_at: Utc::now(),
+        agent_version: env!("CARGO_PKG_VERSION"),
+        os_version: sysinfo::System::long_os_version(),
+        kernel_version: sysinfo::System::kernel_version(),
+        arch: std::env::consts::ARCH,
+        crash_artifacts: crate::crash::list(data_dir),
+        files: files.iter().map(|(name, _)| name.clone()).collect(),
+    };
+    files.insert(
+        0,
+        (
+            "manifest.json".to_string(),
+            serde_json::to_vec_pretty(&manifest)?,
+        ),
+    );
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    let mtime = manifest.created_at.timestamp().max(0) as u64;
+    for (name, data) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        archive
+            .append_data(
+                &mut header,
+                format!("shadow-support/{}", name),
+                data.as_slice(),
+            )
+            .context("Failed to build the support bundle")?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+/// The end of a file, or None if it can't be read
+fn tail(path: &Path) -> Option<Vec<u8>> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(MAX_FILE)))
+        .ok()?;
+    let mut data = Vec::new();
+    file.take(MAX_FILE).read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+/// osqueryd's newest status logs. glog's `osqueryd.INFO` and the like are
+/// symlinks to files that are also listed, so they're skipped, as are the
+/// results files written with `--results-file`.
+fn osquery_logs(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut logs: Vec<(std::time::SystemTime, PathBuf)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !metadata.is_file() || RESULTS_FILES.iter().any(|prefix| name.starts_with(prefix)) {
+                return None;
+            }
+            Some((metadata.modified().ok()?, entry.path()))
+        })
+        .collect();
+    logs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    logs.into_iter()
+        .take(OSQUERY_LOGS)
+        .map(|(_, path)| path)
+        .collect()
+}
+
+fn redact(data: Vec<u8>, secrets: &[&str]) -> Vec<u8> {
+    if secrets.is_empty() {
+        return data;
+    }
+    let mut text = String::from_utf8_lossy(&data).into_owned();
+    for secret in secrets {
+        text = text.replace(secret, REDACTED);
+    }
+    text.into_bytes()
+}
+
+/// Write a bundle to a file only its owner can read
+pub async fn write(path: &Path, bundle: &[u8]) -> Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, bundle)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Default filename for a bundle
+pub fn file_name() -> String {
+    format!(
+        "shadow-support-{}.tar.gz",
+        Utc::now().format("%Y%m%d-%H%M%S")
+    )
+}
+
+/// Build a bundle in the running agent and send it to the server, on behalf
+/// of `user`
+pub async fn upload(
+    data_dir: &Path,
+    status: &SharedStatus,
+    server: &str,
+    api: &ApiClient,
+    org_token: Option<&str>,
+    user: &str,
+) -> Result<String> {
+    let checks = doctor::run(data_dir, Some(status), server).await;
+    let secrets: Vec<&str> = org_token.into_iter().chain(api.enroll_secret()).collect();
+    let bundle = collect(data_dir, Some(&status.snapshot()), &checks, &secrets)?;
+    let bytes = bundle.len();
+    api.upload(UPLOAD_PATH, "application/gzip", bundle)
+        .await
+        .context("Failed to upload the support bundle")?;
+    audit::record_by(
+        data_dir,
+        user,
+        "support_bundle_upload",
+        serde_json::json!({ "bytes": bytes }),
+    )
+    .await?;
+    Ok(format!(
+        "Uploaded a support bundle ({} KB) to the server",
+        bytes.div_ceil(1024)
+    ))
+}
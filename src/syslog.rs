@@ -0,0 +1,322 @@
+//! Syslog output
+//!
+//! Optionally copies the agent's log lines to syslog: to the local daemon over
+//! its socket, or to a remote relay as RFC 5424 messages over TCP or TLS, with
+//! octet-counted framing. The facility and the severity each log level maps to
+//! are configurable. Remote messages are queued and sent in the background, so
+//! a slow or unreachable relay never holds up the agent.
+
+use crate::logging::Level;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Messages queued for a remote relay before new ones are dropped
+const QUEUE_SIZE: usize = 1000;
+
+/// Delay between attempts to reach a remote relay
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(300);
+
+/// Where to send syslog messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The local syslog daemon
+    Local,
+    /// A remote relay over plain TCP, as host:port
+    Tcp(String),
+    /// A remote relay over TLS, as host:port
+    Tls(String),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let with_port = |address: &str, port: u16| {
+            if address.is_empty() {
+                Err(format!("missing host in '{}'", s))
+            } else if address.rsplit_once(':').is_some_and(|(_, p)| p.parse::<u16>().is_ok()) {
+                Ok(address.to_string())
+            } else {
+                Ok(format!("{}:{}", address, port))
+            }
+        };
+        if s == "local" {
+            Ok(Target::Local)
+        } else if let Some(address) = s.strip_prefix("tcp://") {
+            with_port(address, 514).map(Target::Tcp)
+        } else if let Some(address) = s.strip_prefix("tls://") {
+            with_port(address, 6514).map(Target::Tls)
+        } else {
+            Err(format!(
+                "expected 'local', 'tcp://host[:port]', or 'tls://host[:port]', got '{}'",
+                s
+            ))
+        }
+    }
+}
+
+/// Syslog facility
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Facility {
+    User = 1,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Syslog severity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Severity {
+    Emerg = 0,
+    Alert = 1,
+    Crit = 2,
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+/// Which syslog severity an agent log level is sent with, as `level=severity`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeverityMapping {
+    level: Level,
+    severity: Severity,
+}
+
+impl FromStr for SeverityMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (level, severity) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected LEVEL=SEVERITY, got '{}'", s))?;
+        let level = match level.trim() {
+            "error" => Level::Error,
+            "warning" => Level::Warning,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            other => return Err(format!("unknown log level '{}' (error, warning, info, debug)", other)),
+        };
+        let severity = Severity::from_str(severity.trim(), true)?;
+        Ok(Self { level, severity })
+    }
+}
+
+/// How to send the agent's logs to syslog
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    pub target: Target,
+    pub facility: Facility,
+    /// Overrides of the default severity for each level
+    pub severities: Vec<SeverityMapping>,
+    /// CA certificate for a TLS relay, in addition to the public roots
+    pub ca_cert: Option<PathBuf>,
+}
+
+struct Sink {
+    facility: Facility,
+    severities: Vec<SeverityMapping>,
+    hostname: String,
+    transport: Transport,
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Remote(mpsc::Sender<Vec<u8>>),
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+impl Sink {
+    fn priority(&self, level: Level) -> u8 {
+        let severity = self
+            .severities
+            .iter()
+            .rev()
+            .find(|mapping| mapping.level == level)
+            .map(|mapping| mapping.severity as u8)
+            .unwrap_or(level as u8);
+        (self.facility as u8) * 8 + severity
+    }
+}
+
+/// Start sending the agent's logs to syslog
+pub async fn start(config: SyslogConfig) -> Result<()> {
+    let transport = match &config.target {
+        Target::Local => local()?,
+        Target::Tcp(address) => remote(address.clone(), None),
+        Target::Tls(address) => {
+            let connector = tls_connector(config.ca_cert.as_deref().as_slice()).await?;
+            remote(address.clone(), Some(connector))
+        }
+    };
+    let hostname = sysinfo::System::host_name().unwrap_or_else(|| "-".to_string());
+    let sink = Sink {
+        facility: config.facility,
+        severities: config.severities,
+        hostname,
+        transport,
+    };
+    if SINK.set(sink).is_err() {
+        anyhow::bail!("syslog output already started");
+    }
+    Ok(())
+}
+
+/// Copy a log line to syslog, if configured
+pub fn send(level: Level, subsystem: &str, message: &str) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let priority = sink.priority(level);
+    match &sink.transport {
+        #[cfg(unix)]
+        Transport::Local(socket) => {
+            // The traditional format, which every local daemon understands
+            let line = format!(
+                "<{}>{} shadow[{}]: {}",
+                priority,
+                chrono::Local::now().format("%b %e %H:%M:%S"),
+                std::process::id(),
+                message
+            );
+            let _ = socket.send(line.as_bytes());
+        }
+        Transport::Remote(queue) => {
+            let line = format!(
+                "<{}>1 {} {} shadow {} {} [origin software=\"shadow\" swVersion=\"{}\"] {}",
+                priority,
+                chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+                sink.hostname,
+                std::process::id(),
+                subsystem,
+                env!("CARGO_PKG_VERSION"),
+                message
+            );
+            let frame = format!("{} {}", line.len(), line);
+            let _ = queue.try_send(frame.into_bytes());
+        }
+    }
+}
+
+#[cfg(unix)]
+fn local() -> Result<Transport> {
+    let path = if cfg!(target_os = "macos") {
+        "/var/run/syslog"
+    } else {
+        "/dev/log"
+    };
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket
+        .connect(path)
+        .with_context(|| format!("Failed to connect to the local syslog daemon at {}", path))?;
+    socket.set_nonblocking(true)?;
+    Ok(Transport::Local(socket))
+}
+
+#[cfg(not(unix))]
+fn local() -> Result<Transport> {
+    anyhow::bail!("There is no local syslog daemon on this platform; use tcp:// or tls://")
+}
+
+pub async fn tls_connector(ca_certs: &[&Path]) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for path in ca_certs {
+        let pem = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        for cert in CertificateDer::pem_slice_iter(&pem) {
+            let cert = cert.with_context(|| format!("Invalid certificate in {}", path.display()))?;
+            roots.add(cert)?;
+        }
+    }
+    let provider = Arc::new(tokio_rustls::rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn remote(address: String, tls: Option<TlsConnector>) -> Transport {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    tokio::spawn(forward(address, tls, rx));
+    Transport::Remote(tx)
+}
+
+pub async fn connect(
+    address: &str,
+    tls: Option<&TlsConnector>,
+) -> Result<Box<dyn AsyncWrite + Send + Unpin>> {
+    let stream = TcpStream::connect(address).await?;
+    let Some(tls) = tls else {
+        return Ok(Box::new(stream));
+    };
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())?;
+    Ok(Box::new(tls.connect(name, stream).await?))
+}
+
+/// Send queued messages to the relay, reconnecting as needed. Errors go
+/// straight to stderr, since logging them would queue more messages.
+async fn forward(address: String, tls: Option<TlsConnector>, mut queue: mpsc::Receiver<Vec<u8>>) {
+    let mut pending = None;
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        let mut stream = match connect(&address, tls.as_ref()).await {
+            Ok(stream) => {
+                delay = RECONNECT_DELAY;
+                stream
+            }
+            Err(e) => {
+                eprintln!("Failed to connect to syslog relay {}: {:#}", address, e);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                continue;
+            }
+        };
+
+        loop {
+            let frame = match pending.take() {
+                Some(frame) => frame,
+                None => match queue.recv().await {
+                    Some(frame) => frame,
+                    None => return,
+                },
+            };
+            let result = async {
+                stream.write_all(&frame).await?;
+                stream.flush().await
+            };
+            if let Err(e) = result.await {
+                eprintln!("Lost connection to syslog relay {}: {}", address, e);
+                pending = Some(frame);
+                break;
+            }
+        }
+    }
+}
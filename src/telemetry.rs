@@ -0,0 +1,375 @@
+//! OpenTelemetry export
+//!
+//! With `--otlp-endpoint`, the agent sends traces and metrics to an
+//! OpenTelemetry collector over OTLP/HTTP, for orgs that already run one.
+//! Traces come from the agent's tracing spans: provisioning osquery, enrolling,
+//! and each run of osqueryd from start to exit, with the agent's log lines as
+//! span events. Metrics are read from the agent's status on an interval,
+//! except for enrollment attempts and osquery downloads, which are recorded as
+//! they happen so rollouts can see where first-boot time goes, and events from
+//! osqueryd's status logs and requests through the TLS relay (see
+//! [`crate::statuslog`] and [`crate::relay`]). The standard
+//! `OTEL_EXPORTER_OTLP_HEADERS` variable is honored for collector
+//! authentication, alongside `--otlp-header`. The same metrics can be sent to
+//! StatsD instead of, or as well as, a collector (see [`crate::statsd`]).
+
+use crate::distributed;
+use crate::resources::ResourceSampler;
+use crate::statsd::{StatsdConfig, StatsdExporter};
+use crate::status::{secs_since, SharedStatus};
+use anyhow::{Context, Result};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_subscriber::{Layer, Registry};
+
+/// A collector header, e.g. for authentication
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=VALUE, got '{}'", s))?;
+        Ok(Header {
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+}
+
+/// Where and how to export
+#[derive(Debug, Clone)]
+pub struct OtlpConfig {
+    /// Base URL of the collector's OTLP/HTTP receiver, e.g. http://localhost:4318
+    pub endpoint: String,
+    pub headers: Vec<Header>,
+    /// How often metrics are exported
+    pub metrics_interval: Duration,
+}
+
+/// Turns tracing spans into OpenTelemetry spans
+pub type TraceLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Exporters, flushed when dropped
+pub struct Telemetry {
+    tracer_provider: Option<SdkTracerProvider>,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = &self.tracer_provider {
+            let _ = tracer_provider.shutdown();
+        }
+        let _ = self.meter_provider.shutdown();
+    }
+}
+
+/// Set up the exporters, returning them with the layer that turns tracing
+/// spans into OpenTelemetry spans when exporting over OTLP
+pub fn init(
+    otlp: Option<&OtlpConfig>,
+    statsd: Option<&StatsdConfig>,
+) -> Result<(Telemetry, Option<TraceLayer>)> {
+    let mut attributes = vec![KeyValue::new("service.version", env!("CARGO_PKG_VERSION"))];
+    if let Some(host_name) = sysinfo::System::host_name() {
+        attributes.push(KeyValue::new("host.name", host_name));
+    }
+    let resource = Resource::builder()
+        .with_service_name("shadow")
+        .with_attributes(attributes)
+        .build();
+
+    let mut meter_provider = SdkMeterProvider::builder().with_resource(resource.clone());
+    let mut tracer_provider = None;
+    if let Some(config) = otlp {
+        let endpoint = config.endpoint.trim_end_matches('/');
+        let headers: HashMap<String, String> = config
+            .headers
+            .iter()
+            .map(|header| (header.name.clone(), header.value.clone()))
+            .collect();
+
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .with_headers(headers.clone())
+            .build()
+            .context("Failed to set up the OTLP trace exporter")?;
+        tracer_provider = Some(
+            SdkTracerProvider::builder()
+                .with_batch_exporter(spans)
+                .with_resource(resource)
+                .build(),
+        );
+
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .with_headers(headers)
+            .build()
+            .context("Failed to set up the OTLP metric exporter")?;
+        let reader = PeriodicReader::builder(metrics)
+            .with_interval(config.metrics_interval)
+            .build();
+        meter_provider = meter_provider.with_reader(reader);
+    }
+    if let Some(config) = statsd {
+        let reader = PeriodicReader::builder(StatsdExporter::new(config.clone()))
+            .with_interval(config.interval)
+            .build();
+        meter_provider = meter_provider.with_reader(reader);
+    }
+    let meter_provider = meter_provider.build();
+    global::set_meter_provider(meter_provider.clone());
+
+    let layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer("shadow"))
+            .boxed()
+    });
+    let telemetry = Telemetry {
+        tracer_provider,
+        meter_provider,
+    };
+    Ok((telemetry, layer))
+}
+
+/// Report the agent's status as metrics. Does nothing unless [`init`] set up
+/// an exporter.
+pub fn observe(status: SharedStatus) {
+    let meter = global::meter("shadow");
+    let sampler = Arc::new(Mutex::new(ResourceSampler::new()));
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_gauge("shadow.osqueryd.up")
+        .with_description("Whether osqueryd is running")
+        .with_callback(move |observer| {
+            observer.observe(snapshot.snapshot().child_pid.is_some() as u64, &[]);
+        })
+        .build();
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_gauge("shadow.agent.state")
+        .with_description("The agent's current state, as 1 with the state as an attribute")
+        .with_callback(move |observer| {
+            let state = snapshot.snapshot().state.to_string();
+            observer.observe(1, &[KeyValue::new("state", state)]);
+        })
+        .build();
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_counter("shadow.osqueryd.restarts")
+        .with_description("Times osqueryd has been restarted")
+        .with_callback(move |observer| observer.observe(snapshot.snapshot().restarts as u64, &[]))
+        .build();
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_gauge("shadow.agent.uptime")
+        .with_description("Time since the agent started")
+        .with_unit("s")
+        .with_callback(move |observer| {
+            observer.observe(secs_since(snapshot.snapshot().agent_started_at), &[]);
+        })
+        .build();
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_gauge("shadow.osquery.database.size")
+        .with_description("Size of osquery's database")
+        .with_unit("By")
+        .with_callback(move |observer| {
+            if let Some(bytes) = snapshot.snapshot().osquery_db_bytes {
+                observer.observe(bytes, &[]);
+            }
+        })
+        .build();
+
+    let snapshot = status.clone();
+    meter
+        .u64_observable_counter("shadow.osquery.log_upload.failures")
+        .with_description("Times osqueryd failed to send results or status logs to the server")
+        .with_callback(move |observer| observer.observe(snapshot.snapshot().log_upload_failures, &[]))
+        .build();
+
+    meter
+        .u64_observable_gauge("shadow.distributed.duration")
+        .with_description("Percentiles of how long recent live queries took, by stage")
+        .with_unit("ms")
+        .with_callback(|observer| {
+            for stage in distributed::Stage::ALL {
+                let Some(percentiles) = distributed::percentiles(stage) else {
+                    continue;
+                };
+                for (quantile, ms) in [
+                    ("p50", percentiles.p50_ms),
+                    ("p95", percentiles.p95_ms),
+                    ("p99", percentiles.p99_ms),
+                ] {
+                    observer.observe(
+                        ms,
+                        &[KeyValue::new("stage", stage.name()), KeyValue::new("quantile", quantile)],
+                    );
+                }
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("shadow.distributed.queries")
+        .with_description("Live queries timed")
+        .with_callback(|observer| observer.observe(distributed::queries(), &[]))
+        .build();
+
+    // Both resource metrics sample the agent and osqueryd's process tree
+    let processes = move |observe: &dyn Fn(&crate::resources::ResourceUsage, &[KeyValue])| {
+        let mut sampler = sampler.lock().unwrap();
+        if let Some(usage) = sampler.sample(std::process::id()) {
+            observe(&usage, &[KeyValue::new("process", "agent")]);
+        }
+        if let Some(usage) = status.snapshot().child_pid.and_then(|pid| sampler.sample_tree(pid)) {
+            observe(&usage, &[KeyValue::new("process", "osqueryd")]);
+        }
+    };
+    let processes = Arc::new(processes);
+
+    let sample = processes.clone();
+    meter
+        .u64_observable_gauge("shadow.process.memory")
+        .with_description("Resident memory of the agent, and of osqueryd with its workers")
+        .with_unit("By")
+        .with_callback(move |observer| {
+            sample(&|usage, attributes| observer.observe(usage.rss_bytes, attributes));
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("shadow.process.cpu.time")
+        .with_description("CPU time used by the agent, and by osqueryd with its workers")
+        .with_unit("ms")
+        .with_callback(move |observer| {
+            processes(&|usage, attributes| observer.observe(usage.cpu_time_ms, attributes));
+        })
+        .build();
+}
+
+/// Record an enrollment attempt, with `status` the HTTP status code or `error`
+/// if the server couldn't be reached
+pub fn record_enroll(duration: Duration, status: &str) {
+    let meter = global::meter("shadow");
+    let attributes = [KeyValue::new("status", status.to_string())];
+    meter
+        .u64_counter("shadow.enroll.attempts")
+        .with_description("Enrollment attempts")
+        .build()
+        .add(1, &attributes);
+    meter
+        .f64_histogram("shadow.enroll.duration")
+        .with_description("Time the server took to answer an enrollment request")
+        .with_unit("s")
+        .build()
+        .record(duration.as_secs_f64(), &attributes);
+}
+
+/// Record an osquery download from `host` (after redirects, so the CDN edge),
+/// with `result` being `ok`, the HTTP status code, or `error`
+pub fn record_download(duration: Duration, bytes: u64, host: &str, result: &str) {
+    let meter = global::meter("shadow");
+    let attributes = [
+        KeyValue::new("server.address", host.to_string()),
+        KeyValue::new("result", result.to_string()),
+    ];
+    meter
+        .f64_histogram("shadow.osquery.download.duration")
+        .with_description("Time taken to download osquery")
+        .with_unit("s")
+        .build()
+        .record(duration.as_secs_f64(), &attributes);
+    meter
+        .u64_counter("shadow.osquery.download.size")
+        .with_description("Bytes of osquery downloaded")
+        .with_unit("By")
+        .build()
+        .add(bytes, &attributes);
+}
+
+/// Record a notable event from osqueryd's status logs, e.g. `config_refresh`
+pub fn record_osqueryd_event(event: &str) {
+    global::meter("shadow")
+        .u64_counter("shadow.osqueryd.events")
+        .with_description("Notable events in osqueryd's status logs")
+        .build()
+        .add(1, &[KeyValue::new("event", event.to_string())]);
+}
+
+/// Record results the TLS relay dropped from a query, with `reason` being
+/// `rate_limited`, `sampled`, or `duplicate`
+pub fn record_dropped_results(query: &str, reason: &str, count: u64) {
+    global::meter("shadow")
+        .u64_counter("shadow.relay.dropped_results")
+        .with_description("Results dropped by rate limits, sampling, or deduplication")
+        .build()
+        .add(
+            count,
+            &[
+                KeyValue::new("query", query.to_string()),
+                KeyValue::new("reason", reason.to_string()),
+            ],
+        );
+}
+
+/// Record an upload the TLS relay held back to stay under a bandwidth limit,
+/// with `category` being `results` or `carves`
+pub fn record_upload_throttled(category: &str, wait: Duration) {
+    global::meter("shadow")
+        .f64_histogram("shadow.relay.throttled")
+        .with_description("Time uploads waited to stay under a bandwidth limit")
+        .with_unit("s")
+        .build()
+        .record(wait.as_secs_f64(), &[KeyValue::new("category", category.to_string())]);
+}
+
+/// Record a request osqueryd made through the TLS relay, with `result` the
+/// server's HTTP status code or `error` if it couldn't be reached
+pub fn record_relay(endpoint: &str, result: &str, duration: Duration, bytes: u64) {
+    let meter = global::meter("shadow");
+    let attributes = [
+        KeyValue::new("endpoint", endpoint.to_string()),
+        KeyValue::new("result", result.to_string()),
+    ];
+    meter
+        .u64_counter("shadow.relay.requests")
+        .with_description("Requests osqueryd made through the TLS relay")
+        .build()
+        .add(1, &attributes);
+    meter
+        .f64_histogram("shadow.relay.duration")
+        .with_description("Time the server took to answer osqueryd's requests")
+        .with_unit("s")
+        .build()
+        .record(duration.as_secs_f64(), &attributes);
+    meter
+        .u64_counter("shadow.relay.size")
+        .with_description("Bytes osqueryd sent to the server")
+        .with_unit("By")
+        .build()
+        .add(bytes, &attributes);
+}
@@ -0,0 +1,70 @@
+//! Unprivileged operation
+//!
+//! On developer laptops IT often can't grant root, but a user-level agent still
+//! sees a lot: the user's processes, installed apps and packages, browser
+//! extensions, listening ports. With `--unprivileged` everything shadow and
+//! osqueryd write lives under the user's data directory, tables and event
+//! subsystems that need root are turned off instead of failing on every query,
+//! and the enrollment says so, so the server knows coverage is reduced.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Tables that need root to return anything useful
+#[cfg(target_os = "linux")]
+const ROOT_TABLES: &[&str] = &[
+    "apparmor_events",
+    "bpf_process_events",
+    "bpf_socket_events",
+    "iptables",
+    "memory_devices",
+    "process_events",
+    "process_file_events",
+    "seccomp_events",
+    "selinux_events",
+    "shadow",
+    "socket_events",
+    "syslog_events",
+    "user_events",
+];
+
+#[cfg(target_os = "macos")]
+const ROOT_TABLES: &[&str] = &[
+    "es_process_events",
+    "es_process_file_events",
+    "sudoers",
+    "system_extensions",
+];
+
+#[cfg(windows)]
+const ROOT_TABLES: &[&str] = &[
+    "bitlocker_info",
+    "ntfs_journal_events",
+    "powershell_events",
+    "windows_events",
+    "windows_security_center",
+];
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+const ROOT_TABLES: &[&str] = &[];
+
+/// osqueryd flags keeping it within the user's data directory and away from
+/// what needs root
+pub fn osqueryd_flags(data_dir: &Path) -> Vec<(&'static str, OsString)> {
+    vec![
+        ("--disable_tables", ROOT_TABLES.join(",").into()),
+        ("--disable_events", "true".into()),
+        // The default extensions socket is under /var/osquery
+        ("--disable_extensions", "true".into()),
+        ("--extensions_socket", data_dir.join("osquery.em").into()),
+    ]
+}
+
+/// Enrollment tags telling the server what this agent can't see
+pub fn enroll_tags() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("shadow.privileges".to_string(), "unprivileged".to_string()),
+        ("shadow.disabled_tables".to_string(), ROOT_TABLES.join(",")),
+    ])
+}
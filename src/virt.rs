@@ -0,0 +1,217 @@
+//! Virtualization platform
+//!
+//! Whether a host is bare metal, a VM, or a container changes what osquery can
+//! see and which defaults make sense: containers share hardware UUIDs and
+//! can't use the kernel audit subsystem. We detect the platform much like
+//! systemd-detect-virt does, report it in the enrollment, and use it to pick
+//! defaults.
+
+use crate::container::{self, Runtime};
+use crate::preflight::LinuxEvents;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// What the host runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    BareMetal,
+    /// A virtual machine, with the hypervisor's systemd-detect-virt identifier
+    Vm(&'static str),
+    Container(Runtime),
+}
+
+impl Platform {
+    /// Identifier in the style of systemd-detect-virt, e.g. "kvm" or "lxc"
+    pub fn id(self) -> &'static str {
+        match self {
+            Platform::BareMetal => "none",
+            Platform::Vm(hypervisor) => hypervisor,
+            Platform::Container(runtime) => runtime.id(),
+        }
+    }
+
+    fn kind(self) -> &'static str {
+        match self {
+            Platform::BareMetal => "none",
+            Platform::Vm(_) => "vm",
+            Platform::Container(_) => "container",
+        }
+    }
+
+    /// The Linux event backend most likely to work here. Kernel audit only
+    /// works in the host's namespaces, so containers get eBPF, except LXC,
+    /// whose containers are usually unprivileged and can't load probes either.
+    pub fn default_events(self) -> LinuxEvents {
+        match self {
+            Platform::Container(Runtime::Lxc) => LinuxEvents::Off,
+            Platform::Container(_) => LinuxEvents::Bpf,
+            Platform::BareMetal | Platform::Vm(_) => LinuxEvents::Audit,
+        }
+    }
+
+    /// Enrollment tags describing the platform
+    pub fn enroll_tags(self) -> BTreeMap<String, String> {
+        let mut tags = BTreeMap::from([
+            ("platform.virtualization".to_string(), self.id().to_string()),
+            ("platform.type".to_string(), self.kind().to_string()),
+        ]);
+        if is_proxmox_host() {
+            tags.insert("platform.proxmox".to_string(), "host".to_string());
+        }
+        tags
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Platform::BareMetal => write!(f, "bare metal"),
+            Platform::Vm(hypervisor) => write!(f, "{} VM", hypervisor),
+            Platform::Container(Runtime::Wsl) => write!(f, "WSL"),
+            Platform::Container(runtime) => write!(f, "{} container", runtime),
+        }
+    }
+}
+
+/// The platform we're running on
+pub fn detect() -> Platform {
+    if let Some(runtime) = container::detect() {
+        return Platform::Container(runtime);
+    }
+    match hypervisor() {
+        Some(hypervisor) => Platform::Vm(hypervisor),
+        None => Platform::BareMetal,
+    }
+}
+
+/// Firmware vendor and product name prefixes set by hypervisors, with their
+/// systemd-detect-virt identifiers
+const VENDORS: &[(&str, &str)] = &[
+    ("KVM", "kvm"),
+    ("OpenStack", "kvm"),
+    ("KubeVirt", "kvm"),
+    ("Amazon EC2", "amazon"),
+    ("QEMU", "qemu"),
+    ("VMware", "vmware"),
+    ("VMW", "vmware"),
+    ("innotek GmbH", "oracle"),
+    ("VirtualBox", "oracle"),
+    ("Xen", "xen"),
+    ("Bochs", "bochs"),
+    ("Parallels", "parallels"),
+    ("BHYVE", "bhyve"),
+    ("Google Compute Engine", "google"),
+    ("Apple Virtualization", "apple"),
+    ("VirtualMac", "apple"),
+];
+
+/// Identify the hypervisor from firmware (DMI/SMBIOS) strings
+#[cfg_attr(not(any(target_os = "linux", target_os = "macos", windows)), allow(dead_code))]
+fn from_firmware(values: &[String]) -> Option<&'static str> {
+    for value in values {
+        if let Some((_, id)) = VENDORS.iter().find(|(prefix, _)| value.starts_with(prefix)) {
+            return Some(id);
+        }
+    }
+    // Hyper-V and Azure, but not Microsoft's own hardware
+    let has = |expected: &str| values.iter().any(|value| value == expected);
+    (has("Microsoft Corporation") && has("Virtual Machine")).then_some("microsoft")
+}
+
+#[cfg(target_os = "linux")]
+fn hypervisor() -> Option<&'static str> {
+    use std::path::Path;
+
+    let read = |path: &str| std::fs::read_to_string(path).unwrap_or_default();
+    let firmware: Vec<String> = ["sys_vendor", "product_name", "board_vendor", "bios_vendor"]
+        .iter()
+        .map(|field| read(&format!("/sys/class/dmi/id/{}", field)).trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect();
+    if let Some(hypervisor) = from_firmware(&firmware) {
+        return Some(hypervisor);
+    }
+    // Xen guests without DMI; dom0 runs the hypervisor rather than under it
+    if Path::new("/proc/xen").exists() {
+        return (!read("/proc/xen/capabilities").contains("control_d")).then_some("xen");
+    }
+    // ARM guests are described in the device tree
+    let compatible = read("/proc/device-tree/hypervisor/compatible");
+    if compatible.contains("linux,kvm") {
+        return Some("kvm");
+    }
+    if compatible.contains("xen") {
+        return Some("xen");
+    }
+    // The CPU says it's virtualized, but not by whom
+    read("/proc/cpuinfo")
+        .lines()
+        .any(|line| line.starts_with("flags") && line.split_whitespace().any(|flag| flag == "hypervisor"))
+        .then_some("vm-other")
+}
+
+#[cfg(target_os = "macos")]
+fn hypervisor() -> Option<&'static str> {
+    let sysctl = |name: &str| {
+        std::process::Command::new("sysctl")
+            .args(["-n", name])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_default()
+    };
+    if sysctl("kern.hv_vmm_present") != "1" {
+        return None;
+    }
+    // e.g. VMware7,1, Parallels-ARM, VirtualMac2,1
+    Some(from_firmware(&[sysctl("hw.model")]).unwrap_or("vm-other"))
+}
+
+#[cfg(windows)]
+fn hypervisor() -> Option<&'static str> {
+    use windows_sys::Win32::Foundation::ERROR_SUCCESS;
+    use windows_sys::Win32::System::Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ};
+
+    fn wide(value: &str) -> Vec<u16> {
+        value.encode_utf16().chain(std::iter::once(0)).collect()
+    }
+
+    let key = wide(r"HARDWARE\DESCRIPTION\System\BIOS");
+    let read = |name: &str| {
+        let name = wide(name);
+        let mut buffer = [0u16; 256];
+        let mut size = std::mem::size_of_val(&buffer) as u32;
+        // SAFETY: the key and value names are NUL-terminated, and `size` is
+        // the size of `buffer` in bytes
+        let status = unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                key.as_ptr(),
+                name.as_ptr(),
+                RRF_RT_REG_SZ,
+                std::ptr::null_mut(),
+                buffer.as_mut_ptr().cast(),
+                &mut size,
+            )
+        };
+        if status != ERROR_SUCCESS {
+            return None;
+        }
+        let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        Some(String::from_utf16_lossy(&buffer[..len]).trim().to_string())
+    };
+    let firmware: Vec<String> = ["SystemManufacturer", "SystemProductName", "BaseBoardManufacturer", "BIOSVendor"]
+        .iter()
+        .filter_map(|name| read(name))
+        .collect();
+    from_firmware(&firmware)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn hypervisor() -> Option<&'static str> {
+    None
+}
+
+/// Proxmox VE keeps its cluster configuration in /etc/pve
+fn is_proxmox_host() -> bool {
+    cfg!(target_os = "linux") && std::path::Path::new("/etc/pve").is_dir()
+}
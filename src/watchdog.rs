@@ -0,0 +1,251 @@
+//! osquery watchdog monitoring
+//!
+//! osqueryd runs its worker under a watcher process that kills the worker when it
+//! exceeds the configured CPU or memory limits. The watcher logs the kill to stderr,
+//! and the replacement worker logs which scheduled query was in flight at the time.
+//! We scan osqueryd's stderr for both and report the incident to the server.
+//!
+//! Config and TLS errors are picked up along the way so the supervisor can tell
+//! why osqueryd exited, as are failures to send results and status logs, which
+//! osqueryd buffers in its database until the server accepts them.
+
+use crate::api::ApiClient;
+use crate::logging::{self, error, info};
+use crate::status::SharedStatus;
+use serde::Serialize;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+/// How long to wait for the new worker to name the query that was running
+const QUERY_WAIT: Duration = Duration::from_secs(60);
+
+/// Which watchdog limit the worker breached
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogLimit {
+    Memory,
+    Cpu,
+    Other,
+}
+
+impl fmt::Display for WatchdogLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchdogLimit::Memory => write!(f, "memory"),
+            WatchdogLimit::Cpu => write!(f, "cpu"),
+            WatchdogLimit::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A worker kill observed in osqueryd's logs
+#[derive(Debug, Clone, Serialize)]
+pub struct WatchdogKill {
+    pub host_id: String,
+    pub worker_pid: Option<u32>,
+    pub limit: WatchdogLimit,
+    pub detail: String,
+    pub query: Option<String>,
+}
+
+/// Log messages indicating osqueryd can't get its config or reach the server
+const CONFIG_ERROR_MARKERS: &[&str] = &[
+    "Failed enrollment request",
+    "Error reading config",
+    "Cannot request config",
+    "TLS/HTTPS POST request to URI",
+    "TLS/HTTPS GET request to URI",
+];
+
+/// Log messages showing osqueryd got its config after failing to
+const CONFIG_OK_MARKERS: &[&str] = &["Normal configuration delay restored"];
+
+/// How close to an exit a config error has to be for it to explain the exit.
+/// A failed request during a brief outage hours earlier doesn't.
+const CONFIG_ERROR_WINDOW: Duration = Duration::from_secs(60);
+
+/// Log messages from osqueryd's log forwarder failing to send to the server
+const LOG_UPLOAD_MARKERS: &[&str] = &[
+    "Error sending results to logger",
+    "Error sending status to logger",
+];
+
+/// osqueryd's logger endpoint, named in its TLS errors
+const LOGGER_ENDPOINT: &str = "/api/osquery/log";
+
+/// What was observed on osqueryd's stderr during one run
+#[derive(Debug, Default)]
+pub struct LogSummary {
+    /// Number of watchdog worker kills
+    pub watchdog_kills: usize,
+    /// Most recent config or TLS error message, and when it was logged.
+    /// Cleared once osqueryd logs getting its config again.
+    last_config_error: Option<(Instant, String)>,
+}
+
+impl LogSummary {
+    /// The config or TLS error logged shortly before osqueryd exited, if any
+    pub fn recent_config_error(&self) -> Option<&str> {
+        self.last_config_error
+            .as_ref()
+            .filter(|(at, _)| at.elapsed() <= CONFIG_ERROR_WINDOW)
+            .map(|(_, message)| message.as_str())
+    }
+}
+
+/// Interesting osqueryd log lines
+#[derive(Debug, PartialEq, Eq)]
+enum LogEvent {
+    /// `osqueryd worker (1234) stopping: Memory limits exceeded: 312000000`
+    WorkerStopped {
+        pid: Option<u32>,
+        limit: WatchdogLimit,
+        detail: String,
+    },
+    /// `Scheduled query may have failed: pack_foo_bar`
+    QueryMayHaveFailed(String),
+    /// Enrollment, config fetch, or TLS failure
+    ConfigError(String),
+    /// Config fetched again after failing
+    ConfigOk,
+    /// Results or status logs couldn't be sent
+    LogUploadFailed(String),
+}
+
+/// Strip the glog prefix (`W0102 03:04:05.678901 1234 watcher.cpp:123] `)
+fn log_message(line: &str) -> &str {
+    match line.find("] ") {
+        Some(idx) => &line[idx + 2..],
+        None => line,
+    }
+}
+
+fn parse_line(line: &str) -> Option<LogEvent> {
+    let message = log_message(line);
+
+    if let Some(query) = message.strip_prefix("Scheduled query may have failed: ") {
+        return Some(LogEvent::QueryMayHaveFailed(query.trim().to_string()));
+    }
+
+    if LOG_UPLOAD_MARKERS.iter().any(|m| message.contains(m))
+        || message.contains("TLS/HTTPS POST request to URI") && message.contains(LOGGER_ENDPOINT)
+    {
+        return Some(LogEvent::LogUploadFailed(message.trim().to_string()));
+    }
+
+    if CONFIG_ERROR_MARKERS.iter().any(|m| message.contains(m)) {
+        return Some(LogEvent::ConfigError(message.trim().to_string()));
+    }
+
+    if CONFIG_OK_MARKERS.iter().any(|m| message.contains(m)) {
+        return Some(LogEvent::ConfigOk);
+    }
+
+    let rest = message.strip_prefix("osqueryd worker (")?;
+    let (pid, reason) = rest.split_once(") stopping: ")?;
+    let limit = if reason.starts_with("Memory limits exceeded") {
+        WatchdogLimit::Memory
+    } else if reason.starts_with("Maximum sustainable CPU utilization limit exceeded") {
+        WatchdogLimit::Cpu
+    } else {
+        WatchdogLimit::Other
+    };
+
+    Some(LogEvent::WorkerStopped {
+        pid: pid.parse().ok(),
+        limit,
+        detail: reason.trim().to_string(),
+    })
+}
+
+/// Log a kill and post it to the server, if there is one, in the background,
+/// so osqueryd's stderr keeps being drained while it's slow or unreachable
+fn report(server: Option<&ApiClient>, kill: WatchdogKill) {
+    info!(
+        "osquery watchdog killed worker ({} limit): {} [query: {}]",
+        kill.limit,
+        kill.detail,
+        kill.query.as_deref().unwrap_or("unknown")
+    );
+
+    let Some(api) = server.cloned() else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(e) = api.post("/api/shadow/watchdog", &kill).await {
+            error!("Failed to report watchdog kill: {:#}", e);
+        }
+    });
+}
+
+/// Forward osqueryd's stderr to our own while watching for watchdog kills,
+/// which are posted to the server unless `standalone`
+pub async fn monitor_stderr<R: AsyncRead + Unpin>(
+    stderr: R,
+    api: ApiClient,
+    standalone: bool,
+    status: SharedStatus,
+) -> LogSummary {
+    let server = (!standalone).then_some(&api);
+    let mut lines = BufReader::new(stderr).lines();
+    let mut pending: Option<WatchdogKill> = None;
+    let mut summary = LogSummary::default();
+
+    loop {
+        let next = if pending.is_some() {
+            match tokio::time::timeout(QUERY_WAIT, lines.next_line()).await {
+                Ok(next) => next,
+                Err(_) => {
+                    // No query name logged in time, report what we have
+                    if let Some(kill) = pending.take() {
+                        report(server, kill);
+                    }
+                    continue;
+                }
+            }
+        } else {
+            lines.next_line().await
+        };
+
+        let line = match next {
+            Ok(Some(line)) => line,
+            Ok(None) | Err(_) => break,
+        };
+        logging::osqueryd(&line);
+
+        match parse_line(&line) {
+            Some(LogEvent::WorkerStopped { pid, limit, detail }) => {
+                summary.watchdog_kills += 1;
+                if let Some(kill) = pending.take() {
+                    report(server, kill);
+                }
+                pending = Some(WatchdogKill {
+                    host_id: api.host_id().to_string(),
+                    worker_pid: pid,
+                    limit,
+                    detail,
+                    query: None,
+                });
+            }
+            Some(LogEvent::QueryMayHaveFailed(query)) => {
+                if let Some(mut kill) = pending.take() {
+                    kill.query = Some(query);
+                    report(server, kill);
+                }
+            }
+            Some(LogEvent::ConfigError(message)) => {
+                summary.last_config_error = Some((Instant::now(), message));
+            }
+            Some(LogEvent::ConfigOk) => summary.last_config_error = None,
+            Some(LogEvent::LogUploadFailed(message)) => status.log_upload_failed(message),
+            None => {}
+        }
+    }
+
+    if let Some(kill) = pending.take() {
+        report(server, kill);
+    }
+
+    summary
+}
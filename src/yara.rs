@@ -0,0 +1,289 @@
+//! YARA rule synchronization
+//!
+//! osquery's `yara` table and `yara_events` match files against rules named
+//! in the config, but the rule files have to be on the host already. With
+//! `--yara-sync` and the TLS relay, the agent fetches the server's rule bundle
+//! (a gzipped tar of `.yar` files) periodically, checks its SHA-256 and its
+//! signature by `--yara-key`, and unpacks it into a directory of its own
+//! under `yara/` in the data directory. A `current` file names the directory
+//! in use and is replaced in one rename, so osqueryd never sees half a bundle;
+//! the previous directory is kept until the next bundle, for a config still
+//! naming it. The relay adds each rule file to the `yara.signatures` of every
+//! config osqueryd fetches, as a signature group named after the file, and
+//! osqueryd is restarted to load new rules.
+
+use crate::api::ApiClient;
+use crate::audit;
+use crate::logging::{error, info, warning};
+use crate::remote::ServerKey;
+use crate::status::SharedStatus;
+use crate::supervisor::{self, Action};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// Where the agent asks for rules
+const BUNDLE_PATH: &str = "/api/shadow/yara/bundle";
+
+/// Directory in the data directory
+const DIR_NAME: &str = "yara";
+
+/// Names the directory in use
+const CURRENT_FILE: &str = "current";
+
+/// Largest bundle, unpacked
+const MAX_BUNDLE: u64 = 64 * 1024 * 1024;
+
+/// Extensions of rule files
+const EXTENSIONS: &[&str] = &["yar", "yara"];
+
+/// The server's reply: nothing if the host has the latest rules
+#[derive(Debug, Default, Deserialize)]
+struct Bundle {
+    #[serde(default)]
+    version: String,
+    /// Gzipped tar of rule files, in base64
+    bundle: Option<String>,
+    /// SHA-256 of the bundle, in hex
+    #[serde(default)]
+    sha256: String,
+    /// Ed25519 signature of "VERSION\nSHA256", in base64
+    #[serde(default)]
+    signature: String,
+}
+
+/// The synced rules on disk
+pub struct Rules {
+    dir: PathBuf,
+}
+
+impl Rules {
+    pub fn new(data_dir: &Path) -> Self {
+        Rules {
+            dir: data_dir.join(DIR_NAME),
+        }
+    }
+
+    /// The directory in use and its bundle's version, if there is one
+    fn current(&self) -> Option<(PathBuf, String)> {
+        let name = std::fs::read_to_string(self.dir.join(CURRENT_FILE)).ok()?;
+        let (name, version) = name.trim().split_once('\n').unwrap_or((name.trim(), ""));
+        Some((self.dir.join(name), version.to_string()))
+    }
+
+    /// Rule files in use, by signature group
+    fn signatures(&self) -> BTreeMap<String, Vec<String>> {
+        let Some((dir, _)) = self.current() else {
+            return BTreeMap::new();
+        };
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return BTreeMap::new();
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.path();
+                let group = path.file_stem()?.to_str()?.to_string();
+                Some((group, vec![path.to_str()?.to_string()]))
+            })
+            .collect()
+    }
+
+    /// Add the rule files to a config from the server, replacing any of its
+    /// signature groups of the same names
+    pub fn inject(&self, body: &[u8]) -> Result<Vec<u8>> {
+        let signatures = self.signatures();
+        if signatures.is_empty() {
+            return Ok(body.to_vec());
+        }
+        let mut config: Value = serde_json::from_slice(body).context("Config isn't JSON")?;
+        let Some(config_map) = config.as_object_mut() else {
+            anyhow::bail!("Config isn't a JSON object");
+        };
+        let yara = config_map
+            .entry("yara")
+            .or_insert_with(|| serde_json::json!({}));
+        if !yara.is_object() {
+            *yara = serde_json::json!({});
+        }
+        let groups = yara
+            .as_object_mut()
+            .map(|yara| yara.entry("signatures").or_insert_with(|| serde_json::json!({})));
+        if let Some(groups) = groups {
+            if !groups.is_object() {
+                *groups = serde_json::json!({});
+            }
+            if let Some(groups) = groups.as_object_mut() {
+                for (group, files) in signatures {
+                    groups.insert(group, serde_json::json!(files));
+                }
+            }
+        }
+        Ok(serde_json::to_vec(&config)?)
+    }
+
+    /// Check a bundle and make it the one in use, returning its rule files
+    fn install(&self, key: &ServerKey, bundle: &Bundle, data: &[u8]) -> Result<Vec<String>> {
+        let sha256 = format!("{:x}", Sha256::digest(data));
+        if !sha256.eq_ignore_ascii_case(bundle.sha256.trim()) {
+            anyhow::bail!("Bundle's SHA-256 is {}, not {} as the server says", sha256, bundle.sha256);
+        }
+        let signature = BASE64.decode(&bundle.signature).context("Signature isn't base64")?;
+        if !key.verify(format!("{}\n{}", bundle.version, bundle.sha256).as_bytes(), &signature) {
+            anyhow::bail!("Bundle's signature doesn't match --yara-key");
+        }
+
+        let name = sha256[..16].to_string();
+        let target = self.dir.join(&name);
+        let temp = self.dir.join(format!("{}.tmp", name));
+        let _ = std::fs::remove_dir_all(&temp);
+        std::fs::create_dir_all(&temp).with_context(|| format!("Failed to create {}", temp.display()))?;
+        let files = unpack(data, &temp);
+        let files = match files {
+            Ok(files) if !files.is_empty() => files,
+            Ok(_) => {
+                let _ = std::fs::remove_dir_all(&temp);
+                anyhow::bail!("Bundle has no rule files");
+            }
+            Err(e) => {
+                let _ = std::fs::remove_dir_all(&temp);
+                return Err(e);
+            }
+        };
+        let _ = std::fs::remove_dir_all(&target);
+        std::fs::rename(&temp, &target).with_context(|| format!("Failed to create {}", target.display()))?;
+
+        // The swap itself
+        let previous = self.current().map(|(dir, _)| dir);
+        let current = self.dir.join(CURRENT_FILE);
+        let current_temp = self.dir.join(format!("{}.tmp", CURRENT_FILE));
+        std::fs::write(&current_temp, format!("{}\n{}\n", name, bundle.version))
+            .and_then(|_| std::fs::rename(&current_temp, &current))
+            .with_context(|| format!("Failed to write {}", current.display()))?;
+
+        // Older directories than the previous one have no config naming them
+        if let Ok(entries) = std::fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() && path != target && Some(&path) != previous.as_ref() {
+                    let _ = std::fs::remove_dir_all(&path);
+                }
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Unpack a bundle's rule files into `dir`, refusing anything but plain files
+/// at the top level
+fn unpack(data: &[u8], dir: &Path) -> Result<Vec<String>> {
+    let decoder = flate2::read::GzDecoder::new(data).take(MAX_BUNDLE);
+    let mut archive = tar::Archive::new(decoder);
+    let mut files = Vec::new();
+    for entry in archive.entries().context("Bundle isn't a gzipped tar")? {
+        let mut entry = entry.context("Bundle is corrupt")?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let path = entry.path().context("Bundle is corrupt")?.into_owned();
+        let path = path.strip_prefix("./").unwrap_or(&path);
+        let name = path
+            .to_str()
+            .filter(|name| !name.contains(['/', '\\']) && !name.starts_with('.'))
+            .with_context(|| format!("Bundle has {} outside its top level", path.display()))?;
+        if !entry.header().entry_type().is_file() {
+            anyhow::bail!("Bundle has {}, which isn't a plain file", name);
+        }
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if !EXTENSIONS.contains(&extension) {
+            warning!("Skipping {} in the YARA rule bundle, which isn't a .yar file", name);
+            continue;
+        }
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents).context("Bundle is corrupt")?;
+        std::fs::write(dir.join(name), contents).with_context(|| format!("Failed to write {}", name))?;
+        files.push(name.to_string());
+    }
+    Ok(files)
+}
+
+/// Keep the rules in step with the server's until the agent exits, restarting
+/// osqueryd through the supervisor's `requests` when they change
+pub async fn run(
+    api: ApiClient,
+    rules: Arc<Rules>,
+    key: ServerKey,
+    interval: Duration,
+    status: SharedStatus,
+    data_dir: PathBuf,
+    requests: mpsc::Sender<supervisor::Request>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        let version = rules.current().map(|(_, version)| version);
+        let request = serde_json::json!({ "host_id": api.host_id(), "version": version });
+        let result = match api.post_for::<_, Bundle>(BUNDLE_PATH, &request).await {
+            Ok(Bundle { bundle: None, .. }) => Ok(None),
+            Ok(bundle) => {
+                let rules = rules.clone();
+                let key = key.clone();
+                tokio::task::spawn_blocking(move || {
+                    let data = BASE64
+                        .decode(bundle.bundle.as_deref().unwrap_or_default())
+                        .context("Bundle isn't base64")?;
+                    rules.install(&key, &bundle, &data).map(|files| Some((bundle, files)))
+                })
+                .await
+                .unwrap_or_else(|e| Err(anyhow::anyhow!("Failed to install the YARA rules: {}", e)))
+            }
+            Err(e) => Err(e),
+        };
+        let installed = match result {
+            Ok(installed) => {
+                if failing {
+                    info!("YARA rules are syncing again");
+                    failing = false;
+                }
+                installed
+            }
+            Err(e) => {
+                if !failing {
+                    error!("Failed to sync YARA rules: {:#}", e);
+                    failing = true;
+                }
+                continue;
+            }
+        };
+        let Some((bundle, files)) = installed else {
+            continue;
+        };
+        info!("Installed YARA rules {} ({} files)", bundle.version, files.len());
+        audit::record_agent(
+            &data_dir,
+            "yara_rules",
+            serde_json::json!({ "version": bundle.version, "sha256": bundle.sha256, "files": files }),
+        )
+        .await;
+        // A paused osqueryd loads them when it's started again
+        if !status.snapshot().paused() {
+            let (reply, _) = oneshot::channel();
+            let request = supervisor::Request {
+                action: Action::Reload,
+                requested_by: "YARA rule sync".to_string(),
+                reply,
+            };
+            let _ = requests.send(request).await;
+        }
+    }
+}